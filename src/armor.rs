@@ -0,0 +1,123 @@
+//! ASCII armor for encrypted files, so they can be pasted into emails, chat messages, and the like.
+//! An armored file is the whole binary file (header and body), base64-encoded in lines of 64
+//! characters, between [`ARMOR_BEGIN`] and [`ARMOR_END`] lines.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use std::io::{self, BufRead, Read, Write};
+
+/// The line every armored file starts with.
+pub const ARMOR_BEGIN: &str = "-----BEGIN CYST ENCRYPTED FILE-----";
+/// The line every armored file ends with.
+pub const ARMOR_END: &str = "-----END CYST ENCRYPTED FILE-----";
+/// The number of bytes encoded on each line (which makes 64 characters of base64).
+const BYTES_PER_LINE: usize = 48;
+
+/// A writer that armors everything written to it. [`ArmoredWriter::finish`] must be called once
+/// everything has been written, or the output will be incomplete.
+pub struct ArmoredWriter<W: Write> {
+    inner: W,
+    /// Bytes that haven't filled a whole line yet.
+    buf: Vec<u8>,
+}
+impl<W: Write> ArmoredWriter<W> {
+    /// Creates a new armored writer, writing the [`ARMOR_BEGIN`] line straight away.
+    pub fn new(mut inner: W) -> io::Result<Self> {
+        writeln!(inner, "{ARMOR_BEGIN}")?;
+        Ok(Self {
+            inner,
+            buf: Vec::with_capacity(BYTES_PER_LINE),
+        })
+    }
+
+    /// Writes out whatever's left over and the [`ARMOR_END`] line, returning the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        if !self.buf.is_empty() {
+            writeln!(self.inner, "{}", STANDARD.encode(&self.buf))?;
+        }
+        writeln!(self.inner, "{ARMOR_END}")?;
+        self.inner.flush()?;
+
+        Ok(self.inner)
+    }
+}
+impl<W: Write> Write for ArmoredWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        let full_len = self.buf.len() - self.buf.len() % BYTES_PER_LINE;
+        for line in self.buf[..full_len].chunks(BYTES_PER_LINE) {
+            writeln!(self.inner, "{}", STANDARD.encode(line))?;
+        }
+        self.buf.drain(..full_len);
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A reader that removes the armor from an armored file, giving the binary file inside. Lines can
+/// be any length (as long as each is valid base64 on its own), and Windows line endings are
+/// accepted. This fails if the [`ARMOR_END`] line is missing, since the file has been truncated.
+pub struct ArmoredReader<R: BufRead> {
+    inner: R,
+    /// Bytes decoded from the last line that haven't been read yet.
+    buf: Vec<u8>,
+    /// How many bytes of `buf` have already been read.
+    pos: usize,
+    /// Whether we've reached the end line.
+    done: bool,
+}
+impl<R: BufRead> ArmoredReader<R> {
+    /// Creates a new armored reader, checking that the input starts with the [`ARMOR_BEGIN`]
+    /// line.
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let mut line = String::new();
+        inner.read_line(&mut line)?;
+        if line.trim_end() != ARMOR_BEGIN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "armored data doesn't start with the right line",
+            ));
+        }
+
+        Ok(Self {
+            inner,
+            buf: Vec::new(),
+            pos: 0,
+            done: false,
+        })
+    }
+}
+impl<R: BufRead> Read for ArmoredReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() && !self.done {
+            let mut line = String::new();
+            if self.inner.read_line(&mut line)? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "armored data is missing its end line (truncated)",
+                ));
+            }
+            let line = line.trim();
+            if line == ARMOR_END {
+                self.done = true;
+            } else {
+                self.buf = STANDARD.decode(line).map_err(|err| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("invalid base64 in armored data: {err}"),
+                    )
+                })?;
+                self.pos = 0;
+            }
+        }
+
+        let read = buf.len().min(self.buf.len() - self.pos);
+        buf[..read].copy_from_slice(&self.buf[self.pos..self.pos + read]);
+        self.pos += read;
+
+        Ok(read)
+    }
+}
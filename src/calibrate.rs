@@ -0,0 +1,104 @@
+use anyhow::{anyhow, Result};
+use argon2::{Algorithm, Argon2, Params, Version};
+use cyst::Prompter;
+use std::time::{Duration, Instant};
+
+/// The least memory we'll suggest using, in MiB. Below this, Argon2 loses most of its resistance to
+/// GPU attacks, so it's better to go over the target duration.
+const MIN_MEMORY_MIB: u32 = 8;
+/// The most iterations we'll try, so a very long target can't keep us searching forever.
+const MAX_ITERATIONS: u32 = 1 << 16;
+
+/// Argon2id parameters suggested by [`calibrate`], and how long they took on this machine.
+pub struct Calibration {
+    /// The memory cost, in MiB.
+    pub memory_mib: u32,
+    /// The number of iterations.
+    pub iterations: u32,
+    /// How long a derivation with these parameters took.
+    pub duration: Duration,
+}
+
+/// Finds Argon2id parameters that take as close to the target duration as possible on this
+/// machine, without going over it.
+///
+/// The memory cost is kept at the given amount if a single iteration fits in the target, and
+/// halved until it does otherwise (down to [`MIN_MEMORY_MIB`]). The number of iterations is then
+/// found by doubling it until the target is passed, and binary searching between the last two
+/// counts, timing a real derivation for each candidate.
+pub fn calibrate(
+    target: Duration,
+    mut memory_mib: u32,
+    prompter: &mut dyn Prompter,
+) -> Result<Calibration> {
+    let mut time = |memory_mib: u32, iterations: u32| -> Result<Duration> {
+        let duration = time_argon2(memory_mib, iterations)?;
+        prompter.detail(&format!(
+            "{memory_mib} MiB with {iterations} iteration(s) took {:.3}s.",
+            duration.as_secs_f64()
+        ));
+        Ok(duration)
+    };
+
+    // Find how much memory we can afford with a single iteration
+    let mut duration = time(memory_mib, 1)?;
+    while duration > target && memory_mib > MIN_MEMORY_MIB {
+        memory_mib = (memory_mib / 2).max(MIN_MEMORY_MIB);
+        duration = time(memory_mib, 1)?;
+    }
+    if duration > target {
+        return Ok(Calibration {
+            memory_mib,
+            iterations: 1,
+            duration,
+        });
+    }
+
+    // Double the iterations until we go over the target, then search between the last two counts
+    let (mut low, mut low_duration) = (1, duration);
+    let mut high = None;
+    while high.is_none() && low < MAX_ITERATIONS {
+        let iterations = low * 2;
+        let duration = time(memory_mib, iterations)?;
+        if duration > target {
+            high = Some(iterations);
+        } else {
+            (low, low_duration) = (iterations, duration);
+        }
+    }
+    if let Some(mut high) = high {
+        while high - low > 1 {
+            let iterations = low + (high - low) / 2;
+            let duration = time(memory_mib, iterations)?;
+            if duration > target {
+                high = iterations;
+            } else {
+                (low, low_duration) = (iterations, duration);
+            }
+        }
+    }
+
+    Ok(Calibration {
+        memory_mib,
+        iterations: low,
+        duration: low_duration,
+    })
+}
+
+/// Times a single Argon2id derivation with the given parameters.
+fn time_argon2(memory_mib: u32, iterations: u32) -> Result<Duration> {
+    let memory_kib = memory_mib
+        .checked_mul(1024)
+        .ok_or(anyhow!("{memory_mib} MiB is too much memory for Argon2"))?;
+    let params = Params::new(memory_kib, iterations, 1, Some(32))
+        .map_err(|err| anyhow!("invalid Argon2 parameters: {err}"))?;
+    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+
+    let mut key = [0u8; 32];
+    let start = Instant::now();
+    argon2
+        .hash_password_into(b"cyst calibration", &[0u8; 32], &mut key)
+        .map_err(|err| anyhow!("failed to run Argon2: {err}"))?;
+
+    Ok(start.elapsed())
+}
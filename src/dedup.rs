@@ -0,0 +1,483 @@
+//! A dedup-friendly alternative to the usual STREAM body, for files that will be stored by
+//! deduplicating backup tools (like borg or restic).
+//!
+//! With STREAM, every chunk is chained to its position, and a fresh primary key is generated for
+//! every file, so changing a single byte of plaintext (or just encrypting it again) changes the
+//! whole ciphertext. In dedup mode, the plaintext is instead split into chunks at content-defined
+//! boundaries (found with a rolling hash), and each chunk is encrypted independently. An edit only
+//! changes the chunks around it, and encrypting a new version of a file with the header and key
+//! of the previous one (recovered with [`crate::Header::to_dedup_cipher`]) leaves its unchanged
+//! chunks exactly as they were.
+//!
+//! This comes with real security tradeoffs, which is why it's opt-in:
+//!
+//! - Anyone who sees two versions encrypted with the same key can tell which chunks are the same
+//!   in both, and roughly where the edits were (which is exactly what lets them be deduplicated).
+//! - The length of every chunk is visible, and the boundaries depend on the plaintext, so the
+//!   pattern of chunk lengths can fingerprint known files.
+//! - Each chunk's nonce is derived from a secret key, the chunk's index, and its plaintext, so
+//!   encrypting different plaintext in the same place never reuses a nonce. It also means the same
+//!   chunk in the same place always encrypts to the same ciphertext.
+//! - Each chunk is bound to its index and the header (and the last chunk is marked as such), so
+//!   chunks can't be reordered, dropped, or appended within one file. But chunks from different
+//!   versions encrypted with the same key *can* be swapped for each other at the same index,
+//!   producing a valid mix of the two versions.
+//! - Every version encrypted with the same key shares the same options, and satisfying any of them
+//!   decrypts all the versions.
+
+use crate::{
+    error::authentication_failed,
+    file::{read_full, ByteCounter, Progress},
+    header::Header,
+};
+use anyhow::{anyhow, bail, Result};
+use chacha20poly1305::{aead::Aead, aead::Payload, ChaCha20Poly1305, KeyInit};
+use log::debug;
+use ring::{hkdf, hmac};
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+
+/// The number of bytes stored before each chunk's ciphertext: its nonce, and then the length of
+/// its ciphertext (as a little-endian `u32`).
+const CHUNK_PREFIX_LEN: usize = 12 + 4;
+/// The overhead of the AEAD tag on each chunk.
+const TAG_LEN: u64 = 16;
+
+/// The parameters for splitting plaintext into content-defined chunks. These are stored in the
+/// header, so a new version of a file can be chunked in exactly the same way.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct DedupParams {
+    /// The smallest a chunk can be (unless it's the last one).
+    pub min_size: u32,
+    /// The number of bits of the rolling hash that must be zero at a boundary, which makes chunks
+    /// `2^avg_bits` bytes long on average.
+    pub avg_bits: u8,
+    /// The largest a chunk can be.
+    pub max_size: u32,
+}
+impl Default for DedupParams {
+    fn default() -> Self {
+        Self {
+            min_size: 16 * 1024,
+            avg_bits: 16,
+            max_size: 256 * 1024,
+        }
+    }
+}
+
+/// The keys for encrypting and decrypting the independent chunks of a dedup-mode body, derived
+/// from the primary key.
+pub struct DedupCipher {
+    /// The cipher each chunk is encrypted with.
+    cipher: ChaCha20Poly1305,
+    /// The key used to derive each chunk's nonce.
+    nonce_key: hmac::Key,
+    /// The largest a chunk's ciphertext can be, given the largest chunk the plaintext was split
+    /// into.
+    max_chunk_len: u64,
+}
+impl DedupCipher {
+    /// Derives the chunk keys from the primary key, for a body split with the given parameters.
+    pub(crate) fn new(primary_key: &[u8], params: DedupParams) -> Self {
+        let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, &[]).extract(primary_key);
+        let mut cipher_key = [0u8; 32];
+        prk.expand(&[b"cyst dedup chunk key"], hkdf::HKDF_SHA256)
+            .and_then(|okm| okm.fill(&mut cipher_key))
+            .unwrap();
+        let nonce_key = prk
+            .expand(&[b"cyst dedup nonce key"], hmac::HMAC_SHA256)
+            .unwrap()
+            .into();
+
+        Self {
+            cipher: ChaCha20Poly1305::new(cipher_key.as_ref().into()),
+            nonce_key,
+            max_chunk_len: params.max_size as u64 + TAG_LEN,
+        }
+    }
+
+    /// Encrypts the chunk with the given index, returning it with its prefix.
+    fn encrypt_chunk(&self, idx: u64, last: bool, plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+        // The nonce depends on the plaintext, so different plaintext never reuses one
+        let mut context = hmac::Context::with_key(&self.nonce_key);
+        context.update(&idx.to_le_bytes());
+        context.update(&[last as u8]);
+        context.update(plaintext);
+        let tag = context.sign();
+        let nonce = &tag.as_ref()[..12];
+
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                nonce.into(),
+                Payload {
+                    msg: plaintext,
+                    aad: &chunk_aad(idx, last, aad),
+                },
+            )
+            .unwrap();
+        let mut chunk = nonce.to_vec();
+        chunk.extend_from_slice(&(ciphertext.len() as u32).to_le_bytes());
+        chunk.extend(ciphertext);
+
+        chunk
+    }
+
+    /// Decrypts the ciphertext of the chunk with the given index, using the nonce from its prefix.
+    fn decrypt_chunk(
+        &self,
+        idx: u64,
+        last: bool,
+        nonce: &[u8],
+        ciphertext: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>> {
+        self.cipher
+            .decrypt(
+                nonce.into(),
+                Payload {
+                    msg: ciphertext,
+                    aad: &chunk_aad(idx, last, aad),
+                },
+            )
+            .map_err(|_| authentication_failed("decryption failed"))
+    }
+}
+
+/// Gets the associated data for the chunk with the given index, which binds it to its place in
+/// the file and to the header.
+fn chunk_aad(idx: u64, last: bool, aad: &[u8]) -> Vec<u8> {
+    let mut chunk_aad = aad.to_vec();
+    chunk_aad.extend_from_slice(&idx.to_le_bytes());
+    chunk_aad.push(last as u8);
+
+    chunk_aad
+}
+
+/// Encrypts everything from the given reader in dedup mode, splitting it into chunks with the
+/// parameters in the given header, and writes the header and then the chunks to the given writer.
+pub fn encrypt_file_dedup(
+    input: &mut impl Read,
+    output: &mut impl Write,
+    header: &Header,
+    cipher: &DedupCipher,
+) -> Result<()> {
+    encrypt_file_dedup_with_progress(input, output, header, cipher, &mut |_| {})
+}
+
+/// Like [`encrypt_file_dedup`], but calls `on_progress` after each chunk with the total number of
+/// bytes of plaintext encrypted so far, so a progress bar can be shown.
+pub fn encrypt_file_dedup_with_progress(
+    input: &mut impl Read,
+    output: &mut impl Write,
+    header: &Header,
+    cipher: &DedupCipher,
+    on_progress: &mut dyn FnMut(u64),
+) -> Result<()> {
+    let params = header
+        .dedup_params()
+        .ok_or(anyhow!("header was not created in dedup mode"))?;
+    output.write_all(&header.to_bytes())?;
+    let aad = header.authenticated_data();
+
+    let mut progress = Progress::new(on_progress);
+    let mut chunker = Chunker::new(input, params)?;
+    let mut chunk = chunker.next_chunk()?;
+    let mut idx = 0;
+    loop {
+        // We need to know if this is the last chunk before encrypting it
+        let next_chunk = chunker.next_chunk()?;
+        let last = next_chunk.is_empty();
+        output.write_all(&cipher.encrypt_chunk(idx, last, &chunk, &aad))?;
+        progress.advance(chunk.len());
+        if last {
+            break;
+        }
+
+        chunk = next_chunk;
+        idx += 1;
+    }
+    output.flush()?;
+    debug!("Encrypted {} chunk(s) in dedup mode", idx + 1);
+
+    Ok(())
+}
+
+/// Decrypts a dedup-mode body from the given reader (which should be directly after the header),
+/// writing the plaintext to the given output. Like [`crate::decrypt_file`], each chunk is written
+/// as soon as it's been authenticated, and decryption stops once `limit` bytes have been written,
+/// returning whether the whole body was decrypted and authenticated.
+pub fn decrypt_file_dedup(
+    input: &mut impl Read,
+    output: &mut impl Write,
+    cipher: &DedupCipher,
+    aad: &[u8],
+    limit: Option<u64>,
+) -> Result<bool> {
+    decrypt_file_dedup_with_progress(input, output, cipher, aad, limit, &mut |_| {})
+}
+
+/// Like [`decrypt_file_dedup`], but calls `on_progress` after each chunk with the total number of
+/// bytes of plaintext written so far, so a progress bar can be shown.
+pub fn decrypt_file_dedup_with_progress(
+    input: &mut impl Read,
+    output: &mut impl Write,
+    cipher: &DedupCipher,
+    aad: &[u8],
+    limit: Option<u64>,
+    on_progress: &mut dyn FnMut(u64),
+) -> Result<bool> {
+    let mut progress = Progress::new(on_progress);
+    let mut remaining = limit.unwrap_or(u64::MAX);
+    let mut next = read_chunk(input, cipher.max_chunk_len)?.ok_or(anyhow!("file is truncated"))?;
+    let mut idx = 0;
+    loop {
+        let (nonce, ciphertext) = next;
+        let following = read_chunk(input, cipher.max_chunk_len)?;
+        let last = following.is_none();
+        let plaintext = cipher
+            .decrypt_chunk(idx, last, &nonce, &ciphertext, aad)
+            .inspect_err(|_| debug!("Dedup chunk {idx} failed to decrypt"))?;
+        if plaintext.len() as u64 >= remaining && !last {
+            output.write_all(&plaintext[..remaining as usize])?;
+            output.flush()?;
+            progress.advance(remaining as usize);
+            return Ok(false);
+        }
+        let len = plaintext.len().min(remaining as usize);
+        output.write_all(&plaintext[..len])?;
+        remaining -= len as u64;
+        progress.advance(len);
+
+        match following {
+            Some(following) => next = following,
+            None => break,
+        }
+        idx += 1;
+    }
+    output.flush()?;
+    debug!("Decrypted {} chunk(s) in dedup mode", idx + 1);
+
+    Ok(true)
+}
+
+/// Decrypts a dedup-mode body like [`decrypt_file_dedup`], but throws away the plaintext, returning
+/// the number of bytes of it that were verified.
+pub fn verify_file_dedup(input: &mut impl Read, cipher: &DedupCipher, aad: &[u8]) -> Result<u64> {
+    let mut counter = ByteCounter(0);
+    decrypt_file_dedup(input, &mut counter, cipher, aad, None)?;
+
+    Ok(counter.0)
+}
+
+/// Reads the next chunk's nonce and ciphertext from the given reader, returning `None` if there
+/// are no more. No chunk's ciphertext can be longer than `max_len`.
+fn read_chunk(input: &mut impl Read, max_len: u64) -> Result<Option<([u8; 12], Vec<u8>)>> {
+    let mut prefix = [0u8; CHUNK_PREFIX_LEN];
+    match read_full(input, &mut prefix)? {
+        0 => return Ok(None),
+        CHUNK_PREFIX_LEN => {}
+        _ => bail!("file is truncated"),
+    }
+    let nonce = prefix[..12].try_into().unwrap();
+    let len = u32::from_le_bytes(prefix[12..].try_into().unwrap()) as u64;
+    if len < TAG_LEN || len > max_len {
+        bail!("chunk has invalid length (corrupted)");
+    }
+
+    // Read incrementally, so a corrupted length can't make us allocate something enormous
+    let mut ciphertext = Vec::new();
+    if input.take(len).read_to_end(&mut ciphertext)? as u64 != len {
+        bail!("file is truncated");
+    }
+
+    Ok(Some((nonce, ciphertext)))
+}
+
+/// Splits a reader into content-defined chunks using a gear hash, which is cheap to roll and only
+/// depends on the last 64 bytes, so boundaries resynchronise shortly after an edit.
+struct Chunker<'a, R: Read> {
+    input: &'a mut R,
+    params: DedupParams,
+    /// Plaintext that's been read but not yet returned as part of a chunk.
+    pending: Vec<u8>,
+    /// Whether the input has run out.
+    finished: bool,
+}
+impl<'a, R: Read> Chunker<'a, R> {
+    fn new(input: &'a mut R, params: DedupParams) -> Result<Self> {
+        if params.min_size == 0 || params.min_size > params.max_size || params.avg_bits > 32 {
+            bail!("invalid dedup chunking parameters");
+        }
+
+        Ok(Self {
+            input,
+            params,
+            pending: Vec::new(),
+            finished: false,
+        })
+    }
+
+    /// Gets the next chunk, which will only be empty if there are no more (or the whole input was
+    /// empty).
+    fn next_chunk(&mut self) -> io::Result<Vec<u8>> {
+        let max_size = self.params.max_size as usize;
+        // Make sure we have a whole maximum-sized chunk to look through
+        if !self.finished && self.pending.len() < max_size {
+            let old_len = self.pending.len();
+            self.pending.resize(max_size, 0);
+            let read = read_full(self.input, &mut self.pending[old_len..])?;
+            self.pending.truncate(old_len + read);
+            self.finished = self.pending.len() < max_size;
+        }
+
+        let cut = self.find_boundary();
+        let rest = self.pending.split_off(cut);
+        Ok(std::mem::replace(&mut self.pending, rest))
+    }
+
+    /// Finds where the next chunk should end in the pending plaintext.
+    fn find_boundary(&self) -> usize {
+        let min_size = self.params.min_size as usize;
+        if self.pending.len() <= min_size {
+            return self.pending.len();
+        }
+
+        let shift = 64 - self.params.avg_bits as u32;
+        let mut hash = 0u64;
+        for (i, byte) in self.pending.iter().enumerate().skip(min_size) {
+            hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+            if hash.checked_shr(shift).unwrap_or(0) == 0 {
+                return i + 1;
+            }
+        }
+
+        // We didn't find a boundary, so either the chunk has hit the maximum size, or this is the
+        // end of the input
+        self.pending.len()
+    }
+}
+
+/// The random values the gear hash adds for each byte. These are fixed (they're generated from a
+/// constant seed) so that the same plaintext is always chunked the same way.
+const GEAR: [u64; 256] = {
+    // SplitMix64
+    let mut table = [0u64; 256];
+    let mut state = 0x6379_7374_6765_6172u64;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing, vectors::registry, AuthenticationFailed};
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha20Rng;
+
+    /// Small chunking parameters, so the plaintext in these tests spans plenty of chunks.
+    const PARAMS: DedupParams = DedupParams {
+        min_size: 256,
+        avg_bits: 10,
+        max_size: 4096,
+    };
+
+    /// The plaintext encrypted in these tests, which is random (so it has no regular boundaries).
+    fn plaintext() -> Vec<u8> {
+        let mut plaintext = vec![0; 20_000];
+        ChaCha20Rng::seed_from_u64(320).fill(plaintext.as_mut_slice());
+        plaintext
+    }
+
+    /// Encrypts the given plaintext in dedup mode, returning each of the body's chunks (with their
+    /// prefixes).
+    fn encrypt(header: &Header, cipher: &DedupCipher, plaintext: &[u8]) -> Vec<Vec<u8>> {
+        let mut file = Vec::new();
+        encrypt_file_dedup(&mut &plaintext[..], &mut file, header, cipher).unwrap();
+
+        let mut body = &file[header.to_bytes().len()..];
+        let mut chunks = Vec::new();
+        while let Some((nonce, ciphertext)) = read_chunk(&mut body, cipher.max_chunk_len).unwrap() {
+            let mut chunk = nonce.to_vec();
+            chunk.extend_from_slice(&(ciphertext.len() as u32).to_le_bytes());
+            chunk.extend(ciphertext);
+            chunks.push(chunk);
+        }
+
+        chunks
+    }
+
+    /// Decrypts a body made of the given chunks.
+    fn decrypt(header: &Header, cipher: &DedupCipher, chunks: &[Vec<u8>]) -> Result<Vec<u8>> {
+        let mut plaintext = Vec::new();
+        let complete = decrypt_file_dedup(
+            &mut chunks.concat().as_slice(),
+            &mut plaintext,
+            cipher,
+            &header.authenticated_data(),
+            None,
+        )?;
+        assert!(complete);
+
+        Ok(plaintext)
+    }
+
+    #[test]
+    fn round_trips() {
+        let (header, cipher) =
+            Header::new_dedup(&registry(), PARAMS, 1, &mut testing::prompter("dedup")).unwrap();
+        for plaintext in [Vec::new(), b"short".to_vec(), plaintext()] {
+            let chunks = encrypt(&header, &cipher, &plaintext);
+            assert_eq!(decrypt(&header, &cipher, &chunks).unwrap(), plaintext);
+        }
+        assert!(encrypt(&header, &cipher, &plaintext()).len() > 5);
+    }
+
+    #[test]
+    fn unchanged_chunks_encrypt_identically() {
+        let (header, cipher) =
+            Header::new_dedup(&registry(), PARAMS, 1, &mut testing::prompter("dedup")).unwrap();
+        let plaintext = plaintext();
+        let chunks = encrypt(&header, &cipher, &plaintext);
+        assert_eq!(encrypt(&header, &cipher, &plaintext), chunks);
+
+        // An edit in the middle only changes the chunks around it
+        let mut edited = plaintext.clone();
+        edited[plaintext.len() / 2] ^= 1;
+        let edited_chunks = encrypt(&header, &cipher, &edited);
+        assert_eq!(edited_chunks.len(), chunks.len());
+        let changed = chunks
+            .iter()
+            .zip(&edited_chunks)
+            .filter(|(chunk, edited_chunk)| chunk != edited_chunk)
+            .count();
+        assert!((1..=2).contains(&changed), "{changed} chunks changed");
+        assert_eq!(decrypt(&header, &cipher, &edited_chunks).unwrap(), edited);
+    }
+
+    #[test]
+    fn reordered_or_dropped_chunks_are_refused() {
+        let (header, cipher) =
+            Header::new_dedup(&registry(), PARAMS, 1, &mut testing::prompter("dedup")).unwrap();
+        let chunks = encrypt(&header, &cipher, &plaintext());
+
+        let mut swapped = chunks.clone();
+        swapped.swap(1, 2);
+        let mut without_middle = chunks.clone();
+        without_middle.remove(1);
+        let mut without_last = chunks.clone();
+        without_last.pop();
+        for chunks in [swapped, without_middle, without_last] {
+            let err = decrypt(&header, &cipher, &chunks).unwrap_err();
+            assert!(err.is::<AuthenticationFailed>(), "{err:#}");
+        }
+    }
+}
@@ -0,0 +1,612 @@
+//! Error correction for encrypted bodies, so they can survive a little bit rot (e.g. on optical
+//! media or tape). Without it, a single flipped bit makes a whole chunk fail to authenticate.
+//!
+//! The body is split into blocks of [`EccParams::depth`] Reed–Solomon codewords over GF(2^8), each
+//! 255 bytes long, of which [`EccParams::parity`] are parity. Each codeword can correct up to half
+//! as many corrupted bytes as it has parity bytes, wherever they are. The codewords in a block are
+//! interleaved byte-by-byte, so a burst of corruption is spread across all of them: a block can
+//! survive a burst of up to `depth * parity / 2` bytes. The last block is padded with zeros, which
+//! are dropped again when decoding, since the header records how long the body really is.
+//!
+//! This works on the ciphertext, so the AEAD still authenticates everything after correction
+//! (which catches the rare miscorrection of a codeword that's too corrupted). The header itself
+//! isn't protected, so it's worth keeping a copy of it somewhere else (see `--header-out`).
+
+use crate::file::read_full;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+
+/// The length of every codeword, the most GF(2^8) allows.
+const CODEWORD_LEN: usize = 255;
+
+/// How much redundancy an error-corrected body has. These are stored in the header, so the body
+/// can be decoded the same way.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct EccParams {
+    /// The number of parity bytes in each 255-byte codeword, half of which can be corrected.
+    pub parity: u8,
+    /// The number of codewords interleaved in each block.
+    pub depth: u16,
+}
+impl EccParams {
+    /// The default number of codewords interleaved in each block.
+    pub const DEFAULT_DEPTH: u16 = 16;
+
+    /// Creates parameters with the given number of parity bytes per codeword (which must be
+    /// between 2 and 128), and the default interleaving depth.
+    pub fn new(parity: u8) -> io::Result<Self> {
+        let params = Self {
+            parity,
+            depth: Self::DEFAULT_DEPTH,
+        };
+        params.check()?;
+
+        Ok(params)
+    }
+
+    /// Gets how long the given number of bytes will be once encoded.
+    pub fn encoded_len(&self, len: u64) -> u64 {
+        len.div_ceil(self.block_data_len() as u64) * self.block_len() as u64
+    }
+
+    /// Makes sure these parameters make sense, since they might have come from a corrupted header.
+    fn check(&self) -> io::Result<()> {
+        if !(2..=128).contains(&self.parity) || self.depth == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "error correction needs between 2 and 128 parity bytes, and a non-zero depth",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// The number of data bytes in each codeword.
+    fn data_len(&self) -> usize {
+        CODEWORD_LEN - self.parity as usize
+    }
+
+    /// The number of data bytes in each block.
+    fn block_data_len(&self) -> usize {
+        self.depth as usize * self.data_len()
+    }
+
+    /// The number of bytes each block takes up once encoded.
+    pub(crate) fn block_len(&self) -> usize {
+        self.depth as usize * CODEWORD_LEN
+    }
+}
+
+/// A writer that adds error correction to everything written to it. [`EccWriter::finish`] must
+/// be called once everything has been written, or the last block will be missing.
+pub struct EccWriter<W: Write> {
+    inner: W,
+    params: EccParams,
+    codec: Codec,
+    /// Bytes that haven't filled a whole block yet.
+    buf: Vec<u8>,
+}
+impl<W: Write> EccWriter<W> {
+    /// Creates a new error-correcting writer with the given parameters.
+    pub fn new(inner: W, params: EccParams) -> io::Result<Self> {
+        params.check()?;
+        Ok(Self {
+            inner,
+            params,
+            codec: Codec::new(params.parity as usize),
+            buf: Vec::with_capacity(params.block_data_len()),
+        })
+    }
+
+    /// Writes out whatever's left over as a last block, padded with zeros, returning the inner
+    /// writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        if !self.buf.is_empty() {
+            self.buf.resize(self.params.block_data_len(), 0);
+            self.write_block()?;
+        }
+        self.inner.flush()?;
+
+        Ok(self.inner)
+    }
+
+    /// Encodes the first block's worth of data in the buffer, and writes it out.
+    fn write_block(&mut self) -> io::Result<()> {
+        let depth = self.params.depth as usize;
+        let mut block = vec![0u8; self.params.block_len()];
+        for (idx, data) in self.buf[..self.params.block_data_len()]
+            .chunks(self.params.data_len())
+            .enumerate()
+        {
+            for (pos, byte) in self.codec.encode(data).into_iter().enumerate() {
+                block[pos * depth + idx] = byte;
+            }
+        }
+        self.buf.drain(..self.params.block_data_len());
+
+        self.inner.write_all(&block)
+    }
+}
+impl<W: Write> Write for EccWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        while self.buf.len() >= self.params.block_data_len() {
+            self.write_block()?;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A reader that corrects whatever errors it can in an error-corrected body, giving the original
+/// bytes. This fails if a codeword has too many errors to correct, if the body is cut short, or if
+/// there's anything after it.
+pub struct EccReader<R: Read> {
+    inner: R,
+    params: EccParams,
+    codec: Codec,
+    /// How many bytes of the original body haven't been decoded yet.
+    remaining: u64,
+    /// The index of the next block to decode, for reporting.
+    block_idx: u64,
+    /// Bytes decoded from the last block that haven't been read yet.
+    buf: Vec<u8>,
+    /// How many bytes of `buf` have already been read.
+    pos: usize,
+    /// The total number of bytes corrected so far.
+    corrected: u64,
+}
+impl<R: Read> EccReader<R> {
+    /// Creates a new error-correcting reader with the given parameters, for a body that was
+    /// `len` bytes long before it was encoded.
+    pub fn new(inner: R, params: EccParams, len: u64) -> io::Result<Self> {
+        params.check()?;
+        Ok(Self {
+            inner,
+            params,
+            codec: Codec::new(params.parity as usize),
+            remaining: len,
+            block_idx: 0,
+            buf: Vec::new(),
+            pos: 0,
+            corrected: 0,
+        })
+    }
+
+    /// Gets the total number of corrupted bytes that have been corrected so far.
+    pub fn corrected(&self) -> u64 {
+        self.corrected
+    }
+
+    /// Reads and decodes the next block into the buffer.
+    fn read_block(&mut self) -> io::Result<()> {
+        let depth = self.params.depth as usize;
+        let mut block = vec![0u8; self.params.block_len()];
+        // A block that's only partly there might still be correctable, with zeros for the rest
+        let read = read_full(&mut self.inner, &mut block)?;
+        if read == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "error-corrected body is truncated",
+            ));
+        }
+
+        self.buf.clear();
+        self.pos = 0;
+        let mut corrected = 0;
+        let mut codeword = [0u8; CODEWORD_LEN];
+        for idx in 0..depth {
+            for (pos, byte) in codeword.iter_mut().enumerate() {
+                *byte = block[pos * depth + idx];
+            }
+            corrected += self.codec.correct(&mut codeword).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "block {} of the error-corrected body has too many errors to correct",
+                        self.block_idx
+                    ),
+                )
+            })?;
+            self.buf
+                .extend_from_slice(&codeword[..self.params.data_len()]);
+        }
+        if corrected > 0 {
+            warn!(
+                "Corrected {corrected} corrupted byte(s) in block {} of the body",
+                self.block_idx
+            );
+        }
+        debug!(
+            "Decoded block {} of the error-corrected body",
+            self.block_idx
+        );
+
+        // Drop the padding from the last block
+        let len = (self.buf.len() as u64).min(self.remaining);
+        self.buf.truncate(len as usize);
+        self.remaining -= len;
+        self.corrected += corrected as u64;
+        self.block_idx += 1;
+
+        Ok(())
+    }
+}
+impl<R: Read> Read for EccReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.buf.len() {
+            if self.remaining == 0 {
+                // Anything more means the body doesn't match the length in the header
+                if self.inner.read(&mut [0u8])? != 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "error-corrected body is longer than it should be",
+                    ));
+                }
+                return Ok(0);
+            }
+            self.read_block()?;
+        }
+
+        let read = buf.len().min(self.buf.len() - self.pos);
+        buf[..read].copy_from_slice(&self.buf[self.pos..self.pos + read]);
+        self.pos += read;
+
+        Ok(read)
+    }
+}
+
+/// A systematic Reed–Solomon code over GF(2^8) with the given number of parity bytes, and the
+/// primitive element 2 (with the polynomial `0x11d`). Polynomials are stored with the highest
+/// power first.
+struct Codec {
+    parity: usize,
+    /// The generator polynomial, the product of `(x - 2^i)` for each `i` below `parity`.
+    generator: Vec<u8>,
+}
+impl Codec {
+    fn new(parity: usize) -> Self {
+        let mut generator = vec![1];
+        for i in 0..parity {
+            generator = poly_mul(&generator, &[1, gf_pow2(i)]);
+        }
+
+        Self { parity, generator }
+    }
+
+    /// Encodes the given data, returning it with the parity bytes after it.
+    fn encode(&self, data: &[u8]) -> Vec<u8> {
+        let mut codeword = data.to_vec();
+        codeword.resize(data.len() + self.parity, 0);
+        // The parity is the remainder of dividing the data (shifted up) by the generator
+        for i in 0..data.len() {
+            let coef = codeword[i];
+            if coef != 0 {
+                for (j, &gen) in self.generator.iter().enumerate().skip(1) {
+                    codeword[i + j] ^= gf_mul(gen, coef);
+                }
+            }
+        }
+        codeword[..data.len()].copy_from_slice(data);
+
+        codeword
+    }
+
+    /// Corrects the given codeword in place, returning the number of bytes that were corrected, or
+    /// `None` if there are too many errors to correct.
+    fn correct(&self, codeword: &mut [u8]) -> Option<usize> {
+        let syndromes = self.syndromes(codeword);
+        if syndromes.iter().all(|&syndrome| syndrome == 0) {
+            return Some(0);
+        }
+
+        let locator = self.error_locator(&syndromes[1..])?;
+        let positions = error_positions(&locator, codeword.len())?;
+        correct_errors(codeword, &syndromes, &positions)?;
+        // Too many errors can look like a different set of fewer errors
+        if self
+            .syndromes(codeword)
+            .iter()
+            .any(|&syndrome| syndrome != 0)
+        {
+            return None;
+        }
+
+        Some(positions.len())
+    }
+
+    /// Evaluates the codeword at each root of the generator, which gives all zeros if there are no
+    /// errors. This has an extra zero at the start, which the Forney algorithm relies on.
+    fn syndromes(&self, codeword: &[u8]) -> Vec<u8> {
+        let mut syndromes = vec![0];
+        syndromes.extend((0..self.parity).map(|i| poly_eval(codeword, gf_pow2(i))));
+
+        syndromes
+    }
+
+    /// Finds the error locator polynomial from the syndromes, using the Berlekamp–Massey
+    /// algorithm, or `None` if there are too many errors to correct.
+    fn error_locator(&self, syndromes: &[u8]) -> Option<Vec<u8>> {
+        let mut locator = vec![1];
+        let mut old_locator = vec![1];
+        for i in 0..self.parity {
+            let mut delta = syndromes[i];
+            for j in 1..locator.len() {
+                delta ^= gf_mul(locator[locator.len() - 1 - j], syndromes[i - j]);
+            }
+            old_locator.push(0);
+            if delta != 0 {
+                if old_locator.len() > locator.len() {
+                    let new_locator = poly_scale(&old_locator, delta);
+                    old_locator = poly_scale(&locator, gf_inverse(delta));
+                    locator = new_locator;
+                }
+                locator = poly_add(&locator, &poly_scale(&old_locator, delta));
+            }
+        }
+        let leading_zeros = locator.iter().take_while(|&&coef| coef == 0).count();
+        locator.drain(..leading_zeros);
+
+        let errors = locator.len().checked_sub(1)?;
+        (errors * 2 <= self.parity).then_some(locator)
+    }
+}
+
+/// Finds the positions of the errors (from the start of the codeword) by finding the roots of the
+/// error locator, or `None` if it doesn't have as many as it should.
+fn error_positions(locator: &[u8], len: usize) -> Option<Vec<usize>> {
+    let reversed = locator.iter().rev().copied().collect::<Vec<_>>();
+    let positions = (0..len)
+        .filter(|&i| poly_eval(&reversed, gf_pow2(i)) == 0)
+        .map(|i| len - 1 - i)
+        .collect::<Vec<_>>();
+
+    (positions.len() == locator.len() - 1).then_some(positions)
+}
+
+/// Corrects the errors at the given positions with the Forney algorithm, or returns `None` if
+/// that's impossible.
+fn correct_errors(codeword: &mut [u8], syndromes: &[u8], positions: &[usize]) -> Option<()> {
+    let powers = positions
+        .iter()
+        .map(|pos| codeword.len() - 1 - pos)
+        .collect::<Vec<_>>();
+    let mut locator = vec![1];
+    for &power in &powers {
+        locator = poly_mul(&locator, &poly_add(&[1], &[gf_pow2(power), 0]));
+    }
+
+    // The error evaluator is the syndromes times the locator, modulo x^(errors + 1)
+    let reversed_syndromes = syndromes.iter().rev().copied().collect::<Vec<_>>();
+    let product = poly_mul(&reversed_syndromes, &locator);
+    let evaluator = &product[product.len().saturating_sub(locator.len())..];
+
+    let roots = powers
+        .iter()
+        .map(|&power| gf_pow2(power))
+        .collect::<Vec<_>>();
+    for (idx, &root) in roots.iter().enumerate() {
+        let root_inverse = gf_inverse(root);
+        let locator_derivative = roots
+            .iter()
+            .enumerate()
+            .filter(|&(other_idx, _)| other_idx != idx)
+            .fold(1, |acc, (_, &other)| {
+                gf_mul(acc, 1 ^ gf_mul(root_inverse, other))
+            });
+        if locator_derivative == 0 {
+            return None;
+        }
+        let value = gf_mul(root, poly_eval(evaluator, root_inverse));
+        codeword[positions[idx]] ^= gf_div(value, locator_derivative);
+    }
+
+    Some(())
+}
+
+/// Powers of 2 in GF(2^8), twice over, so products of logarithms can index it directly.
+const EXP: [u8; 512] = {
+    let mut table = [0u8; 512];
+    let mut x = 1u16;
+    let mut i = 0;
+    while i < 255 {
+        table[i] = x as u8;
+        table[i + 255] = x as u8;
+        x <<= 1;
+        if x & 0x100 != 0 {
+            x ^= 0x11d;
+        }
+        i += 1;
+    }
+    table[510] = table[0];
+    table[511] = table[1];
+    table
+};
+/// Logarithms (base 2) in GF(2^8). That of zero is meaningless.
+const LOG: [u8; 256] = {
+    let mut table = [0u8; 256];
+    let mut i = 0;
+    while i < 255 {
+        table[EXP[i] as usize] = i as u8;
+        i += 1;
+    }
+    table
+};
+
+fn gf_mul(x: u8, y: u8) -> u8 {
+    if x == 0 || y == 0 {
+        return 0;
+    }
+    EXP[LOG[x as usize] as usize + LOG[y as usize] as usize]
+}
+
+fn gf_div(x: u8, y: u8) -> u8 {
+    if x == 0 {
+        return 0;
+    }
+    EXP[(LOG[x as usize] as usize + 255 - LOG[y as usize] as usize) % 255]
+}
+
+fn gf_inverse(x: u8) -> u8 {
+    EXP[255 - LOG[x as usize] as usize]
+}
+
+/// Raises 2 to the given power.
+fn gf_pow2(power: usize) -> u8 {
+    EXP[power % 255]
+}
+
+fn poly_scale(poly: &[u8], x: u8) -> Vec<u8> {
+    poly.iter().map(|&coef| gf_mul(coef, x)).collect()
+}
+
+fn poly_add(p: &[u8], q: &[u8]) -> Vec<u8> {
+    let len = p.len().max(q.len());
+    let mut sum = vec![0u8; len];
+    for (i, &coef) in p.iter().enumerate() {
+        sum[i + len - p.len()] = coef;
+    }
+    for (i, &coef) in q.iter().enumerate() {
+        sum[i + len - q.len()] ^= coef;
+    }
+
+    sum
+}
+
+fn poly_mul(p: &[u8], q: &[u8]) -> Vec<u8> {
+    let mut product = vec![0u8; p.len() + q.len() - 1];
+    for (i, &p_coef) in p.iter().enumerate() {
+        for (j, &q_coef) in q.iter().enumerate() {
+            product[i + j] ^= gf_mul(p_coef, q_coef);
+        }
+    }
+
+    product
+}
+
+fn poly_eval(poly: &[u8], x: u8) -> u8 {
+    poly.iter().fold(0, |acc, &coef| gf_mul(acc, x) ^ coef)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        file::{ciphertext_len, decrypt_file, encrypt_body},
+        header::Header,
+        testing,
+        vectors::registry,
+        AuthenticationFailed,
+    };
+    use anyhow::Result;
+    use rand::{seq::index, Rng, SeedableRng};
+    use rand_chacha::ChaCha20Rng;
+
+    /// The number of parity bytes the bodies in these tests have.
+    const PARITY: u8 = 8;
+
+    /// The plaintext encrypted in these tests, which spans a few blocks.
+    fn plaintext() -> Vec<u8> {
+        (0..10_000).map(|idx| (idx % 251) as u8).collect()
+    }
+
+    /// Encrypts the plaintext with error correction, returning the header and the encoded body.
+    fn encrypt() -> (Header, Vec<u8>) {
+        let plaintext = plaintext();
+        let params = EccParams::new(PARITY).unwrap();
+        let (mut header, encryptor) = testing::header("ecc");
+        header
+            .set_body_len(ciphertext_len(plaintext.len() as u64))
+            .unwrap();
+        header.set_ecc_params(params).unwrap();
+        let mut writer = EccWriter::new(Vec::new(), params).unwrap();
+        encrypt_body(
+            &mut plaintext.as_slice(),
+            &mut writer,
+            &header,
+            encryptor,
+            None,
+        )
+        .unwrap();
+
+        (header, writer.finish().unwrap())
+    }
+
+    /// Decrypts the given encoded body, returning the plaintext and how many bytes were corrected.
+    fn decrypt(header: &Header, body: &[u8]) -> Result<(Vec<u8>, u64)> {
+        let body_len = header.body_len().unwrap();
+        let mut reader = EccReader::new(body, header.ecc_params().unwrap(), body_len)?;
+        let (decryptor, location) = header.to_decryptor(
+            &registry(),
+            Some("ecc"),
+            false,
+            1,
+            &mut testing::prompter("ecc"),
+        )?;
+        let mut plaintext = Vec::new();
+        decrypt_file(
+            &mut reader,
+            location,
+            Some(body_len),
+            &mut plaintext,
+            decryptor,
+            &header.authenticated_data(),
+            None,
+        )?;
+
+        Ok((plaintext, reader.corrected()))
+    }
+
+    /// Corrupts `errors` bytes at random in every codeword of the given encoded body, returning
+    /// how many bytes were corrupted in all.
+    fn corrupt(body: &mut [u8], errors: usize, rng: &mut ChaCha20Rng) -> u64 {
+        let depth = EccParams::DEFAULT_DEPTH as usize;
+        let mut corrupted = 0;
+        for block in body.chunks_mut(depth * CODEWORD_LEN) {
+            for idx in 0..depth {
+                for pos in index::sample(rng, CODEWORD_LEN, errors) {
+                    block[pos * depth + idx] ^= rng.gen_range(1..=255);
+                    corrupted += 1;
+                }
+            }
+        }
+
+        corrupted
+    }
+
+    #[test]
+    fn corrects_half_the_parity_in_every_codeword() {
+        let mut rng = ChaCha20Rng::seed_from_u64(352);
+        let (header, mut body) = encrypt();
+        let corrupted = corrupt(&mut body, PARITY as usize / 2, &mut rng);
+
+        let (decrypted, corrected) = decrypt(&header, &body).unwrap();
+        assert_eq!(decrypted, plaintext());
+        assert_eq!(corrected, corrupted);
+    }
+
+    #[test]
+    fn fails_cleanly_beyond_what_can_be_corrected() {
+        let mut rng = ChaCha20Rng::seed_from_u64(352);
+        for errors in [PARITY as usize / 2 + 1, PARITY as usize, CODEWORD_LEN / 2] {
+            let (header, mut body) = encrypt();
+            corrupt(&mut body, errors, &mut rng);
+
+            // A codeword that's too corrupted is either noticed, or miscorrected into something
+            // that doesn't authenticate
+            let err = decrypt(&header, &body).unwrap_err();
+            let uncorrectable = err
+                .downcast_ref::<io::Error>()
+                .is_some_and(|err| err.kind() == io::ErrorKind::InvalidData);
+            assert!(
+                uncorrectable || err.is::<AuthenticationFailed>(),
+                "{errors} error(s): {err:#}"
+            );
+        }
+    }
+}
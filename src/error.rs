@@ -0,0 +1,19 @@
+use std::fmt::{self, Display};
+
+/// The error for when some ciphertext can't be authenticated, either because the key is wrong
+/// (e.g. the user got one of the factors wrong), or because the data has been corrupted or
+/// tampered with. The two are indistinguishable by design. Callers can check for this with
+/// `anyhow::Error::is`, like [`crate::Cancelled`].
+#[derive(Debug)]
+pub struct AuthenticationFailed;
+impl Display for AuthenticationFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "authentication failed (wrong key, or corrupted data)")
+    }
+}
+impl std::error::Error for AuthenticationFailed {}
+
+/// Creates an error with the given message, caused by an [`AuthenticationFailed`].
+pub(crate) fn authentication_failed(msg: impl Display + Send + Sync + 'static) -> anyhow::Error {
+    anyhow::Error::new(AuthenticationFailed).context(msg)
+}
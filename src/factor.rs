@@ -1,4 +1,5 @@
-use anyhow::Result;
+use crate::prompt::Prompter;
+use anyhow::{bail, Result};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
@@ -7,6 +8,10 @@ use std::collections::HashMap;
 /// from a keyfile, or a combination of a hardware token and a PIN. The first two options are
 /// single-factor options, but the third has two factors. In essence, the options are the "OR"
 /// disjunctions, and the factors are the "AND" conjunctions.
+///
+/// Factors must do all their interaction with the user through the [`Prompter`] they're given,
+/// including telling the user things (like Shamir shares). They must never print to stdout, since
+/// the ciphertext or plaintext may be being written there.
 pub trait Factor {
     /// The data this factor produces during creation, which it needs for later derivation. This
     /// might be something like a salt, a nonce, or something similar. Some factors will have no
@@ -20,37 +25,141 @@ pub trait Factor {
     /// Gets the name of this factor, which will be given to the user in prompting them which
     /// factors they want to choose. This must be globally unique among all factors.
     fn name() -> &'static str;
+    /// Gets a short, one-line description of this factor, to help the user choose between them.
+    fn description() -> &'static str;
     /// Creates an instance of this factor by prompting the user, returning the data we'll need to
     /// derive this factor in future and a key.
-    fn create() -> Result<(Self::Data, Self::Key)>;
+    fn create(prompter: &mut dyn Prompter) -> Result<(Self::Data, Self::Key)>;
     /// Derives this factor from the data it was created with. This should prompt the user as
     /// necessary to derive the same key as it originally created.
-    fn derive(data: Self::Data) -> Result<Self::Key>;
+    fn derive(data: Self::Data, prompter: &mut dyn Prompter) -> Result<Self::Key>;
+    /// Whether or not deriving this factor requires network access. Such factors are derived after
+    /// all others in an option, so a failure in a local factor doesn't waste a network fetch.
+    fn requires_network() -> bool {
+        false
+    }
+    /// Checks whether whatever this factor needs besides the user (like a keyfile, a server, or a
+    /// device) is available right now, without prompting the user or deriving the key, returning a
+    /// short description of what was found. This must never change anything (like counting a
+    /// wrong PIN) or fetch anything secret. Factors that need nothing but the user (like a
+    /// passphrase) have nothing to check, and return `None`.
+    fn probe(_data: Self::Data) -> Result<Option<String>> {
+        Ok(None)
+    }
+    /// Roughly estimates how many bits of entropy went into a key this factor just created (given
+    /// as its raw bytes), for warning the user about options that would be easy to guess. This
+    /// only needs to be implemented by factors whose keys come from something the user chose (like
+    /// a passphrase or a PIN), and the default of `None` means the key is random, and so strong.
+    fn entropy(_data: &Self::Data, _key: &[u8]) -> Option<f64> {
+        None
+    }
+    /// Creates an instance of this factor like [`Factor::create`], also returning a rough estimate
+    /// of the entropy that went into it, as for [`Factor::entropy`] (which is what this uses by
+    /// default). Factors whose key is random but protected by something the user chose (like a
+    /// passphrase that wraps it) can only judge that while they still have what the user entered,
+    /// so they should override this instead, and have `create` call it.
+    fn create_with_entropy(
+        prompter: &mut dyn Prompter,
+    ) -> Result<(Self::Data, Self::Key, Option<f64>)> {
+        let (data, key) = Self::create(prompter)?;
+        let entropy = Self::entropy(&data, key.as_ref());
+
+        Ok((data, key, entropy))
+    }
+    /// Gets roughly how much memory deriving this factor will take, in bytes, going by its data.
+    /// This only needs to be implemented by factors whose data sets the cost of their own
+    /// derivation (like a PIN's Argon2 parameters), since it's read from untrusted headers to
+    /// refuse files that would make us allocate something huge. The default is `0`.
+    fn memory_needed(_data: &Self::Data) -> u64 {
+        0
+    }
+    /// Checks whether this factor can be created on this system right now (e.g. that the program
+    /// or service it relies on is there), so it can be marked as unavailable when the user is
+    /// choosing factors, rather than failing halfway through its prompts. This only affects
+    /// creating the factor: one that's already in a header is always derived when it's needed,
+    /// and fails with its own error if it can't be.
+    fn available() -> Result<()> {
+        Ok(())
+    }
 }
 
 /// A type-erased version of [`Factor`] that returns raw serialised data and keys.
 pub trait BoxedFactor {
     fn name(&self) -> &'static str;
-    fn create(&self) -> Result<(Vec<u8>, Vec<u8>)>;
-    fn derive(&self, data: &[u8]) -> Result<Vec<u8>>;
+    fn description(&self) -> &'static str;
+    fn create(&self, prompter: &mut dyn Prompter) -> Result<(Vec<u8>, Vec<u8>)>;
+    fn derive(&self, data: &[u8], prompter: &mut dyn Prompter) -> Result<Vec<u8>>;
+    fn requires_network(&self) -> bool;
+    fn probe(&self, data: &[u8]) -> Result<Option<String>>;
+    fn create_with_entropy(
+        &self,
+        prompter: &mut dyn Prompter,
+    ) -> Result<(Vec<u8>, Vec<u8>, Option<f64>)>;
+    fn memory_needed(&self, data: &[u8]) -> u64;
+    fn available(&self) -> Result<()>;
 }
 impl<F: Factor> BoxedFactor for F {
     fn name(&self) -> &'static str {
         F::name()
     }
 
-    fn create(&self) -> Result<(Vec<u8>, Vec<u8>)> {
-        let (data, key) = F::create()?;
+    fn description(&self) -> &'static str {
+        F::description()
+    }
+
+    fn create(&self, prompter: &mut dyn Prompter) -> Result<(Vec<u8>, Vec<u8>)> {
+        let (data, key) = F::create(prompter)?;
         let data_bytes = bincode::serialize(&data)?;
         let key_bytes = key.as_ref().to_vec();
         Ok((data_bytes, key_bytes))
     }
 
-    fn derive(&self, data_bytes: &[u8]) -> Result<Vec<u8>> {
+    fn derive(&self, data_bytes: &[u8], prompter: &mut dyn Prompter) -> Result<Vec<u8>> {
         let data: F::Data = bincode::deserialize(data_bytes)?;
-        Ok(F::derive(data)?.as_ref().to_vec())
+        Ok(F::derive(data, prompter)?.as_ref().to_vec())
+    }
+
+    fn requires_network(&self) -> bool {
+        F::requires_network()
+    }
+
+    fn probe(&self, data_bytes: &[u8]) -> Result<Option<String>> {
+        let data: F::Data = bincode::deserialize(data_bytes)?;
+        F::probe(data)
+    }
+
+    fn create_with_entropy(
+        &self,
+        prompter: &mut dyn Prompter,
+    ) -> Result<(Vec<u8>, Vec<u8>, Option<f64>)> {
+        let (data, key, entropy) = F::create_with_entropy(prompter)?;
+        let data_bytes = bincode::serialize(&data)?;
+        Ok((data_bytes, key.as_ref().to_vec(), entropy))
+    }
+
+    fn memory_needed(&self, data_bytes: &[u8]) -> u64 {
+        // Data that can't be read will fail to derive long before it allocates anything
+        bincode::deserialize(data_bytes).map_or(0, |data| F::memory_needed(&data))
+    }
+
+    fn available(&self) -> Result<()> {
+        F::available()
     }
 }
 
 /// A registry of many different factors, indexed by their names.
 pub type FactorRegistry = HashMap<&'static str, Box<dyn BoxedFactor>>;
+
+/// Checks that the given factor round-trips correctly: that it can be created, and that deriving
+/// it from the data it produced (after serialisation) yields the same key. This will involve
+/// prompting the user for both steps.
+pub fn round_trip(factor: &dyn BoxedFactor, prompter: &mut dyn Prompter) -> Result<()> {
+    let (data, key) = factor.create(prompter)?;
+    prompter.info("Factor created, now re-deriving it...");
+    let derived_key = factor.derive(&data, prompter)?;
+    if derived_key != key {
+        bail!("derived key did not match created key");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,137 @@
+use super::{
+    clean_pasted,
+    passphrase::{PASSPHRASE_ENV, PASSPHRASE_FILE_ENV},
+};
+use crate::{factor::Factor, prompt::Prompter, rng::CystRng};
+use anyhow::{bail, Context, Result};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{self, Write},
+    process::{Command, Stdio},
+};
+
+/// A factor that encrypts random key bytes to one or more [age](https://age-encryption.org)
+/// recipients, so they can be recovered with any of the corresponding identities. This lets teams
+/// who already manage age keys (X25519 `age1...` keys or `ssh-ed25519` keys) use them with cyst.
+///
+/// This uses the `age` binary, which must be installed and on the `PATH`, rather than the `age`
+/// crate, which isn't a dependency yet. Where the binary is missing, the factor isn't offered.
+pub struct AgeFactor;
+impl Factor for AgeFactor {
+    type Data = AgeFactorData;
+    type Key = Vec<u8>;
+
+    fn name() -> &'static str {
+        "age recipients"
+    }
+    fn description() -> &'static str {
+        "Random key bytes encrypted to one or more age recipients"
+    }
+    fn create(prompter: &mut dyn Prompter) -> Result<(Self::Data, Self::Key)> {
+        let mut recipients = Vec::new();
+        loop {
+            let recipient = prompter.input(
+                "Enter an age recipient (age1... or ssh-ed25519 ...)",
+                None,
+                &|recipient| {
+                    if recipient.starts_with("age1") || recipient.starts_with("ssh-ed25519 ") {
+                        Ok(())
+                    } else {
+                        Err(
+                            "recipient must be an X25519 (age1...) or ssh-ed25519 public key"
+                                .to_string(),
+                        )
+                    }
+                },
+            )?;
+            recipients.push(recipient.trim().to_string());
+
+            if !prompter.confirm("Add another recipient?")? {
+                break;
+            }
+        }
+
+        let key = CystRng.gen::<[u8; 32]>();
+        let mut args = vec!["--encrypt".to_string()];
+        for recipient in recipients {
+            args.push("--recipient".to_string());
+            args.push(recipient);
+        }
+        let ciphertext = run_age(&args, &key)?;
+
+        Ok((AgeFactorData { ciphertext }, key.to_vec()))
+    }
+    fn derive(data: Self::Data, prompter: &mut dyn Prompter) -> Result<Self::Key> {
+        let identity = prompter.input(
+            "Enter the path to your age identity file (or SSH private key)",
+            None,
+            &|_| Ok(()),
+        )?;
+
+        let key = run_age(
+            &[
+                "--decrypt".to_string(),
+                "--identity".to_string(),
+                clean_pasted(&identity).to_string(),
+            ],
+            &data.ciphertext,
+        )?;
+        if key.len() != 32 {
+            bail!("age decrypted data had incorrect length (corrupted)");
+        }
+
+        Ok(key)
+    }
+    fn probe(_data: Self::Data) -> Result<Option<String>> {
+        // Which identity will be used isn't known until it's asked for, so all that can be checked
+        // is that age itself is there
+        let version = run_age(&["--version".to_string()], &[])?;
+
+        Ok(Some(format!(
+            "age {} is installed",
+            String::from_utf8_lossy(&version).trim()
+        )))
+    }
+    fn available() -> Result<()> {
+        match Command::new("age").arg("--version").output() {
+            Ok(output) if output.status.success() => Ok(()),
+            Ok(output) => bail!("`age --version` failed ({})", output.status),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                bail!("this factor needs the `age` binary, which isn't installed (or isn't on the PATH)")
+            }
+            Err(err) => Err(err).context("failed to run `age`"),
+        }
+    }
+}
+
+/// Runs the `age` binary with the given arguments, feeding it the given input and returning its
+/// output. Anything `age` prints to stderr (e.g. passphrase prompts for identities) is passed
+/// through to the user.
+fn run_age(args: &[String], input: &[u8]) -> Result<Vec<u8>> {
+    let mut child = Command::new("age")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        // This has no business seeing any passphrases meant for us
+        .env_remove(PASSPHRASE_ENV)
+        .env_remove(PASSPHRASE_FILE_ENV)
+        .spawn()
+        .context("failed to run `age` (is it installed?)")?;
+    // Write in full and close stdin so age knows the input is finished
+    child.stdin.take().unwrap().write_all(input)?;
+
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        bail!("`age` failed ({})", output.status);
+    }
+
+    Ok(output.stdout)
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AgeFactorData {
+    /// The factor's key, encrypted to the recipients with age.
+    ciphertext: Vec<u8>,
+}
@@ -0,0 +1,324 @@
+use super::{
+    clean_pasted,
+    keyfile::{prompt_new_keyfile_path, write_keyfile},
+    pin::prompt_pin,
+};
+use crate::{error::authentication_failed, factor::Factor, prompt::Prompter, rng::CystRng};
+use anyhow::{anyhow, bail, Context, Result};
+use argon2::Argon2;
+use blake2::{Blake2s256, Digest};
+use log::debug;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// The default number of wrong PINs in a row that wipe the keyfile.
+const DEFAULT_LIMIT: u32 = 5;
+
+/// A keyfile protected by a PIN, which is wiped from this device after too many wrong PINs in a
+/// row, for defence in depth on a single device. The number of wrong PINs so far is kept in a
+/// counter file beside the keyfile, and an attempt is counted before the PIN is even checked, so
+/// interrupting a check doesn't get a free guess. A right PIN resets the counter.
+///
+/// This only stops someone guessing through cyst itself. Anyone with raw access to the files can
+/// copy the keyfile first, or just reset the counter, and then guess the PIN as often as they like,
+/// so the keyfile must be kept somewhere they can't get at it.
+pub struct CountedKeyfileFactor;
+impl Factor for CountedKeyfileFactor {
+    type Data = CountedKeyfileFactorData;
+    type Key = [u8; 32];
+
+    fn name() -> &'static str {
+        "Counted keyfile + PIN"
+    }
+    fn description() -> &'static str {
+        "A keyfile on this device and a PIN, with the keyfile wiped after too many wrong PINs"
+    }
+    fn create(prompter: &mut dyn Prompter) -> Result<(Self::Data, Self::Key)> {
+        let contents = CystRng.gen::<[u8; 32]>();
+        let path = prompt_new_keyfile_path(prompter, "Enter a path to write the keyfile to")?;
+        write_keyfile(&path, &contents)
+            .with_context(|| format!("failed to write the keyfile to {path:?}"))?;
+        // Store absolute paths so both files can be found from anywhere
+        let keyfile_path = std::fs::canonicalize(&path)?;
+        let counter_path = loop {
+            let counter_path = prompter.input(
+                "Enter a path to keep the count of wrong PINs at",
+                Some(&format!("{}.attempts", keyfile_path.display())),
+                &|_| Ok(()),
+            )?;
+            let counter_path = PathBuf::from(clean_pasted(&counter_path));
+            // Like the keyfile, make sure the user really means to overwrite anything there
+            if !counter_path.exists()
+                || prompter.confirm(&format!(
+                    "{counter_path:?} already exists. Overwrite it? (Whatever's in it will be lost!)"
+                ))?
+            {
+                break counter_path;
+            }
+        };
+        write_counter(&counter_path, 0)?;
+        let counter_path = std::fs::canonicalize(&counter_path)?;
+
+        let limit: u32 = prompter.parsed(
+            "How many wrong PINs in a row should wipe the keyfile?",
+            Some(DEFAULT_LIMIT),
+        )?;
+        if limit == 0 {
+            bail!("at least one attempt must be allowed");
+        }
+        let length: u8 = prompter.parsed("How many digits should the PIN have?", Some(6))?;
+        let pin = prompt_pin(prompter, "Enter a PIN", length)?;
+
+        let salt = CystRng.gen::<[u8; 32]>();
+        let key = derive_key(&contents, &pin, &salt)?;
+
+        Ok((
+            CountedKeyfileFactorData {
+                salt,
+                length,
+                fingerprint: fingerprint(&key),
+                keyfile_path,
+                counter_path,
+                limit,
+            },
+            key,
+        ))
+    }
+    fn derive(data: Self::Data, prompter: &mut dyn Prompter) -> Result<Self::Key> {
+        let (keyfile_path, contents) = match std::fs::read(&data.keyfile_path) {
+            Ok(contents) => (data.keyfile_path.clone(), contents),
+            // It might just have been moved
+            Err(_) => {
+                let path = prompter.input(
+                    &format!(
+                        "Couldn't read the keyfile at {:?}, enter where it is now",
+                        data.keyfile_path
+                    ),
+                    None,
+                    &|_| Ok(()),
+                )?;
+                let path = PathBuf::from(clean_pasted(&path));
+                let contents =
+                    std::fs::read(&path).with_context(|| "failed to read from given path")?;
+                (path, contents)
+            }
+        };
+        if contents.len() != 32 {
+            bail!("keyfile had incorrect length (wiped or corrupted)");
+        }
+
+        let failures = read_counter(&data.counter_path)?;
+        debug!("{failures} wrong PIN(s) so far, of {} allowed", data.limit);
+        if failures >= data.limit {
+            wipe_keyfile(&keyfile_path)?;
+            bail!("too many wrong PINs have been entered, so the keyfile has been wiped");
+        }
+
+        let pin = prompt_pin(prompter, "Enter the PIN", data.length)?;
+        // Count the attempt before checking it, so interrupting the check doesn't save one
+        write_counter(&data.counter_path, failures + 1)?;
+        let key = derive_key(&contents, &pin, &data.salt)?;
+        if fingerprint(&key) != data.fingerprint {
+            let left = data.limit - (failures + 1);
+            if left == 0 {
+                wipe_keyfile(&keyfile_path)?;
+                return Err(authentication_failed(
+                    "PIN is incorrect, and that was the last attempt, so the keyfile has been wiped",
+                ));
+            }
+            return Err(authentication_failed(format!(
+                "PIN is incorrect ({left} more wrong PIN(s) will wipe the keyfile)"
+            )));
+        }
+        write_counter(&data.counter_path, 0)?;
+
+        Ok(key)
+    }
+    fn probe(data: Self::Data) -> Result<Option<String>> {
+        let Ok(metadata) = std::fs::metadata(&data.keyfile_path) else {
+            bail!(
+                "there's no keyfile at {:?} (you'll be asked where it is now)",
+                data.keyfile_path
+            );
+        };
+        if metadata.len() != 32 {
+            bail!("the keyfile has the wrong length (wiped or corrupted)");
+        }
+        let failures = read_counter(&data.counter_path)?;
+        if failures >= data.limit {
+            bail!("too many wrong PINs have been entered, so the keyfile will be wiped");
+        }
+
+        Ok(Some(format!(
+            "the keyfile is there, with {} of {} PIN attempt(s) left",
+            data.limit - failures,
+            data.limit
+        )))
+    }
+}
+
+/// Derives the key from the keyfile's contents and the PIN.
+fn derive_key(contents: &[u8], pin: &str, salt: &[u8; 32]) -> Result<[u8; 32]> {
+    let mut input = contents.to_vec();
+    input.extend_from_slice(pin.as_bytes());
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(&input, salt, &mut key)
+        .map_err(|err| anyhow!("failed to derive key from keyfile and PIN: {err}"))?;
+
+    Ok(key)
+}
+
+/// Computes the fingerprint of a key that's stored in the header, to tell whether the PIN was
+/// right. Checking a guess against this needs the keyfile too, so it gives nothing away on its own.
+fn fingerprint(key: &[u8]) -> [u8; 16] {
+    let digest = Blake2s256::new()
+        .chain_update(b"cyst counted keyfile")
+        .chain_update(key)
+        .finalize();
+
+    digest[..16].try_into().unwrap()
+}
+
+/// Reads how many wrong PINs have been entered in a row from the counter file. A missing counter
+/// file counts as none.
+fn read_counter(path: &Path) -> Result<u32> {
+    match std::fs::read_to_string(path) {
+        Ok(count) => count
+            .trim()
+            .parse()
+            .map_err(|_| anyhow!("the count of wrong PINs at {path:?} is corrupted")),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(err) => Err(anyhow!(err).context(format!(
+            "failed to read the count of wrong PINs at {path:?}"
+        ))),
+    }
+}
+
+/// Writes how many wrong PINs have been entered in a row to the counter file.
+fn write_counter(path: &Path, count: u32) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .with_context(|| format!("failed to write the count of wrong PINs to {path:?}"))?;
+    writeln!(file, "{count}")?;
+    file.sync_all()?;
+
+    Ok(())
+}
+
+/// Overwrites the keyfile at the given path with zeroes and then deletes it.
+fn wipe_keyfile(path: &Path) -> Result<()> {
+    debug!("Wiping the keyfile at {path:?}");
+    let len = std::fs::metadata(path)?.len();
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    file.write_all(&vec![0u8; len as usize])?;
+    file.sync_all()?;
+    drop(file);
+    std::fs::remove_file(path).with_context(|| format!("failed to delete the keyfile at {path:?}"))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CountedKeyfileFactorData {
+    /// The salt used in deriving the key from the keyfile's contents and the PIN.
+    salt: [u8; 32],
+    /// The number of digits in the PIN.
+    length: u8,
+    /// A fingerprint of the key, to tell whether the PIN was right.
+    fingerprint: [u8; 16],
+    /// The absolute path to the keyfile (which can be given anew if it's been moved).
+    keyfile_path: PathBuf,
+    /// The absolute path to the file counting wrong PINs in a row.
+    counter_path: PathBuf,
+    /// How many wrong PINs in a row wipe the keyfile.
+    limit: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{error::AuthenticationFailed, vectors::ScriptedPrompter};
+
+    /// Creates a counted keyfile in the given directory that allows three wrong PINs, returning
+    /// its serialised data (so it can be derived more than once).
+    fn create(dir: &Path) -> Result<Vec<u8>> {
+        let (data, _) = CountedKeyfileFactor::create(&mut ScriptedPrompter::new(vec![
+            (
+                "Enter a path to write the keyfile to",
+                dir.join("keyfile").to_string_lossy().into_owned(),
+            ),
+            (
+                "Enter a path to keep the count of wrong PINs at",
+                dir.join("attempts").to_string_lossy().into_owned(),
+            ),
+            ("How many wrong PINs", "3".to_string()),
+            ("How many digits", "4".to_string()),
+            ("Enter a PIN", "1234".to_string()),
+        ]))?;
+
+        Ok(bincode::serialize(&data).unwrap())
+    }
+
+    /// Derives the counted keyfile with the given data and PIN.
+    fn derive(data: &[u8], pin: &str) -> Result<[u8; 32]> {
+        CountedKeyfileFactor::derive(
+            bincode::deserialize(data).unwrap(),
+            &mut ScriptedPrompter::new(vec![("Enter the PIN", pin.to_string())]),
+        )
+    }
+
+    #[test]
+    fn right_pin_resets_the_count() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = create(dir.path()).unwrap();
+        let key = derive(&data, "1234").unwrap();
+
+        for _ in 0..2 {
+            assert!(derive(&data, "0000")
+                .unwrap_err()
+                .is::<AuthenticationFailed>());
+        }
+        assert_eq!(read_counter(&dir.path().join("attempts")).unwrap(), 2);
+        assert_eq!(derive(&data, "1234").unwrap(), key);
+        assert_eq!(read_counter(&dir.path().join("attempts")).unwrap(), 0);
+        // So there are three attempts again
+        for _ in 0..2 {
+            assert!(derive(&data, "0000").is_err());
+        }
+        assert_eq!(derive(&data, "1234").unwrap(), key);
+    }
+
+    #[test]
+    fn too_many_wrong_pins_wipe_the_keyfile() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = create(dir.path()).unwrap();
+
+        for _ in 0..2 {
+            assert!(derive(&data, "0000").is_err());
+        }
+        let err = derive(&data, "0000").unwrap_err().to_string();
+        assert!(err.contains("the keyfile has been wiped"), "{err}");
+        assert!(!dir.path().join("keyfile").exists());
+        assert!(derive(&data, "1234").is_err());
+    }
+
+    #[test]
+    fn existing_counter_is_only_overwritten_when_confirmed() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("attempts"), "something else").unwrap();
+
+        let err = create(dir.path()).unwrap_err().to_string();
+        assert!(err.contains("already exists"), "{err}");
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("attempts")).unwrap(),
+            "something else"
+        );
+    }
+}
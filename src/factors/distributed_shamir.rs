@@ -0,0 +1,282 @@
+use super::{
+    clean_pasted,
+    ephemeral::{download_ephemeral, probe_ephemeral, upload_ephemeral},
+    head_status,
+    keyfile::write_keyfile,
+};
+use crate::{error::authentication_failed, factor::Factor, prompt::Prompter, rng::CystRng};
+use anyhow::{bail, Context, Result};
+use blake2::{Blake2s256, Digest};
+use log::debug;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use shamirsecretsharing::{combine_shares, create_shares, DATA_SIZE as SHAMIR_DATA_SIZE};
+use std::{io::Read, path::PathBuf, time::Duration};
+use ureq::{Agent, AgentBuilder};
+
+/// The timeout for each request to a share's URL, in seconds.
+const TIMEOUT_SECS: u64 = 30;
+
+/// A factor based on Shamir secret sharing like [`super::ShamirFactor`], but rather than being
+/// shown to the user, each share is stored somewhere different: at a URL that accepts `PUT` and
+/// `GET` requests (like an S3-compatible bucket or a WebDAV share), on the ephemeral file host, or
+/// at a local path. When deriving, the shares are fetched automatically until there are enough of
+/// them, so it doesn't matter if a few of the places they're stored can't be reached.
+///
+/// Where the shares are stored is recorded in the header, so anyone with the encrypted file can
+/// try to fetch them. This is only as strong as the access controls on those places (no requests
+/// are authenticated, so a URL must only be reachable by whoever should have it), and it's best
+/// combined with other factors.
+pub struct DistributedShamirFactor;
+impl Factor for DistributedShamirFactor {
+    type Data = DistributedShamirFactorData;
+    type Key = Vec<u8>;
+
+    fn name() -> &'static str {
+        "Distributed Shamir shares"
+    }
+    fn description() -> &'static str {
+        "A secret split into shares stored in different places, a quorum of which are fetched automatically"
+    }
+    fn create(prompter: &mut dyn Prompter) -> Result<(Self::Data, Self::Key)> {
+        let num_shares: u8 = prompter.parsed(
+            "How many shares do you want to create (each stored somewhere different)?",
+            None,
+        )?;
+        let num_quorum: u8 = prompter.parsed(
+            "How many of these shares should be required to decrypt the data?",
+            None,
+        )?;
+
+        let mut secret = [0u8; SHAMIR_DATA_SIZE];
+        CystRng.fill(&mut secret);
+        let shares = create_shares(&secret, num_shares, num_quorum)
+            .with_context(|| "failed to split into shares")?;
+
+        let mut stored_shares = Vec::new();
+        for (idx, share) in shares.iter().enumerate() {
+            let location = match prompter.select(
+                &format!("Where should share #{} be stored?", idx + 1),
+                &[
+                    "A URL that accepts PUT and GET requests (e.g. an S3-compatible bucket)",
+                    "The ephemeral file host (the share will expire!)",
+                    "A local path",
+                ],
+            )? {
+                0 => {
+                    let url =
+                        prompter.input("Enter the URL to store the share at", None, &|url| {
+                            if url.starts_with("http://") || url.starts_with("https://") {
+                                Ok(())
+                            } else {
+                                Err("the URL must start with http:// or https://".to_string())
+                            }
+                        })?;
+                    ShareLocation::Url(url)
+                }
+                1 => {
+                    let expiry: u64 = prompter.parsed(
+                        "How many minutes do you want this share to be kept for?",
+                        None,
+                    )?;
+                    ShareLocation::Ephemeral {
+                        url: String::new(),
+                        expiry,
+                    }
+                }
+                _ => {
+                    let path = prompter.input(
+                        "Enter the path to write the share to",
+                        None,
+                        &|_| Ok(()),
+                    )?;
+                    ShareLocation::Path(PathBuf::from(clean_pasted(&path)))
+                }
+            };
+            let location = location
+                .store(share, prompter)
+                .with_context(|| format!("failed to store share #{}", idx + 1))?;
+            stored_shares.push(StoredShare {
+                location,
+                fingerprint: fingerprint(share),
+            });
+        }
+
+        Ok((
+            DistributedShamirFactorData {
+                num_quorum,
+                shares: stored_shares,
+            },
+            secret.to_vec(),
+        ))
+    }
+    fn derive(data: Self::Data, prompter: &mut dyn Prompter) -> Result<Self::Key> {
+        let needed = data.num_quorum as usize;
+        let mut shares = Vec::new();
+        for (idx, stored) in data.shares.iter().enumerate() {
+            if shares.len() == needed {
+                break;
+            }
+            prompter.info(&format!("Fetching share #{}...", idx + 1));
+            match stored.location.fetch(prompter) {
+                // A share that's been tampered with would stop the others from combining
+                Ok(share) if fingerprint(&share) == stored.fingerprint => shares.push(share),
+                Ok(_) => prompter.notice(&format!(
+                    "Share #{} isn't what was stored (corrupted?), so it won't be used.",
+                    idx + 1
+                )),
+                Err(err) => prompter.notice(&format!(
+                    "Couldn't fetch share #{}, so it won't be used: {err:#}",
+                    idx + 1
+                )),
+            }
+        }
+        if shares.len() < needed {
+            return Err(authentication_failed(format!(
+                "only {} of the {needed} shares needed could be fetched",
+                shares.len()
+            )));
+        }
+
+        let secret = combine_shares(&shares).with_context(|| "failed to combine shares")?;
+        if let Some(secret) = secret {
+            Ok(secret)
+        } else {
+            bail!("failed to combine secrets (some are likely corrupted)");
+        }
+    }
+    fn requires_network() -> bool {
+        true
+    }
+    fn probe(data: Self::Data) -> Result<Option<String>> {
+        let reachable = data
+            .shares
+            .iter()
+            .filter(|stored| stored.location.probe().is_ok())
+            .count();
+        let needed = data.num_quorum as usize;
+        if reachable < needed {
+            bail!(
+                "only {reachable} of the {} shares can be reached, but {needed} are needed",
+                data.shares.len()
+            );
+        }
+
+        Ok(Some(format!(
+            "{reachable} of the {} shares can be reached, and {needed} are needed",
+            data.shares.len()
+        )))
+    }
+}
+
+/// Computes the fingerprint of a share that's stored in the header, so a share that's been changed
+/// can be told apart from one that's just missing. No single share gives anything away, so neither
+/// does this.
+fn fingerprint(share: &[u8]) -> [u8; 16] {
+    let digest = Blake2s256::new()
+        .chain_update(b"cyst distributed shamir")
+        .chain_update(share)
+        .finalize();
+
+    digest[..16].try_into().unwrap()
+}
+
+/// An HTTP agent with the timeout for share requests.
+fn agent() -> Agent {
+    AgentBuilder::new()
+        .timeout(Duration::from_secs(TIMEOUT_SECS))
+        .build()
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DistributedShamirFactorData {
+    /// The number of shares needed to recover the secret.
+    num_quorum: u8,
+    /// Where each share is stored.
+    shares: Vec<StoredShare>,
+}
+
+/// A share stored somewhere, and a fingerprint of what was stored.
+#[derive(Serialize, Deserialize)]
+struct StoredShare {
+    location: ShareLocation,
+    fingerprint: [u8; 16],
+}
+
+/// Somewhere a share is stored.
+#[derive(Serialize, Deserialize)]
+enum ShareLocation {
+    /// A URL that the share was sent to with a `PUT` request, and can be fetched from with `GET`.
+    Url(String),
+    /// The ephemeral file host, with the URL it gave for the share (once it's been uploaded), and
+    /// how many minutes it was asked to keep it for.
+    Ephemeral { url: String, expiry: u64 },
+    /// A local file.
+    Path(PathBuf),
+}
+impl ShareLocation {
+    /// Stores the given share here, returning where it can be fetched from.
+    fn store(self, share: &[u8], prompter: &mut dyn Prompter) -> Result<Self> {
+        Ok(match self {
+            Self::Url(url) => {
+                prompter.detail(&format!("Sending a PUT request to {url}."));
+                let resp = agent()
+                    .put(&url)
+                    .set("Content-Type", "application/octet-stream")
+                    .send_bytes(share)?;
+                debug!("Share host responded with status {}", resp.status());
+                Self::Url(url)
+            }
+            Self::Ephemeral { expiry, .. } => Self::Ephemeral {
+                url: upload_ephemeral(share, expiry, prompter)?,
+                expiry,
+            },
+            Self::Path(path) => {
+                write_keyfile(&path, share)?;
+                // Store an absolute path so the share can be found from anywhere
+                Self::Path(std::fs::canonicalize(path)?)
+            }
+        })
+    }
+
+    /// Checks that the share stored here can be fetched, without fetching it.
+    fn probe(&self) -> Result<()> {
+        match self {
+            Self::Url(url) => {
+                let status = head_status(&agent(), url)?;
+                if !(200..300).contains(&status) {
+                    bail!("{url} answered with status {status}");
+                }
+            }
+            Self::Ephemeral { url, .. } => {
+                probe_ephemeral(url)?;
+            }
+            Self::Path(path) => {
+                if !path.is_file() {
+                    bail!("there's no share at {path:?}");
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fetches the share stored here.
+    fn fetch(&self, prompter: &mut dyn Prompter) -> Result<Vec<u8>> {
+        match self {
+            Self::Url(url) => {
+                prompter.detail(&format!("Sending a GET request to {url}."));
+                let resp = agent().get(url).call()?;
+                debug!("Share host responded with status {}", resp.status());
+                // Nothing we store is anywhere near this long
+                let mut share = Vec::new();
+                resp.into_reader().take(1024).read_to_end(&mut share)?;
+                Ok(share)
+            }
+            Self::Ephemeral { url, .. } => download_ephemeral(url, prompter),
+            Self::Path(path) => {
+                std::fs::read(path).with_context(|| format!("failed to read from {path:?}"))
+            }
+        }
+    }
+}
@@ -1,10 +1,19 @@
-use crate::factor::Factor;
-use anyhow::{bail, Result};
-use rand::{rngs::OsRng, Rng};
+use super::head_status;
+use crate::{error::authentication_failed, factor::Factor, prompt::Prompter, rng::CystRng};
+use anyhow::{anyhow, bail, Result};
+use argon2::Argon2;
+use chacha20poly1305::{aead::Aead, AeadCore, ChaCha20Poly1305, KeyInit};
+use log::debug;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
+use std::io::Read;
 
 /// A factor for ephemeral random data, made by uploading a keyfile to a temporary file hosting
 /// service. Once this expires, the option it's part of will entirely cease functioning!
+///
+/// Anyone with the URL can download the data, so it can optionally be encrypted under a
+/// passphrase before it's uploaded. Then the URL alone isn't enough, and the passphrase is needed
+/// too (though it's only as strong as the passphrase once the data has been downloaded).
 pub struct EphemeralFactor;
 impl Factor for EphemeralFactor {
     type Data = EphemeralFactorData;
@@ -13,64 +22,165 @@ impl Factor for EphemeralFactor {
     fn name() -> &'static str {
         "Ephemeral data"
     }
-    fn create() -> Result<(Self::Data, Self::Key)> {
+    fn description() -> &'static str {
+        "A keyfile uploaded to a temporary file host, which stops working once it expires"
+    }
+    fn create(prompter: &mut dyn Prompter) -> Result<(Self::Data, Self::Key)> {
         // Generate random data
-        let data = OsRng.gen::<[u8; 32]>();
+        let data = CystRng.gen::<[u8; 32]>();
         // Prompt the user for the expiry
-        let expiry = dialoguer::Input::<u64>::new()
-            .with_prompt("How many minutes do you want this ephemeral factor to be valid for?")
-            .interact()
-            .unwrap();
-        // Upload it to a temporary file hosting service (disabling short URL generation to prevent
-        // brute-forcing)
-        eprintln!("Uploading ephemeral data to the cloud...");
-        let resp = ureq::put(&format!("https://oshi.at/?expire={expiry}&shorturl=0"))
-            .set("Content-Type", "application/octet-stream")
-            .send_bytes(&data)?;
-        if resp.status() == 200 {
-            eprintln!("Upload successful!");
-            let resp_str = resp.into_string()?;
-            let lines = resp_str
-                .lines()
-                .filter(|line| !line.trim().is_empty())
-                .collect::<Vec<_>>();
-            if lines.len() != 3 {
-                bail!("unexpected response from ephemeral data service");
+        let expiry: u64 = prompter.parsed(
+            "How many minutes do you want this ephemeral factor to be valid for?",
+            None,
+        )?;
+        // Encrypt it under a passphrase first, if the user wants the URL alone not to be enough
+        let salt = prompter
+            .confirm(
+                "Protect the ephemeral data with a passphrase, so the URL alone isn't enough?",
+            )?
+            .then(|| CystRng.gen::<[u8; 32]>());
+        let upload = match &salt {
+            Some(salt) => {
+                let passphrase = prompter
+                    .new_passphrase("Enter a passphrase for the ephemeral data", &|_| Ok(()))?;
+                let cipher =
+                    ChaCha20Poly1305::new(passphrase_key(&passphrase, salt)?.as_ref().into());
+                let nonce = ChaCha20Poly1305::generate_nonce(CystRng);
+                let mut upload = nonce.to_vec();
+                upload.extend(cipher.encrypt(&nonce, data.as_ref()).unwrap());
+                upload
             }
-            // Line 1 is the admin, line 2 is the download, and line 3 is the Tor download
-            let download_line = lines[1].trim();
-            // The URL is that up to the first space
-            let url = download_line.split_whitespace().next().unwrap();
+            None => data.to_vec(),
+        };
+        let url = upload_ephemeral(&upload, expiry, prompter)?;
 
-            Ok((
-                EphemeralFactorData {
-                    url: url.to_string(),
-                },
-                data,
-            ))
-        } else {
-            bail!("failed to upload ephemeral data: {}", resp.into_string()?);
-        }
+        Ok((EphemeralFactorData { url, salt }, data))
+    }
+    fn derive(data: Self::Data, prompter: &mut dyn Prompter) -> Result<Self::Key> {
+        let download = download_ephemeral(&data.url, prompter)?;
+        debug!(
+            "Downloaded {} byte(s) of ephemeral data ({})",
+            download.len(),
+            if data.salt.is_some() {
+                "encrypted under a passphrase"
+            } else {
+                "unencrypted"
+            }
+        );
+        let key = match &data.salt {
+            Some(salt) => {
+                if download.len() != 12 + 32 + 16 {
+                    bail!("ephemeral data had incorrect length (corrupted)");
+                }
+                let (nonce, ciphertext) = download.split_at(12);
+                let passphrase = prompter
+                    .password("Enter the passphrase for the ephemeral data", &|_| Ok(()))?;
+                let cipher =
+                    ChaCha20Poly1305::new(passphrase_key(&passphrase, salt)?.as_ref().into());
+                cipher.decrypt(nonce.into(), ciphertext).map_err(|_| {
+                    authentication_failed(
+                        "failed to decrypt the ephemeral data (wrong passphrase?)",
+                    )
+                })?
+            }
+            None => download,
+        };
+        key.try_into()
+            .map_err(|_| anyhow!("ephemeral data had incorrect length (corrupted)"))
+    }
+    fn requires_network() -> bool {
+        true
     }
-    fn derive(data: Self::Data) -> Result<Self::Key> {
-        // Download the file
-        eprintln!("Downloading ephemeral data from the cloud...");
-        let resp = ureq::get(&data.url).call()?;
-        if resp.status() == 200 {
-            eprintln!("Download successful!");
-            let mut data = [0u8; 32];
-            resp.into_reader().read_exact(&mut data)?;
-            Ok(data)
-        } else {
-            bail!(
-                "failed to download ephemeral data (may have expired): {}",
-                resp.into_string()?
-            );
-        }
+    fn probe(data: Self::Data) -> Result<Option<String>> {
+        probe_ephemeral(&data.url).map(Some)
     }
 }
 
+/// Uploads the given data to a temporary file hosting service, where it'll be kept for the given
+/// number of minutes, returning the URL it can be downloaded from.
+pub(super) fn upload_ephemeral(
+    data: &[u8],
+    expiry: u64,
+    prompter: &mut dyn Prompter,
+) -> Result<String> {
+    // Disable short URL generation to prevent brute-forcing
+    prompter.info("Uploading ephemeral data to the cloud...");
+    let resp = ureq::put(&format!("https://oshi.at/?expire={expiry}&shorturl=0"))
+        .set("Content-Type", "application/octet-stream")
+        .send_bytes(data)?;
+    debug!(
+        "Ephemeral data host responded with status {}",
+        resp.status()
+    );
+    if resp.status() != 200 {
+        bail!("failed to upload ephemeral data: {}", resp.into_string()?);
+    }
+    prompter.info("Upload successful!");
+    let resp_str = resp.into_string()?;
+    let lines = resp_str
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .collect::<Vec<_>>();
+    if lines.len() != 3 {
+        bail!("unexpected response from ephemeral data service");
+    }
+    // Line 1 is the admin, line 2 is the download, and line 3 is the Tor download
+    let download_line = lines[1].trim();
+    // The URL is that up to the first space
+    let url = download_line.split_whitespace().next().unwrap();
+    prompter.detail(&format!("The ephemeral data is at {url} until it expires."));
+
+    Ok(url.to_string())
+}
+
+/// Downloads data uploaded with [`upload_ephemeral`] from the given URL, failing if it's expired.
+pub(super) fn download_ephemeral(url: &str, prompter: &mut dyn Prompter) -> Result<Vec<u8>> {
+    prompter.info("Downloading ephemeral data from the cloud...");
+    prompter.detail(&format!("Downloading from {url}."));
+    let resp = ureq::get(url).call()?;
+    debug!(
+        "Ephemeral data host responded with status {}",
+        resp.status()
+    );
+    if resp.status() != 200 {
+        bail!(
+            "failed to download ephemeral data (may have expired): {}",
+            resp.into_string()?
+        );
+    }
+    prompter.info("Download successful!");
+    // Nothing we upload is anywhere near this long
+    let mut download = Vec::new();
+    resp.into_reader().take(1024).read_to_end(&mut download)?;
+
+    Ok(download)
+}
+
+/// Checks that data uploaded with [`upload_ephemeral`] is still at the given URL, without
+/// downloading it.
+pub(super) fn probe_ephemeral(url: &str) -> Result<String> {
+    let status = head_status(&ureq::agent(), url)?;
+    if status != 200 {
+        bail!("the host answered with status {status} (the data may have expired)");
+    }
+
+    Ok("the data is still there".to_string())
+}
+
+/// Derives the key the ephemeral data is encrypted under from the passphrase protecting it.
+fn passphrase_key(passphrase: &str, salt: &[u8; 32]) -> Result<[u8; 32]> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|err| anyhow!("failed to derive key from passphrase: {err}"))?;
+
+    Ok(key)
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct EphemeralFactorData {
     url: String,
+    /// The salt for deriving a key from the passphrase the data was encrypted under before it was
+    /// uploaded, if it was (otherwise, the data was uploaded as it is).
+    salt: Option<[u8; 32]>,
 }
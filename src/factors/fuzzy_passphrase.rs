@@ -0,0 +1,176 @@
+use super::passphrase::{passphrase_entropy, passphrase_from_env};
+use crate::{factor::Factor, prompt::Prompter, rng::CystRng};
+use anyhow::{anyhow, bail, Result};
+use argon2::Argon2;
+use chacha20poly1305::{aead::Aead, AeadCore, ChaCha20Poly1305, KeyInit};
+use rand::{seq::SliceRandom, Rng};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// A passphrase that still works with a common typo in it, for users who'd otherwise be locked out
+/// by a single slip (e.g. because of a motor impairment). Like [`super::RecoveryCodesFactor`], this
+/// generates a random key and encrypts it separately under the passphrase and each of the typos
+/// that should be tolerated, so any of them will derive the same key.
+///
+/// This is less secure than an exact passphrase: every tolerated typo is another passphrase an
+/// attacker might guess, and there can be a few dozen of them for a long passphrase. The tolerance
+/// can be turned off entirely, which makes this the same as an ordinary passphrase (but slower).
+/// The passphrase can be provided through the environment in the same way as for
+/// [`super::PassphraseFactor`].
+pub struct FuzzyPassphraseFactor;
+impl Factor for FuzzyPassphraseFactor {
+    type Data = FuzzyPassphraseFactorData;
+    type Key = Vec<u8>;
+
+    fn name() -> &'static str {
+        "Typo-tolerant passphrase"
+    }
+    fn description() -> &'static str {
+        "A passphrase that still works with a common typo in it (less secure than an exact one)"
+    }
+    fn create(prompter: &mut dyn Prompter) -> Result<(Self::Data, Self::Key)> {
+        let (data, key, _) = Self::create_with_entropy(prompter)?;
+        Ok((data, key))
+    }
+    fn create_with_entropy(
+        prompter: &mut dyn Prompter,
+    ) -> Result<(Self::Data, Self::Key, Option<f64>)> {
+        let tolerance = prompter.select(
+            "Which typos should be tolerated? (Each one tolerated makes the passphrase easier to guess.)",
+            &[
+                "None (only the exact passphrase)",
+                "Two neighbouring characters swapped",
+                "Two neighbouring characters swapped, or one character missed or doubled",
+            ],
+        )?;
+        let passphrase = prompter.new_passphrase("Enter a passphrase", &|passphrase| {
+            if passphrase.is_empty() {
+                Err("the passphrase can't be empty".to_string())
+            } else {
+                Ok(())
+            }
+        })?;
+        let mut variants = typo_variants(&passphrase, tolerance);
+        // Every tolerated typo is another passphrase that would be guessed just as easily
+        let entropy = (passphrase_entropy(&passphrase) - (variants.len() as f64).log2()).max(0.0);
+        // The order would otherwise give away which one is the real passphrase
+        variants.shuffle(&mut CystRng);
+
+        let key = CystRng.gen::<[u8; 32]>();
+        let salt = CystRng.gen::<[u8; 32]>();
+        prompter.info(&format!(
+            "Deriving keys for the passphrase and {} typo(s) of it (this may take a while)...",
+            variants.len() - 1
+        ));
+        let mut wrapped_keys = Vec::new();
+        for variant in variants {
+            let cipher = ChaCha20Poly1305::new(variant_key(&variant, &salt)?.as_ref().into());
+            let nonce = ChaCha20Poly1305::generate_nonce(CystRng);
+            let ciphertext = cipher.encrypt(&nonce, key.as_ref()).unwrap();
+            wrapped_keys.push((nonce.into(), ciphertext));
+        }
+
+        Ok((
+            FuzzyPassphraseFactorData { salt, wrapped_keys },
+            key.to_vec(),
+            Some(entropy),
+        ))
+    }
+    fn derive(data: Self::Data, prompter: &mut dyn Prompter) -> Result<Self::Key> {
+        let passphrase = match passphrase_from_env(prompter)? {
+            Some(passphrase) => passphrase,
+            None => prompter.password("Enter the passphrase", &|_| Ok(()))?,
+        };
+
+        // Only one derivation is needed, since every variant shares the same salt
+        let cipher = ChaCha20Poly1305::new(variant_key(&passphrase, &data.salt)?.as_ref().into());
+        for (nonce, ciphertext) in &data.wrapped_keys {
+            if let Ok(key) = cipher.decrypt(nonce.into(), ciphertext.as_ref()) {
+                return Ok(key);
+            }
+        }
+        bail!("passphrase is incorrect");
+    }
+}
+
+/// Gets the given passphrase and every typo of it that should be tolerated at the given level (0
+/// for none, 1 for swapped neighbouring characters, and 2 for missed or doubled characters too),
+/// without duplicates.
+fn typo_variants(passphrase: &str, tolerance: usize) -> Vec<String> {
+    let chars = passphrase.chars().collect::<Vec<_>>();
+    let mut variants = vec![passphrase.to_string()];
+    if tolerance >= 1 {
+        for idx in 0..chars.len().saturating_sub(1) {
+            let mut swapped = chars.clone();
+            swapped.swap(idx, idx + 1);
+            variants.push(swapped.into_iter().collect());
+        }
+    }
+    if tolerance >= 2 {
+        for idx in 0..chars.len() {
+            let mut missed = chars.clone();
+            missed.remove(idx);
+            variants.push(missed.into_iter().collect());
+
+            let mut doubled = chars.clone();
+            doubled.insert(idx, chars[idx]);
+            variants.push(doubled.into_iter().collect());
+        }
+    }
+
+    // Typos like swapping two identical characters just give the passphrase back
+    let mut seen = HashSet::new();
+    variants.retain(|variant| !variant.is_empty() && seen.insert(variant.clone()));
+    variants
+}
+
+/// Derives the key that wraps the factor's key from the passphrase or one of its typos.
+fn variant_key(variant: &str, salt: &[u8; 32]) -> Result<[u8; 32]> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(variant.as_bytes(), salt, &mut key)
+        .map_err(|err| anyhow!("failed to derive key from passphrase: {err}"))?;
+
+    Ok(key)
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FuzzyPassphraseFactorData {
+    /// The salt used for deriving keys from the passphrase and all its tolerated typos.
+    salt: [u8; 32],
+    /// The factor's key, encrypted separately under the passphrase and each tolerated typo (in a
+    /// random order), along with the nonces used.
+    wrapped_keys: Vec<([u8; 12], Vec<u8>)>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn typo_variants_at_each_tolerance() {
+        let cases: &[(&str, usize, &[&str])] = &[
+            ("abc", 0, &["abc"]),
+            ("abc", 1, &["abc", "bac", "acb"]),
+            // Swapping the two `a`s gives the passphrase back
+            ("aab", 1, &["aab", "aba"]),
+            (
+                "abc",
+                2,
+                &[
+                    "abc", "bac", "acb", "bc", "aabc", "ac", "abbc", "ab", "abcc",
+                ],
+            ),
+            // Missing the only character would leave nothing
+            ("a", 2, &["a", "aa"]),
+            ("aa", 2, &["aa", "a", "aaa"]),
+        ];
+        for (passphrase, tolerance, expected) in cases {
+            assert_eq!(
+                typo_variants(passphrase, *tolerance),
+                *expected,
+                "{passphrase:?} at tolerance {tolerance}"
+            );
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use super::head_status;
+use crate::{factor::Factor, prompt::Prompter, rng::CystRng};
+use anyhow::{anyhow, bail, Context, Result};
+use log::debug;
+use rand::Rng;
+use rustls::{
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    ClientConfig, RootCertStore,
+};
+use serde::{Deserialize, Serialize};
+use std::{io::Read, sync::Arc, time::Duration};
+use ureq::{Agent, AgentBuilder, Request};
+
+/// The default timeout for requests to the key-release service, in seconds.
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+
+/// A factor that stores random key bytes with a key-release service the user already runs, and
+/// fetches them again over HTTPS when deriving. Unlike the ephemeral factor, this doesn't rely on
+/// a public file host: the service is expected to authenticate requests and enforce whatever
+/// policy the user wants (rate limits, approvals, etc.) before releasing the key.
+///
+/// Requests are authenticated with either a bearer token (which is asked for every time, and never
+/// stored) or a TLS client certificate. TLS certificates are always verified, either against the
+/// built-in web roots or against a CA certificate the user provides for their service.
+pub struct HttpKeyFactor;
+impl Factor for HttpKeyFactor {
+    type Data = HttpKeyFactorData;
+    type Key = Vec<u8>;
+
+    fn name() -> &'static str {
+        "HTTP key release"
+    }
+    fn description() -> &'static str {
+        "Random key bytes released by your own key service over HTTPS"
+    }
+    fn create(prompter: &mut dyn Prompter) -> Result<(Self::Data, Self::Key)> {
+        let url = prompter.input("Enter the URL to store the key at", None, &|url| {
+            if url.starts_with("https://") {
+                Ok(())
+            } else {
+                Err("the key-release service must use https".to_string())
+            }
+        })?;
+        let auth = match prompter.select(
+            "How should requests to the service be authenticated?",
+            &["Bearer token", "TLS client certificate"],
+        )? {
+            0 => HttpAuth::Bearer,
+            _ => HttpAuth::ClientCert {
+                cert_path: prompter.input(
+                    "Enter the path to the client certificate (PEM)",
+                    None,
+                    &|_| Ok(()),
+                )?,
+                key_path: prompter.input(
+                    "Enter the path to the client certificate's private key (PEM)",
+                    None,
+                    &|_| Ok(()),
+                )?,
+            },
+        };
+        let ca_cert_path =
+            if prompter.confirm("Does the service use a certificate from your own CA?")? {
+                Some(prompter.input(
+                    "Enter the path to the CA certificate to trust (PEM)",
+                    None,
+                    &|_| Ok(()),
+                )?)
+            } else {
+                None
+            };
+        let timeout_secs = prompter.parsed(
+            "How many seconds should requests to the service be allowed to take?",
+            Some(DEFAULT_TIMEOUT_SECS),
+        )?;
+        let data = HttpKeyFactorData {
+            url,
+            auth,
+            ca_cert_path,
+            timeout_secs,
+        };
+
+        let key = CystRng.gen::<[u8; 32]>();
+        let request = data.request("PUT", prompter)?;
+        prompter.info("Storing the key with the key-release service...");
+        request
+            .set("Content-Type", "application/octet-stream")
+            .send_bytes(&key)
+            .context("failed to store key with the key-release service")?;
+
+        Ok((data, key.to_vec()))
+    }
+    fn derive(data: Self::Data, prompter: &mut dyn Prompter) -> Result<Self::Key> {
+        let request = data.request("GET", prompter)?;
+        prompter.info("Fetching the key from the key-release service...");
+        let resp = request
+            .call()
+            .context("failed to fetch key from the key-release service")?;
+        debug!(
+            "Key-release service responded with status {}",
+            resp.status()
+        );
+
+        // Read one byte more than we need, so we can tell if the response is too long
+        let mut key = Vec::new();
+        resp.into_reader().take(33).read_to_end(&mut key)?;
+        if key.len() != 32 {
+            debug!("Key-release service returned {} byte(s)", key.len());
+            bail!("key-release service returned a key of the wrong length");
+        }
+
+        Ok(key)
+    }
+    fn requires_network() -> bool {
+        true
+    }
+    fn probe(data: Self::Data) -> Result<Option<String>> {
+        // Requests aren't authenticated here (a bearer token would have to be asked for), so any
+        // answer at all means the service is up
+        let status = head_status(&data.agent()?, &data.url)?;
+
+        Ok(Some(format!(
+            "the service can be reached (it answered with status {status})"
+        )))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct HttpKeyFactorData {
+    /// The URL the key is stored at.
+    url: String,
+    /// How requests to the service are authenticated.
+    auth: HttpAuth,
+    /// The path to a CA certificate to verify the service's certificate against, instead of the
+    /// built-in web roots.
+    ca_cert_path: Option<String>,
+    /// The timeout for the whole of each request, in seconds.
+    timeout_secs: u64,
+}
+impl HttpKeyFactorData {
+    /// Builds a request to the service with the given method, prompting the user for a bearer
+    /// token if that's needed.
+    fn request(&self, method: &str, prompter: &mut dyn Prompter) -> Result<Request> {
+        let request = self.agent()?.request(method, &self.url);
+        prompter.detail(&format!("Sending a {method} request to {}.", self.url));
+        Ok(match &self.auth {
+            HttpAuth::Bearer => {
+                let token = prompter.password(
+                    "Enter the bearer token for the key-release service",
+                    &|_| Ok(()),
+                )?;
+                request.set("Authorization", &format!("Bearer {}", token.trim()))
+            }
+            HttpAuth::ClientCert { .. } => request,
+        })
+    }
+
+    /// Creates an HTTP agent with this factor's timeout and TLS settings.
+    fn agent(&self) -> Result<Agent> {
+        let mut roots = RootCertStore::empty();
+        match &self.ca_cert_path {
+            Some(path) => {
+                for cert in CertificateDer::pem_file_iter(path)
+                    .with_context(|| format!("failed to read CA certificate from '{path}'"))?
+                {
+                    roots.add(cert.with_context(|| {
+                        format!("failed to parse CA certificate from '{path}'")
+                    })?)?;
+                }
+            }
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+
+        let builder = ClientConfig::builder().with_root_certificates(roots);
+        let config = match &self.auth {
+            HttpAuth::Bearer => builder.with_no_client_auth(),
+            HttpAuth::ClientCert {
+                cert_path,
+                key_path,
+            } => {
+                let certs = CertificateDer::pem_file_iter(cert_path)
+                    .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                    .with_context(|| {
+                        format!("failed to read client certificate from '{cert_path}'")
+                    })?;
+                let key = PrivateKeyDer::from_pem_file(key_path).with_context(|| {
+                    format!("failed to read client certificate key from '{key_path}'")
+                })?;
+                builder
+                    .with_client_auth_cert(certs, key)
+                    .map_err(|err| anyhow!("invalid client certificate: {err}"))?
+            }
+        };
+
+        Ok(AgentBuilder::new()
+            .timeout(Duration::from_secs(self.timeout_secs))
+            .tls_config(Arc::new(config))
+            .build())
+    }
+}
+
+/// How requests to a key-release service are authenticated.
+#[derive(Serialize, Deserialize)]
+enum HttpAuth {
+    /// A bearer token, which the user is asked for whenever it's needed.
+    Bearer,
+    /// A TLS client certificate, and its private key, read from the given PEM files.
+    ClientCert { cert_path: String, key_path: String },
+}
@@ -1,43 +1,118 @@
-use crate::factor::Factor;
-use anyhow::{bail, Context, Result};
-use dialoguer::Input;
-use rand::{rngs::OsRng, Rng};
+use super::clean_pasted;
+use crate::{factor::Factor, prompt::Prompter, rng::CystRng};
+use anyhow::{anyhow, bail, Context, Result};
+use argon2::Argon2;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+};
 
 /// An encryption factor using a keyfile.
+///
+/// The key isn't the keyfile's contents themselves, but what a salted Argon2 derivation makes of
+/// them, so a keyfile that's predictable (e.g. one that's been replaced with a text file) can't
+/// contribute weak key material directly. For the random keyfiles this generates, that's
+/// unnecessary but harmless.
 pub struct KeyfileFactor;
 impl Factor for KeyfileFactor {
-    type Data = ();
+    type Data = KeyfileFactorData;
     type Key = [u8; 32];
 
     fn name() -> &'static str {
         "Keyfile"
     }
-    fn create() -> Result<(Self::Data, Self::Key)> {
+    fn description() -> &'static str {
+        "A file of random key bytes, generated when the option is created"
+    }
+    fn create(prompter: &mut dyn Prompter) -> Result<(Self::Data, Self::Key)> {
         // Generate random data
-        let key = OsRng.gen::<[u8; 32]>();
-        // Prompt the user for a path to write to
-        let path: String = Input::new()
-            .with_prompt("Enter a path to write the keyfile to")
-            .interact()
-            .unwrap();
-        std::fs::write(&path, &key).with_context(|| "failed to write to given path")?;
-
-        Ok(((), key))
+        let key = CystRng.gen::<[u8; 32]>();
+        let path = prompt_new_keyfile_path(prompter, "Enter a path to write the keyfile to")?;
+        write_keyfile(&path, &key)
+            .with_context(|| format!("failed to write the keyfile to {path:?}"))?;
+
+        let data = KeyfileFactorData {
+            salt: CystRng.gen(),
+        };
+        let key = stretch_key(&key, &data)?;
+
+        Ok((data, key))
     }
-    fn derive(_: Self::Data) -> Result<Self::Key> {
+    fn derive(data: Self::Data, prompter: &mut dyn Prompter) -> Result<Self::Key> {
         // Get the path from the user
-        let path: String = Input::new()
-            .with_prompt("Enter the path to the keyfile")
-            .interact()
-            .unwrap();
+        let path = prompter.input("Enter the path to the keyfile", None, &|_| Ok(()))?;
 
-        let raw_key = std::fs::read(&path).with_context(|| "failed to read from given path")?;
+        let raw_key =
+            std::fs::read(clean_pasted(&path)).with_context(|| "failed to read from given path")?;
         if raw_key.len() != 32 {
             bail!("keyfile had incorrect length (corrupted)");
         }
-        let mut key = [0u8; 32];
-        key.copy_from_slice(&raw_key);
 
-        Ok(key)
+        stretch_key(&raw_key, &data)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct KeyfileFactorData {
+    /// The salt used in deriving the key from the keyfile's contents.
+    salt: [u8; 32],
+}
+
+/// Runs the contents of a keyfile through Argon2 with the salt in the given data.
+fn stretch_key(contents: &[u8], data: &KeyfileFactorData) -> Result<[u8; 32]> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(contents, &data.salt, &mut key)
+        .map_err(|err| anyhow!("failed to derive key from keyfile: {err}"))?;
+
+    Ok(key)
+}
+
+/// Prompts the user for a path to write a new keyfile (or anything else secret) to with the given
+/// question, making sure they really mean to overwrite anything that's already there.
+pub(super) fn prompt_new_keyfile_path(
+    prompter: &mut dyn Prompter,
+    question: &str,
+) -> Result<PathBuf> {
+    loop {
+        let path = prompter.input(question, None, &|path| {
+            if Path::new(&clean_pasted(path)).is_dir() {
+                Err("that's a directory, not a file".to_string())
+            } else {
+                Ok(())
+            }
+        })?;
+        let path = PathBuf::from(clean_pasted(&path));
+        if !path.exists()
+            || prompter.confirm(&format!(
+                "{path:?} already exists. Overwrite it? (Whatever's in it will be lost!)"
+            ))?
+        {
+            return Ok(path);
+        }
+    }
+}
+
+/// Writes the given key to a keyfile at the given path, which only we can read (on Unix). The
+/// keyfile is read back afterwards, since a write that was silently cut short would otherwise only
+/// be noticed when the keyfile is next needed, when it's too late.
+pub(super) fn write_keyfile(path: &Path, key: &[u8]) -> Result<()> {
+    let mut file = OpenOptions::new();
+    file.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut file, 0o600);
+    let mut file = file.open(path)?;
+    // The mode above only applies to new files, and this might be overwriting an existing one
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+    file.write_all(key)?;
+    file.sync_all()?;
+
+    if std::fs::read(path)? != key {
+        bail!("the keyfile didn't read back the same as it was written");
     }
+    Ok(())
 }
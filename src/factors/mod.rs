@@ -1,19 +1,165 @@
+#[cfg(feature = "age")]
+mod age;
+mod counted_keyfile;
+#[cfg(feature = "distributed-shamir")]
+mod distributed_shamir;
+#[cfg(feature = "ephemeral")]
 mod ephemeral;
+mod fuzzy_passphrase;
+#[cfg(feature = "http-key")]
+mod http_key;
 mod keyfile;
+#[cfg(feature = "network-presence")]
+mod network_presence;
 mod passphrase;
+mod peppered_passphrase;
+mod pin;
+mod recovery_codes;
+mod security_questions;
+#[cfg(feature = "shamir")]
 mod shamir;
+#[cfg(all(unix, feature = "ssh-agent"))]
+mod ssh_agent;
 
 use crate::factor::{Factor, FactorRegistry};
-use ephemeral::EphemeralFactor;
-use keyfile::KeyfileFactor;
-use passphrase::PassphraseFactor;
-use shamir::ShamirFactor;
+#[cfg(feature = "age")]
+pub use age::AgeFactor;
+pub use counted_keyfile::CountedKeyfileFactor;
+#[cfg(feature = "distributed-shamir")]
+pub use distributed_shamir::DistributedShamirFactor;
+#[cfg(feature = "ephemeral")]
+pub use ephemeral::EphemeralFactor;
+pub use fuzzy_passphrase::FuzzyPassphraseFactor;
+#[cfg(feature = "http-key")]
+pub use http_key::HttpKeyFactor;
+pub use keyfile::KeyfileFactor;
+#[cfg(feature = "network-presence")]
+pub use network_presence::NetworkPresenceFactor;
+pub use passphrase::{PassphraseFactor, PASSPHRASE_ENV, PASSPHRASE_FILE_ENV};
+pub use peppered_passphrase::PepperedPassphraseFactor;
+pub use pin::PinFactor;
+pub use recovery_codes::RecoveryCodesFactor;
+pub use security_questions::SecurityQuestionsFactor;
+#[cfg(feature = "shamir")]
+pub use shamir::{ShamirFactor, SHARES_FILE_ENV};
+#[cfg(all(unix, feature = "ssh-agent"))]
+pub use ssh_agent::SshAgentFactor;
 
+/// Every factor that can be left out of a build, along with the Cargo feature that enables it.
+/// This is kept separately from the factors themselves (which won't be compiled in if their
+/// feature isn't enabled) so we can tell the user how to get a missing factor. Factors that are
+/// always available aren't listed.
+const OPTIONAL_FACTORS: &[(&str, &str)] = &[
+    ("Distributed Shamir shares", "distributed-shamir"),
+    ("Ephemeral data", "ephemeral"),
+    ("HTTP key release", "http-key"),
+    ("Network presence", "network-presence"),
+    ("SSH agent", "ssh-agent"),
+    ("Shamir secret sharing", "shamir"),
+    ("age recipients", "age"),
+];
+
+/// Cleans up a path or value the user has pasted in. Surrounding whitespace is removed (including
+/// the `\r` left by a Windows line ending), and then a pair of matching quotes around the whole
+/// thing, which some terminals add when a file is dragged into them.
+pub(crate) fn clean_pasted(input: &str) -> &str {
+    let input = input.trim();
+    ['"', '\'']
+        .into_iter()
+        .find_map(|quote| {
+            input
+                .strip_prefix(quote)
+                .and_then(|input| input.strip_suffix(quote))
+        })
+        .map_or(input, str::trim)
+}
+
+/// Sends a `HEAD` request to the given URL with the given agent, to check it can be reached without
+/// fetching anything, returning the status it answered with. An error status still means it was
+/// reached, so only failing to get an answer at all is an error.
+#[cfg(feature = "network")]
+pub(crate) fn head_status(agent: &ureq::Agent, url: &str) -> anyhow::Result<u16> {
+    match agent.head(url).call() {
+        Ok(resp) => Ok(resp.status()),
+        Err(ureq::Error::Status(status, _)) => Ok(status),
+        Err(err) => Err(anyhow::Error::new(err).context(format!("couldn't reach {url}"))),
+    }
+}
+
+/// Gets the Cargo feature that enables the factor with the given name, if it's one of the factors
+/// that can be left out of a build. For any other name, there's no such factor at all.
+pub fn factor_feature(name: &str) -> Option<&'static str> {
+    OPTIONAL_FACTORS
+        .iter()
+        .find(|(factor, _)| *factor == name)
+        .map(|(_, feature)| *feature)
+}
+
+/// Gets a registry of all the factors available in this build. Apart from the core factors, which
+/// are always available, this depends on which Cargo features cyst was built with.
 pub fn get_factors() -> FactorRegistry {
     let mut factors = FactorRegistry::new();
     factors.insert(PassphraseFactor::name(), Box::new(PassphraseFactor));
+    factors.insert(
+        PepperedPassphraseFactor::name(),
+        Box::new(PepperedPassphraseFactor),
+    );
+    factors.insert(
+        FuzzyPassphraseFactor::name(),
+        Box::new(FuzzyPassphraseFactor),
+    );
+    factors.insert(KeyfileFactor::name(), Box::new(KeyfileFactor));
+    factors.insert(CountedKeyfileFactor::name(), Box::new(CountedKeyfileFactor));
+    factors.insert(PinFactor::name(), Box::new(PinFactor));
+    factors.insert(RecoveryCodesFactor::name(), Box::new(RecoveryCodesFactor));
+    factors.insert(
+        SecurityQuestionsFactor::name(),
+        Box::new(SecurityQuestionsFactor),
+    );
+    #[cfg(feature = "ephemeral")]
     factors.insert(EphemeralFactor::name(), Box::new(EphemeralFactor));
+    #[cfg(feature = "shamir")]
     factors.insert(ShamirFactor::name(), Box::new(ShamirFactor));
-    factors.insert(KeyfileFactor::name(), Box::new(KeyfileFactor));
+    #[cfg(feature = "distributed-shamir")]
+    factors.insert(
+        DistributedShamirFactor::name(),
+        Box::new(DistributedShamirFactor),
+    );
+    #[cfg(feature = "age")]
+    factors.insert(AgeFactor::name(), Box::new(AgeFactor));
+    #[cfg(feature = "http-key")]
+    factors.insert(HttpKeyFactor::name(), Box::new(HttpKeyFactor));
+    #[cfg(feature = "network-presence")]
+    factors.insert(
+        NetworkPresenceFactor::name(),
+        Box::new(NetworkPresenceFactor),
+    );
+    #[cfg(all(unix, feature = "ssh-agent"))]
+    factors.insert(SshAgentFactor::name(), Box::new(SshAgentFactor));
     factors
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pasted_values_are_cleaned() {
+        let cases = [
+            ("\"/home/me/key file\"\r\n", "/home/me/key file"),
+            ("'/home/me/key file'", "/home/me/key file"),
+            ("  ' /home/me/key '  ", "/home/me/key"),
+            // Unbalanced quotes are left alone, since they might be part of the value
+            ("\"/home/me/key", "\"/home/me/key"),
+            ("/home/me/key'", "/home/me/key'"),
+            ("\"/home/me/key'", "\"/home/me/key'"),
+            ("\"", "\""),
+            ("\"\"", ""),
+            ("018d3c0a7f29b4e6\r", "018d3c0a7f29b4e6"),
+            ("/home/me/key", "/home/me/key"),
+        ];
+        for (pasted, cleaned) in cases {
+            assert_eq!(clean_pasted(pasted), cleaned, "{pasted:?}");
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use super::head_status;
+use crate::{factor::Factor, prompt::Prompter, rng::CystRng};
+use anyhow::{bail, Context, Result};
+use blake2::{Blake2s256, Digest};
+use log::debug;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::{io::Read, time::Duration};
+use ureq::{Agent, AgentBuilder};
+
+/// The default timeout for requests to the internal host, in seconds. This is short, since a host
+/// on the local network should answer almost immediately, and waiting long to find out that we're
+/// somewhere else isn't useful.
+const DEFAULT_TIMEOUT_SECS: u64 = 5;
+
+/// A factor that can only be satisfied on a particular network, by storing random key bytes on a
+/// host that's only reachable from there (e.g. an internal web server), and fetching them again
+/// when deriving. Outside that network, the host can't be reached, so the factor fails.
+///
+/// This is a convenience gate, not real security: anyone who can reach the host (or who has
+/// fetched the key from it once) can satisfy it. The host is expected to accept the key with a
+/// `PUT` request and serve it back for `GET` requests, just like a key-release service, but
+/// without any authentication.
+pub struct NetworkPresenceFactor;
+impl Factor for NetworkPresenceFactor {
+    type Data = NetworkPresenceFactorData;
+    type Key = Vec<u8>;
+
+    fn name() -> &'static str {
+        "Network presence"
+    }
+    fn description() -> &'static str {
+        "Random key bytes stored on a host only reachable from a particular network (a convenience, not security)"
+    }
+    fn create(prompter: &mut dyn Prompter) -> Result<(Self::Data, Self::Key)> {
+        prompter.notice(
+            "Note: anyone who can reach the host (or has fetched the key from it) can satisfy this factor, so it's only a convenience.",
+        );
+        let url = prompter.input(
+            "Enter the URL on the internal host to store the key at",
+            None,
+            &|url| {
+                if url.starts_with("http://") || url.starts_with("https://") {
+                    Ok(())
+                } else {
+                    Err("the URL must start with http:// or https://".to_string())
+                }
+            },
+        )?;
+        let timeout_secs = prompter.parsed(
+            "How many seconds should the host be given to answer before deciding this isn't the right network?",
+            Some(DEFAULT_TIMEOUT_SECS),
+        )?;
+
+        let key = CystRng.gen::<[u8; 32]>();
+        let data = NetworkPresenceFactorData {
+            url,
+            fingerprint: fingerprint(&key),
+            timeout_secs,
+        };
+        prompter.info("Storing the key on the internal host...");
+        prompter.detail(&format!("Sending a PUT request to {}.", data.url));
+        let resp = data
+            .agent()
+            .put(&data.url)
+            .set("Content-Type", "application/octet-stream")
+            .send_bytes(&key)
+            .with_context(|| format!("failed to store the key at {}", data.url))?;
+        debug!("Internal host responded with status {}", resp.status());
+
+        Ok((data, key.to_vec()))
+    }
+    fn derive(data: Self::Data, prompter: &mut dyn Prompter) -> Result<Self::Key> {
+        prompter.info("Checking that we're on the expected network...");
+        prompter.detail(&format!("Sending a GET request to {}.", data.url));
+        let resp = match data.agent().get(&data.url).call() {
+            Ok(resp) => resp,
+            Err(ureq::Error::Transport(err)) => {
+                debug!("Couldn't reach the internal host: {err}");
+                // The error already says which URL it was
+                bail!("not on the expected network (couldn't reach the host: {err})");
+            }
+            Err(ureq::Error::Status(status, _)) => {
+                bail!(
+                    "not on the expected network ({} responded with status {status})",
+                    data.url
+                );
+            }
+        };
+        debug!("Internal host responded with status {}", resp.status());
+
+        // Read one byte more than we need, so we can tell if the response is too long
+        let mut key = Vec::new();
+        resp.into_reader().take(33).read_to_end(&mut key)?;
+        // A different host at the same address won't serve the same key
+        if key.len() != 32 || fingerprint(&key) != data.fingerprint {
+            debug!(
+                "Internal host served {} byte(s) that didn't match",
+                key.len()
+            );
+            bail!(
+                "not on the expected network ({} didn't serve the expected key)",
+                data.url
+            );
+        }
+
+        Ok(key)
+    }
+    fn requires_network() -> bool {
+        true
+    }
+    fn probe(data: Self::Data) -> Result<Option<String>> {
+        let status =
+            head_status(&data.agent(), &data.url).context("not on the expected network")?;
+        if !(200..300).contains(&status) {
+            bail!(
+                "not on the expected network ({} responded with status {status})",
+                data.url
+            );
+        }
+
+        Ok(Some("the host can be reached".to_string()))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct NetworkPresenceFactorData {
+    /// The URL the key is stored at.
+    url: String,
+    /// A fingerprint of the key, to tell whether the host that answered is the one that has it.
+    fingerprint: [u8; 16],
+    /// The timeout for the whole of each request, in seconds.
+    timeout_secs: u64,
+}
+impl NetworkPresenceFactorData {
+    /// Creates an HTTP agent with this factor's timeout.
+    fn agent(&self) -> Agent {
+        AgentBuilder::new()
+            .timeout(Duration::from_secs(self.timeout_secs))
+            .build()
+    }
+}
+
+/// Computes the fingerprint of a key that's stored in the header. The key is random, so this gives
+/// nothing away about it.
+fn fingerprint(key: &[u8]) -> [u8; 16] {
+    let digest = Blake2s256::new()
+        .chain_update(b"cyst network presence")
+        .chain_update(key)
+        .finalize();
+
+    digest[..16].try_into().unwrap()
+}
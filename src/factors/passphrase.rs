@@ -1,8 +1,16 @@
-use crate::factor::Factor;
-use anyhow::Result;
-use dialoguer::Password;
+use crate::{factor::Factor, prompt::Prompter};
+use anyhow::{Context, Result};
+
+/// The environment variable that, if set, holds the passphrase to use when deriving the factor.
+pub const PASSPHRASE_ENV: &str = "CYST_PASSPHRASE";
+/// The environment variable that, if set, holds the path to a file containing the passphrase to
+/// use when deriving the factor.
+pub const PASSPHRASE_FILE_ENV: &str = "CYST_PASSPHRASE_FILE";
 
 /// A passphrase encryption factor, based solely on user input.
+///
+/// For automation, the passphrase can be provided through the environment when deriving (see
+/// [`PASSPHRASE_ENV`] and [`PASSPHRASE_FILE_ENV`]), in which case the user won't be prompted.
 pub struct PassphraseFactor;
 impl Factor for PassphraseFactor {
     type Data = ();
@@ -11,18 +19,77 @@ impl Factor for PassphraseFactor {
     fn name() -> &'static str {
         "Passphrase"
     }
-    fn create() -> Result<(Self::Data, Self::Key)> {
-        let passphrase = Password::new()
-            .with_prompt("Enter a passphrase")
-            .interact()
-            .unwrap();
+    fn description() -> &'static str {
+        "A passphrase you type in (or provide through the environment)"
+    }
+    fn create(prompter: &mut dyn Prompter) -> Result<(Self::Data, Self::Key)> {
+        let passphrase = prompter.new_passphrase("Enter a passphrase", &|_| Ok(()))?;
         Ok(((), passphrase.into_bytes()))
     }
-    fn derive(_: Self::Data) -> Result<Self::Key> {
-        let passphrase = Password::new()
-            .with_prompt("Enter the passphrase")
-            .interact()
-            .unwrap();
+    fn derive(_: Self::Data, prompter: &mut dyn Prompter) -> Result<Self::Key> {
+        let passphrase = match passphrase_from_env(prompter)? {
+            Some(passphrase) => passphrase,
+            None => prompter.password("Enter the passphrase", &|_| Ok(()))?,
+        };
         Ok(passphrase.into_bytes())
     }
+    fn entropy(_data: &Self::Data, key: &[u8]) -> Option<f64> {
+        Some(passphrase_entropy(&String::from_utf8_lossy(key)))
+    }
+}
+
+/// Roughly estimates the entropy of a passphrase in bits, as if each of its characters were chosen
+/// at random from the kinds of character it uses (lowercase, uppercase, digits, and anything else).
+/// A character that repeats the one before it doesn't count, so `aaaaaaaa` isn't mistaken for a
+/// strong passphrase. This is generous to passphrases made of words, but it's only meant to catch
+/// ones that are obviously too short.
+pub(super) fn passphrase_entropy(passphrase: &str) -> f64 {
+    let chars = passphrase.chars().collect::<Vec<_>>();
+    let pool = [
+        (chars.iter().any(char::is_ascii_lowercase), 26),
+        (chars.iter().any(char::is_ascii_uppercase), 26),
+        (chars.iter().any(char::is_ascii_digit), 10),
+        (
+            chars.iter().any(|c| !c.is_ascii_alphanumeric()),
+            // Printable ASCII symbols and space, with some allowance for anything else
+            33,
+        ),
+    ]
+    .into_iter()
+    .filter(|(used, _)| *used)
+    .map(|(_, size)| size)
+    .sum::<u32>();
+    let len = chars
+        .iter()
+        .enumerate()
+        .filter(|(idx, c)| *idx == 0 || chars[idx - 1] != **c)
+        .count();
+
+    len as f64 * f64::from(pool.max(1)).log2()
+}
+
+/// Gets the passphrase from the environment, if it's been provided there. Only the names of the
+/// variables are ever printed, never their values.
+pub(super) fn passphrase_from_env(prompter: &mut dyn Prompter) -> Result<Option<String>> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        prompter.info(&format!("Using the passphrase from ${PASSPHRASE_ENV}."));
+        return Ok(Some(passphrase));
+    }
+    if let Some(path) = std::env::var_os(PASSPHRASE_FILE_ENV) {
+        prompter.info(&format!(
+            "Using the passphrase from the file in ${PASSPHRASE_FILE_ENV}."
+        ));
+        let mut passphrase = std::fs::read_to_string(&path)
+            .with_context(|| format!("failed to read passphrase from {path:?}"))?;
+        // Files made with `echo` will have a trailing newline that isn't part of the passphrase
+        if passphrase.ends_with('\n') {
+            passphrase.pop();
+            if passphrase.ends_with('\r') {
+                passphrase.pop();
+            }
+        }
+        return Ok(Some(passphrase));
+    }
+
+    Ok(None)
 }
@@ -0,0 +1,149 @@
+use super::{
+    clean_pasted,
+    keyfile::{prompt_new_keyfile_path, write_keyfile},
+    passphrase::passphrase_from_env,
+};
+use crate::{factor::Factor, prompt::Prompter, rng::CystRng};
+use anyhow::{bail, Context, Result};
+use rand::Rng;
+use ring::hkdf;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// A passphrase hardened with a *pepper*: a random value kept in a file on the device, which is
+/// combined with the passphrase to produce the key. If the encrypted file leaks without the pepper
+/// file, the passphrase can't be attacked offline, since every guess would need the pepper too.
+///
+/// Unlike a keyfile, the pepper alone is useless without the passphrase. Only the path to the
+/// pepper file is stored in the header, never the pepper itself. The passphrase can be provided
+/// through the environment in the same way as for [`super::PassphraseFactor`].
+pub struct PepperedPassphraseFactor;
+impl Factor for PepperedPassphraseFactor {
+    type Data = PepperedPassphraseFactorData;
+    type Key = [u8; 32];
+
+    fn name() -> &'static str {
+        "Passphrase + pepper file"
+    }
+    fn description() -> &'static str {
+        "A passphrase combined with a random pepper file kept on this device"
+    }
+    fn create(prompter: &mut dyn Prompter) -> Result<(Self::Data, Self::Key)> {
+        let pepper = CystRng.gen::<[u8; 32]>();
+        // The pepper is as secret as a keyfile, so it's written the same way
+        let path = prompt_new_keyfile_path(
+            prompter,
+            "Enter a path to write the pepper file to (keep it on this device, apart from the encrypted file)",
+        )?;
+        write_keyfile(&path, &pepper)
+            .with_context(|| format!("failed to write the pepper file to {path:?}"))?;
+        // Store an absolute path so the pepper can be found from anywhere
+        let pepper_path = std::fs::canonicalize(path)?;
+        let passphrase = prompter.new_passphrase("Enter a passphrase", &|_| Ok(()))?;
+
+        Ok((
+            PepperedPassphraseFactorData { pepper_path },
+            combine(&pepper, passphrase.as_bytes()),
+        ))
+    }
+    fn derive(data: Self::Data, prompter: &mut dyn Prompter) -> Result<Self::Key> {
+        let pepper = match std::fs::read(&data.pepper_path) {
+            Ok(pepper) => pepper,
+            // It might just have been moved
+            Err(_) => {
+                let path = prompter.input(
+                    &format!(
+                        "Couldn't read the pepper file at {:?}, enter where it is now",
+                        data.pepper_path
+                    ),
+                    None,
+                    &|_| Ok(()),
+                )?;
+                std::fs::read(clean_pasted(&path))
+                    .with_context(|| "failed to read from given path")?
+            }
+        };
+        if pepper.len() != 32 {
+            bail!("pepper file had incorrect length (corrupted)");
+        }
+        let passphrase = match passphrase_from_env(prompter)? {
+            Some(passphrase) => passphrase,
+            None => prompter.password("Enter the passphrase", &|_| Ok(()))?,
+        };
+
+        Ok(combine(&pepper, passphrase.as_bytes()))
+    }
+    fn probe(data: Self::Data) -> Result<Option<String>> {
+        let Ok(metadata) = std::fs::metadata(&data.pepper_path) else {
+            bail!(
+                "there's no pepper file at {:?} (you'll be asked where it is now)",
+                data.pepper_path
+            );
+        };
+        if metadata.len() != 32 {
+            bail!("the pepper file has the wrong length (corrupted)");
+        }
+
+        Ok(Some("the pepper file is there".to_string()))
+    }
+}
+
+/// Combines the pepper and the passphrase into a key with HKDF, using the pepper as the salt.
+fn combine(pepper: &[u8], passphrase: &[u8]) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    hkdf::Salt::new(hkdf::HKDF_SHA256, pepper)
+        .extract(passphrase)
+        .expand(&[b"cyst peppered passphrase"], hkdf::HKDF_SHA256)
+        .and_then(|okm| okm.fill(&mut key))
+        .unwrap();
+
+    key
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PepperedPassphraseFactorData {
+    /// The absolute path the pepper file was written to.
+    pepper_path: PathBuf,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing::PASSPHRASE, vectors::ScriptedPrompter};
+
+    fn prompter(path: &std::path::Path) -> ScriptedPrompter {
+        ScriptedPrompter::new(vec![
+            (
+                "Enter a path to write the pepper file to",
+                path.to_string_lossy().into_owned(),
+            ),
+            ("Enter a passphrase", PASSPHRASE.to_string()),
+            ("Confirm the passphrase", PASSPHRASE.to_string()),
+            ("Enter the passphrase", PASSPHRASE.to_string()),
+        ])
+    }
+
+    #[test]
+    fn pepper_is_written_like_a_keyfile() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pepper");
+        let (data, key) = PepperedPassphraseFactor::create(&mut prompter(&path)).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        assert_eq!(
+            PepperedPassphraseFactor::derive(data, &mut prompter(&path)).unwrap(),
+            key
+        );
+
+        // Creating another one in the same place has to be confirmed first
+        let err = PepperedPassphraseFactor::create(&mut prompter(&path))
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("already exists"), "{err}");
+    }
+}
@@ -0,0 +1,134 @@
+use crate::{factor::Factor, prompt::Prompter, rng::CystRng};
+use anyhow::{anyhow, bail, Result};
+use argon2::{Algorithm, Argon2, Params, Version};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// The default memory cost of the PIN's key derivation, in MiB.
+const DEFAULT_MEMORY_MIB: u32 = 256;
+/// The default number of iterations of the PIN's key derivation.
+const DEFAULT_ITERATIONS: u32 = 4;
+
+/// A factor for a short numeric PIN, which is run through a deliberately expensive Argon2
+/// derivation (independent of, and much more costly than, the one used for the option as a whole)
+/// to make brute-forcing it slower.
+///
+/// This only raises the bar for an attacker: a PIN has very little entropy, and it is *not* a
+/// substitute for a proper passphrase.
+pub struct PinFactor;
+impl Factor for PinFactor {
+    type Data = PinFactorData;
+    type Key = [u8; 32];
+
+    fn name() -> &'static str {
+        "PIN"
+    }
+    fn description() -> &'static str {
+        "A short numeric PIN, with a deliberately slow key derivation"
+    }
+    fn create(prompter: &mut dyn Prompter) -> Result<(Self::Data, Self::Key)> {
+        let length: u8 = prompter.parsed("How many digits should the PIN have?", Some(6))?;
+        let memory_mib: u32 = prompter.parsed(
+            "How much memory should deriving the PIN take, in MiB? (Higher is slower to brute-force.)",
+            Some(DEFAULT_MEMORY_MIB),
+        )?;
+        // Argon2 takes the memory in KiB, as a `u32`
+        let Some(memory_kib) = memory_mib
+            .checked_mul(1024)
+            .filter(|&memory_kib| memory_kib >= Params::MIN_M_COST)
+        else {
+            bail!(
+                "deriving the PIN must take between 1 and {} MiB of memory",
+                Params::MAX_M_COST / 1024
+            );
+        };
+        let iterations: u32 = prompter.parsed(
+            "How many iterations should deriving the PIN take? (Higher is slower to brute-force.)",
+            Some(DEFAULT_ITERATIONS),
+        )?;
+        let pin = prompt_pin(prompter, "Enter a PIN", length)?;
+
+        let data = PinFactorData {
+            salt: CystRng.gen(),
+            length,
+            memory_kib,
+            iterations,
+        };
+        prompter.info("Deriving a key from the PIN (this will take a while)...");
+        let key = derive_key(&pin, &data)?;
+
+        Ok((data, key))
+    }
+    fn derive(data: Self::Data, prompter: &mut dyn Prompter) -> Result<Self::Key> {
+        let pin = prompt_pin(prompter, "Enter the PIN", data.length)?;
+        prompter.info("Deriving a key from the PIN (this will take a while)...");
+        derive_key(&pin, &data)
+    }
+    fn entropy(data: &Self::Data, _key: &[u8]) -> Option<f64> {
+        Some(f64::from(data.length) * 10f64.log2())
+    }
+    fn memory_needed(data: &Self::Data) -> u64 {
+        data.memory_kib as u64 * 1024
+    }
+}
+
+/// Prompts the user for a PIN of the given length, which must contain only digits.
+pub(super) fn prompt_pin(prompter: &mut dyn Prompter, prompt: &str, length: u8) -> Result<String> {
+    prompter.password(&format!("{prompt} ({length} digits)"), &|pin| {
+        if pin.len() != length as usize || !pin.chars().all(|c| c.is_ascii_digit()) {
+            Err(format!("PIN must be exactly {length} digits"))
+        } else {
+            Ok(())
+        }
+    })
+}
+
+/// Runs the given PIN through Argon2 with the parameters in the given data.
+fn derive_key(pin: &str, data: &PinFactorData) -> Result<[u8; 32]> {
+    let params = Params::new(data.memory_kib, data.iterations, 1, Some(32))
+        .map_err(|err| anyhow!("invalid PIN derivation parameters: {err}"))?;
+    let mut key = [0u8; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(pin.as_bytes(), &data.salt, &mut key)
+        .map_err(|err| anyhow!("failed to derive key from PIN: {err}"))?;
+
+    Ok(key)
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PinFactorData {
+    /// The salt used in deriving a key from the PIN.
+    salt: [u8; 32],
+    /// The number of digits in the PIN.
+    length: u8,
+    /// The memory cost of deriving the key, in KiB.
+    memory_kib: u32,
+    /// The number of iterations used in deriving the key.
+    iterations: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vectors::ScriptedPrompter;
+
+    /// Creates a PIN factor whose derivation takes the given amount of memory, in MiB.
+    fn create(memory_mib: &str) -> Result<(PinFactorData, [u8; 32])> {
+        PinFactor::create(&mut ScriptedPrompter::new(vec![
+            ("How many digits", "6".to_string()),
+            ("How much memory", memory_mib.to_string()),
+            ("How many iterations", "1".to_string()),
+            ("Enter a PIN", "123456".to_string()),
+        ]))
+    }
+
+    #[test]
+    fn memory_that_overflows_is_refused() {
+        for memory_mib in ["0", "4194304", &u32::MAX.to_string()] {
+            let err = create(memory_mib).err().unwrap().to_string();
+            assert!(err.contains("between 1 and 4194303 MiB"), "{err}");
+        }
+        let (data, _) = create("1").unwrap();
+        assert_eq!(data.memory_kib, 1024);
+    }
+}
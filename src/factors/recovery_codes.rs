@@ -0,0 +1,127 @@
+use crate::{factor::Factor, prompt::Prompter, rng::CystRng};
+use anyhow::{anyhow, bail, Result};
+use argon2::Argon2;
+use chacha20poly1305::{aead::Aead, AeadCore, ChaCha20Poly1305, KeyInit};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// The characters recovery codes are made of. This leaves out characters that are easily confused
+/// with one another (0/O, 1/I/L).
+const CODE_ALPHABET: &[u8] = b"23456789ABCDEFGHJKMNPQRSTUVWXYZ";
+/// The number of groups of characters in each code.
+const CODE_GROUPS: usize = 3;
+/// The number of characters in each group of a code.
+const CODE_GROUP_LEN: usize = 4;
+
+/// A factor based on a set of recovery codes, any *one* of which can be used to derive the key.
+/// This works by generating a random key and encrypting it separately under each code.
+///
+/// Note that, because the header isn't modified on decryption, codes can't actually be "used up":
+/// it's up to the user to cross them off once they've used them.
+pub struct RecoveryCodesFactor;
+impl Factor for RecoveryCodesFactor {
+    type Data = RecoveryCodesFactorData;
+    type Key = Vec<u8>;
+
+    fn name() -> &'static str {
+        "Recovery codes"
+    }
+    fn description() -> &'static str {
+        "A set of printable codes, any one of which unlocks the option"
+    }
+    fn create(prompter: &mut dyn Prompter) -> Result<(Self::Data, Self::Key)> {
+        let num_codes: u8 =
+            prompter.parsed("How many recovery codes do you want to create?", Some(8))?;
+        if num_codes == 0 {
+            bail!("at least one recovery code must be created");
+        }
+
+        let key = CystRng.gen::<[u8; 32]>();
+        let salt = CystRng.gen::<[u8; 32]>();
+        let mut wrapped_keys = Vec::new();
+        prompter.notice("Your recovery codes are below. Any one of them will satisfy this factor, so store them safely!");
+        for i in 0..num_codes {
+            let code = generate_code();
+            prompter.notice(&format!("Code #{}: {}", i + 1, format_code(&code)));
+
+            let cipher = ChaCha20Poly1305::new(code_key(&code, &salt)?.as_ref().into());
+            let nonce = ChaCha20Poly1305::generate_nonce(CystRng);
+            let ciphertext = cipher.encrypt(&nonce, key.as_ref()).unwrap();
+            wrapped_keys.push((nonce.into(), ciphertext));
+        }
+
+        Ok((RecoveryCodesFactorData { salt, wrapped_keys }, key.to_vec()))
+    }
+    fn derive(data: Self::Data, prompter: &mut dyn Prompter) -> Result<Self::Key> {
+        let code = prompter.input("Enter one of your recovery codes", None, &|code| {
+            parse_code(code).map(|_| ())
+        })?;
+        // We validated the code above
+        let code = parse_code(&code).unwrap();
+
+        // Try unwrapping each key with the one provided code
+        let cipher = ChaCha20Poly1305::new(code_key(&code, &data.salt)?.as_ref().into());
+        for (nonce, ciphertext) in &data.wrapped_keys {
+            if let Ok(key) = cipher.decrypt(nonce.into(), ciphertext.as_ref()) {
+                return Ok(key);
+            }
+        }
+        bail!("recovery code is not valid for this factor");
+    }
+}
+
+/// Generates a new random recovery code, without any separators.
+fn generate_code() -> String {
+    (0..CODE_GROUPS * CODE_GROUP_LEN)
+        .map(|_| CODE_ALPHABET[CystRng.gen_range(0..CODE_ALPHABET.len())] as char)
+        .collect()
+}
+
+/// Formats the given code (without separators) into dash-separated groups for display.
+fn format_code(code: &str) -> String {
+    code.as_bytes()
+        .chunks(CODE_GROUP_LEN)
+        .map(|group| String::from_utf8_lossy(group))
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Parses a code entered by the user, ignoring case, whitespace, and dashes, and returning it
+/// without separators if it's valid.
+fn parse_code(code: &str) -> Result<String, String> {
+    let code = code
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .map(|c| c.to_ascii_uppercase())
+        .collect::<String>();
+    if code.len() != CODE_GROUPS * CODE_GROUP_LEN {
+        return Err(format!(
+            "recovery codes have {} characters, like {}",
+            CODE_GROUPS * CODE_GROUP_LEN,
+            format_code(&"X".repeat(CODE_GROUPS * CODE_GROUP_LEN))
+        ));
+    }
+    if let Some(c) = code.chars().find(|c| !CODE_ALPHABET.contains(&(*c as u8))) {
+        return Err(format!("'{c}' never appears in recovery codes"));
+    }
+
+    Ok(code)
+}
+
+/// Derives the key that wraps the factor's key from a single recovery code.
+fn code_key(code: &str, salt: &[u8; 32]) -> Result<[u8; 32]> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(code.as_bytes(), salt, &mut key)
+        .map_err(|err| anyhow!("failed to derive key from recovery code: {err}"))?;
+
+    Ok(key)
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RecoveryCodesFactorData {
+    /// The salt used for deriving keys from all the codes.
+    salt: [u8; 32],
+    /// The factor's key, encrypted separately under each code, along with the nonces used.
+    wrapped_keys: Vec<([u8; 12], Vec<u8>)>,
+}
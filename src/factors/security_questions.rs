@@ -0,0 +1,127 @@
+use crate::{factor::Factor, prompt::Prompter, rng::CystRng};
+use anyhow::{bail, Result};
+use rand::Rng;
+use ring::hkdf;
+use serde::{Deserialize, Serialize};
+
+/// How many bits of entropy we count each answer as having. This is deliberately low: answers tend
+/// to come from small sets (like cities or pets' names), and can often be looked up or guessed by
+/// anyone who knows the user.
+const ANSWER_BITS: f64 = 8.0;
+
+/// A factor based on the answers to a set of security questions chosen by the user. The questions
+/// are stored in the header (so they can be asked again), and the answers are normalised (so case
+/// and extra whitespace don't matter) and run through HKDF together to produce the key.
+///
+/// This is meant as a low-tech recovery path, and it's weak: answers to security questions tend to
+/// be guessable, or findable by anyone who knows the user. It should be combined with other
+/// factors, or at least used with questions whose answers really are private.
+pub struct SecurityQuestionsFactor;
+impl Factor for SecurityQuestionsFactor {
+    type Data = SecurityQuestionsFactorData;
+    type Key = [u8; 32];
+
+    fn name() -> &'static str {
+        "Security questions"
+    }
+    fn description() -> &'static str {
+        "Answers to questions you choose (weak, as answers are often guessable)"
+    }
+    fn create(prompter: &mut dyn Prompter) -> Result<(Self::Data, Self::Key)> {
+        prompter.notice("Warning: answers to security questions are often easy to guess or look up, so this factor is weak on its own. Choose questions only you know the answers to.");
+        let num_questions: u8 =
+            prompter.parsed("How many questions do you want to set?", Some(3))?;
+        if num_questions == 0 {
+            bail!("at least one question must be set");
+        }
+
+        let mut questions = Vec::new();
+        let mut answers = Vec::new();
+        for i in 0..num_questions {
+            let question = prompter.input(
+                &format!("Enter question #{} (NOT secret)", i + 1),
+                None,
+                &|question| {
+                    if question.trim().is_empty() {
+                        Err("question must not be empty".to_string())
+                    } else {
+                        Ok(())
+                    }
+                },
+            )?;
+            let answer = prompt_answer(prompter, &question)?;
+            questions.push(question.trim().to_string());
+            answers.push(answer);
+        }
+
+        let data = SecurityQuestionsFactorData {
+            salt: CystRng.gen(),
+            questions,
+        };
+        let key = derive_key(&answers, &data.salt);
+        Ok((data, key))
+    }
+    fn derive(data: Self::Data, prompter: &mut dyn Prompter) -> Result<Self::Key> {
+        let mut answers = Vec::new();
+        for question in &data.questions {
+            answers.push(prompt_answer(prompter, question)?);
+        }
+
+        Ok(derive_key(&answers, &data.salt))
+    }
+    fn entropy(data: &Self::Data, _key: &[u8]) -> Option<f64> {
+        Some(data.questions.len() as f64 * ANSWER_BITS)
+    }
+}
+
+/// Prompts the user for the answer to the given question, returning it normalised.
+fn prompt_answer(prompter: &mut dyn Prompter, question: &str) -> Result<String> {
+    let answer = prompter.password(question, &|answer| {
+        if normalise_answer(answer).is_empty() {
+            Err("answer must not be empty".to_string())
+        } else {
+            Ok(())
+        }
+    })?;
+
+    Ok(normalise_answer(&answer))
+}
+
+/// Normalises an answer so trivial differences in how it's typed don't matter. The answer is
+/// lowercased (using Unicode's rules), leading and trailing whitespace is removed, and every run of
+/// whitespace inside it becomes a single space. So `"New  York"` and `" new york "` are both
+/// `"new york"`. Punctuation is left alone, so `"St. Paul"` and `"St Paul"` don't match.
+fn normalise_answer(answer: &str) -> String {
+    answer
+        .to_lowercase()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Derives a key from the given (normalised) answers with HKDF. Each answer is prefixed with its
+/// length, so answers can't run into one another.
+fn derive_key(answers: &[String], salt: &[u8]) -> [u8; 32] {
+    let mut ikm = Vec::new();
+    for answer in answers {
+        ikm.extend_from_slice(&(answer.len() as u64).to_le_bytes());
+        ikm.extend_from_slice(answer.as_bytes());
+    }
+
+    let mut key = [0u8; 32];
+    hkdf::Salt::new(hkdf::HKDF_SHA256, salt)
+        .extract(&ikm)
+        .expand(&[b"cyst security questions"], hkdf::HKDF_SHA256)
+        .and_then(|okm| okm.fill(&mut key))
+        .unwrap();
+
+    key
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SecurityQuestionsFactorData {
+    /// The salt used in deriving the key from the answers.
+    salt: [u8; 32],
+    /// The questions, in the order their answers are combined.
+    questions: Vec<String>,
+}
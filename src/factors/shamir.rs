@@ -1,12 +1,20 @@
-use crate::factor::Factor;
+use super::clean_pasted;
+use crate::{factor::Factor, prompt::Prompter, rng::CystRng};
 use anyhow::{bail, Context, Result};
-use dialoguer::Input;
-use rand::{rngs::OsRng, Rng};
+use rand::Rng;
 use shamirsecretsharing::{combine_shares, create_shares, DATA_SIZE as SHAMIR_DATA_SIZE};
+use std::path::Path;
+
+/// The environment variable that, if set, holds the path to a file of shares to use when deriving
+/// the factor, one hex share per line.
+pub const SHARES_FILE_ENV: &str = "CYST_SHARES_FILE";
 
 /// A factor based on Shamir secret sharing, whereby a random secret is split into the
 /// user-provided number of shares, which are outputted. A quorum of these can then be brought back
 /// together to decrypt the data.
+///
+/// When deriving, the shares can be read from a file instead of being entered one by one (see
+/// [`SHARES_FILE_ENV`]).
 pub struct ShamirFactor;
 impl Factor for ShamirFactor {
     type Data = u8;
@@ -15,39 +23,48 @@ impl Factor for ShamirFactor {
     fn name() -> &'static str {
         "Shamir secret sharing"
     }
-    fn create() -> Result<(Self::Data, Self::Key)> {
-        let num_shares: u8 = Input::new()
-            .with_prompt("How many shares do you want to create?")
-            .interact()
-            .unwrap();
-        let num_quorum: u8 = Input::new()
-            .with_prompt("How many of these shares should be required to decrypt the data?")
-            .interact()
-            .unwrap();
+    fn description() -> &'static str {
+        "A secret split into shares, a quorum of which must be brought back together"
+    }
+    fn create(prompter: &mut dyn Prompter) -> Result<(Self::Data, Self::Key)> {
+        let num_shares: u8 = prompter.parsed("How many shares do you want to create?", None)?;
+        let num_quorum: u8 = prompter.parsed(
+            "How many of these shares should be required to decrypt the data?",
+            None,
+        )?;
 
         let mut secret = [0u8; SHAMIR_DATA_SIZE];
-        OsRng.fill(&mut secret);
+        CystRng.fill(&mut secret);
         let shares = create_shares(&secret, num_shares, num_quorum)
             .with_context(|| "failed to split into shares")?;
 
-        // Convert each share to hex and print it
+        // Convert each share to hex and show it (never on stdout, which might be the ciphertext)
         for (i, share) in shares.iter().enumerate() {
-            println!("Share #{}: {}", i + 1, hex::encode(share));
+            prompter.notice(&format!("Share #{}: {}", i + 1, hex::encode(share)));
         }
 
         Ok((num_quorum, secret.to_vec()))
     }
-    fn derive(num_quorum: Self::Data) -> Result<Self::Key> {
-        let mut shares = Vec::new();
-        for i in 0..num_quorum {
-            let share_hex: String = Input::new()
-                .with_prompt(&format!("Enter share #{}", i + 1))
-                .interact()
-                .unwrap();
-            let share = hex::decode(share_hex.trim())
-                .with_context(|| "failed to decode share (are you sure it's correct?)")?;
-            shares.push(share);
-        }
+    fn derive(num_quorum: Self::Data, prompter: &mut dyn Prompter) -> Result<Self::Key> {
+        let shares = match std::env::var_os(SHARES_FILE_ENV) {
+            Some(path) => {
+                prompter.info(&format!(
+                    "Using the shares from the file in ${SHARES_FILE_ENV}."
+                ));
+                shares_from_file(Path::new(&path), num_quorum)?
+            }
+            None => {
+                let mut shares = Vec::new();
+                for i in 0..num_quorum {
+                    let share_hex =
+                        prompter.input(&format!("Enter share #{}", i + 1), None, &|_| Ok(()))?;
+                    let share = hex::decode(clean_pasted(&share_hex))
+                        .with_context(|| "failed to decode share (are you sure it's correct?)")?;
+                    shares.push(share);
+                }
+                shares
+            }
+        };
 
         let secret = combine_shares(&shares).with_context(|| "failed to combine shares")?;
         if let Some(secret) = secret {
@@ -57,3 +74,153 @@ impl Factor for ShamirFactor {
         }
     }
 }
+
+/// Reads the given number of shares from the given file, which has one hex share per line. Blank
+/// lines and lines starting with `#` are ignored, as is anything after the shares we need.
+fn shares_from_file(path: &Path, num_quorum: u8) -> Result<Vec<Vec<u8>>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read shares from {path:?}"))?;
+
+    let mut shares = Vec::new();
+    let lines = contents
+        .lines()
+        .enumerate()
+        .map(|(idx, line)| (idx + 1, clean_pasted(line)))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'));
+    for (line_num, line) in lines.take(num_quorum as usize) {
+        let share = hex::decode(line)
+            .with_context(|| format!("line {line_num} of {path:?} is not valid hex"))?;
+        shares.push(share);
+    }
+    if shares.len() < num_quorum as usize {
+        bail!(
+            "{path:?} only has {} share(s), but {num_quorum} are needed",
+            shares.len()
+        );
+    }
+
+    Ok(shares)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        factor::FactorRegistry,
+        file::{ciphertext_len, decrypt_file, encrypt_file},
+        header::Header,
+        prompt::Verbosity,
+        testing,
+        vectors::ScriptedPrompter,
+    };
+
+    /// A prompter that answers from a script, but also keeps every message it's shown, so the
+    /// shares it was shown can be checked.
+    struct RecordingPrompter {
+        /// The prompter that answers the questions.
+        script: ScriptedPrompter,
+        /// Every message that's been shown, in order.
+        messages: Vec<String>,
+    }
+    impl RecordingPrompter {
+        /// Creates a prompter that answers from the given script.
+        fn new(script: ScriptedPrompter) -> Self {
+            Self {
+                script,
+                messages: Vec::new(),
+            }
+        }
+    }
+    impl Prompter for RecordingPrompter {
+        fn input(
+            &mut self,
+            prompt: &str,
+            default: Option<&str>,
+            validate: &dyn Fn(&str) -> Result<(), String>,
+        ) -> Result<String> {
+            self.script.input(prompt, default, validate)
+        }
+        fn password(
+            &mut self,
+            prompt: &str,
+            validate: &dyn Fn(&str) -> Result<(), String>,
+        ) -> Result<String> {
+            self.script.password(prompt, validate)
+        }
+        fn select(&mut self, prompt: &str, items: &[&str]) -> Result<usize> {
+            self.script.select(prompt, items)
+        }
+        fn confirm(&mut self, prompt: &str) -> Result<bool> {
+            self.script.confirm(prompt)
+        }
+        fn message(&mut self, verbosity: Verbosity, msg: &str) {
+            self.messages.push(msg.to_string());
+            self.script.message(verbosity, msg);
+        }
+    }
+
+    /// Whether `needle` appears anywhere in `haystack`.
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack
+            .windows(needle.len())
+            .any(|window| window == needle)
+    }
+
+    /// The shares are shown through the prompter, never written to the output, so encrypting to
+    /// stdout gives exactly the file and nothing else.
+    #[test]
+    fn shares_never_end_up_in_the_output() {
+        let mut registry = FactorRegistry::new();
+        registry.insert(ShamirFactor::name(), Box::new(ShamirFactor));
+        let mut script = testing::prompter("shamir");
+        script.set("Choose an encryption factor", ShamirFactor::name());
+        script.set("How many shares", "3");
+        script.set("How many of these shares", "2");
+        let mut prompter = RecordingPrompter::new(script);
+        let (mut header, encryptor) = Header::new(&registry, None, 1, &mut prompter).unwrap();
+
+        let plaintext = b"the plaintext, not a share";
+        header
+            .set_body_len(ciphertext_len(plaintext.len() as u64))
+            .unwrap();
+        let mut stdout = Vec::new();
+        encrypt_file(&mut &plaintext[..], &mut stdout, &header, encryptor, None).unwrap();
+
+        let shares = prompter
+            .messages
+            .iter()
+            .filter_map(|msg| msg.strip_prefix("Share #")?.split_once(": "))
+            .map(|(_, share)| share.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(shares.len(), 3);
+        // A header, followed by exactly the body
+        let mut body = stdout.as_slice();
+        let read_header = Header::from_file(&mut body).unwrap();
+        assert_eq!(body.len() as u64, ciphertext_len(plaintext.len() as u64));
+        assert!(!contains(&stdout, b"Share #"));
+        for share in &shares {
+            assert!(!contains(&stdout, share.as_bytes()));
+            assert!(!contains(&stdout, &hex::decode(share).unwrap()));
+        }
+
+        // The shares that were shown are the ones that decrypt it
+        let mut script = testing::prompter("shamir");
+        script.set("Enter share #1", &shares[2]);
+        script.set("Enter share #2", &shares[0]);
+        let (decryptor, location) = read_header
+            .to_decryptor(&registry, Some("shamir"), false, 1, &mut script)
+            .unwrap();
+        let mut decrypted = Vec::new();
+        decrypt_file(
+            &mut body,
+            location,
+            read_header.body_len(),
+            &mut decrypted,
+            decryptor,
+            &read_header.authenticated_data(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(decrypted, plaintext);
+    }
+}
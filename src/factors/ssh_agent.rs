@@ -0,0 +1,237 @@
+use crate::{factor::Factor, prompt::Prompter, rng::CystRng};
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine};
+use rand::Rng;
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{Read, Write},
+    os::unix::net::UnixStream,
+};
+
+/// The agent protocol message type for a generic failure.
+const SSH_AGENT_FAILURE: u8 = 5;
+/// The agent protocol message type for requesting the list of keys.
+const SSH_AGENTC_REQUEST_IDENTITIES: u8 = 11;
+/// The agent protocol message type for the list of keys.
+const SSH_AGENT_IDENTITIES_ANSWER: u8 = 12;
+/// The agent protocol message type for requesting a signature.
+const SSH_AGENTC_SIGN_REQUEST: u8 = 13;
+/// The agent protocol message type for a signature.
+const SSH_AGENT_SIGN_RESPONSE: u8 = 14;
+
+/// A factor that uses a key held in the user's SSH agent. A random challenge is generated and
+/// signed by the agent, and the hash of the signature is used as the key. This only works for key
+/// types whose signatures are deterministic (like Ed25519), which is checked when the factor is
+/// created.
+pub struct SshAgentFactor;
+impl Factor for SshAgentFactor {
+    type Data = SshAgentFactorData;
+    type Key = [u8; 32];
+
+    fn name() -> &'static str {
+        "SSH agent"
+    }
+    fn description() -> &'static str {
+        "A signature from a key in your SSH agent (e.g. Ed25519)"
+    }
+    fn create(prompter: &mut dyn Prompter) -> Result<(Self::Data, Self::Key)> {
+        let mut agent = Agent::connect()?;
+        let identities = agent.identities()?;
+        if identities.is_empty() {
+            bail!("the ssh agent has no keys loaded (add one with `ssh-add`)");
+        }
+
+        let items = identities
+            .iter()
+            .map(|(blob, comment)| {
+                format!(
+                    "{comment} ({}, {})",
+                    key_type(blob).unwrap_or("unknown"),
+                    format_fingerprint(&fingerprint(blob))
+                )
+            })
+            .collect::<Vec<_>>();
+        let items = items.iter().map(String::as_str).collect::<Vec<_>>();
+        let key_idx = prompter.select("Choose a key from the ssh agent", &items)?;
+        let (blob, _) = &identities[key_idx];
+
+        // Sign twice to make sure the signature will be the same when we need to derive this again
+        let challenge = CystRng.gen::<[u8; 32]>();
+        let signature = agent.sign(blob, &challenge)?;
+        if agent.sign(blob, &challenge)? != signature {
+            bail!(
+                "signatures from this key are not deterministic (key type '{}'), so it can't be used (try an ed25519 key)",
+                key_type(blob).unwrap_or("unknown")
+            );
+        }
+
+        Ok((
+            SshAgentFactorData {
+                challenge,
+                fingerprint: fingerprint(blob),
+            },
+            signature_key(&signature),
+        ))
+    }
+    fn derive(data: Self::Data, prompter: &mut dyn Prompter) -> Result<Self::Key> {
+        let mut agent = Agent::connect()?;
+        let identities = agent.identities()?;
+        let Some((blob, _)) = identities
+            .iter()
+            .find(|(blob, _)| fingerprint(blob) == data.fingerprint)
+        else {
+            bail!(
+                "the ssh agent doesn't have the key {} loaded",
+                format_fingerprint(&data.fingerprint)
+            );
+        };
+
+        prompter
+            .info("Asking the ssh agent to sign the challenge (you may need to confirm this)...");
+        let signature = agent.sign(blob, &data.challenge)?;
+        Ok(signature_key(&signature))
+    }
+    fn probe(data: Self::Data) -> Result<Option<String>> {
+        let fingerprint_str = format_fingerprint(&data.fingerprint);
+        if !Agent::connect()?
+            .identities()?
+            .iter()
+            .any(|(blob, _)| fingerprint(blob) == data.fingerprint)
+        {
+            bail!("the ssh agent doesn't have the key {fingerprint_str} loaded");
+        }
+
+        Ok(Some(format!("the key {fingerprint_str} is loaded")))
+    }
+    fn available() -> Result<()> {
+        if Agent::connect()?.identities()?.is_empty() {
+            bail!("the ssh agent has no keys loaded");
+        }
+
+        Ok(())
+    }
+}
+
+/// A connection to the user's SSH agent.
+struct Agent(UnixStream);
+impl Agent {
+    /// Connects to the agent given by `$SSH_AUTH_SOCK`.
+    fn connect() -> Result<Self> {
+        let path = std::env::var_os("SSH_AUTH_SOCK")
+            .context("no ssh agent is running (`SSH_AUTH_SOCK` is not set)")?;
+        let stream = UnixStream::connect(&path).context("failed to connect to ssh agent")?;
+        Ok(Self(stream))
+    }
+
+    /// Lists the public key blobs and comments of all the keys in the agent.
+    fn identities(&mut self) -> Result<Vec<(Vec<u8>, String)>> {
+        let mut reader = self.request(SSH_AGENTC_REQUEST_IDENTITIES, &[])?;
+        if reader.byte()? != SSH_AGENT_IDENTITIES_ANSWER {
+            bail!("ssh agent refused to list keys");
+        }
+        let num_keys = reader.u32()?;
+        let mut identities = Vec::new();
+        for _ in 0..num_keys {
+            let blob = reader.string()?.to_vec();
+            let comment = String::from_utf8_lossy(reader.string()?).into_owned();
+            identities.push((blob, comment));
+        }
+
+        Ok(identities)
+    }
+
+    /// Asks the agent to sign the given data with the key whose public blob is given.
+    fn sign(&mut self, blob: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        let mut payload = Vec::new();
+        write_string(&mut payload, blob);
+        write_string(&mut payload, data);
+        payload.extend_from_slice(&0u32.to_be_bytes());
+
+        let mut reader = self.request(SSH_AGENTC_SIGN_REQUEST, &payload)?;
+        match reader.byte()? {
+            SSH_AGENT_SIGN_RESPONSE => Ok(reader.string()?.to_vec()),
+            SSH_AGENT_FAILURE => bail!("ssh agent refused to sign (was the request declined?)"),
+            _ => bail!("unexpected response from ssh agent"),
+        }
+    }
+
+    /// Sends a message of the given type to the agent, returning a reader over its response.
+    fn request(&mut self, msg_type: u8, payload: &[u8]) -> Result<MessageReader> {
+        let len = payload.len() as u32 + 1;
+        let mut msg = len.to_be_bytes().to_vec();
+        msg.push(msg_type);
+        msg.extend_from_slice(payload);
+        self.0
+            .write_all(&msg)
+            .context("failed to send request to ssh agent")?;
+
+        let mut len_bytes = [0u8; 4];
+        self.0
+            .read_exact(&mut len_bytes)
+            .context("failed to read response from ssh agent")?;
+        let mut response = vec![0u8; u32::from_be_bytes(len_bytes) as usize];
+        self.0
+            .read_exact(&mut response)
+            .context("failed to read response from ssh agent")?;
+
+        Ok(MessageReader(response, 0))
+    }
+}
+
+/// A reader over a message from the agent, tracking the current position.
+struct MessageReader(Vec<u8>, usize);
+impl MessageReader {
+    fn take(&mut self, len: usize) -> Result<&[u8]> {
+        if self.0.len() - self.1 < len {
+            bail!("truncated response from ssh agent");
+        }
+        self.1 += len;
+        Ok(&self.0[self.1 - len..self.1])
+    }
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+    fn string(&mut self) -> Result<&[u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+}
+
+/// Writes a length-prefixed string in the agent protocol's format.
+fn write_string(buf: &mut Vec<u8>, data: &[u8]) {
+    buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    buf.extend_from_slice(data);
+}
+
+/// Gets the type of a key (e.g. `ssh-ed25519`) from its public blob.
+fn key_type(blob: &[u8]) -> Option<&str> {
+    let len = u32::from_be_bytes(blob.get(..4)?.try_into().ok()?) as usize;
+    std::str::from_utf8(blob.get(4..4 + len)?).ok()
+}
+
+/// Computes the SHA-256 fingerprint of a key from its public blob.
+fn fingerprint(blob: &[u8]) -> [u8; 32] {
+    digest(&SHA256, blob).as_ref().try_into().unwrap()
+}
+
+/// Formats a fingerprint the same way OpenSSH does.
+fn format_fingerprint(fingerprint: &[u8; 32]) -> String {
+    format!("SHA256:{}", STANDARD_NO_PAD.encode(fingerprint))
+}
+
+/// Derives the factor's key from the agent's signature.
+fn signature_key(signature: &[u8]) -> [u8; 32] {
+    digest(&SHA256, signature).as_ref().try_into().unwrap()
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SshAgentFactorData {
+    /// The random challenge the agent signs.
+    challenge: [u8; 32],
+    /// The SHA-256 fingerprint of the key used.
+    fingerprint: [u8; 32],
+}
@@ -1,101 +1,957 @@
-use crate::header::Header;
-use anyhow::{anyhow, Result};
+use crate::{
+    error::{authentication_failed, AuthenticationFailed},
+    header::{Header, StreamLocation},
+};
+use anyhow::{anyhow, bail, Result};
+use blake2::{Blake2b512, Digest};
 use chacha20poly1305::{
-    aead::stream::{DecryptorBE32, EncryptorBE32},
+    aead::{
+        self,
+        stream::{DecryptorBE32, EncryptorBE32, StreamBE32, StreamPrimitive},
+        Payload,
+    },
     ChaCha20Poly1305,
 };
+use log::debug;
 use std::{
     fs::File,
-    io::{Read, Seek, Write},
-    path::Path,
+    io::{self, Read, Seek, SeekFrom, Write},
 };
 
 /// The size of buffer used for streaming encryption.
-const ENCRYPTION_BUF_SIZE: u64 = 4096;
+pub(crate) const ENCRYPTION_BUF_SIZE: u64 = 4096;
 /// The size of the buffer used for streaming decryption. This needs to be 16 bytes larger to
 /// account for the overhead of the STREAM protocol.
 const DECRYPTION_BUF_SIZE: u64 = ENCRYPTION_BUF_SIZE + 16;
 
-/// Encrypts the given path, writing the data encrypted with the given stream encryptor to the
-/// output path. The provided header will be written as well.
+/// Gets the length of the ciphertext produced by encrypting a plaintext of the given length.
+pub fn ciphertext_len(plaintext_len: u64) -> u64 {
+    // Even an empty plaintext gets a (final) chunk
+    let num_chunks = plaintext_len.div_ceil(ENCRYPTION_BUF_SIZE).max(1);
+    plaintext_len + num_chunks * (DECRYPTION_BUF_SIZE - ENCRYPTION_BUF_SIZE)
+}
+
+/// Gets the length of the plaintext that encrypts to a ciphertext of the given length (the inverse
+/// of [`ciphertext_len`]), or `None` if there isn't one.
+fn plaintext_len(len: u64) -> Option<u64> {
+    let num_chunks = len.div_ceil(DECRYPTION_BUF_SIZE).max(1);
+    let plaintext_len =
+        len.checked_sub(num_chunks * (DECRYPTION_BUF_SIZE - ENCRYPTION_BUF_SIZE))?;
+    (ciphertext_len(plaintext_len) == len).then_some(plaintext_len)
+}
+
+/// Works out how long the given input is, if that can be known before reading it. Pipes and other
+/// special files don't have a length, and some files that seem to be regular (like those in
+/// `/proc`) claim to be empty when they aren't, so an empty file is only trusted if it really has
+/// nothing to read. This leaves the input's cursor at the start.
+pub fn known_len(input: &mut File) -> Result<Option<u64>> {
+    let metadata = input.metadata()?;
+    if !metadata.is_file() {
+        return Ok(None);
+    }
+    if metadata.len() > 0 {
+        return Ok(Some(metadata.len()));
+    }
+    if input.read(&mut [0u8])? == 0 {
+        return Ok(Some(0));
+    }
+    input.rewind()?;
+
+    Ok(None)
+}
+
+/// Encrypts everything from the given reader, writing the given header and then the data
+/// encrypted with the given stream encryptor to the given writer.
+///
+/// If the header was created with a decoy, a reader for the decoy and its encryptor should be
+/// given too, and its ciphertext will be written after the real ciphertext.
+///
+/// If the header records the length of the body (see [`Header::set_body_len`]), the input must be
+/// exactly the length that was worked out from. It's only read as it's encrypted, so changing it
+/// in the meantime (e.g. encrypting a log that's still being written to) isn't supported: if it
+/// turns out longer or shorter, this fails before the last chunk is written, rather than producing
+/// a file that decrypts to a truncated or padded plaintext.
 pub fn encrypt_file(
-    input_path: &Path,
-    output_path: Option<&Path>,
-    header: Header,
-    mut encryptor: EncryptorBE32<ChaCha20Poly1305>,
+    input: &mut impl Read,
+    output: &mut impl Write,
+    header: &Header,
+    encryptor: EncryptorBE32<ChaCha20Poly1305>,
+    decoy: Option<(&mut dyn Read, EncryptorBE32<ChaCha20Poly1305>)>,
+) -> Result<()> {
+    encrypt_file_with_progress(input, output, header, encryptor, decoy, &mut |_| {})
+}
+
+/// Like [`encrypt_file`], but calls `on_progress` after each chunk with the total number of bytes
+/// of plaintext encrypted so far (including any decoy), so a progress bar can be shown.
+pub fn encrypt_file_with_progress(
+    input: &mut impl Read,
+    output: &mut impl Write,
+    header: &Header,
+    encryptor: EncryptorBE32<ChaCha20Poly1305>,
+    decoy: Option<(&mut dyn Read, EncryptorBE32<ChaCha20Poly1305>)>,
+    on_progress: &mut dyn FnMut(u64),
 ) -> Result<()> {
-    let mut output: Box<dyn Write> = if let Some(output_path) = output_path {
-        Box::new(File::create(output_path)?)
-    } else {
-        Box::new(std::io::stdout().lock())
-    };
     // Write the header immediately
     output.write_all(&header.to_bytes())?;
+    encrypt_body_with_progress(input, output, header, encryptor, decoy, on_progress)
+}
+
+/// Like [`encrypt_file`], but only writes the ciphertext, not the header. This is for storing the
+/// header separately (from [`Header::to_bytes`]), so it can be kept somewhere safer than the bulk
+/// of the data. The ciphertext can be decrypted with [`decrypt_file`] just the same.
+pub fn encrypt_body(
+    input: &mut impl Read,
+    output: &mut impl Write,
+    header: &Header,
+    encryptor: EncryptorBE32<ChaCha20Poly1305>,
+    decoy: Option<(&mut dyn Read, EncryptorBE32<ChaCha20Poly1305>)>,
+) -> Result<()> {
+    encrypt_body_with_progress(input, output, header, encryptor, decoy, &mut |_| {})
+}
+
+/// Like [`encrypt_body`], but reports progress like [`encrypt_file_with_progress`].
+pub fn encrypt_body_with_progress(
+    input: &mut impl Read,
+    output: &mut impl Write,
+    header: &Header,
+    encryptor: EncryptorBE32<ChaCha20Poly1305>,
+    decoy: Option<(&mut dyn Read, EncryptorBE32<ChaCha20Poly1305>)>,
+    on_progress: &mut dyn FnMut(u64),
+) -> Result<()> {
+    let aad = header.authenticated_data();
+
+    let mut progress = Progress::new(on_progress);
+    // The real ciphertext ends where the decoy starts, if there is one
+    let real_len = header
+        .decoy_offset()
+        .or(header.body_len())
+        .and_then(plaintext_len);
+    let mut input = ExpectedLenReader::new(input, real_len);
+    let mut written = encrypt_chunks(&mut input, output, encryptor, &aad, &mut progress)?;
+    match (decoy, header.decoy_offset()) {
+        (Some((decoy_input, decoy_encryptor)), Some(decoy_offset)) => {
+            // Anything else would put the decoy in the wrong place
+            if written != decoy_offset {
+                bail!("input was not the length given when the header was created");
+            }
+            let decoy_len = header
+                .body_len()
+                .and_then(|body_len| body_len.checked_sub(decoy_offset))
+                .and_then(plaintext_len);
+            let mut decoy_input = ExpectedLenReader::new(decoy_input, decoy_len);
+            written += encrypt_chunks(
+                &mut decoy_input,
+                output,
+                decoy_encryptor,
+                &aad,
+                &mut progress,
+            )?;
+        }
+        (None, None) => {}
+        _ => bail!("a decoy must be given if and only if the header was created with one"),
+    }
+    output.flush()?;
+    // Otherwise the file would never decrypt
+    if header
+        .body_len()
+        .is_some_and(|body_len| written != body_len)
+    {
+        bail!("input was not the length given when the header was created");
+    }
+
+    Ok(())
+}
+
+/// Resumes an encryption by [`encrypt_file`] that was interrupted part-way through, continuing the
+/// ciphertext in `output` from where it left off. The output's cursor should be directly after the
+/// header, and the given stream should be recovered from that header (with [`Header::to_stream`]).
+/// This returns the number of bytes of plaintext that had already been encrypted, and so were
+/// skipped.
+///
+/// The primary key is never stored part-way through encryption, which is why the user has to
+/// satisfy one of the options to resume. Any chunks at the end of the output that don't
+/// authenticate (e.g. because they were only partly written) are thrown away and encrypted again.
+///
+/// Encrypting a chunk again with *different* plaintext would reuse a nonce though, and the chunks
+/// that are thrown away can't be decrypted to compare them with the input. **The caller must make
+/// sure the input hasn't changed since the encryption was interrupted.** The CLI does this with
+/// the `.partial.state` file, which records the input's size and modification time, and won't
+/// offer to resume if either differs (an edit that keeps both the same would slip through). The
+/// last chunk that's kept is checked against the input too, and this fails if they differ, but
+/// that's only a sanity check: it can't notice changes further on.
+///
+/// Resuming isn't supported for files with a decoy.
+pub fn resume_encrypt_file(
+    input: &mut (impl Read + Seek),
+    output: &mut File,
+    stream: StreamBE32<ChaCha20Poly1305>,
+    aad: &[u8],
+) -> Result<u64> {
+    let body_start = output.stream_position()?;
+    let body_len = output.metadata()?.len().saturating_sub(body_start);
+
+    // Find the last complete chunk that authenticates (working backwards, as only the end should
+    // be damaged)
+    let mut chunks = body_len / DECRYPTION_BUF_SIZE;
+    let mut buffer = [0; DECRYPTION_BUF_SIZE as usize];
+    let mut last_plaintext = None;
+    while chunks > 0 {
+        output.seek(SeekFrom::Start(
+            body_start + (chunks - 1) * DECRYPTION_BUF_SIZE,
+        ))?;
+        output.read_exact(&mut buffer)?;
+        let payload = Payload { msg: &buffer, aad };
+        if let Ok(plaintext) = stream.decrypt(chunk_position(chunks - 1)?, false, payload) {
+            last_plaintext = Some(plaintext);
+            break;
+        }
+        chunks -= 1;
+    }
+
+    // Make sure the input is still what we were encrypting
+    let skipped = chunks * ENCRYPTION_BUF_SIZE;
+    if let Some(last_plaintext) = last_plaintext {
+        input.seek(SeekFrom::Start(skipped - ENCRYPTION_BUF_SIZE))?;
+        let mut input_buffer = [0; ENCRYPTION_BUF_SIZE as usize];
+        let len = read_full(input, &mut input_buffer)?;
+        if input_buffer[..len] != last_plaintext {
+            bail!("the input has changed since the encryption was interrupted, so it can't be resumed");
+        }
+    } else {
+        input.seek(SeekFrom::Start(0))?;
+    }
+
+    // Throw away anything after that, and carry on from there
+    output.set_len(body_start + chunks * DECRYPTION_BUF_SIZE)?;
+    output.seek(SeekFrom::End(0))?;
+    let encryptor = ResumedEncryptor {
+        stream,
+        position: chunk_position(chunks)?,
+    };
+    encrypt_chunks(
+        input,
+        output,
+        encryptor,
+        aad,
+        &mut Progress::new(&mut |_| {}),
+    )?;
+    output.flush()?;
+
+    Ok(skipped)
+}
+
+/// Gets the STREAM position of the chunk with the given index.
+fn chunk_position(idx: u64) -> Result<u32> {
+    u32::try_from(idx).map_err(|_| anyhow!("file is too large to encrypt"))
+}
 
-    // Encrypt chunks of the input file and write them directly to the output file
-    let mut input = File::open(input_path)?;
-    let input_size = input.metadata()?.len();
+/// Something that can encrypt a STREAM chunk-by-chunk.
+trait ChunkEncryptor {
+    /// Encrypts the next chunk, which isn't the last.
+    fn encrypt_next(&mut self, payload: Payload) -> aead::Result<Vec<u8>>;
+    /// Encrypts the last chunk.
+    fn encrypt_last(self, payload: Payload) -> aead::Result<Vec<u8>>;
+}
+impl ChunkEncryptor for EncryptorBE32<ChaCha20Poly1305> {
+    fn encrypt_next(&mut self, payload: Payload) -> aead::Result<Vec<u8>> {
+        EncryptorBE32::encrypt_next(self, payload)
+    }
+    fn encrypt_last(self, payload: Payload) -> aead::Result<Vec<u8>> {
+        EncryptorBE32::encrypt_last(self, payload)
+    }
+}
+
+/// An encryptor that starts part-way through a STREAM, for resuming an interrupted encryption
+/// (the encryptors from `aead` always start at the beginning).
+struct ResumedEncryptor {
+    stream: StreamBE32<ChaCha20Poly1305>,
+    /// The position of the next chunk to encrypt.
+    position: u32,
+}
+impl ChunkEncryptor for ResumedEncryptor {
+    fn encrypt_next(&mut self, payload: Payload) -> aead::Result<Vec<u8>> {
+        let next_position = self.position.checked_add(1).ok_or(aead::Error)?;
+        let encrypted = self.stream.encrypt(self.position, false, payload)?;
+        self.position = next_position;
+
+        Ok(encrypted)
+    }
+    fn encrypt_last(self, payload: Payload) -> aead::Result<Vec<u8>> {
+        self.stream.encrypt(self.position, true, payload)
+    }
+}
+
+/// Encrypts chunks of the given reader and writes them directly to the given output, returning
+/// the number of bytes of ciphertext written. Each chunk's plaintext is counted towards the given
+/// progress.
+///
+/// An empty input still gets a single (empty) last chunk, and an input that's an exact multiple of
+/// the chunk size ends with a full last chunk rather than an extra empty one, as
+/// [`ciphertext_len`] expects.
+fn encrypt_chunks(
+    input: &mut (impl Read + ?Sized),
+    output: &mut impl Write,
+    mut encryptor: impl ChunkEncryptor,
+    aad: &[u8],
+    progress: &mut Progress,
+) -> Result<u64> {
+    let mut written = 0;
+    let mut chunks = 0;
     let mut buffer = [0; ENCRYPTION_BUF_SIZE as usize];
+    let mut next_buffer = [0; ENCRYPTION_BUF_SIZE as usize];
+    let mut len = read_full(input, &mut buffer)?;
     loop {
-        // If we have more bytes left than the buffer size, we aren't at the last chunk (handled
-        // specially by the algorithm)
-        let bytes_left = input_size - input.stream_position()?;
-        if bytes_left > ENCRYPTION_BUF_SIZE {
-            input.read(&mut buffer)?;
-            let encrypted = encryptor
-                .encrypt_next(buffer.as_ref())
-                .map_err(|_| anyhow!("encryption failed"))?;
-            output.write_all(&encrypted)?;
+        // We can only tell whether this is the last chunk (handled specially by the algorithm) by
+        // trying to read the next one
+        let next_len = if len == buffer.len() {
+            read_full(input, &mut next_buffer)?
         } else {
-            let read = input.read(&mut buffer)?;
+            0
+        };
+        if next_len == 0 {
             let encrypted = encryptor
-                .encrypt_last(&buffer[..read])
+                .encrypt_last(Payload {
+                    msg: &buffer[..len],
+                    aad,
+                })
                 .map_err(|_| anyhow!("last encryption failed"))?;
             output.write_all(&encrypted)?;
+            written += encrypted.len() as u64;
+            chunks += 1;
+            progress.advance(len);
 
             break;
         }
+
+        let encrypted = encryptor
+            .encrypt_next(Payload {
+                msg: buffer.as_ref(),
+                aad,
+            })
+            .map_err(|_| anyhow!("encryption failed"))?;
+        output.write_all(&encrypted)?;
+        written += encrypted.len() as u64;
+        chunks += 1;
+        progress.advance(buffer.len());
+
+        std::mem::swap(&mut buffer, &mut next_buffer);
+        len = next_len;
     }
+    debug!("Encrypted {chunks} chunk(s) into {written} byte(s) of ciphertext");
 
-    Ok(())
+    Ok(written)
 }
 
-/// Decrypts the given file using the provided decryptor. It is assumed that the given [`File`]
-/// will be at the start of the ciphertext (after the header).
+/// Decrypts the stream at the given location in the given reader using the provided decryptor
+/// and the header's authenticated data, writing the plaintext to the given output. It is assumed
+/// that the reader will be at the start of the body (directly after the header, or at the start of
+/// a detached body from [`encrypt_body`]).
+///
+/// Plaintext is written as soon as each chunk has been authenticated, so if this fails, some of it
+/// may already have been written. Callers writing to a file should write to a temporary file and
+/// only move it into place once this succeeds.
+///
+/// If a `limit` is given, decryption will stop once that many bytes of plaintext have been written.
+/// Every chunk written is still authenticated individually (so its contents and position in the
+/// file are genuine), but if we stop before the final chunk, we never check how the stream ends,
+/// so truncation or tampering later in the file will go unnoticed. This returns whether or not the
+/// entire ciphertext was decrypted and authenticated.
+///
+/// If the header recorded the length of the body (see [`Header::body_len`]), it should be given
+/// as `body_len`. The whole body will then be read (even past the end of this stream), and if it
+/// isn't exactly that long, this fails with an error saying so, rather than just that a chunk
+/// didn't authenticate.
 pub fn decrypt_file(
-    input: &mut File,
-    output_path: Option<&Path>,
-    mut decryptor: DecryptorBE32<ChaCha20Poly1305>,
-) -> Result<()> {
-    let mut output: Box<dyn Write> = if let Some(output_path) = output_path {
-        Box::new(File::create(output_path)?)
-    } else {
-        Box::new(std::io::stdout().lock())
+    input: &mut impl Read,
+    location: StreamLocation,
+    body_len: Option<u64>,
+    output: &mut impl Write,
+    decryptor: DecryptorBE32<ChaCha20Poly1305>,
+    aad: &[u8],
+    limit: Option<u64>,
+) -> Result<bool> {
+    decrypt_file_with_progress(
+        input,
+        location,
+        body_len,
+        output,
+        decryptor,
+        aad,
+        limit,
+        &mut |_| {},
+    )
+}
+
+/// Like [`decrypt_file`], but calls `on_progress` after each chunk with the total number of bytes
+/// of plaintext written so far, so a progress bar can be shown.
+#[allow(clippy::too_many_arguments)]
+pub fn decrypt_file_with_progress(
+    input: &mut impl Read,
+    location: StreamLocation,
+    body_len: Option<u64>,
+    output: &mut impl Write,
+    decryptor: DecryptorBE32<ChaCha20Poly1305>,
+    aad: &[u8],
+    limit: Option<u64>,
+    on_progress: &mut dyn FnMut(u64),
+) -> Result<bool> {
+    let mut progress = Progress::new(on_progress);
+    let Some(body_len) = body_len else {
+        return decrypt_stream(
+            input,
+            location,
+            output,
+            decryptor,
+            aad,
+            limit,
+            &mut progress,
+        );
     };
 
-    // Decrypt chunks of the input file and write them directly to the output file
-    let input_size = input.metadata()?.len();
+    let mut input = ReadCounter {
+        inner: input,
+        count: 0,
+    };
+    let res = decrypt_stream(
+        &mut input,
+        location,
+        output,
+        decryptor,
+        aad,
+        limit,
+        &mut progress,
+    );
+    match &res {
+        // If we stopped early, we never meant to read the whole body
+        Ok(false) => return res,
+        // A body of the wrong length will usually fail to authenticate, so work out if that's why
+        Err(err) if !err.is::<AuthenticationFailed>() => return res,
+        _ => {}
+    }
+    io::copy(&mut input, &mut io::sink())?;
+    if input.count != body_len {
+        return Err(authentication_failed(format!(
+            "the body is {} bytes long, but should be {body_len} bytes (it has been truncated or \
+             extended)",
+            input.count
+        )));
+    }
+
+    res
+}
+
+/// Decrypts the stream at the given location in the given reader, for [`decrypt_file`].
+fn decrypt_stream(
+    input: &mut impl Read,
+    location: StreamLocation,
+    output: &mut impl Write,
+    decryptor: DecryptorBE32<ChaCha20Poly1305>,
+    aad: &[u8],
+    limit: Option<u64>,
+    progress: &mut Progress,
+) -> Result<bool> {
+    // Skip over any streams before this one (reading rather than seeking, so this works on pipes)
+    debug!(
+        "Decrypting the stream at offset {} (length {:?})",
+        location.offset, location.len
+    );
+    let skipped = io::copy(&mut input.take(location.offset), &mut io::sink())?;
+    if skipped != location.offset {
+        return Err(authentication_failed("file is truncated"));
+    }
+
+    match location.len {
+        // Don't read past the end of this stream into another one
+        Some(len) => decrypt_chunks(
+            &mut input.take(len),
+            output,
+            decryptor,
+            aad,
+            limit,
+            progress,
+        ),
+        None => decrypt_chunks(input, output, decryptor, aad, limit, progress),
+    }
+}
+
+/// Decrypts the stream at the given location in the given reader like [`decrypt_file`], but
+/// throws away the plaintext, just checking that every chunk (including the last) authenticates.
+/// This returns the number of bytes of plaintext that were verified.
+pub fn verify_file(
+    input: &mut impl Read,
+    location: StreamLocation,
+    body_len: Option<u64>,
+    decryptor: DecryptorBE32<ChaCha20Poly1305>,
+    aad: &[u8],
+) -> Result<u64> {
+    let mut counter = ByteCounter(0);
+    decrypt_file(
+        input,
+        location,
+        body_len,
+        &mut counter,
+        decryptor,
+        aad,
+        None,
+    )?;
+
+    Ok(counter.0)
+}
+
+/// Decrypts chunks of the given reader and writes them to the given output, failing if any chunk
+/// can't be authenticated. This will stop early if the given limit on the number of plaintext bytes
+/// is reached, returning whether or not the whole stream was decrypted. Each chunk's plaintext is
+/// counted towards the given progress.
+///
+/// This mirrors [`encrypt_chunks`], so the last chunk is whichever one is followed by the end of
+/// the input, even if it's full. An input with no chunks at all (not even an empty last one) fails
+/// to authenticate, so a body truncated to nothing is never mistaken for an empty plaintext.
+fn decrypt_chunks(
+    input: &mut impl Read,
+    output: &mut impl Write,
+    mut decryptor: DecryptorBE32<ChaCha20Poly1305>,
+    aad: &[u8],
+    limit: Option<u64>,
+    progress: &mut Progress,
+) -> Result<bool> {
+    // Decrypt chunks of the input and write them directly to the output
     let mut buffer = [0; DECRYPTION_BUF_SIZE as usize];
+    let mut next_buffer = [0; DECRYPTION_BUF_SIZE as usize];
+    let mut len = read_full(input, &mut buffer)?;
+    let mut remaining = limit.unwrap_or(u64::MAX);
+    let mut chunks = 0u64;
     loop {
-        // If we have more bytes left than the buffer size, we aren't at the last chunk (handled
-        // specially by the algorithm)
-        let bytes_left = input_size - input.stream_position()?;
-        if bytes_left > DECRYPTION_BUF_SIZE {
-            input.read(&mut buffer)?;
-            let decrypted = decryptor
-                .decrypt_next(buffer.as_ref())
-                .map_err(|_| anyhow!("decryption failed"))?;
-            output.write_all(&decrypted)?;
+        // We can only tell whether this is the last chunk (handled specially by the algorithm) by
+        // trying to read the next one
+        let next_len = if len == buffer.len() {
+            read_full(input, &mut next_buffer)?
         } else {
-            let read = input.read(&mut buffer)?;
+            0
+        };
+        if next_len == 0 {
             let decrypted = decryptor
-                .decrypt_last(&buffer[..read])
-                .map_err(|_| anyhow!("last decryption failed"))?;
-            output.write_all(&decrypted)?;
+                .decrypt_last(Payload {
+                    msg: &buffer[..len],
+                    aad,
+                })
+                .map_err(|_| {
+                    debug!("Chunk {chunks} (the last) failed to authenticate");
+                    authentication_failed("last decryption failed")
+                })?;
+            let len = decrypted.len().min(remaining as usize);
+            output.write_all(&decrypted[..len])?;
+            progress.advance(len);
 
             break;
         }
+
+        let decrypted = decryptor
+            .decrypt_next(Payload {
+                msg: buffer.as_ref(),
+                aad,
+            })
+            .map_err(|_| {
+                debug!("Chunk {chunks} failed to authenticate");
+                authentication_failed("decryption failed")
+            })?;
+        chunks += 1;
+        if decrypted.len() as u64 >= remaining {
+            output.write_all(&decrypted[..remaining as usize])?;
+            output.flush()?;
+            progress.advance(remaining as usize);
+            debug!("Stopped at the limit after decrypting {chunks} chunk(s)");
+            return Ok(false);
+        }
+        output.write_all(&decrypted)?;
+        remaining -= decrypted.len() as u64;
+        progress.advance(decrypted.len());
+
+        std::mem::swap(&mut buffer, &mut next_buffer);
+        len = next_len;
     }
+    output.flush()?;
+    debug!("Decrypted {} chunk(s)", chunks + 1);
 
-    Ok(())
+    Ok(true)
+}
+
+/// Reads from the given reader until the buffer is full or the reader runs out, returning the
+/// number of bytes read. Unlike [`Read::read_exact`], running out early isn't an error.
+pub(crate) fn read_full(input: &mut (impl Read + ?Sized), buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match input.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(filled)
+}
+
+/// Hashes the given plaintext in the way recorded in [`crate::FileMetadata::hash`], reading it to
+/// the end.
+pub fn plaintext_hash(input: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut hasher = PlaintextHasher::default();
+    io::copy(input, &mut hasher)?;
+
+    Ok(hasher.finish())
+}
+
+/// A writer that hashes everything written to it, in the way recorded in
+/// [`crate::FileMetadata::hash`] (BLAKE2b-512, which is what `b2sum` prints). This can be used to
+/// hash the plaintext as it's decrypted.
+#[derive(Default)]
+pub struct PlaintextHasher(Blake2b512);
+impl PlaintextHasher {
+    /// Gets the digest of everything written so far.
+    pub fn finish(self) -> Vec<u8> {
+        self.0.finalize().to_vec()
+    }
+}
+impl Write for PlaintextHasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A running count of the bytes of plaintext processed, which is reported to a callback after each
+/// chunk (see [`encrypt_file_with_progress`]).
+pub(crate) struct Progress<'a> {
+    done: u64,
+    on_progress: &'a mut dyn FnMut(u64),
+}
+impl<'a> Progress<'a> {
+    /// Starts counting from zero, reporting to the given callback.
+    pub(crate) fn new(on_progress: &'a mut dyn FnMut(u64)) -> Self {
+        Self {
+            done: 0,
+            on_progress,
+        }
+    }
+
+    /// Counts another chunk of the given length, and reports the new total.
+    pub(crate) fn advance(&mut self, len: usize) {
+        self.done += len as u64;
+        (self.on_progress)(self.done);
+    }
+}
+
+/// A reader that counts the number of bytes read through it.
+struct ReadCounter<'a, R> {
+    inner: &'a mut R,
+    count: u64,
+}
+impl<R: Read> Read for ReadCounter<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count += read as u64;
+        Ok(read)
+    }
+}
+
+/// A reader that fails if the input it wraps isn't the expected length, as soon as it can tell. If
+/// the input is longer, this fails when reading past the expected length, rather than at the end
+/// of the input, so a file that's growing indefinitely can't keep it going forever.
+struct ExpectedLenReader<'a, R: ?Sized> {
+    inner: &'a mut R,
+    /// The length the input should be, if it's known.
+    len: Option<u64>,
+    /// How much of the input has been read so far.
+    pos: u64,
+}
+impl<'a, R: Read + ?Sized> ExpectedLenReader<'a, R> {
+    /// Wraps the given input, which should be `len` bytes long (if that's known).
+    fn new(inner: &'a mut R, len: Option<u64>) -> Self {
+        Self { inner, len, pos: 0 }
+    }
+}
+impl<R: Read + ?Sized> Read for ExpectedLenReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(len) = self.len else {
+            return self.inner.read(buf);
+        };
+        if self.pos == len {
+            if read_full(self.inner, &mut [0u8])? != 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("the input grew while it was being encrypted (it was {len} bytes long when encryption started), which isn't supported"),
+                ));
+            }
+            return Ok(0);
+        }
+
+        let max = buf
+            .len()
+            .min(usize::try_from(len - self.pos).unwrap_or(usize::MAX));
+        let read = self.inner.read(&mut buf[..max])?;
+        if read == 0 && max > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "the input shrank while it was being encrypted (it ended after {} bytes, but was {len} bytes long when encryption started), which isn't supported",
+                    self.pos
+                ),
+            ));
+        }
+        self.pos += read as u64;
+
+        Ok(read)
+    }
+}
+
+/// A writer that discards everything written to it, just counting the number of bytes.
+pub(crate) struct ByteCounter(pub u64);
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{header::StreamLocation, testing};
+    use chacha20poly1305::{
+        aead::stream::{DecryptorBE32, NewStream},
+        KeyInit,
+    };
+    use std::io::Cursor;
+
+    /// Creates the STREAM every body in these tests is encrypted with, from a fixed key.
+    fn stream() -> StreamBE32<ChaCha20Poly1305> {
+        StreamBE32::from_aead(ChaCha20Poly1305::new(&[7; 32].into()), &[9; 7].into())
+    }
+
+    /// Encrypts the given plaintext with the fixed key, under a header that records the length of
+    /// the body, returning the header and the whole file.
+    fn encrypt(plaintext: &[u8]) -> (Header, Vec<u8>) {
+        let (header, file, res) = encrypt_declared(plaintext, plaintext.len() as u64);
+        res.unwrap();
+
+        (header, file)
+    }
+
+    /// Encrypts the given input with the fixed key, under a header created for a plaintext of
+    /// `len` bytes, returning the header, everything that was written, and whether it succeeded.
+    fn encrypt_declared(input: &[u8], len: u64) -> (Header, Vec<u8>, Result<()>) {
+        let (mut header, _) = testing::header("test");
+        header.set_body_len(ciphertext_len(len)).unwrap();
+        let mut file = Vec::new();
+        let encryptor = EncryptorBE32::from_stream_primitive(stream());
+        let res = encrypt_file(&mut Cursor::new(input), &mut file, &header, encryptor, None);
+
+        (header, file, res)
+    }
+
+    /// Decrypts the body of the given file (after the given header) with the fixed key.
+    fn decrypt(header: &Header, file: &[u8]) -> Result<Vec<u8>> {
+        let mut plaintext = Vec::new();
+        decrypt_file(
+            &mut &file[header.to_bytes().len()..],
+            StreamLocation {
+                offset: 0,
+                len: None,
+            },
+            header.body_len(),
+            &mut plaintext,
+            DecryptorBE32::from_stream_primitive(stream()),
+            &header.authenticated_data(),
+            None,
+        )?;
+
+        Ok(plaintext)
+    }
+
+    #[test]
+    fn round_trips_around_chunk_boundaries() {
+        let chunk = ENCRYPTION_BUF_SIZE as usize;
+        for len in [0, 1, chunk, chunk + 1] {
+            let plaintext = (0..len).map(|idx| (idx % 251) as u8).collect::<Vec<_>>();
+            let (header, file) = encrypt(&plaintext);
+
+            let body_len = file.len() - header.to_bytes().len();
+            assert_eq!(body_len as u64, ciphertext_len(len as u64), "{len} bytes");
+            assert_eq!(decrypt(&header, &file).unwrap(), plaintext, "{len} bytes");
+        }
+    }
+
+    #[test]
+    fn empty_plaintext_is_a_single_empty_last_chunk() {
+        let (header, file) = encrypt(&[]);
+        let body = &file[header.to_bytes().len()..];
+        // Nothing but the tag
+        assert_eq!(body.len() as u64, DECRYPTION_BUF_SIZE - ENCRYPTION_BUF_SIZE);
+
+        let aad = header.authenticated_data();
+        let decrypted = DecryptorBE32::from_stream_primitive(stream())
+            .decrypt_last(Payload {
+                msg: body,
+                aad: &aad,
+            })
+            .unwrap();
+        assert!(decrypted.is_empty());
+    }
+
+    /// Checks that what was written of a body that should have been `len` bytes of plaintext
+    /// stops before its last chunk, so it can never be mistaken for a complete file.
+    fn assert_no_last_chunk(header: &Header, file: &[u8], len: u64) {
+        let body_len = (file.len() - header.to_bytes().len()) as u64;
+        assert_eq!(body_len % DECRYPTION_BUF_SIZE, 0);
+        assert!(body_len < ciphertext_len(len));
+        assert!(decrypt(header, file).is_err());
+    }
+
+    #[test]
+    fn input_that_grew_is_refused() {
+        let len = 2 * ENCRYPTION_BUF_SIZE + 100;
+        let input = vec![1; len as usize + 100];
+        let (header, file, res) = encrypt_declared(&input, len);
+
+        let err = format!("{:#}", res.unwrap_err());
+        assert!(err.contains("the input grew"), "{err}");
+        assert_no_last_chunk(&header, &file, len);
+    }
+
+    #[test]
+    fn input_that_shrank_is_refused() {
+        let len = 2 * ENCRYPTION_BUF_SIZE + 100;
+        let input = vec![1; len as usize - 50];
+        let (header, file, res) = encrypt_declared(&input, len);
+
+        let err = format!("{:#}", res.unwrap_err());
+        assert!(err.contains("the input shrank"), "{err}");
+        assert_no_last_chunk(&header, &file, len);
+    }
+
+    #[test]
+    fn truncated_body_fails_to_authenticate() {
+        let len = ENCRYPTION_BUF_SIZE + 100;
+        let (header, file) = encrypt(&vec![1; len as usize]);
+        let truncated = &file[..file.len() - 5];
+
+        let err = decrypt(&header, truncated).unwrap_err();
+        assert!(err.is::<AuthenticationFailed>());
+        let msg = format!("{err:#}");
+        assert!(
+            msg.contains(&format!(
+                "the body is {} bytes long, but should be {} bytes",
+                ciphertext_len(len) - 5,
+                ciphertext_len(len)
+            )),
+            "{msg}"
+        );
+    }
+
+    /// Encrypts the given plaintext, then cuts the file off part-way through its third chunk (as if
+    /// the encryption had been interrupted), returning the header, the whole file, and the
+    /// interrupted one (with its cursor after the header, ready to resume).
+    fn interrupted(plaintext: &[u8]) -> (Header, Vec<u8>, File) {
+        let (header, file) = encrypt(plaintext);
+        let header_len = header.to_bytes().len() as u64;
+        let mut partial = tempfile::tempfile().unwrap();
+        partial
+            .write_all(&file[..(header_len + 2 * DECRYPTION_BUF_SIZE + 100) as usize])
+            .unwrap();
+        partial.seek(SeekFrom::Start(header_len)).unwrap();
+
+        (header, file, partial)
+    }
+
+    #[test]
+    fn resumed_encryption_matches_uninterrupted() {
+        let plaintext = (0..3 * ENCRYPTION_BUF_SIZE + 100)
+            .map(|idx| (idx % 251) as u8)
+            .collect::<Vec<_>>();
+        let (header, file, mut partial) = interrupted(&plaintext);
+
+        let aad = header.authenticated_data();
+        let skipped =
+            resume_encrypt_file(&mut Cursor::new(&plaintext), &mut partial, stream(), &aad)
+                .unwrap();
+        assert_eq!(skipped, 2 * ENCRYPTION_BUF_SIZE);
+        let mut resumed = Vec::new();
+        partial.rewind().unwrap();
+        partial.read_to_end(&mut resumed).unwrap();
+        assert_eq!(resumed, file);
+    }
+
+    #[test]
+    fn resuming_with_changed_input_is_refused() {
+        let mut plaintext = vec![1; 3 * ENCRYPTION_BUF_SIZE as usize + 100];
+        let (header, _, mut partial) = interrupted(&plaintext);
+        let partial_len = partial.metadata().unwrap().len();
+        // Change the last chunk that was completely written
+        plaintext[ENCRYPTION_BUF_SIZE as usize + 10] = 2;
+
+        let aad = header.authenticated_data();
+        let err = resume_encrypt_file(&mut Cursor::new(&plaintext), &mut partial, stream(), &aad)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("the input has changed"), "{err}");
+        // Nothing was encrypted again
+        assert_eq!(partial.metadata().unwrap().len(), partial_len);
+    }
+
+    /// Encrypts everything from the given input under a header that doesn't know how long it is,
+    /// returning the whole file.
+    fn encrypt_unknown_len(input: &mut File) -> Vec<u8> {
+        let (header, encryptor) = testing::header("stream");
+        assert_eq!(header.body_len(), None);
+        let mut file = Vec::new();
+        encrypt_file(input, &mut file, &header, encryptor, None).unwrap();
+
+        file
+    }
+
+    #[test]
+    fn regular_files_have_a_known_len() {
+        let mut input = tempfile::tempfile().unwrap();
+        assert_eq!(known_len(&mut input).unwrap(), Some(0));
+        input.write_all(&[1; 100]).unwrap();
+        assert_eq!(known_len(&mut input).unwrap(), Some(100));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pipes_have_no_known_len() {
+        let plaintext = vec![3; ENCRYPTION_BUF_SIZE as usize + 100];
+        let (reader, mut writer) = io::pipe().unwrap();
+        let writing = {
+            let plaintext = plaintext.clone();
+            std::thread::spawn(move || writer.write_all(&plaintext))
+        };
+        let mut input = File::from(std::os::fd::OwnedFd::from(reader));
+
+        assert_eq!(known_len(&mut input).unwrap(), None);
+        let file = encrypt_unknown_len(&mut input);
+        writing.join().unwrap().unwrap();
+        assert_eq!(testing::decrypt(&file, "stream").unwrap(), plaintext);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn proc_files_have_no_known_len() {
+        // This claims to be an empty regular file, but it isn't empty
+        let mut input = File::open("/proc/self/cmdline").unwrap();
+        assert_eq!(input.metadata().unwrap().len(), 0);
+
+        assert_eq!(known_len(&mut input).unwrap(), None);
+        let file = encrypt_unknown_len(&mut input);
+        let plaintext = std::fs::read("/proc/self/cmdline").unwrap();
+        assert!(!plaintext.is_empty());
+        assert_eq!(testing::decrypt(&file, "stream").unwrap(), plaintext);
+    }
 }
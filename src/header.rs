@@ -1,113 +1,1256 @@
-use crate::factor::FactorRegistry;
-use anyhow::{anyhow, Result};
-use argon2::Argon2;
+use crate::{
+    dedup::{DedupCipher, DedupParams},
+    ecc::EccParams,
+    error::authentication_failed,
+    factor::{BoxedFactor, FactorRegistry},
+    factors::factor_feature,
+    file::{ciphertext_len, ENCRYPTION_BUF_SIZE},
+    kdf::Kdf,
+    padding::PaddingScheme,
+    prompt::{Cancelled, Prompter},
+    rng::{self, CystRng},
+};
+use anyhow::{anyhow, bail, Result};
+use blake2::{Blake2s256, Digest};
 use chacha20poly1305::{
     aead::{
-        stream::{DecryptorBE32, Encryptor, EncryptorBE32},
-        Aead,
+        stream::{DecryptorBE32, Encryptor, EncryptorBE32, NewStream, StreamBE32},
+        Aead, Payload,
     },
     AeadCore, ChaCha20Poly1305, KeyInit,
 };
-use dialoguer::{Confirm, Input, Select};
-use rand::{rngs::OsRng, Rng};
-use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fs::File, io::Read};
+use log::debug;
+use rand::Rng;
+use ring::hkdf;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use shamirsecretsharing::hazmat::{combine_keyshares, create_keyshares};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::{self, Display},
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+use tempfile::NamedTempFile;
+
+/// The version of the file format this version of cyst writes. This isn't stored in files (the
+/// earliest ones have nowhere to put it), but it's bumped whenever the format changes in a way
+/// older versions can't read, which the test vectors (see [`crate::vectors`]) make sure of.
+pub const FORMAT_VERSION: u32 = 4;
+/// How many bits of entropy an option that can decrypt the file on its own must have for the user
+/// not to be warned that it's weak (see [`Factor::entropy`](crate::Factor::entropy)).
+const WEAK_OPTION_BITS: f64 = 50.0;
+/// The largest header we'll try to read, which is far larger than any real header.
+const MAX_HEADER_LEN: u64 = 16 * 1024 * 1024;
+/// How long to wait before the first retry of a failed option. This doubles with each further
+/// failure, up to [`MAX_RETRY_DELAY`], to slow down anyone guessing at the prompts.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+/// The longest we'll ever wait before a retry.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
 
 /// A header for data encrypted using Cyst.
 #[derive(Serialize, Deserialize)]
 pub struct Header {
     /// All the options available for decrypting the file, indexed by their user-provided names.
     options: HashMap<String, OptionData>,
-    /// The nonce used to encrypt the file's contents.
-    ///
-    /// Don't ask me why this is size 7, it's got something to do with the encryptor parameters for
-    /// the STREAM construction but it's horribly documented and I'm just going off failing
-    /// assertions screaming 7 at me.
-    nonce: [u8; 7],
+    /// Optional information about the file that was encrypted. This is *not* encrypted, but it is
+    /// authenticated.
+    metadata: Option<FileMetadata>,
+    /// Where the decoy's ciphertext starts in the body, if this header was just created with a
+    /// decoy. This is deliberately never stored.
+    #[serde(skip)]
+    decoy_offset: Option<u64>,
+    /// The primary key (with its stream's location) of the real plaintext, if this header was just
+    /// created. This is deliberately never stored, since it decrypts the file on its own.
+    #[serde(skip)]
+    escrow_key: Option<Vec<u8>>,
+    /// The parameters for splitting the plaintext into chunks, if the body was encrypted in
+    /// dedup mode (see [`crate::dedup`]) rather than as a single STREAM.
+    dedup: Option<DedupParams>,
+    /// The total length of the body (everything after the header), if it was known when the file
+    /// was encrypted. This is authenticated, so truncating or extending the body can be detected
+    /// outright, rather than just showing up as a chunk that fails to decrypt.
+    body_len: Option<u64>,
+    /// The parameters of the error correction added to the body, if it has any (see
+    /// [`crate::ecc`]).
+    ecc: Option<EccParams>,
+    /// The size the serialized header is padded to (or a multiple of it, if it's grown since), if
+    /// it's padded at all. This is authenticated, so the padding can't be changed unnoticed.
+    padded_len: Option<u64>,
+    /// A note for anyone who comes across the file, which can be read without decrypting it. This
+    /// is *not* encrypted, but it is authenticated.
+    public_note: Option<String>,
+    /// Fields added since the first version of the format, which are stored after the rest (see
+    /// [`HeaderExtensions`]).
+    #[serde(skip)]
+    extensions: HeaderExtensions,
 }
 impl Header {
     /// Creates a new header by prompting the user to set up the encryption options they want. This
     /// returns the header and an encryptor ready to encrypt the data chunk-by-chunk.
-    pub fn new(registry: &FactorRegistry) -> Result<(Self, EncryptorBE32<ChaCha20Poly1305>)> {
-        // Generate a nonce (used to actually encrypt the data)
-        let primary_key = OsRng.gen::<[u8; 32]>();
-        let nonce = OsRng.gen::<[u8; 7]>();
+    ///
+    /// Once all the prompting is done, the options' keys are derived on up to `threads` threads at
+    /// once, since each one takes a deliberately expensive key derivation.
+    pub fn new(
+        registry: &FactorRegistry,
+        metadata: Option<FileMetadata>,
+        threads: usize,
+        prompter: &mut dyn Prompter,
+    ) -> Result<(Self, EncryptorBE32<ChaCha20Poly1305>)> {
+        let mut options = HashMap::new();
+        let primary_key = prompt_options(
+            &mut options,
+            &mut HashMap::new(),
+            StreamLocation {
+                offset: 0,
+                len: None,
+            },
+            registry,
+            prompter,
+        )?;
+        warn_unused_gates(&options, prompter);
+        wrap_options(&mut options, threads, prompter);
 
-        // Prompt the user for a series of options
-        let mut is_first = true;
+        Ok((
+            Self {
+                options,
+                metadata,
+                decoy_offset: None,
+                escrow_key: Some(encode_primary_key(
+                    &primary_key,
+                    StreamLocation {
+                        offset: 0,
+                        len: None,
+                    },
+                )),
+                dedup: None,
+                body_len: None,
+                ecc: None,
+                padded_len: None,
+                public_note: None,
+                extensions: HeaderExtensions::default(),
+            },
+            Encryptor::from_stream_primitive(stream(&primary_key)),
+        ))
+    }
+
+    /// Like [`Header::new`], but for encrypting in dedup mode, splitting the plaintext into chunks
+    /// with the given parameters. This returns the header and the cipher to encrypt the chunks
+    /// with. Dedup mode can't store any metadata (see [`Header::dedup_params`]).
+    pub fn new_dedup(
+        registry: &FactorRegistry,
+        params: DedupParams,
+        threads: usize,
+        prompter: &mut dyn Prompter,
+    ) -> Result<(Self, DedupCipher)> {
+        let mut options = HashMap::new();
+        let primary_key = prompt_options(
+            &mut options,
+            &mut HashMap::new(),
+            StreamLocation {
+                offset: 0,
+                len: None,
+            },
+            registry,
+            prompter,
+        )?;
+        warn_unused_gates(&options, prompter);
+        wrap_options(&mut options, threads, prompter);
+
+        Ok((
+            Self {
+                options,
+                metadata: None,
+                decoy_offset: None,
+                escrow_key: Some(encode_primary_key(
+                    &primary_key,
+                    StreamLocation {
+                        offset: 0,
+                        len: None,
+                    },
+                )),
+                dedup: Some(params),
+                body_len: None,
+                ecc: None,
+                padded_len: None,
+                public_note: None,
+                extensions: HeaderExtensions::default(),
+            },
+            DedupCipher::new(&primary_key, params),
+        ))
+    }
+
+    /// Like [`Header::new`], but also prompts the user to set up *duress* options, which decrypt
+    /// a decoy instead of the real plaintext. This returns an encryptor for the real plaintext
+    /// (which must be `plaintext_len` bytes long), and then one for the decoy, whose ciphertext
+    /// should come directly after the real ciphertext.
+    ///
+    /// Nothing in the header itself shows that there's a decoy: each option's stream location is
+    /// encrypted along with its primary key, and duress options are otherwise just like any other
+    /// option.
+    pub fn with_decoy(
+        registry: &FactorRegistry,
+        metadata: Option<FileMetadata>,
+        plaintext_len: u64,
+        threads: usize,
+        prompter: &mut dyn Prompter,
+    ) -> Result<(
+        Self,
+        EncryptorBE32<ChaCha20Poly1305>,
+        EncryptorBE32<ChaCha20Poly1305>,
+    )> {
+        let real_len = ciphertext_len(plaintext_len);
         let mut options = HashMap::new();
+        // Duress options can be gated behind the same gates as the real ones
+        let mut gate_keys = HashMap::new();
+        let primary_key = prompt_options(
+            &mut options,
+            &mut gate_keys,
+            StreamLocation {
+                offset: 0,
+                len: Some(real_len),
+            },
+            registry,
+            prompter,
+        )?;
+        prompter.info("Now set up the duress options, which will decrypt the decoy instead.");
+        let decoy_primary_key = prompt_options(
+            &mut options,
+            &mut gate_keys,
+            StreamLocation {
+                offset: real_len,
+                len: None,
+            },
+            registry,
+            prompter,
+        )?;
+        warn_unused_gates(&options, prompter);
+        wrap_options(&mut options, threads, prompter);
+
+        Ok((
+            Self {
+                options,
+                metadata,
+                decoy_offset: Some(real_len),
+                escrow_key: Some(encode_primary_key(
+                    &primary_key,
+                    StreamLocation {
+                        offset: 0,
+                        len: Some(real_len),
+                    },
+                )),
+                dedup: None,
+                body_len: None,
+                ecc: None,
+                padded_len: None,
+                public_note: None,
+                extensions: HeaderExtensions::default(),
+            },
+            Encryptor::from_stream_primitive(stream(&primary_key)),
+            Encryptor::from_stream_primitive(stream(&decoy_primary_key)),
+        ))
+    }
+
+    /// Derives a decryptor from this header by prompting the user to provide details to satisfy
+    /// one of the decryption options. This also returns the location of the ciphertext that
+    /// option decrypts.
+    ///
+    /// If the name of an `option` is given, that option will be used without asking the user to
+    /// choose one. Otherwise, if `auto_option` is set, each option will be attempted in turn until
+    /// one succeeds.
+    ///
+    /// If the user fails to satisfy the option they chose (e.g. by mistyping a passphrase), they'll
+    /// be offered the chance to try again, choosing the same option or a different one, up to
+    /// `attempts` times in all. Every factor is derived afresh on each attempt, so nothing from a
+    /// failed attempt (like a half-finished download) is reused.
+    pub fn to_decryptor(
+        &self,
+        registry: &FactorRegistry,
+        option: Option<&str>,
+        auto_option: bool,
+        attempts: u32,
+        prompter: &mut dyn Prompter,
+    ) -> Result<(DecryptorBE32<ChaCha20Poly1305>, StreamLocation)> {
+        let (stream, location) =
+            self.to_stream(registry, option, auto_option, attempts, prompter)?;
+        Ok((DecryptorBE32::from_stream_primitive(stream), location))
+    }
+
+    /// Like [`Header::to_decryptor`], but returns the underlying STREAM primitive, which can
+    /// encrypt or decrypt a chunk at any position. This is what's needed to resume an interrupted
+    /// encryption.
+    ///
+    /// Gates can't be chosen here, as they don't decrypt the file themselves, but the user will be
+    /// asked to satisfy whatever gates the chosen option is gated behind first.
+    pub fn to_stream(
+        &self,
+        registry: &FactorRegistry,
+        option: Option<&str>,
+        auto_option: bool,
+        attempts: u32,
+        prompter: &mut dyn Prompter,
+    ) -> Result<(StreamBE32<ChaCha20Poly1305>, StreamLocation)> {
+        if self.dedup.is_some() {
+            bail!("this file was encrypted in dedup mode, so it has no STREAM");
+        }
+        let (primary_key, location) =
+            self.unlock(registry, option, auto_option, attempts, prompter)?;
+
+        Ok((stream(&primary_key), location))
+    }
+
+    /// Like [`Header::to_decryptor`], but for files encrypted in dedup mode, returning the cipher
+    /// for their chunks. This can also be used to encrypt a new version of the file with the same
+    /// header, so its unchanged chunks stay the same.
+    pub fn to_dedup_cipher(
+        &self,
+        registry: &FactorRegistry,
+        option: Option<&str>,
+        auto_option: bool,
+        attempts: u32,
+        prompter: &mut dyn Prompter,
+    ) -> Result<DedupCipher> {
+        let Some(params) = self.dedup else {
+            bail!("this file was not encrypted in dedup mode");
+        };
+        let (primary_key, _) = self.unlock(registry, option, auto_option, attempts, prompter)?;
+
+        Ok(DedupCipher::new(&primary_key, params))
+    }
+
+    /// Like [`Header::to_decryptor`], but uses an escrow key (see [`Header::escrow_key`]) instead
+    /// of any of the options, so the user isn't prompted at all.
+    pub fn escrow_decryptor(
+        &self,
+        escrow_key: &[u8],
+    ) -> Result<(DecryptorBE32<ChaCha20Poly1305>, StreamLocation)> {
+        if self.dedup.is_some() {
+            bail!("this file was encrypted in dedup mode, so it has no STREAM");
+        }
+        let (primary_key, location) = decode_escrow_key(escrow_key)?;
+
+        Ok((
+            DecryptorBE32::from_stream_primitive(stream(&primary_key)),
+            location,
+        ))
+    }
+
+    /// Like [`Header::to_dedup_cipher`], but uses an escrow key (see [`Header::escrow_key`])
+    /// instead of any of the options.
+    pub fn escrow_dedup_cipher(&self, escrow_key: &[u8]) -> Result<DedupCipher> {
+        let Some(params) = self.dedup else {
+            bail!("this file was not encrypted in dedup mode");
+        };
+        let (primary_key, _) = decode_escrow_key(escrow_key)?;
+
+        Ok(DedupCipher::new(&primary_key, params))
+    }
+
+    /// Prompts the user to satisfy one of the options that decrypts the file (see
+    /// [`Header::to_decryptor`]), returning what it decrypts in the same form as an escrow key
+    /// (see [`Header::escrow_key`]). This is what's needed to merge another file's options into
+    /// this one's (see [`Header::merge_options`]).
+    pub fn unlock_escrow_key(
+        &self,
+        registry: &FactorRegistry,
+        option: Option<&str>,
+        auto_option: bool,
+        attempts: u32,
+        prompter: &mut dyn Prompter,
+    ) -> Result<Vec<u8>> {
+        let (primary_key, location) =
+            self.unlock(registry, option, auto_option, attempts, prompter)?;
+
+        Ok(encode_primary_key(&primary_key, location))
+    }
+
+    /// Prompts the user to satisfy one of the options that decrypts the file (see
+    /// [`Header::to_decryptor`]), returning the raw key material of the stream it decrypts rather
+    /// than a decryptor, so the ciphertext can be inspected or decrypted with other tools. This is
+    /// only meant for debugging: anyone who sees it can decrypt the file without any of its
+    /// factors.
+    pub fn unlock_raw_stream(
+        &self,
+        registry: &FactorRegistry,
+        option: Option<&str>,
+        auto_option: bool,
+        attempts: u32,
+        prompter: &mut dyn Prompter,
+    ) -> Result<RawStream> {
+        if self.dedup.is_some() {
+            bail!("this file was encrypted in dedup mode, so it has no STREAM");
+        }
+        let (primary_key, location) =
+            self.unlock(registry, option, auto_option, attempts, prompter)?;
+
+        Ok(RawStream {
+            nonce_prefix: stream_nonce(&primary_key),
+            key: primary_key,
+            chunk_len: ENCRYPTION_BUF_SIZE,
+            aad: self.authenticated_data(),
+            location,
+        })
+    }
+
+    /// Prompts the user to satisfy one of the options that decrypts the file (see
+    /// [`Header::to_decryptor`]), returning the primary key and the location of the stream it
+    /// decrypts.
+    fn unlock(
+        &self,
+        registry: &FactorRegistry,
+        option: Option<&str>,
+        auto_option: bool,
+        attempts: u32,
+        prompter: &mut dyn Prompter,
+    ) -> Result<(Vec<u8>, StreamLocation)> {
+        // Gates are only ever satisfied on the way to the options behind them
+        let summaries = self
+            .option_summaries()
+            .into_iter()
+            .filter(|summary| !summary.is_gate)
+            .collect::<Vec<_>>();
+        if let Some(name) = option {
+            if !summaries.iter().any(|summary| summary.name == name) {
+                let names = summaries
+                    .iter()
+                    .map(|summary| format!("'{}'", summary.name))
+                    .collect::<Vec<_>>();
+                bail!(
+                    "no option named '{name}' that can decrypt the file (the options are {})",
+                    names.join(", ")
+                );
+            }
+        }
+        if auto_option && option.is_none() {
+            for summary in summaries {
+                let name = summary.name;
+                prompter.info(&format!("Trying option '{name}'..."));
+                debug!("Automatically trying option '{name}'");
+                match self
+                    .unlock_option(name, registry, prompter)
+                    .and_then(decode_primary_key)
+                {
+                    Ok(unlocked) => {
+                        prompter.info(&format!("Option '{name}' succeeded."));
+                        return Ok(unlocked);
+                    }
+                    // The user wants to stop entirely, not just skip this option
+                    Err(err) if err.is::<Cancelled>() => return Err(err),
+                    Err(err) => prompter.info(&format!("Option '{name}' failed: {err:#}")),
+                }
+            }
+            return Err(authentication_failed(
+                "none of the options could be satisfied",
+            ));
+        }
+
+        // Prompt the user for which option they want to take (unless they've already said),
+        // reminding them what each one needs, and let them try again if they fail
+        let items = summaries
+            .iter()
+            .map(|summary| summary.to_string())
+            .collect::<Vec<_>>();
+        let items = items.iter().map(String::as_str).collect::<Vec<_>>();
+        let mut delay = RETRY_DELAY;
+        let mut attempt = 1;
         loop {
-            // Always prompt for a first option, and otherwise confirm with the user first
-            if is_first
-                || Confirm::new()
-                    .with_prompt("Add another encryption option?")
-                    .interact()
-                    .unwrap()
+            let name = match option {
+                Some(name) => name,
+                None => {
+                    let option_idx = prompter.select("Choose an option for decryption", &items)?;
+                    summaries[option_idx].name
+                }
+            };
+            debug!("Unlocking option '{name}' (attempt {attempt} of {attempts})");
+            let err = match self
+                .unlock_option(name, registry, prompter)
+                .and_then(decode_primary_key)
             {
-                is_first = false;
-                let (name, option_data) = prompt_option(&primary_key, registry)?;
-                options.insert(name, option_data);
-            } else {
-                break;
+                Ok(unlocked) => return Ok(unlocked),
+                Err(err) => err,
+            };
+            debug!("Option '{name}' failed on attempt {attempt}: {err:#}");
+            if err.is::<Cancelled>() || attempt >= attempts {
+                return Err(err);
+            }
+
+            prompter.notice(&format!("Option '{name}' failed: {err:#}"));
+            let left = attempts - attempt;
+            if !prompter.confirm(&format!("Try again? ({left} attempt(s) left)"))? {
+                return Err(err);
             }
+            prompter.info(&format!(
+                "Waiting {}s before trying again...",
+                delay.as_secs()
+            ));
+            thread::sleep(delay);
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+            attempt += 1;
         }
+    }
 
-        let cipher = ChaCha20Poly1305::new(primary_key.as_ref().into());
-        let encryptor = Encryptor::from_aead(cipher, nonce.as_ref().into());
+    /// Checks that the option with the given name can be satisfied by prompting the user for each
+    /// of its factors (and those of any gates it's behind) and attempting to recover the primary
+    /// key (or gate key) with them.
+    pub fn check_option(
+        &self,
+        name: &str,
+        registry: &FactorRegistry,
+        prompter: &mut dyn Prompter,
+    ) -> Result<()> {
+        self.unlock_option(name, registry, prompter)?;
 
-        Ok((Self { options, nonce }, encryptor))
+        Ok(())
     }
 
-    /// Derives a decryptor from this header by prompting the user to provide details to satisfy
-    /// one of the decryption options.
-    pub fn to_decryptor(
+    /// Probes every factor of every option (see [`Factor::probe`](crate::Factor::probe)), without
+    /// prompting the user or decrypting anything, timing how long each probe takes. This shows
+    /// whether the things the factors need (like a keyfile, or a server) are there before anyone
+    /// needs to decrypt the file. The results are in the same order as
+    /// [`Header::option_summaries`], with each option's factors in order.
+    pub fn probe_factors(&self, registry: &FactorRegistry) -> Vec<FactorProbe<'_>> {
+        let mut names = self.options.keys().collect::<Vec<_>>();
+        names.sort();
+        names
+            .into_iter()
+            .flat_map(|name| {
+                self.options[name].factors.iter().map(move |instance| {
+                    let start = Instant::now();
+                    let result = match registry.get(instance.name.as_str()) {
+                        Some(factor) => factor.probe(&instance.data),
+                        None => Err(unavailable_factor(&instance.name)),
+                    };
+                    debug!("Probed factor '{}' of option '{name}'", instance.name);
+                    FactorProbe {
+                        option: name,
+                        factor: &instance.name,
+                        result,
+                        elapsed: start.elapsed(),
+                    }
+                })
+            })
+            .collect()
+    }
+
+    /// Estimates how costly each option would be to decrypt with, without prompting the user or
+    /// decrypting anything. Each key derivation an option needs (its own, and those of the gates
+    /// it's behind) is timed on this machine with the parameters stored for it, and the factors
+    /// are checked for whether they need network access. Gates aren't estimated on their own,
+    /// since they can't decrypt anything. The results are sorted from the cheapest option to the
+    /// most costly, with those that can't be used in this build last.
+    ///
+    /// Factors' own derivations (e.g. a PIN's) aren't included, since their data is opaque here.
+    pub fn estimate_options(
         &self,
         registry: &FactorRegistry,
-    ) -> Result<DecryptorBE32<ChaCha20Poly1305>> {
-        // Prompt the user for which option they want to take
-        let mut options = self.options.keys().collect::<Vec<_>>();
-        options.sort();
-        let option_idx = Select::new()
-            .with_prompt("Choose an option for decryption")
-            .items(&options)
-            .interact()
-            .unwrap();
-        let option_data = &self.options[options[option_idx]];
+        prompter: &mut dyn Prompter,
+    ) -> Result<Vec<OptionEstimate<'_>>> {
+        // Options often share parameters, so each set is only timed once
+        let mut timings: Vec<(Kdf, Duration)> = Vec::new();
+        let mut estimates = Vec::new();
+        for (name, option_data) in &self.options {
+            if option_data.is_gate {
+                continue;
+            }
+            let gates = self.gate_chain(option_data)?;
+            let chain = gates
+                .iter()
+                .map(|gate| &self.options[*gate])
+                .chain([option_data]);
 
-        // Prompt the user for each factor in the option
-        let mut total_key = Vec::new();
-        for (factor_name, factor_data) in &option_data.factors {
-            eprintln!("Please follow the prompts for factor '{}':", factor_name);
-            let factor = &registry
-                .get(factor_name.as_str())
-                .ok_or(anyhow!("unknown factor '{factor_name}'"))?;
-            // Hand over to the factor's prompting process to derive its key
-            let key = factor.derive(&factor_data)?;
-            total_key.extend(key);
-        }
-
-        // Derive the option key from the total key and the salt
-        let mut key = [0u8; 32];
-        Argon2::default()
-            .hash_password_into(&total_key, &option_data.salt, &mut key)
-            .unwrap();
-        // And use that to decrypt the primary key
-        let cipher = ChaCha20Poly1305::new(key.as_ref().into());
-        let primary_key = cipher
-            .decrypt(
-                &option_data.primary_key_nonce.into(),
-                option_data.primary_key_ciphertext.as_ref(),
+            let mut estimate = OptionEstimate {
+                name,
+                kdf: option_data.kdf.name(),
+                gates: gates.len(),
+                factors_needed: 0,
+                kdf_time: Duration::ZERO,
+                memory: 0,
+                requires_network: false,
+                usable: Ok(()),
+            };
+            for data in chain {
+                let kdf_time = match timings.iter().find(|(kdf, _)| *kdf == data.kdf) {
+                    Some((_, kdf_time)) => *kdf_time,
+                    None => {
+                        let start = Instant::now();
+                        data.kdf.derive(b"cyst estimate", &[0; 32])?;
+                        let kdf_time = start.elapsed();
+                        prompter.detail(&format!(
+                            "Deriving a key with {} took {:.3}s.",
+                            data.kdf.name(),
+                            kdf_time.as_secs_f64()
+                        ));
+                        timings.push((data.kdf, kdf_time));
+                        kdf_time
+                    }
+                };
+                estimate.kdf_time += kdf_time;
+                estimate.memory = estimate.memory.max(data.memory_needed(registry));
+                estimate.factors_needed += data
+                    .quorum
+                    .as_ref()
+                    .map_or(data.factors.len(), |quorum| quorum.threshold as usize);
+                for instance in &data.factors {
+                    match registry.get(instance.name.as_str()) {
+                        Some(factor) => estimate.requires_network |= factor.requires_network(),
+                        None if estimate.usable.is_ok() => {
+                            estimate.usable = Err(unavailable_factor(&instance.name))
+                        }
+                        None => {}
+                    }
+                }
+            }
+            estimates.push(estimate);
+        }
+        estimates.sort_by_key(|estimate| {
+            (
+                estimate.usable.is_err(),
+                estimate.kdf_time,
+                estimate.factors_needed,
+                estimate.name,
             )
-            .map_err(|_| anyhow!("decryption failed"))?;
+        });
+
+        Ok(estimates)
+    }
+
+    /// Prompts the user to satisfy the option with the given name (after the gates it's behind, if
+    /// there are any), returning what it decrypts: the primary key and its stream's location, or a
+    /// gate key.
+    fn unlock_option(
+        &self,
+        name: &str,
+        registry: &FactorRegistry,
+        prompter: &mut dyn Prompter,
+    ) -> Result<Vec<u8>> {
+        let option_data = self
+            .options
+            .get(name)
+            .ok_or(anyhow!("no option named '{name}'"))?;
+        debug!(
+            "Option '{name}' has {} factor(s), needs {}, and is {}",
+            option_data.factors.len(),
+            option_data
+                .quorum
+                .as_ref()
+                .map_or(option_data.factors.len(), |quorum| quorum.threshold
+                    as usize),
+            match &option_data.gated_behind {
+                Some(gate) => format!("behind the gate '{gate}'"),
+                None => "not behind a gate".to_string(),
+            }
+        );
+        let gate_key = self.unlock_gates(option_data, registry, prompter)?;
+        let keys = option_data.derive_keys(registry, prompter)?;
+        prompter.detail(&format!("Unlocking option '{name}' with the derived keys."));
+
+        let secret = option_data.unwrap_secret(&keys, gate_key.as_deref())?;
+
+        option_data.follow_links(secret)
+    }
+
+    /// Prompts the user to satisfy each of the gates the given option is behind, from the outermost
+    /// in, returning the gate key the option needs (if it's behind a gate at all).
+    fn unlock_gates(
+        &self,
+        option_data: &OptionData,
+        registry: &FactorRegistry,
+        prompter: &mut dyn Prompter,
+    ) -> Result<Option<Vec<u8>>> {
+        let mut gate_key = None;
+        for gate in self.gate_chain(option_data)?.into_iter().rev() {
+            prompter.info(&format!("First, please satisfy the gate '{gate}'."));
+            let gate_data = &self.options[gate];
+            let keys = gate_data.derive_keys(registry, prompter)?;
+            gate_key = Some(gate_data.unwrap_secret(&keys, gate_key.as_deref())?);
+        }
+
+        Ok(gate_key)
+    }
+
+    /// Works out the chain of gates the given option is behind, from the innermost out, making sure
+    /// it doesn't loop (which we never create, but a corrupted header could have).
+    fn gate_chain<'a>(&'a self, option_data: &'a OptionData) -> Result<Vec<&'a str>> {
+        let mut chain = Vec::new();
+        let mut current = option_data;
+        while let Some(gate) = &current.gated_behind {
+            if chain.contains(&gate.as_str()) {
+                bail!("options are gated behind each other in a loop (corrupted)");
+            }
+            current = self
+                .options
+                .get(gate)
+                .filter(|gate_data| gate_data.is_gate)
+                .ok_or(anyhow!("'{gate}' is not a gate (corrupted)"))?;
+            chain.push(gate.as_str());
+        }
+
+        Ok(chain)
+    }
+
+    /// Re-creates every instance of the given factor in the option with the given name, leaving
+    /// the option's other factors, the other options, and the ciphertext untouched. The user must
+    /// first satisfy the whole option as it currently is (along with any gates it's behind) to
+    /// recover the primary key (or gate key), which is then re-encrypted under the new factor keys
+    /// with a fresh salt and nonce.
+    pub fn recreate_factor(
+        &mut self,
+        option_name: &str,
+        factor_name: &str,
+        registry: &FactorRegistry,
+        prompter: &mut dyn Prompter,
+    ) -> Result<()> {
+        let gate_key = {
+            let option_data = self
+                .options
+                .get(option_name)
+                .ok_or(anyhow!("no option named '{option_name}'"))?;
+            self.unlock_gates(option_data, registry, prompter)?
+        };
+        let option_data = self.options.get_mut(option_name).unwrap();
+        let factor = registry
+            .get(factor_name)
+            .ok_or_else(|| unavailable_factor(factor_name))?;
+        if !option_data
+            .factors
+            .iter()
+            .any(|instance| instance.name == factor_name)
+        {
+            bail!("option '{option_name}' has no '{factor_name}' factor");
+        }
+
+        prompter.info(&format!(
+            "First, please satisfy the option '{option_name}' as it currently is."
+        ));
+        let keys = option_data.derive_keys(registry, prompter)?;
+        let secret = option_data.unwrap_secret(&keys, gate_key.as_deref())?;
+
+        if option_data.quorum.is_some() {
+            // The user might not have satisfied every factor, so we can't split the quorum key
+            // afresh. Instead, just the share of the factor being replaced is re-encrypted.
+            for (idx, key) in keys.iter().enumerate() {
+                let instance = &option_data.factors[idx];
+                if instance.name != factor_name {
+                    continue;
+                }
+                let old_key = match key {
+                    Some(key) => key.clone(),
+                    None => {
+                        prompter.info("The factor being replaced is needed too.");
+                        derive_factor(instance, factor.as_ref(), prompter)?
+                    }
+                };
+                let share = option_data.open_share(idx, &old_key)?;
+                prompter.info(&format!(
+                    "Please follow the prompts to create the new '{factor_name}' factor:"
+                ));
+                let (data, new_key) = factor.create(prompter)?;
+                let sealed = option_data.seal_share(idx, &new_key, &share);
+                option_data.factors[idx].data = data;
+                if let Some(quorum) = &mut option_data.quorum {
+                    quorum.shares[idx] = sealed;
+                }
+            }
+            return Ok(());
+        }
+
+        // Every factor was satisfied, since the option needs all of them
+        let mut keys = keys.into_iter().flatten().collect::<Vec<_>>();
+        for (instance, key) in option_data.factors.iter_mut().zip(keys.iter_mut()) {
+            if instance.name == factor_name {
+                prompter.info(&format!(
+                    "Please follow the prompts to create the new '{factor_name}' factor:"
+                ));
+                (instance.data, *key) = factor.create(prompter)?;
+            }
+        }
+        option_data.wrap_secret(option_name, &secret, &keys, gate_key.as_deref());
+
+        Ok(())
+    }
+
+    /// Re-creates every instance of the given factor in the option with the given name, like
+    /// [`Header::recreate_factor`], but without relying on the factor being replaced, which might
+    /// have stopped working by itself (e.g. ephemeral data that's expired). The user must satisfy
+    /// all the option's other factors (even if it only needs a quorum of them), and then the
+    /// factor being replaced is tried too. If it still works, the option's secret is recovered as
+    /// usual. If not, the given escrow key (see [`Header::unlock_escrow_key`]) is encrypted in its
+    /// place, which revives the option.
+    ///
+    /// Reviving an option like this isn't possible if it's a gate or has links, since it doesn't
+    /// decrypt the primary key directly then. Unless the option needs only a quorum of its
+    /// factors, its other factors can't be checked either, so they're taken as given. If the file
+    /// has a decoy, the escrow key must be the one for the same plaintext the option decrypted,
+    /// which there's no way to check. This returns whether the factor being replaced still worked.
+    pub fn refresh_factor(
+        &mut self,
+        option_name: &str,
+        factor_name: &str,
+        escrow_key: &[u8],
+        registry: &FactorRegistry,
+        prompter: &mut dyn Prompter,
+    ) -> Result<bool> {
+        let gate_key = {
+            let option_data = self
+                .options
+                .get(option_name)
+                .ok_or(anyhow!("no option named '{option_name}'"))?;
+            self.unlock_gates(option_data, registry, prompter)?
+        };
+        let option_data = self.options.get_mut(option_name).unwrap();
+        let factor = registry
+            .get(factor_name)
+            .ok_or_else(|| unavailable_factor(factor_name))?;
+        if !option_data
+            .factors
+            .iter()
+            .any(|instance| instance.name == factor_name)
+        {
+            bail!("option '{option_name}' has no '{factor_name}' factor");
+        }
+
+        prompter.info(&format!(
+            "First, please satisfy the other factors of the option '{option_name}'."
+        ));
+        let mut keys = Vec::new();
+        for instance in &option_data.factors {
+            if instance.name == factor_name {
+                keys.push(None);
+                continue;
+            }
+            let other_factor = registry
+                .get(instance.name.as_str())
+                .ok_or_else(|| unavailable_factor(&instance.name))?;
+            keys.push(Some(derive_factor(
+                instance,
+                other_factor.as_ref(),
+                prompter,
+            )?));
+        }
+
+        prompter.info(&format!(
+            "Now checking whether the '{factor_name}' factor still works."
+        ));
+        let mut still_works = true;
+        for (instance, key) in option_data.factors.iter().zip(keys.iter_mut()) {
+            if instance.name != factor_name {
+                continue;
+            }
+            match derive_factor(instance, factor.as_ref(), prompter) {
+                Ok(old_key) => *key = Some(old_key),
+                Err(err) if err.is::<Cancelled>() => return Err(err),
+                Err(err) => {
+                    prompter.notice(&format!(
+                        "Factor '{factor_name}' no longer works, so option '{option_name}' was unusable: {err:#}"
+                    ));
+                    still_works = false;
+                    break;
+                }
+            }
+        }
+
+        let secret = if still_works {
+            option_data.unwrap_secret(&keys, gate_key.as_deref())?
+        } else {
+            if option_data.is_gate || !option_data.links.is_empty() {
+                bail!("option '{option_name}' doesn't decrypt the primary key directly, so it can't be revived");
+            }
+            // This would otherwise be done when unlocking the option
+            option_data.kdf.check()?;
+            decode_escrow_key(escrow_key)?;
+            if option_data.quorum.is_some() {
+                for (idx, key) in keys.iter().enumerate() {
+                    if let Some(key) = key {
+                        option_data.open_share(idx, key)?;
+                    }
+                }
+            } else {
+                prompter.notice("The other factors can't be checked without it, so make sure you satisfied them correctly!");
+            }
+            escrow_key.to_vec()
+        };
+
+        let mut new_keys = Vec::new();
+        for (instance, key) in option_data.factors.iter_mut().zip(keys) {
+            if instance.name == factor_name {
+                prompter.info(&format!(
+                    "Please follow the prompts to create the new '{factor_name}' factor:"
+                ));
+                let (data, new_key) = factor.create(prompter)?;
+                instance.data = data;
+                new_keys.push(new_key);
+            } else {
+                // Every other factor was satisfied above
+                new_keys.push(key.unwrap());
+            }
+        }
+        option_data.wrap_secret(option_name, &secret, &new_keys, gate_key.as_deref());
+
+        Ok(still_works)
+    }
+
+    /// Adds every option of `other` to this header, so they decrypt this file too. Both headers'
+    /// escrow keys (see [`Header::unlock_escrow_key`]) are needed, and the caller must make sure
+    /// both files hold the same plaintext: this only checks the keys themselves.
+    ///
+    /// An option can't simply be re-encrypted without satisfying it, so `other`'s options are
+    /// copied as they are, with a *link* added to each of them: this header's escrow key,
+    /// encrypted under a key derived from `other`'s primary key. Satisfying one of the merged
+    /// options decrypts `other`'s primary key as before, which then decrypts the link. Gates are
+    /// copied along with the options behind them, and any option whose name is already taken is
+    /// renamed (the user is asked for a new name). This returns the names of the added options.
+    ///
+    /// `other` can't have a decoy, since its duress options would decrypt a different primary key
+    /// that the link can't be decrypted with (and they can't be told apart from the real ones).
+    pub fn merge_options(
+        &mut self,
+        escrow_key: &[u8],
+        other: Header,
+        other_escrow_key: &[u8],
+        prompter: &mut dyn Prompter,
+    ) -> Result<Vec<String>> {
+        decode_escrow_key(escrow_key)?;
+        let (other_primary_key, other_location) = decode_escrow_key(other_escrow_key)?;
+        if other_location.offset != 0 || other_location.len.is_some() {
+            bail!("the file being merged in has a decoy, so its options can't be merged");
+        }
+        let link = OptionLink::seal(&other_primary_key, escrow_key);
+
+        // Work out every new name up front, so gates can be renamed along with the options behind
+        // them
+        let mut other_options = other.options.into_iter().collect::<Vec<_>>();
+        other_options.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let mut renamed = HashMap::new();
+        for (name, _) in &other_options {
+            let new_name =
+                if self.options.contains_key(name) || renamed.values().any(|taken| taken == name) {
+                    prompter.input(
+                        &format!(
+                            "There's already an option named '{name}', so enter a new name for it"
+                        ),
+                        None,
+                        &|new_name| {
+                            if self.options.contains_key(new_name)
+                                || renamed.values().any(|taken| taken == new_name)
+                            {
+                                Err(format!("there's already an option named '{new_name}'"))
+                            } else {
+                                Ok(())
+                            }
+                        },
+                    )?
+                } else {
+                    name.clone()
+                };
+            renamed.insert(name.clone(), new_name);
+        }
+
+        let mut added = Vec::new();
+        for (name, mut option_data) in other_options {
+            let new_name = renamed[&name].clone();
+            option_data.gated_behind = option_data
+                .gated_behind
+                .map(|gate| renamed.get(&gate).cloned().unwrap_or(gate));
+            // Gates decrypt gate keys, which don't need linking to anything
+            if !option_data.is_gate {
+                option_data.links.push(link.clone());
+            }
+            debug!(
+                "Merging option '{name}' as '{new_name}' with {} link(s)",
+                option_data.links.len()
+            );
+            self.options.insert(new_name.clone(), option_data);
+            added.push(new_name);
+        }
+
+        Ok(added)
+    }
+
+    /// Returns summaries of all the options in this header, sorted by option name.
+    pub fn option_summaries(&self) -> Vec<OptionSummary<'_>> {
+        let mut summaries = self
+            .options
+            .iter()
+            .map(|(name, option_data)| OptionSummary {
+                name,
+                description: option_data.description.as_deref(),
+                is_gate: option_data.is_gate,
+                gated_behind: option_data.gated_behind.as_deref(),
+                factors: option_data
+                    .factors
+                    .iter()
+                    .map(|instance| instance.name.as_str())
+                    .collect(),
+                quorum: option_data.quorum.as_ref().map(|quorum| quorum.threshold),
+                kdf: option_data.kdf.name(),
+            })
+            .collect::<Vec<_>>();
+        summaries.sort_by_key(|summary| summary.name);
+
+        summaries
+    }
+
+    /// Lays out the options in this header as plans, sorted by option name, so they can be shown the
+    /// same way as options that are still being set up.
+    #[cfg(feature = "tui")]
+    pub(crate) fn option_plans(&self) -> Vec<OptionPlan> {
+        let mut plans = self
+            .options
+            .iter()
+            .map(|(name, option_data)| OptionPlan {
+                name: name.clone(),
+                description: option_data.description.clone(),
+                is_gate: option_data.is_gate,
+                gated_behind: option_data.gated_behind.clone(),
+                factors: option_data
+                    .factors
+                    .iter()
+                    .map(|instance| FactorPlan {
+                        name: instance.name.clone(),
+                        hint: instance.hint.clone(),
+                    })
+                    .collect(),
+                quorum: option_data.quorum.as_ref().map(|quorum| quorum.threshold),
+                kdf: option_data.kdf,
+            })
+            .collect::<Vec<_>>();
+        plans.sort_by(|a, b| a.name.cmp(&b.name));
+
+        plans
+    }
+
+    /// Gets the metadata stored about the encrypted file, if there is any. Note that this is only
+    /// authenticated once the ciphertext has been decrypted.
+    pub fn metadata(&self) -> Option<&FileMetadata> {
+        self.metadata.as_ref()
+    }
+
+    /// Gets the parameters the plaintext was split into chunks with, if the file was encrypted in
+    /// dedup mode.
+    ///
+    /// In dedup mode, every chunk is bound to the header's authenticated data, and new versions of
+    /// the file are encrypted under the same header, so nothing in that data can belong to just one
+    /// version. That's why metadata, a public note, and an added suffix can't be used with it.
+    pub fn dedup_params(&self) -> Option<DedupParams> {
+        self.dedup
+    }
+
+    /// Gets the total length the body should be, if it was known when the file was encrypted.
+    pub fn body_len(&self) -> Option<u64> {
+        self.body_len
+    }
+
+    /// Records the total length of the body that will be encrypted with this header (including
+    /// any decoy), which can be computed with [`ciphertext_len`]. This must be set before
+    /// encrypting (since it's part of the authenticated data), and the body must then be exactly
+    /// this long, or encryption will fail. It can't be set in dedup mode, whose ciphertext length
+    /// isn't known in advance.
+    pub fn set_body_len(&mut self, body_len: u64) -> Result<()> {
+        if self.dedup.is_some() {
+            bail!("the length of a dedup-mode body can't be known in advance");
+        }
+        self.body_len = Some(body_len);
+
+        Ok(())
+    }
+
+    /// Gets the parameters of the error correction added to the body, if it has any. The body
+    /// must then be read through an [`EccReader`](crate::EccReader) before decrypting it.
+    pub fn ecc_params(&self) -> Option<EccParams> {
+        self.ecc
+    }
+
+    /// Records that error correction with the given parameters will be added to the body, by
+    /// writing it through an [`EccWriter`](crate::EccWriter). The length of the body must be set
+    /// too (see [`Header::set_body_len`]), since it's needed to drop the padding again. This can't
+    /// be used in dedup mode.
+    pub fn set_ecc_params(&mut self, params: EccParams) -> Result<()> {
+        if self.dedup.is_some() {
+            bail!("error correction can't be used in dedup mode");
+        }
+        if self.body_len.is_none() {
+            bail!("the body's length must be set before error correction can be added");
+        }
+        self.ecc = Some(params);
+
+        Ok(())
+    }
+
+    /// Estimates the most memory that decrypting a file with this header could take at once, in
+    /// bytes, going by the parameters the header gives: the most expensive key derivation of any
+    /// option (including its factors' own derivations, like a PIN's), the largest dedup chunk, or
+    /// an error-correction block, whichever is largest. This lets a file from an untrusted source
+    /// be refused before it makes us allocate something huge.
+    pub fn memory_needed(&self, registry: &FactorRegistry) -> u64 {
+        let kdf = self
+            .options
+            .values()
+            .map(|option_data| option_data.memory_needed(registry))
+            .max()
+            .unwrap_or(0);
+        // Each chunk is decrypted into a separate buffer from its ciphertext
+        let dedup = self.dedup.map_or(0, |params| 2 * params.max_size as u64);
+        let ecc = self.ecc.map_or(0, |params| params.block_len() as u64);
+
+        kdf.max(dedup).max(ecc)
+    }
+
+    /// Gets the size the serialized header is padded to, if it's padded.
+    pub fn padded_len(&self) -> Option<u64> {
+        self.padded_len
+    }
+
+    /// Pads the serialized header (see [`Header::to_bytes`]) to the given size, so its length
+    /// doesn't give away how many options and factors it has. If it's ever bigger than that (e.g.
+    /// after merging in more options), it's padded to the next multiple of the size instead. This
+    /// must be set before encrypting, since it's part of the authenticated data, and it can't be
+    /// used in dedup mode, where a header can be reused for later versions of a file.
+    ///
+    /// This only hides the structure of the header, not that the file is encrypted with cyst (or
+    /// that it's padded, since padded headers all have the same few sizes).
+    pub fn set_padded_len(&mut self, padded_len: u64) -> Result<()> {
+        if self.dedup.is_some() {
+            bail!("headers can't be padded in dedup mode");
+        }
+        if padded_len == 0 || padded_len > MAX_HEADER_LEN {
+            bail!("headers can only be padded to between 1 byte and {MAX_HEADER_LEN} bytes");
+        }
+        self.padded_len = Some(padded_len);
+
+        Ok(())
+    }
+
+    /// Gets the public note left on the file, if there is one. This can't be trusted until the
+    /// file has been decrypted, which authenticates it.
+    pub fn public_note(&self) -> Option<&str> {
+        self.public_note.as_deref()
+    }
 
-        let cipher = ChaCha20Poly1305::new(primary_key.as_slice().into());
-        Ok(DecryptorBE32::from_aead(cipher, self.nonce.as_ref().into()))
+    /// Leaves a note on the file that can be read without decrypting it (e.g. who to ask for the
+    /// keys). It isn't encrypted, so it shouldn't say anything secret, but it must be set before
+    /// encrypting, since it's part of the authenticated data, and any change to it will make
+    /// decryption fail. It can't be used in dedup mode (see [`Header::dedup_params`]).
+    pub fn set_public_note(&mut self, note: String) -> Result<()> {
+        if self.dedup.is_some() {
+            bail!("public notes can't be used in dedup mode");
+        }
+        self.public_note = Some(note);
+
+        Ok(())
+    }
+
+    /// Gets how the plaintext was padded to hide its length, if it was. The decrypted plaintext
+    /// must then be written through an [`UnpaddingWriter`](crate::padding::UnpaddingWriter) to
+    /// strip the padding.
+    pub fn plaintext_padding(&self) -> Option<PaddingScheme> {
+        self.extensions.plaintext_padding
+    }
+
+    /// Records that the plaintext will be padded with the given scheme to hide its length, by
+    /// reading it through a [`PaddedReader`](crate::padding::PaddedReader). The length of the
+    /// body must then be set from the padded length (see [`PaddingScheme::padded_len`]). This
+    /// must be set before encrypting, since it's part of the authenticated data, and it can't be
+    /// used in dedup mode or with a decoy, whose ciphertext starts where the unpadded plaintext's
+    /// ends. Stored metadata gives away the plaintext's length, so it shouldn't be used with this.
+    ///
+    /// Files with padded plaintexts can't be read by versions of cyst before format version 2.
+    pub fn set_plaintext_padding(&mut self, scheme: PaddingScheme) -> Result<()> {
+        if self.dedup.is_some() {
+            bail!("plaintexts can't be padded in dedup mode");
+        }
+        if self.decoy_offset.is_some() {
+            bail!("plaintexts can't be padded when there's a decoy");
+        }
+        self.extensions.plaintext_padding = Some(scheme);
+
+        Ok(())
+    }
+
+    /// Gets the suffix that was added to the plaintext's filename to name the encrypted file (like
+    /// `.cyst`), if it was named automatically. Stripping this from the encrypted file's name gives
+    /// back the original name. This can't be trusted until the file has been decrypted, which
+    /// authenticates it.
+    pub fn added_suffix(&self) -> Option<&str> {
+        self.extensions.added_suffix.as_deref()
+    }
+
+    /// Records the suffix added to the plaintext's filename to name the encrypted file, so the
+    /// original name can be restored exactly by stripping it off again, rather than by guessing
+    /// which part of the name is an extension. This isn't secret, but it must be set before
+    /// encrypting, since it's part of the authenticated data, and it can't be used in dedup mode
+    /// (see [`Header::dedup_params`]).
+    ///
+    /// Files with an added suffix can't be read by versions of cyst before format version 3.
+    pub fn set_added_suffix(&mut self, suffix: String) -> Result<()> {
+        if self.dedup.is_some() {
+            bail!("added suffixes can't be recorded in dedup mode");
+        }
+        if suffix.is_empty() {
+            bail!("the added suffix can't be empty");
+        }
+        self.extensions.added_suffix = Some(suffix);
+
+        Ok(())
+    }
+
+    /// Gets where the decoy's ciphertext should start in the body, if this header was just created
+    /// with [`Header::with_decoy`]. This is always `None` for headers read from a file.
+    pub fn decoy_offset(&self) -> Option<u64> {
+        self.decoy_offset
+    }
+
+    /// Gets the escrow key of the real plaintext, if this header was just created (this is always
+    /// `None` for headers read from a file). This is the file's primary key, along with where its
+    /// ciphertext is, and it can be used to decrypt the file without satisfying any option (see
+    /// [`Header::escrow_decryptor`]), for "break glass" recovery.
+    ///
+    /// Anyone who gets hold of this can decrypt the file, however its options are changed later,
+    /// so it should only ever be stored somewhere physically secure.
+    pub fn escrow_key(&self) -> Option<&[u8]> {
+        self.escrow_key.as_deref()
+    }
+
+    /// Computes a digest of all the parts of this header that are bound to the ciphertext. This
+    /// should be used as associated data for every chunk, so tampering with these fields will
+    /// cause decryption to fail.
+    ///
+    /// This deliberately excludes the options, which can be changed without touching the
+    /// ciphertext.
+    pub fn authenticated_data(&self) -> [u8; 32] {
+        let mut bytes = bincode::serialize(&(
+            &self.metadata,
+            self.body_len,
+            self.padded_len,
+            &self.public_note,
+        ))
+        .unwrap();
+        bytes.extend_from_slice(&self.extensions.to_bytes());
+        Blake2s256::digest(bytes).into()
     }
 
     /// Writes this header to bytes, including a length prefix to allow it to be read back later.
     /// Raw ciphertext can be written directly after this.
+    ///
+    /// If the header is padded (see [`Header::set_padded_len`]), zeroes are added after it, and the
+    /// length prefix covers them too. The header knows its own length, so they're skipped when
+    /// it's read back.
     pub fn to_bytes(&self) -> Vec<u8> {
-        let header_bytes = bincode::serialize(self).unwrap();
+        let mut header_bytes = bincode::serialize(self).unwrap();
+        // The options' bindings are kept with the options until now
+        let bindings = self
+            .options
+            .iter()
+            .filter_map(|(name, option_data)| Some((name.clone(), option_data.binding.clone()?)))
+            .collect::<BTreeMap<_, _>>();
+        let mut extensions = self.extensions.clone();
+        extensions.option_bindings = (!bindings.is_empty()).then_some(bindings);
+        header_bytes.extend_from_slice(&extensions.to_bytes());
+        if let Some(padded_len) = self.padded_len {
+            let len = (header_bytes.len() as u64).div_ceil(padded_len).max(1) * padded_len;
+            header_bytes.resize(len as usize, 0);
+        }
         let header_len = header_bytes.len() as u64;
 
         let mut bytes = Vec::new();
@@ -117,105 +1260,2142 @@ impl Header {
         bytes
     }
 
-    /// Reads a header from the given file, returning it and leaving the file's cursor directly
-    /// after the headerv (presumably at the beginning of ciphertext).
-    pub fn from_file(file: &mut File) -> Result<Self> {
+    /// Replaces the header of the encrypted file at the given path with this one, leaving the
+    /// ciphertext after it untouched. The new header can be any length: the old one's length is
+    /// read from the file, and the ciphertext is copied over from just after it.
+    ///
+    /// The new file is written to a temporary file (with the same permissions) and moved into
+    /// place only once it's complete, so the original is never left half-rewritten.
+    pub fn rewrite_in_file(&self, path: &Path) -> Result<()> {
+        let mut file = File::open(path)?;
+        let mut header_len_bytes = [0u8; 8];
+        file.read_exact(&mut header_len_bytes)?;
+        let header_len = u64::from_le_bytes(header_len_bytes);
+        if header_len > MAX_HEADER_LEN {
+            bail!("header has an invalid length (is this a cyst file?)");
+        }
+        file.seek(SeekFrom::Start(8 + header_len))?;
+
+        let dir = path
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        let mut temp_file = NamedTempFile::new_in(dir)?;
+        temp_file
+            .as_file()
+            .set_permissions(file.metadata()?.permissions())?;
+        temp_file.write_all(&self.to_bytes())?;
+        io::copy(&mut file, &mut temp_file)?;
+        temp_file.persist(path)?;
+
+        Ok(())
+    }
+
+    /// Reads a header from the given file (or any other reader, like an
+    /// [`ArmoredReader`](crate::ArmoredReader)), returning it and leaving the file's cursor directly
+    /// after the header (presumably at the beginning of ciphertext).
+    pub fn from_file(file: &mut impl Read) -> Result<Self> {
+        Self::from_file_with_limit(file, MAX_HEADER_LEN)
+    }
+
+    /// Like [`Header::from_file`], but refuses to read a header longer than `max_len` bytes, for
+    /// bounding how much memory a file from an untrusted source can make us allocate.
+    pub fn from_file_with_limit(file: &mut impl Read, max_len: u64) -> Result<Self> {
         // Read the length of the header, then read that many bytes
         let mut header_len_bytes = [0u8; 8];
         file.read_exact(&mut header_len_bytes)?;
         let header_len = u64::from_le_bytes(header_len_bytes);
+        // Anything else is almost certainly not a header at all (e.g. a detached body), and we
+        // don't want to try to allocate a random amount of memory for it
+        if header_len > MAX_HEADER_LEN {
+            bail!("header has an invalid length (is this a cyst file?)");
+        }
+        if header_len > max_len {
+            bail!("the header is {header_len} bytes long, which is more than the limit of {max_len} bytes");
+        }
         let mut header_bytes = vec![0u8; header_len as usize];
         file.read_exact(&mut header_bytes)?;
 
-        // Deserialise the header
-        let header: Self = bincode::deserialize(&header_bytes)?;
+        // Deserialise the header, then any extensions after it (anything after those is padding,
+        // which is all zeroes, so it can't be mistaken for extensions)
+        let mut header: Self = bincode::deserialize(&header_bytes)?;
+        let mut rest = &header_bytes[bincode::serialized_size(&header)? as usize..];
+        if rest.iter().any(|&byte| byte != 0) {
+            header.extensions = HeaderExtensions::from_bytes(&mut rest)?;
+        }
+        for (name, binding) in header.extensions.option_bindings.take().unwrap_or_default() {
+            let option_data = header
+                .options
+                .get_mut(&name)
+                .ok_or_else(|| anyhow!("binding for nonexistent option '{name}' (corrupted)"))?;
+            if binding.factor_salts.len() != option_data.factors.len() {
+                bail!("binding for option '{name}' has the wrong number of salts (corrupted)");
+            }
+            option_data.binding = Some(binding);
+        }
+        let padding = rest;
+        let real_len = header_len - padding.len() as u64;
+        if !padding.is_empty() {
+            // Nothing can be hidden in the padding, and the real header must be the one that says
+            // it's padded
+            if header.padded_len.is_none() || padding.iter().any(|&byte| byte != 0) {
+                bail!("header has unexpected data after it (corrupted)");
+            }
+        }
+        debug!(
+            "Read a {real_len}-byte header (padded to {header_len} bytes) with {} option(s)",
+            header.options.len()
+        );
 
         Ok(header)
     }
 }
 
-/// The data associated with an encryption option. From this, and the user's responses to factor
-/// prompts, a decryption key can be derived.
-#[derive(Serialize, Deserialize)]
-struct OptionData {
-    /// The randomly-generated salt used to derive the final key from all the factor keys.
-    salt: [u8; 32],
-    /// All the factors used in this option, and their respective data.
-    factors: Vec<(String, Vec<u8>)>,
-    /// The nonce used for encrypting the primary key.
-    primary_key_nonce: [u8; 12],
-    /// The primary key, encrypted with this option's key.
-    primary_key_ciphertext: Vec<u8>,
+/// Encryption options that have been set up once, so headers for any number of files can be
+/// created from them without prompting again (e.g. to encrypt a batch of files with the same
+/// passphrase).
+///
+/// Every header gets its own primary key, gate keys, and salts, so no two files share a key, but
+/// the factors themselves are only created once: every file's options are derived from the same
+/// factor keys, and anything a factor stores (like an uploaded keyfile) is shared between them. This
+/// holds on to those factor keys until it's dropped.
+pub struct HeaderTemplate {
+    /// The options that were set up, with their factor keys still waiting to be used.
+    options: HashMap<String, OptionData>,
 }
+impl HeaderTemplate {
+    /// Prompts the user to set up the encryption options they want, like [`Header::new`], but
+    /// without creating a header yet.
+    pub fn new(registry: &FactorRegistry, prompter: &mut dyn Prompter) -> Result<Self> {
+        let mut options = HashMap::new();
+        // This primary key is never used, since each header gets a fresh one
+        prompt_options(
+            &mut options,
+            &mut HashMap::new(),
+            StreamLocation {
+                offset: 0,
+                len: None,
+            },
+            registry,
+            prompter,
+        )?;
+        warn_unused_gates(&options, prompter);
 
-/// Prompts the user for a single factor, returning its name, data, and key.
-fn prompt_factor(registry: &FactorRegistry) -> Result<(&'static str, Vec<u8>, Vec<u8>)> {
-    // Prompt the user to select a factor
-    let mut factor_names = registry.keys().collect::<Vec<_>>();
-    factor_names.sort();
-    let factor_idx = Select::new()
-        .with_prompt("Choose an encryption factor to use")
-        .items(&factor_names)
-        .interact()
-        .unwrap();
-    let factor = &registry[factor_names[factor_idx]];
-    // Enter that factor's prompting process and get its data and a key
-    let (data, key) = factor.create()?;
-    Ok((factor.name(), data, key))
-}
+        Ok(Self { options })
+    }
 
-/// Prompts the user for a series of factors, encrypting the given primary key and returning the
-/// data needed to decrypt the resulting ciphertext, along with the user-provided name of the
-/// option.
-fn prompt_option(
-    primary_key: &[u8; 32],
-    registry: &FactorRegistry,
-) -> Result<(String, OptionData)> {
-    let name: String = Input::new()
-        .with_prompt("Enter a name for this encryption option")
-        .interact_text()
+    /// Creates a new header with these options, returning it and an encryptor ready to encrypt the
+    /// data chunk-by-chunk, like [`Header::new`]. The options' keys are derived on up to `threads`
+    /// threads at once.
+    pub fn header(
+        &self,
+        metadata: Option<FileMetadata>,
+        threads: usize,
+        prompter: &mut dyn Prompter,
+    ) -> Result<(Header, EncryptorBE32<ChaCha20Poly1305>)> {
+        let location = StreamLocation {
+            offset: 0,
+            len: None,
+        };
+        let primary_key = CystRng.gen::<[u8; 32]>();
+        let secret = encode_primary_key(&primary_key, location);
+        let mut names = self.options.keys().collect::<Vec<_>>();
+        // Keep the RNG's output in a fixed order (see `wrap_options`)
+        names.sort();
+        let gate_keys = names
+            .iter()
+            .filter(|name| self.options[**name].is_gate)
+            .map(|name| (name.as_str(), CystRng.gen::<[u8; 32]>()))
+            .collect::<HashMap<_, _>>();
+
+        let mut options = HashMap::new();
+        for name in names {
+            let template = &self.options[name];
+            // Every option in a template still has its factor keys
+            let pending = template.pending.as_ref().unwrap();
+            let option_secret = match gate_keys.get(name.as_str()) {
+                Some(gate_key) => gate_key.as_slice(),
+                None => secret.as_slice(),
+            };
+            let gated_behind = template
+                .gated_behind
+                .as_ref()
+                .map(|gate| (gate.clone(), gate_keys[gate.as_str()].as_slice()));
+            let mut option_data = OptionData::new(
+                option_secret,
+                template.description.clone(),
+                template.is_gate,
+                gated_behind,
+                template.factors.clone(),
+                &pending.keys,
+                template.quorum.as_ref().map(|quorum| quorum.threshold),
+            );
+            option_data.kdf = template.kdf;
+            options.insert(name.clone(), option_data);
+        }
+        wrap_options(&mut options, threads, prompter);
+
+        Ok((
+            Header {
+                options,
+                metadata,
+                decoy_offset: None,
+                escrow_key: Some(encode_primary_key(&primary_key, location)),
+                dedup: None,
+                body_len: None,
+                ecc: None,
+                padded_len: None,
+                public_note: None,
+                extensions: HeaderExtensions::default(),
+            },
+            Encryptor::from_stream_primitive(stream(&primary_key)),
+        ))
+    }
+}
+
+/// An encryption option laid out before any of its factors have been created, so all the options
+/// can be arranged at once before the user is asked to create them (see
+/// [`Prompter::plan_options`]). These are also made from an existing header, to show its options
+/// the same way.
+#[derive(Clone)]
+pub struct OptionPlan {
+    /// The name of the option.
+    pub(crate) name: String,
+    /// A reminder of what the option is for.
+    pub(crate) description: Option<String>,
+    /// Whether the option is a gate, which only unlocks the options behind it.
+    pub(crate) is_gate: bool,
+    /// The gate the option is behind, if any.
+    pub(crate) gated_behind: Option<String>,
+    /// The factors to create for the option, in order.
+    pub(crate) factors: Vec<FactorPlan>,
+    /// How many of the factors are needed, if not all of them.
+    pub(crate) quorum: Option<u8>,
+    /// The key derivation function for the option's key.
+    pub(crate) kdf: Kdf,
+}
+impl OptionPlan {
+    /// Creates a plan for an option with the given name and no factors yet, which decrypts the
+    /// file and has its key derived with the default KDF.
+    #[cfg(any(test, feature = "tui"))]
+    pub(crate) fn new(name: String) -> Self {
+        Self {
+            name,
+            description: None,
+            is_gate: false,
+            gated_behind: None,
+            factors: Vec::new(),
+            quorum: None,
+            kdf: Kdf::default(),
+        }
+    }
+}
+
+/// A factor to create for an option, in an [`OptionPlan`].
+#[derive(Clone)]
+pub(crate) struct FactorPlan {
+    /// The name of the factor.
+    pub(crate) name: String,
+    /// A hint to show before deriving the factor (e.g. where a keyfile is kept).
+    pub(crate) hint: Option<String>,
+}
+
+/// Creates the STREAM primitive for the ciphertext from the primary key.
+fn stream(primary_key: &[u8]) -> StreamBE32<ChaCha20Poly1305> {
+    let cipher = ChaCha20Poly1305::new(primary_key.into());
+    StreamBE32::from_aead(cipher, stream_nonce(primary_key).as_ref().into())
+}
+
+/// Derives the nonce for encrypting the file's contents from the primary key.
+///
+/// Rather than storing a random nonce, we derive it with HKDF. Each primary key is freshly
+/// generated for a single file and only ever used for one STREAM, so a nonce derived from it is
+/// unique to that stream. This means a faulty RNG can't produce a dangerous nonce collision on its
+/// own (only alongside a colliding primary key, which would be catastrophic anyway), and there's
+/// one less field in the header.
+///
+/// Don't ask me why this is size 7, it's got something to do with the encryptor parameters for
+/// the STREAM construction but it's horribly documented and I'm just going off failing
+/// assertions screaming 7 at me.
+fn stream_nonce(primary_key: &[u8]) -> [u8; 7] {
+    let mut nonce = [0u8; 7];
+    hkdf::Salt::new(hkdf::HKDF_SHA256, &[])
+        .extract(primary_key)
+        .expand(&[b"cyst stream nonce"], NonceLen)
+        .and_then(|okm| okm.fill(&mut nonce))
+        .unwrap();
+
+    nonce
+}
+
+/// The length of the STREAM nonce, for use with HKDF.
+struct NonceLen;
+impl hkdf::KeyType for NonceLen {
+    fn len(&self) -> usize {
+        7
+    }
+}
+
+/// The raw key material of a stream of ciphertext (see [`Header::unlock_raw_stream`]).
+///
+/// The stream is ChaCha20-Poly1305 in the STREAM construction: the plaintext is split into chunks
+/// of `chunk_len` bytes (the last one may be shorter, or even empty), and each is encrypted
+/// separately and followed by its 16-byte tag. The 12-byte nonce of each chunk is the
+/// `nonce_prefix`, then the chunk's index as a big-endian 32-bit integer, then a byte that's 1
+/// for the last chunk and 0 for every other. Every chunk uses `aad` as its associated data.
+pub struct RawStream {
+    /// The ChaCha20-Poly1305 key (the file's primary key).
+    pub key: Vec<u8>,
+    /// The first 7 bytes of every chunk's nonce.
+    pub nonce_prefix: [u8; 7],
+    /// The length of each chunk of plaintext.
+    pub chunk_len: u64,
+    /// The associated data of every chunk (see [`Header::authenticated_data`]).
+    pub aad: [u8; 32],
+    /// Where the stream is in the body.
+    pub location: StreamLocation,
+}
+
+/// Where a stream of ciphertext is in the body of an encrypted file (everything after the header).
+/// Usually there's just one stream, but a file with a decoy has two, one after the other.
+#[derive(Clone, Copy)]
+pub struct StreamLocation {
+    /// The offset of the stream from the start of the body.
+    pub offset: u64,
+    /// The length of the stream, or `None` if it runs to the end of the file.
+    pub len: Option<u64>,
+}
+impl StreamLocation {
+    /// Encodes this location as bytes, to be encrypted alongside a primary key. This is always the
+    /// same length, so a location can't be told apart from any other by the size of its
+    /// ciphertext.
+    fn to_bytes(self) -> [u8; 16] {
+        let mut bytes = [0u8; 16];
+        bytes[..8].copy_from_slice(&self.offset.to_le_bytes());
+        bytes[8..].copy_from_slice(&self.len.unwrap_or(u64::MAX).to_le_bytes());
+
+        bytes
+    }
+
+    /// Decodes a location from the bytes produced by [`StreamLocation::to_bytes`].
+    fn from_bytes(bytes: &[u8; 16]) -> Self {
+        let offset = u64::from_le_bytes(bytes[..8].try_into().unwrap());
+        let len = u64::from_le_bytes(bytes[8..].try_into().unwrap());
+
+        Self {
+            offset,
+            len: (len != u64::MAX).then_some(len),
+        }
+    }
+}
+
+/// Information about an encrypted file, stored in its header if the user asks for it.
+#[derive(Serialize, Deserialize)]
+pub struct FileMetadata {
+    /// The original name of the file (without any directories).
+    pub filename: Option<String>,
+    /// A hint as to the MIME type of the file.
+    pub mime: Option<String>,
+    /// A free-form comment provided by the user.
+    pub comment: Option<String>,
+    /// The size of the plaintext, in bytes.
+    pub size: u64,
+    /// A BLAKE2b-512 digest of the plaintext (see [`crate::plaintext_hash`]), if the user asked for
+    /// one. Anyone who can guess the plaintext can use this to confirm their guess!
+    pub hash: Option<Vec<u8>>,
+    /// The Unix permissions of the original file (like `0o644`), if the user asked for them to be
+    /// restored on decryption.
+    pub mode: Option<u32>,
+}
+
+/// The fields added to the header since the first version of the format. These are serialized
+/// separately, after the rest of the header, and only if any of them are set, so a header that
+/// doesn't use them is exactly what older versions wrote (and computes the same authenticated
+/// data), and older headers, which don't have them at all, can still be read.
+///
+/// New fields must only ever be added to the end, and must be `Option`s, since extensions written
+/// before a field was added just stop short of it (see [`HeaderExtensions::from_bytes`]).
+///
+/// The options' bindings are the exception: they belong with the options, so they're kept in each
+/// [`OptionData`] and only put here while the header is being written or read. Like the options,
+/// they're left out of the authenticated data, so options can still be changed without touching
+/// the ciphertext.
+#[derive(Default, Clone, PartialEq)]
+struct HeaderExtensions {
+    /// How the plaintext was padded to hide its length, if it was (see [`crate::padding`]).
+    plaintext_padding: Option<PaddingScheme>,
+    /// The suffix added to the plaintext's filename to name the encrypted file, if it was named
+    /// automatically (added in format version 3).
+    added_suffix: Option<String>,
+    /// The binding of each option that has one, by the option's name (added in format version 4).
+    option_bindings: Option<BTreeMap<String, OptionBinding>>,
+}
+impl HeaderExtensions {
+    /// Serializes these extensions to be appended to the rest of the header, which is nothing at
+    /// all if none of them are set.
+    fn to_bytes(&self) -> Vec<u8> {
+        if *self == Self::default() {
+            return Vec::new();
+        }
+        let mut bytes = bincode::serialize(&self.plaintext_padding).unwrap();
+        // Unset fields at the end are left off, so older versions can still read extensions that
+        // only use the fields they know about
+        if self.added_suffix.is_some() || self.option_bindings.is_some() {
+            bytes.extend_from_slice(&bincode::serialize(&self.added_suffix).unwrap());
+        }
+        if self.option_bindings.is_some() {
+            bytes.extend_from_slice(&bincode::serialize(&self.option_bindings).unwrap());
+        }
+
+        bytes
+    }
+
+    /// Deserializes extensions written by [`HeaderExtensions::to_bytes`], leaving `bytes` at
+    /// whatever comes after them (the header's padding, if any). Fields after the first are only
+    /// read if there's anything left, so extensions written by an older version, which end early,
+    /// can still be read. An unset field is a single zero byte, so reading one from the padding
+    /// instead is harmless.
+    fn from_bytes(bytes: &mut &[u8]) -> Result<Self> {
+        let plaintext_padding = Self::read_field(bytes)?;
+        let added_suffix = if bytes.is_empty() {
+            None
+        } else {
+            Self::read_field(bytes)?
+        };
+        let option_bindings = if bytes.is_empty() {
+            None
+        } else {
+            Self::read_field(bytes)?
+        };
+
+        Ok(Self {
+            plaintext_padding,
+            added_suffix,
+            option_bindings,
+        })
+    }
+
+    /// Deserializes a single field from the start of `bytes`, leaving it at whatever comes after.
+    /// This reads from the slice directly, rather than treating it as a reader, so a corrupted
+    /// length can't make us allocate any more than there is to read.
+    fn read_field<T: Serialize + DeserializeOwned>(bytes: &mut &[u8]) -> Result<T> {
+        let field = bincode::deserialize(bytes)?;
+        *bytes = &bytes[bincode::serialized_size(&field)? as usize..];
+
+        Ok(field)
+    }
+}
+
+/// What an option's key is bound to, beyond the option's own salt: the name the option was
+/// created with, and a salt for each of its factors. This makes the keys of two options unrelated
+/// even if they share factors with exactly the same keys. Options from before format version 4
+/// don't have one, and are given one the next time they're wrapped.
+///
+/// The name is stored rather than taken from the option, since an option can be renamed without
+/// being unlocked (see [`Header::merge_options`]).
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+struct OptionBinding {
+    /// The name of the option when its key was last derived.
+    name: String,
+    /// A random salt for each of the option's factors (in the same order as the factors).
+    factor_salts: Vec<[u8; 32]>,
+}
+
+/// A single factor in an option, as stored in the header.
+#[derive(Serialize, Deserialize, Clone)]
+struct FactorInstance {
+    /// The name of the factor.
+    name: String,
+    /// The data the factor produced when it was created, which it needs to derive its key.
+    data: Vec<u8>,
+    /// A hint provided by the user to show before deriving this factor (e.g. where a keyfile is
+    /// kept). This is stored in plain sight, so it must never contain anything secret.
+    hint: Option<String>,
+}
+
+/// The result of probing one of the factors in a header (see [`Header::probe_factors`]).
+pub struct FactorProbe<'a> {
+    /// The name of the option the factor is in.
+    pub option: &'a str,
+    /// The name of the factor.
+    pub factor: &'a str,
+    /// What the probe found: a description of what's available, `None` if the factor has nothing
+    /// to check, or why it can't be used right now.
+    pub result: Result<Option<String>>,
+    /// How long the probe took.
+    pub elapsed: Duration,
+}
+
+/// An estimate of how costly one of the options in a header would be to decrypt with (see
+/// [`Header::estimate_options`]).
+pub struct OptionEstimate<'a> {
+    /// The name of the option.
+    pub name: &'a str,
+    /// The name of the key derivation function the option's key is derived with.
+    pub kdf: &'static str,
+    /// How many gates have to be satisfied before the option.
+    pub gates: usize,
+    /// How many factors have to be satisfied, including those of the gates.
+    pub factors_needed: usize,
+    /// How long the key derivations of the option and its gates took on this machine.
+    pub kdf_time: Duration,
+    /// Roughly how much memory the most expensive of those derivations takes, in bytes.
+    pub memory: u64,
+    /// Whether any of the factors of the option or its gates needs network access.
+    pub requires_network: bool,
+    /// Whether every factor of the option and its gates is in this build, or why not.
+    pub usable: Result<()>,
+}
+
+/// A summary of one of the options in a header, for showing to the user.
+pub struct OptionSummary<'a> {
+    /// The name of the option.
+    pub name: &'a str,
+    /// The description the user gave the option, if they gave one.
+    pub description: Option<&'a str>,
+    /// Whether the option is a gate, which only unlocks the options gated behind it.
+    pub is_gate: bool,
+    /// The name of the gate the option is behind, if it's behind one.
+    pub gated_behind: Option<&'a str>,
+    /// The names of the factors the option requires.
+    pub factors: Vec<&'a str>,
+    /// How many of the factors are needed, if the option doesn't need all of them.
+    pub quorum: Option<u8>,
+    /// The name of the key derivation function the option's key is derived with.
+    pub kdf: &'static str,
+}
+impl Display for OptionSummary<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        if self.is_gate {
+            write!(f, " [gate]")?;
+        }
+        if let Some(description) = self.description {
+            write!(f, " \u{2014} {description}")?;
+        }
+        match self.quorum {
+            Some(quorum) => write!(f, " (factors: any {quorum} of {}", self.factors.join(", "))?,
+            None => write!(f, " (factors: {}", self.factors.join(" + "))?,
+        }
+        if let Some(gate) = self.gated_behind {
+            write!(f, ", behind gate '{gate}'")?;
+        }
+        // Argon2id is the default, so it's not worth mentioning
+        if self.kdf != "Argon2id" {
+            write!(f, ", derived with {}", self.kdf)?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// The data associated with an encryption option. From this, and the user's responses to factor
+/// prompts, a decryption key can be derived.
+///
+/// Options can also be *gates*: rather than decrypting the file, a gate decrypts a random gate key,
+/// which is an extra input to the key of every option gated behind it. This makes it possible to
+/// require two separate options in sequence (e.g. a corporate key and then a personal passphrase),
+/// where each can have its own factors and be changed independently. Options are only ever gated
+/// behind gates that already exist when they're created, so there can't be any loops.
+///
+/// An option can also need only a *quorum* of its factors (e.g. any 3 of 5). Then, rather than
+/// combining every factor's key, a random quorum key is split with Shamir secret sharing, and each
+/// factor's share is encrypted with that factor's key. Any quorum of factors can recover their
+/// shares, and so the quorum key, which takes the place of the factor keys in the option's key.
+#[derive(Serialize, Deserialize)]
+struct OptionData {
+    /// The randomly-generated salt used to derive the final key from all the factor keys.
+    salt: [u8; 32],
+    /// The key derivation function used to derive the final key from all the factor keys.
+    kdf: Kdf,
+    /// A description of the option provided by the user, to remind them what it's for.
+    description: Option<String>,
+    /// Whether this option is a gate, which decrypts a gate key rather than the primary key.
+    is_gate: bool,
+    /// The name of the gate this option is behind, if any. That gate must be satisfied first, and
+    /// its gate key is needed to derive this option's key.
+    gated_behind: Option<String>,
+    /// All the factors used in this option, and their respective data.
+    factors: Vec<FactorInstance>,
+    /// If only some of the factors are needed, how many, and each factor's share of the quorum key.
+    quorum: Option<Quorum>,
+    /// The nonce used for encrypting the primary key.
+    primary_key_nonce: [u8; 12],
+    /// The primary key and the location of the stream it decrypts (or, for a gate, the gate key),
+    /// encrypted with this option's key.
+    primary_key_ciphertext: Vec<u8>,
+    /// The links from what this option decrypts to the primary key it unlocks, if it was merged
+    /// in from other files (see [`Header::merge_options`]). Each one is followed in turn.
+    links: Vec<OptionLink>,
+    /// What this option's key is bound to, if it was created since format version 4. This is
+    /// stored in the header's extensions, not here (see [`HeaderExtensions`]).
+    #[serde(skip)]
+    binding: Option<OptionBinding>,
+    /// What's needed to encrypt the secret, if this option has just been created and its secret
+    /// hasn't been encrypted yet (see [`wrap_options`]). This is deliberately never stored.
+    #[serde(skip)]
+    pending: Option<PendingWrap>,
+}
+impl OptionData {
+    /// Creates a new option with the given factors (and their data), which will encrypt the given
+    /// secret (the primary key and its stream's location, or a gate key) under the keys those
+    /// factors produced, and the gate key of the gate it's behind. If a quorum is given, only that
+    /// many of the factors will be needed (this must be between 1 and the number of factors). The
+    /// option's key will be derived with Argon2id, unless `kdf` is changed before wrapping.
+    ///
+    /// The secret isn't actually encrypted until [`wrap_options`] is called, which lets the
+    /// expensive key derivations for several options run in parallel.
+    fn new(
+        secret: &[u8],
+        description: Option<String>,
+        is_gate: bool,
+        gated_behind: Option<(String, &[u8])>,
+        factors: Vec<FactorInstance>,
+        keys: &[Vec<u8>],
+        quorum: Option<u8>,
+    ) -> Self {
+        let (gated_behind, gate_key) = gated_behind.unzip();
+        Self {
+            salt: [0u8; 32],
+            kdf: Kdf::default(),
+            description,
+            is_gate,
+            gated_behind,
+            factors,
+            quorum: quorum.map(|threshold| Quorum {
+                threshold,
+                shares: Vec::new(),
+            }),
+            primary_key_nonce: [0u8; 12],
+            primary_key_ciphertext: Vec::new(),
+            links: Vec::new(),
+            binding: None,
+            pending: Some(PendingWrap {
+                secret: secret.to_vec(),
+                keys: keys.to_vec(),
+                gate_key: gate_key.map(<[u8]>::to_vec),
+            }),
+        }
+    }
+
+    /// Encrypts the given secret under the given factor keys (and gate key, if this option is
+    /// behind a gate), using a fresh salt and nonce, and binding the key to the given name of the
+    /// option and fresh salts for each factor. If the option needs a quorum of its factors, this
+    /// also splits a fresh quorum key between them.
+    fn wrap_secret(
+        &mut self,
+        name: &str,
+        secret: &[u8],
+        keys: &[Vec<u8>],
+        gate_key: Option<&[u8]>,
+    ) {
+        // Derive a proper symmetric key using random salts
+        self.salt = CystRng.gen::<[u8; 32]>();
+        self.binding = Some(OptionBinding {
+            name: name.to_string(),
+            factor_salts: self
+                .factors
+                .iter()
+                .map(|_| CystRng.gen::<[u8; 32]>())
+                .collect(),
+        });
+        let key = match self.quorum.as_ref().map(|quorum| quorum.threshold) {
+            Some(threshold) => {
+                let quorum_key = CystRng.gen::<[u8; 32]>();
+                // The quorum was checked when the option was created
+                let shares = create_keyshares(&quorum_key, keys.len() as u8, threshold).unwrap();
+                let shares = shares
+                    .iter()
+                    .zip(keys)
+                    .enumerate()
+                    .map(|(idx, (share, key))| self.seal_share(idx, key, share))
+                    .collect();
+                self.quorum = Some(Quorum { threshold, shares });
+                self.combine_keys(
+                    [("cyst quorum".to_string(), None, quorum_key.as_slice())],
+                    gate_key,
+                )
+            }
+            None => self.combine_keys(self.labelled_keys(keys.iter().map(Vec::as_slice)), gate_key),
+        }
+        // The KDF's parameters were checked when the option was created (or when it was unlocked,
+        // if it's being rewrapped)
+        .unwrap();
+
+        // Encrypt the secret with that
+        let cipher = ChaCha20Poly1305::new(key.as_ref().into());
+        let nonce = ChaCha20Poly1305::generate_nonce(CystRng);
+        self.primary_key_ciphertext = cipher.encrypt(&nonce, secret).unwrap();
+        self.primary_key_nonce = nonce.into();
+    }
+
+    /// Prompts the user for each factor in this option, returning the keys they produce (in the
+    /// same order as the factors).
+    ///
+    /// If the option only needs a quorum of its factors, this stops once enough have been
+    /// satisfied, and the user can skip any factors they don't have (as long as there are enough
+    /// others left). A factor that fails to derive, or whose key doesn't decrypt its share, is
+    /// reported and skipped too, rather than failing the whole option. Only if there aren't enough
+    /// factors left to make up the quorum does this fail. Factors that weren't used are `None`.
+    fn derive_keys(
+        &self,
+        registry: &FactorRegistry,
+        prompter: &mut dyn Prompter,
+    ) -> Result<Vec<Option<Vec<u8>>>> {
+        // Make sure we have every factor before prompting for any of them
+        let mut factors = self
+            .factors
+            .iter()
+            .enumerate()
+            .map(|(idx, instance)| {
+                let factor = registry
+                    .get(instance.name.as_str())
+                    .ok_or_else(|| unavailable_factor(&instance.name))?;
+                Ok((idx, instance, factor))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        // Derive factors that need the network last, so failures elsewhere don't waste a fetch
+        factors.sort_by_key(|(_, _, factor)| factor.requires_network());
+        if factors
+            .iter()
+            .any(|(_, _, factor)| factor.requires_network())
+        {
+            prompter.detail("Factors that need network access will be derived last.");
+        }
+
+        // Prompt the user for each factor in the option
+        let mut keys = vec![None; factors.len()];
+        let Some(quorum) = &self.quorum else {
+            for (idx, instance, factor) in factors {
+                keys[idx] = Some(derive_factor(instance, factor.as_ref(), prompter)?);
+            }
+            return Ok(keys);
+        };
+
+        let threshold = quorum.threshold as usize;
+        let total = factors.len();
+        let mut satisfied = 0;
+        for (pos, (idx, instance, factor)) in factors.into_iter().enumerate() {
+            let needed = threshold - satisfied;
+            let left = total - pos;
+            if needed == 0 || left < needed {
+                break;
+            }
+            if left > needed
+                && !prompter.confirm(&format!(
+                    "Use factor '{}'? ({needed} more needed, from {left} left)",
+                    instance.name
+                ))?
+            {
+                continue;
+            }
+
+            match derive_factor(instance, factor.as_ref(), prompter)
+                .and_then(|key| self.open_share(idx, &key).map(|_| key))
+            {
+                Ok(key) => {
+                    keys[idx] = Some(key);
+                    satisfied += 1;
+                }
+                // The user wants to stop entirely, not just skip this factor
+                Err(err) if err.is::<Cancelled>() => return Err(err),
+                Err(err) => prompter.notice(&format!(
+                    "Factor '{}' failed, so it won't count towards the quorum: {err:#}",
+                    instance.name
+                )),
+            }
+        }
+        if satisfied < threshold {
+            return Err(authentication_failed(format!(
+                "only {satisfied} of the {threshold} factors needed could be satisfied"
+            )));
+        }
+
+        Ok(keys)
+    }
+
+    /// Decrypts this option's secret (the primary key and its stream's location, or a gate key)
+    /// using the keys derived from each of its factors, and the gate key of the gate it's behind.
+    fn unwrap_secret(&self, keys: &[Option<Vec<u8>>], gate_key: Option<&[u8]>) -> Result<Vec<u8>> {
+        let key = self.key(keys, gate_key)?;
+        let cipher = ChaCha20Poly1305::new(key.as_ref().into());
+        let secret = cipher
+            .decrypt(
+                &self.primary_key_nonce.into(),
+                self.primary_key_ciphertext.as_ref(),
+            )
+            .map_err(|_| {
+                debug!("The option's key didn't decrypt its secret");
+                authentication_failed("decryption failed")
+            })?;
+        let expected_len = if self.is_gate { 32 } else { 32 + 16 };
+        if secret.len() != expected_len {
+            bail!("decrypted key had incorrect length (corrupted)");
+        }
+
+        Ok(secret)
+    }
+
+    /// Follows this option's links (if it has any) from the secret it decrypted to the primary key
+    /// of the file it's now in (and its stream's location).
+    fn follow_links(&self, mut secret: Vec<u8>) -> Result<Vec<u8>> {
+        for link in &self.links {
+            secret = link.open(&secret[..32])?;
+        }
+
+        Ok(secret)
+    }
+
+    /// Works out the key for this option from the keys produced by each of its factors (in order,
+    /// with `None` for any the user didn't satisfy, which is only allowed if the option needs just
+    /// a quorum of them), and the gate key of the gate it's behind (if any). This must be exactly
+    /// the same for encryption and decryption!
+    fn key(&self, keys: &[Option<Vec<u8>>], gate_key: Option<&[u8]>) -> Result<[u8; 32]> {
+        match &self.quorum {
+            Some(quorum) => {
+                let quorum_key = self.quorum_key(quorum, keys)?;
+                self.combine_keys(
+                    [("cyst quorum".to_string(), None, quorum_key.as_slice())],
+                    gate_key,
+                )
+            }
+            None => {
+                let keys = keys
+                    .iter()
+                    .map(|key| key.as_deref())
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or(anyhow!("every factor of this option must be satisfied"))?;
+                self.combine_keys(self.labelled_keys(keys.into_iter()), gate_key)
+            }
+        }
+    }
+
+    /// Gets roughly how much memory deriving this option's key takes, in bytes: the most that
+    /// either its own KDF or any one of its factors needs (they're derived one at a time). Factors
+    /// that aren't in the registry can't be derived, so they don't count.
+    fn memory_needed(&self, registry: &FactorRegistry) -> u64 {
+        self.factors
+            .iter()
+            .filter_map(|instance| {
+                let factor = registry.get(instance.name.as_str())?;
+                Some(factor.memory_needed(&instance.data))
+            })
+            .fold(self.kdf.memory_needed(), u64::max)
+    }
+
+    /// Labels the keys produced by each factor in this option (in order) with the factor's name
+    /// and index, for [`OptionData::combine_keys`].
+    fn labelled_keys<'a>(
+        &self,
+        keys: impl Iterator<Item = &'a [u8]>,
+    ) -> Vec<(String, Option<usize>, &'a [u8])> {
+        self.factors
+            .iter()
+            .zip(keys)
+            .enumerate()
+            .map(|(idx, (instance, key))| {
+                (format!("cyst factor: {}", instance.name), Some(idx), key)
+            })
+            .collect()
+    }
+
+    /// Combines the given labelled keys (each factor's key, with its index, or the quorum key), and
+    /// the gate key of the gate this option is behind (if any), into a single key for the option.
+    ///
+    /// Each key is first run through HKDF with its label (see [`OptionData::expand_key`]), so every
+    /// key contributes a fixed-length, domain-separated key, no matter how long or short it was.
+    /// The gate key goes through HKDF in the same way. These are then concatenated and run through
+    /// the option's KDF (Argon2id, unless the user chose otherwise) with the option's salt.
+    fn combine_keys<'a>(
+        &self,
+        labelled_keys: impl IntoIterator<Item = (String, Option<usize>, &'a [u8])>,
+        gate_key: Option<&'a [u8]>,
+    ) -> Result<[u8; 32]> {
+        let labelled_keys = labelled_keys
+            .into_iter()
+            .chain(gate_key.map(|gate_key| ("cyst gate".to_string(), None, gate_key)));
+        let mut total_key = Vec::new();
+        for (label, factor_idx, key) in labelled_keys {
+            total_key.extend(self.expand_key(&label, factor_idx, key));
+        }
+
+        let start = Instant::now();
+        let key = self.kdf.derive(&total_key, &self.salt)?;
+        debug!(
+            "{} derivation of an option's key from {} input key(s) took {:?}",
+            self.kdf.name(),
+            total_key.len() / 32,
+            start.elapsed()
+        );
+
+        Ok(key)
+    }
+
+    /// Recovers this option's quorum key from the shares of the factors the user satisfied.
+    fn quorum_key(&self, quorum: &Quorum, keys: &[Option<Vec<u8>>]) -> Result<Vec<u8>> {
+        if quorum.threshold == 0
+            || quorum.threshold as usize > self.factors.len()
+            || quorum.shares.len() != self.factors.len()
+        {
+            bail!("option has an invalid quorum (corrupted)");
+        }
+        let shares = keys
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, key)| Some(self.open_share(idx, key.as_ref()?)))
+            .take(quorum.threshold as usize)
+            .collect::<Result<Vec<_>>>()?;
+        if shares.len() < quorum.threshold as usize {
+            return Err(authentication_failed(format!(
+                "only {} of the {} factors needed were satisfied",
+                shares.len(),
+                quorum.threshold
+            )));
+        }
+
+        combine_keyshares(&shares).map_err(|_| anyhow!("invalid quorum share (corrupted)"))
+    }
+
+    /// Runs the given key through HKDF with the given label, giving a fixed-length key that's only
+    /// used for that purpose. If the key came from one of this option's factors, its index should
+    /// be given too.
+    ///
+    /// If the option has a binding, the key is extracted with the factor's own salt (or the
+    /// option's salt, if it isn't a factor's), and the option's bound name is added to the label,
+    /// so the same key gives unrelated results in every option and for every factor. Otherwise,
+    /// the option's salt and the label alone are used, as before format version 4.
+    fn expand_key(&self, label: &str, factor_idx: Option<usize>, key: &[u8]) -> [u8; 32] {
+        let (salt, info): (&[u8], Vec<&[u8]>) = match &self.binding {
+            Some(binding) => (
+                // The number of salts was checked when the binding was read
+                factor_idx.map_or(&self.salt, |idx| &binding.factor_salts[idx]),
+                vec![
+                    label.as_bytes(),
+                    b"\0cyst option: ",
+                    binding.name.as_bytes(),
+                ],
+            ),
+            None => (&self.salt, vec![label.as_bytes()]),
+        };
+        let mut derived = [0u8; 32];
+        hkdf::Salt::new(hkdf::HKDF_SHA256, salt)
+            .extract(key)
+            .expand(&info, hkdf::HKDF_SHA256)
+            .and_then(|okm| okm.fill(&mut derived))
+            // This can only fail if the output is too long, and 32 bytes is fine for SHA-256
+            .unwrap();
+
+        derived
+    }
+
+    /// Creates the cipher for the quorum share of the factor at the given index, from the key that
+    /// factor produced.
+    fn share_cipher(&self, idx: usize, factor_key: &[u8]) -> ChaCha20Poly1305 {
+        let label = format!("cyst quorum share: {}", self.factors[idx].name);
+        let key = self.expand_key(&label, Some(idx), factor_key);
+
+        ChaCha20Poly1305::new(&key.into())
+    }
+
+    /// Encrypts the given quorum share for the factor at the given index, with the key that factor
+    /// produced. The share is bound to its index, so shares can't be swapped between factors.
+    fn seal_share(&self, idx: usize, factor_key: &[u8], share: &[u8]) -> EncryptedShare {
+        let nonce = ChaCha20Poly1305::generate_nonce(CystRng);
+        let ciphertext = self
+            .share_cipher(idx, factor_key)
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: share,
+                    aad: &(idx as u32).to_le_bytes(),
+                },
+            )
+            .unwrap();
+
+        EncryptedShare {
+            nonce: nonce.into(),
+            ciphertext,
+        }
+    }
+
+    /// Decrypts the quorum share of the factor at the given index, with the key that factor
+    /// produced.
+    fn open_share(&self, idx: usize, factor_key: &[u8]) -> Result<Vec<u8>> {
+        let share = self
+            .quorum
+            .as_ref()
+            .and_then(|quorum| quorum.shares.get(idx))
+            .ok_or(anyhow!("factor has no quorum share (corrupted)"))?;
+        self.share_cipher(idx, factor_key)
+            .decrypt(
+                &share.nonce.into(),
+                Payload {
+                    msg: &share.ciphertext,
+                    aad: &(idx as u32).to_le_bytes(),
+                },
+            )
+            .map_err(|_| authentication_failed("decryption of the factor's quorum share failed"))
+    }
+}
+
+/// The secret a newly created option will encrypt, and the keys it will encrypt it under.
+struct PendingWrap {
+    secret: Vec<u8>,
+    keys: Vec<Vec<u8>>,
+    gate_key: Option<Vec<u8>>,
+}
+
+/// Encrypts the secrets of all the newly created options in the given map (see
+/// [`OptionData::new`]). Each option's key takes an expensive derivation, but the options
+/// are independent of one another, so they're split between up to the given number of threads.
+///
+/// If the RNG has been replaced (see [`rng::with_rng`]), everything is done on this thread, in
+/// order of the options' names, so the same RNG always produces the same options.
+fn wrap_options(
+    options: &mut HashMap<String, OptionData>,
+    threads: usize,
+    prompter: &mut dyn Prompter,
+) {
+    let mut jobs = options
+        .iter_mut()
+        .filter_map(|(name, option_data)| {
+            let pending = option_data.pending.take()?;
+            Some((name, option_data, pending))
+        })
+        .collect::<Vec<_>>();
+    if rng::is_replaced() {
+        jobs.sort_by_key(|(name, _, _)| *name);
+        for (name, option_data, pending) in jobs {
+            option_data.wrap_secret(
+                name,
+                &pending.secret,
+                &pending.keys,
+                pending.gate_key.as_deref(),
+            );
+        }
+        return;
+    }
+    let chunk_size = jobs.len().div_ceil(threads.max(1)).max(1);
+    prompter.detail(&format!(
+        "Deriving the keys of {} option(s) on {} thread(s).",
+        jobs.len(),
+        jobs.len().div_ceil(chunk_size)
+    ));
+
+    std::thread::scope(|scope| {
+        for chunk in jobs.chunks_mut(chunk_size) {
+            scope.spawn(move || {
+                for (name, option_data, pending) in chunk {
+                    option_data.wrap_secret(
+                        name,
+                        &pending.secret,
+                        &pending.keys,
+                        pending.gate_key.as_deref(),
+                    );
+                }
+            });
+        }
+    });
+}
+
+/// How many of an option's factors are needed, if it doesn't need all of them.
+#[derive(Serialize, Deserialize)]
+struct Quorum {
+    /// The number of factors needed.
+    threshold: u8,
+    /// Each factor's share of the quorum key (in the same order as the factors).
+    shares: Vec<EncryptedShare>,
+}
+
+/// A factor's share of a quorum key, encrypted with a key derived from the key that factor
+/// produces.
+#[derive(Serialize, Deserialize)]
+struct EncryptedShare {
+    nonce: [u8; 12],
+    ciphertext: Vec<u8>,
+}
+
+/// The escrow key of one file, encrypted under a key derived from the primary key of another,
+/// which lets the options of the second decrypt the first (see [`Header::merge_options`]).
+#[derive(Serialize, Deserialize, Clone)]
+struct OptionLink {
+    nonce: [u8; 12],
+    ciphertext: Vec<u8>,
+}
+impl OptionLink {
+    /// Encrypts the given escrow key under the given primary key.
+    fn seal(primary_key: &[u8], escrow_key: &[u8]) -> Self {
+        let nonce = ChaCha20Poly1305::generate_nonce(CystRng);
+        let ciphertext = link_cipher(primary_key)
+            .encrypt(&nonce, escrow_key)
+            .unwrap();
+
+        Self {
+            nonce: nonce.into(),
+            ciphertext,
+        }
+    }
+
+    /// Decrypts the escrow key in this link with the given primary key.
+    fn open(&self, primary_key: &[u8]) -> Result<Vec<u8>> {
+        let escrow_key = link_cipher(primary_key)
+            .decrypt(&self.nonce.into(), self.ciphertext.as_ref())
+            .map_err(|_| authentication_failed("decryption of the merged option's link failed"))?;
+        if escrow_key.len() != 32 + 16 {
+            bail!("linked primary key had incorrect length (corrupted)");
+        }
+
+        Ok(escrow_key)
+    }
+}
+
+/// Creates the cipher for an [`OptionLink`] from the primary key it's encrypted under. This is
+/// derived with HKDF, so the primary key itself is never used for anything but its stream.
+fn link_cipher(primary_key: &[u8]) -> ChaCha20Poly1305 {
+    let mut key = [0u8; 32];
+    hkdf::Salt::new(hkdf::HKDF_SHA256, &[])
+        .extract(primary_key)
+        .expand(&[b"cyst option link"], hkdf::HKDF_SHA256)
+        .and_then(|okm| okm.fill(&mut key))
         .unwrap();
 
+    ChaCha20Poly1305::new(&key.into())
+}
+
+/// Prompts the user to derive the given instance of a factor, returning the key it produces.
+fn derive_factor(
+    instance: &FactorInstance,
+    factor: &dyn BoxedFactor,
+    prompter: &mut dyn Prompter,
+) -> Result<Vec<u8>> {
+    prompter.info(&format!(
+        "Please follow the prompts for factor '{}':",
+        instance.name
+    ));
+    if let Some(hint) = &instance.hint {
+        prompter.notice(&format!("Hint: {hint}"));
+    }
+    // Hand over to the factor's prompting process to derive its key
+    debug!(
+        "Deriving factor '{}' from {} byte(s) of data",
+        instance.name,
+        instance.data.len()
+    );
+    let start = Instant::now();
+    let key = factor.derive(&instance.data, prompter);
+    match &key {
+        // Not even the key's length is logged, since for some factors it's the secret's length
+        Ok(_) => debug!(
+            "Factor '{}' produced a key in {:?}",
+            instance.name,
+            start.elapsed()
+        ),
+        Err(err) => debug!(
+            "Factor '{}' failed after {:?}: {err:#}",
+            instance.name,
+            start.elapsed()
+        ),
+    }
+
+    key
+}
+
+/// Encodes the primary key and its stream's location as the secret for an option to encrypt.
+fn encode_primary_key(primary_key: &[u8], location: StreamLocation) -> Vec<u8> {
+    let mut secret = primary_key.to_vec();
+    secret.extend_from_slice(&location.to_bytes());
+
+    secret
+}
+
+/// Decodes the primary key and its stream's location from the secret an option decrypted.
+fn decode_primary_key(mut secret: Vec<u8>) -> Result<(Vec<u8>, StreamLocation)> {
+    if secret.len() != 32 + 16 {
+        bail!("decrypted primary key had incorrect length (corrupted)");
+    }
+    let location = StreamLocation::from_bytes(&secret.split_off(32).try_into().unwrap());
+
+    Ok((secret, location))
+}
+
+/// Decodes an escrow key given by the user, which is just the secret every option encrypts.
+fn decode_escrow_key(escrow_key: &[u8]) -> Result<(Vec<u8>, StreamLocation)> {
+    if escrow_key.len() != 32 + 16 {
+        bail!(
+            "escrow key has the wrong length (it should be {} bytes)",
+            32 + 16
+        );
+    }
+
+    decode_primary_key(escrow_key.to_vec())
+}
+
+/// The error for when a header uses a factor that isn't in the registry. That's either because
+/// the factor wasn't compiled into this build, or because there's no such factor at all, in which
+/// case the header is most likely corrupt.
+fn unavailable_factor(name: &str) -> anyhow::Error {
+    match factor_feature(name) {
+        // The SSH agent factor can't be enabled at all on other platforms
+        Some("ssh-agent") if cfg!(not(unix)) => {
+            anyhow!("factor '{name}' is only available on Unix platforms")
+        }
+        Some(feature) => anyhow!(
+            "factor '{name}' is not available in this build (rebuild cyst with the '{feature}' \
+             feature to use it)"
+        ),
+        None => anyhow!(
+            "unknown factor '{name}' (the header may be corrupted, or from a newer version of cyst)"
+        ),
+    }
+}
+
+/// Warns the user about any gates in the given options that have nothing gated behind them, since
+/// they can't be used for anything.
+fn warn_unused_gates(options: &HashMap<String, OptionData>, prompter: &mut dyn Prompter) {
+    let mut unused = options
+        .iter()
+        .filter(|(name, option_data)| {
+            option_data.is_gate
+                && !options
+                    .values()
+                    .any(|other| other.gated_behind.as_ref() == Some(name))
+        })
+        .map(|(name, _)| name.as_str())
+        .collect::<Vec<_>>();
+    unused.sort();
+    for name in unused {
+        prompter.notice(&format!(
+            "Warning: nothing is gated behind the gate '{name}', so it can't be used."
+        ));
+    }
+}
+
+/// Prompts the user for a series of options that all decrypt a stream at the given location,
+/// adding them to the given map (along with the keys of any gates they create to `gate_keys`).
+/// This generates and returns the primary key for that stream.
+///
+/// If the prompter can lay out all the options at once (see [`Prompter::plan_options`]), they're
+/// created from that plan. Otherwise they're asked about one at a time.
+fn prompt_options(
+    options: &mut HashMap<String, OptionData>,
+    gate_keys: &mut HashMap<String, [u8; 32]>,
+    location: StreamLocation,
+    registry: &FactorRegistry,
+    prompter: &mut dyn Prompter,
+) -> Result<[u8; 32]> {
+    // Generate the primary key (used to actually encrypt the data)
+    let primary_key = CystRng.gen::<[u8; 32]>();
+    let secret = encode_primary_key(&primary_key, location);
+
+    let mut existing = options.keys().map(String::as_str).collect::<Vec<_>>();
+    existing.sort();
+    let mut gates = gate_keys.keys().map(String::as_str).collect::<Vec<_>>();
+    gates.sort();
+    if let Some(plans) = prompter.plan_options(registry, &existing, &gates)? {
+        check_plans(&plans, &existing, &gates)?;
+        for plan in plans {
+            let (option_data, gate_key) =
+                create_option(&plan, &secret, gate_keys, registry, prompter)?;
+            if let Some(gate_key) = gate_key {
+                gate_keys.insert(plan.name.clone(), gate_key);
+            }
+            options.insert(plan.name, option_data);
+        }
+
+        return Ok(primary_key);
+    }
+
+    // Prompt the user for a series of options
     let mut is_first = true;
-    let mut factors = Vec::new();
-    let mut total_key = Vec::new();
+    let mut decryptable = false;
     loop {
-        // Always prompt for a first factor, and otherwise confirm with the user first
-        if is_first
-            || Confirm::new()
-                .with_prompt("Add another factor?")
-                .interact()
-                .unwrap()
-        {
+        // Always prompt for a first option, and otherwise confirm with the user first (unless
+        // we've only got gates so far, which would leave no way to decrypt the stream)
+        if !is_first && !decryptable {
+            prompter.info(
+                "So far there are only gates, so please add an option that decrypts the file.",
+            );
+        }
+        if is_first || !decryptable || prompter.confirm("Add another encryption option?")? {
             is_first = false;
-            let (name, data, key) = prompt_factor(registry)?;
-            // Save the factor's details and extend the all-factors key
-            factors.push((name.to_string(), data));
-            total_key.extend(key);
+            let (name, option_data, gate_key) =
+                prompt_option(&secret, options, gate_keys, registry, prompter)?;
+            match gate_key {
+                Some(gate_key) => {
+                    gate_keys.insert(name.clone(), gate_key);
+                }
+                None => decryptable = true,
+            }
+            options.insert(name, option_data);
         } else {
             break;
         }
     }
 
-    // Derive a proper symmetric key using a random salt
-    let salt = OsRng.gen::<[u8; 32]>();
-    let mut key = [0u8; 32];
-    Argon2::default()
-        .hash_password_into(&total_key, &salt, &mut key)
-        .unwrap();
+    Ok(primary_key)
+}
+
+/// Makes sure the given plans can all be created alongside the options (and gates) that already
+/// exist: every name must be new, at least one option must decrypt the stream, and each option
+/// can only be gated behind a gate that already exists or comes before it (so gates can never
+/// form a loop).
+pub(crate) fn check_plans(plans: &[OptionPlan], existing: &[&str], gates: &[&str]) -> Result<()> {
+    let mut gates = gates.to_vec();
+    for (idx, plan) in plans.iter().enumerate() {
+        if plan.name.is_empty() {
+            bail!("every option needs a name");
+        }
+        if existing.contains(&plan.name.as_str())
+            || plans[..idx].iter().any(|other| other.name == plan.name)
+        {
+            bail!("there's already an option named '{}'", plan.name);
+        }
+        if let Some(gate) = &plan.gated_behind {
+            if !gates.contains(&gate.as_str()) {
+                bail!(
+                    "option '{}' is behind the gate '{gate}', which has to come before it",
+                    plan.name
+                );
+            }
+        }
+        if plan.factors.is_empty() {
+            bail!("option '{}' has no factors", plan.name);
+        }
+        if let Some(quorum) = plan.quorum {
+            if quorum == 0 || quorum as usize > plan.factors.len() {
+                bail!(
+                    "option '{}' can't require {quorum} of its {} factor(s)",
+                    plan.name,
+                    plan.factors.len()
+                );
+            }
+        }
+        if plan.is_gate {
+            gates.push(&plan.name);
+        }
+    }
+    if plans.iter().all(|plan| plan.is_gate) {
+        bail!("there are only gates, so nothing would decrypt the file");
+    }
 
-    // Encrypt the primary key with that
-    let cipher = ChaCha20Poly1305::new(key.as_ref().into());
-    let nonce = ChaCha20Poly1305::generate_nonce(OsRng);
-    let primary_key_ciphertext = cipher.encrypt(&nonce, primary_key.as_ref()).unwrap();
+    Ok(())
+}
+
+/// Creates the option laid out in the given plan, prompting the user to create each of its factors
+/// in turn, and returns it along with its gate key (if it's a gate), like [`prompt_option`]. The
+/// plan must already have been checked with [`check_plans`].
+fn create_option(
+    plan: &OptionPlan,
+    secret: &[u8],
+    gate_keys: &HashMap<String, [u8; 32]>,
+    registry: &FactorRegistry,
+    prompter: &mut dyn Prompter,
+) -> Result<(OptionData, Option<[u8; 32]>)> {
+    prompter.info(&format!("Creating the option '{}'.", plan.name));
+    let (factors, keys) = loop {
+        let mut factors = Vec::new();
+        let mut keys = Vec::new();
+        let mut entropies = Vec::new();
+        for factor_plan in &plan.factors {
+            let factor = registry
+                .get(factor_plan.name.as_str())
+                .ok_or_else(|| unavailable_factor(&factor_plan.name))?;
+            prompter.info(&format!(
+                "Please follow the prompts for factor '{}':",
+                factor_plan.name
+            ));
+            // A factor that fails shouldn't waste the ones already done (which might have
+            // uploaded something, or shown the user secrets they've since stored)
+            let (data, key, entropy) = loop {
+                match create_factor(factor.as_ref(), prompter) {
+                    Ok(created) => break created,
+                    Err(err) if err.is::<Cancelled>() => return Err(err),
+                    Err(err) => {
+                        prompter.notice(&format!("Couldn't create that factor: {err:#}."));
+                        if !prompter.confirm("Try creating it again?")? {
+                            return Err(err);
+                        }
+                    }
+                }
+            };
+            factors.push(FactorInstance {
+                name: factor_plan.name.clone(),
+                data,
+                hint: factor_plan.hint.clone(),
+            });
+            keys.push(key);
+            entropies.push(entropy);
+        }
+
+        if confirm_strength(
+            &entropies,
+            plan.quorum,
+            plan.is_gate || plan.gated_behind.is_some(),
+            prompter,
+        )? {
+            break (factors, keys);
+        }
+        prompter.info("Create this option's factors again.");
+    };
+
+    Ok(build_option(plan, factors, &keys, secret, gate_keys))
+}
+
+/// Builds an option from its plan and the factors that were created for it, encrypting the given
+/// secret (or a fresh gate key, which is returned too, if it's a gate). Its key is only derived
+/// once [`wrap_options`] is run.
+fn build_option(
+    plan: &OptionPlan,
+    factors: Vec<FactorInstance>,
+    keys: &[Vec<u8>],
+    secret: &[u8],
+    gate_keys: &HashMap<String, [u8; 32]>,
+) -> (OptionData, Option<[u8; 32]>) {
+    let gate_key = plan.is_gate.then(|| CystRng.gen::<[u8; 32]>());
+    let mut option_data = OptionData::new(
+        gate_key
+            .as_ref()
+            .map_or(secret, |gate_key| gate_key.as_slice()),
+        plan.description.clone(),
+        plan.is_gate,
+        plan.gated_behind
+            .as_ref()
+            .map(|gate| (gate.clone(), gate_keys[gate].as_slice())),
+        factors,
+        keys,
+        // Requiring all of them is just a normal option
+        plan.quorum
+            .filter(|&quorum| quorum as usize != plan.factors.len()),
+    );
+    option_data.kdf = plan.kdf;
+
+    (option_data, gate_key)
+}
+
+/// Prompts the user to choose one of the factors in the registry, marking any that can't be
+/// created here (like one that needs a program that isn't installed) and asking again if one of
+/// those is chosen.
+pub(crate) fn choose_factor<'a>(
+    registry: &'a FactorRegistry,
+    prompter: &mut dyn Prompter,
+) -> Result<&'a dyn BoxedFactor> {
+    let mut factor_names = registry.keys().copied().collect::<Vec<_>>();
+    factor_names.sort();
+    let availability = factor_names
+        .iter()
+        .map(|name| registry[name].available())
+        .collect::<Vec<_>>();
+    let items = factor_names
+        .iter()
+        .zip(&availability)
+        .map(|(name, available)| match available {
+            Ok(()) => name.to_string(),
+            Err(err) => format!("{name} (unavailable: {err:#})"),
+        })
+        .collect::<Vec<_>>();
+    let items = items.iter().map(String::as_str).collect::<Vec<_>>();
+    loop {
+        let factor_idx = prompter.select("Choose an encryption factor to use", &items)?;
+        match &availability[factor_idx] {
+            Ok(()) => break Ok(registry[factor_names[factor_idx]].as_ref()),
+            Err(err) => prompter.notice(&format!(
+                "{} can't be used right now: {err:#}. Choose another factor.",
+                factor_names[factor_idx]
+            )),
+        }
+    }
+}
+
+/// Runs the given factor's prompting process to create an instance of it, returning its data, its
+/// key, and its estimate of the entropy that went into it (see [`Factor::entropy`](crate::Factor::entropy)).
+fn create_factor(
+    factor: &dyn BoxedFactor,
+    prompter: &mut dyn Prompter,
+) -> Result<(Vec<u8>, Vec<u8>, Option<f64>)> {
+    debug!("Creating factor '{}'", factor.name());
+    let (data, key, entropy) = factor.create_with_entropy(prompter)?;
+    debug!(
+        "Factor '{}' produced {} byte(s) of data and a key",
+        factor.name(),
+        data.len()
+    );
+
+    Ok((data, key, entropy))
+}
+
+/// Prompts the user to enter a hint for a factor, returning `None` if they leave it empty.
+pub(crate) fn prompt_hint(prompter: &mut dyn Prompter) -> Result<Option<String>> {
+    let hint = prompter.input(
+        "Enter a hint to show when this factor is needed, like where it's kept (optional, NOT secret)",
+        Some(""),
+        &|_| Ok(()),
+    )?;
+
+    Ok(Some(hint.trim().to_string()).filter(|hint| !hint.is_empty()))
+}
+
+/// Prompts the user for a single factor (and an optional hint for it), returning the instance of
+/// it to store, its key, and the entropy that went into it.
+fn prompt_factor(
+    registry: &FactorRegistry,
+    prompter: &mut dyn Prompter,
+) -> Result<(FactorInstance, Vec<u8>, Option<f64>)> {
+    let factor = choose_factor(registry, prompter)?;
+    // Enter that factor's prompting process and get its data and a key
+    let (data, key, entropy) = create_factor(factor, prompter)?;
+    let hint = prompt_hint(prompter)?;
 
     Ok((
-        name,
-        OptionData {
-            salt,
-            factors,
-            primary_key_nonce: nonce.into(),
-            primary_key_ciphertext,
+        FactorInstance {
+            name: factor.name().to_string(),
+            data,
+            hint,
         },
+        key,
+        entropy,
     ))
 }
+
+/// Roughly estimates how many bits of entropy someone would have to guess to satisfy an option
+/// with the given quorum, from what each of its factors estimated when it was created (see
+/// [`Factor::entropy`](crate::Factor::entropy)). A factor with a random key makes every
+/// combination it's needed in strong, so this is infinite if there's no way around those.
+fn option_entropy(entropies: &[Option<f64>], quorum: Option<u8>) -> f64 {
+    let mut entropies = entropies
+        .iter()
+        .map(|entropy| entropy.unwrap_or(f64::INFINITY))
+        .collect::<Vec<_>>();
+    // The weakest factors are the ones someone would go after
+    entropies.sort_by(f64::total_cmp);
+    let needed = quorum.map_or(entropies.len(), usize::from);
+
+    entropies.into_iter().take(needed).sum()
+}
+
+/// Checks that an option whose factors had the given entropies (see [`option_entropy`]) and the
+/// given quorum isn't so easy to guess that it undermines the others, asking the user to confirm
+/// if it is. Options are alternatives, so a weak one undermines all the strong ones, but gates and
+/// the options behind them need each other, so those (`gated`) are left alone.
+fn confirm_strength(
+    entropies: &[Option<f64>],
+    quorum: Option<u8>,
+    gated: bool,
+    prompter: &mut dyn Prompter,
+) -> Result<bool> {
+    let entropy = option_entropy(entropies, quorum);
+    if gated || entropy >= WEAK_OPTION_BITS {
+        return Ok(true);
+    }
+
+    prompter.confirm(&format!(
+        "This option can decrypt the entire file with only about {entropy:.0} bits of entropy (like a weak passphrase), and any one option is enough, so it's the weakest link. Continue anyway?"
+    ))
+}
+
+/// Prompts the user for a series of factors, encrypting the given secret (the primary key and its
+/// stream's location) and returning the data needed to decrypt the resulting ciphertext, along
+/// with the user-provided name of the option, which won't be any of the names in `existing`.
+///
+/// The user can also choose to gate the option behind one of the gates in `gate_keys`, or to make
+/// it a gate itself, in which case it encrypts a fresh gate key instead, which is returned too.
+fn prompt_option(
+    secret: &[u8],
+    existing: &HashMap<String, OptionData>,
+    gate_keys: &HashMap<String, [u8; 32]>,
+    registry: &FactorRegistry,
+    prompter: &mut dyn Prompter,
+) -> Result<(String, OptionData, Option<[u8; 32]>)> {
+    let name = prompter.input("Enter a name for this encryption option", None, &|name| {
+        if existing.contains_key(name) {
+            Err(format!("there's already an option named '{name}'"))
+        } else {
+            Ok(())
+        }
+    })?;
+    let description = prompter.input(
+        "Enter a description for this option (optional)",
+        Some(""),
+        &|_| Ok(()),
+    )?;
+    let description = Some(description.trim().to_string()).filter(|desc| !desc.is_empty());
+
+    // Only gates that already exist can be chosen, so gates can never form a loop
+    let gated_behind = if gate_keys.is_empty() {
+        None
+    } else {
+        let mut gates = gate_keys.keys().map(String::as_str).collect::<Vec<_>>();
+        gates.sort();
+        let items = ["(none)"]
+            .into_iter()
+            .chain(gates.iter().copied())
+            .collect::<Vec<_>>();
+        match prompter.select("Choose a gate to put this option behind", &items)? {
+            0 => None,
+            idx => Some(gates[idx - 1]),
+        }
+    };
+    let is_gate = prompter.confirm(
+        "Make this option a gate, which only unlocks the options behind it instead of decrypting the file?",
+    )?;
+
+    let (factors, keys, quorum) = loop {
+        let mut is_first = true;
+        let mut factors = Vec::new();
+        let mut keys = Vec::new();
+        let mut entropies = Vec::new();
+        loop {
+            // Always prompt for a first factor, and otherwise confirm with the user first
+            if is_first || prompter.confirm("Add another factor?")? {
+                // A factor that fails shouldn't waste the ones already done (which might have
+                // uploaded something, or shown the user secrets they've since stored)
+                let (instance, key, entropy) = match prompt_factor(registry, prompter) {
+                    Ok(factor) => factor,
+                    Err(err) if err.is::<Cancelled>() => return Err(err),
+                    Err(err) => {
+                        prompter.notice(&format!(
+                            "Couldn't create that factor: {err:#}. Any factors already added to this option are kept."
+                        ));
+                        continue;
+                    }
+                };
+                is_first = false;
+                // Save the factor's details and its key
+                factors.push(instance);
+                keys.push(key);
+                entropies.push(entropy);
+            } else {
+                break;
+            }
+        }
+
+        // With several factors, the user might only want to require some of them
+        let quorum = if factors.len() > 1
+            && factors.len() <= u8::MAX as usize
+            && prompter.confirm("Require only some of these factors, rather than all of them?")?
+        {
+            let num_factors = factors.len();
+            let quorum = prompter.input(
+                &format!("How many of the {num_factors} factors should be required?"),
+                None,
+                &|input| match input.trim().parse::<usize>() {
+                    Ok(quorum) if (1..=num_factors).contains(&quorum) => Ok(()),
+                    _ => Err(format!("must be a number from 1 to {num_factors}")),
+                },
+            )?;
+            // We validated this above
+            Some(quorum.trim().parse::<u8>().unwrap())
+        } else {
+            None
+        };
+
+        if confirm_strength(
+            &entropies,
+            quorum,
+            is_gate || gated_behind.is_some(),
+            prompter,
+        )? {
+            break (factors, keys, quorum);
+        }
+        prompter.info("Choose this option's factors again.");
+    };
+
+    let plan = OptionPlan {
+        name,
+        description,
+        is_gate,
+        gated_behind: gated_behind.map(str::to_string),
+        factors: factors
+            .iter()
+            .map(|instance| FactorPlan {
+                name: instance.name.clone(),
+                hint: instance.hint.clone(),
+            })
+            .collect(),
+        quorum,
+        kdf: Kdf::prompt(prompter)?,
+    };
+    let (option_data, gate_key) = build_option(&plan, factors, &keys, secret, gate_keys);
+
+    Ok((plan.name, option_data, gate_key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        error::AuthenticationFailed,
+        factor::Factor,
+        factors::{FuzzyPassphraseFactor, PinFactor, SecurityQuestionsFactor},
+        file::{ciphertext_len, encrypt_file},
+        prompt::Verbosity,
+        testing,
+        vectors::{registry, ScriptedPrompter},
+    };
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha20Rng;
+
+    /// Creates the bytes of a header that uses every extension.
+    fn header_bytes() -> Vec<u8> {
+        let (mut header, _) = testing::header("garbage");
+        header
+            .set_plaintext_padding(PaddingScheme::Multiple(64))
+            .unwrap();
+        header.set_added_suffix(".cyst".to_string()).unwrap();
+
+        header.to_bytes()
+    }
+
+    #[test]
+    fn random_headers_are_refused() {
+        let mut rng = ChaCha20Rng::seed_from_u64(360);
+        for _ in 0..1000 {
+            let len = rng.gen_range(0..512);
+            let mut bytes = (len as u64).to_le_bytes().to_vec();
+            bytes.extend((0..len).map(|_| rng.gen::<u8>()));
+            assert!(Header::from_file_with_limit(&mut bytes.as_slice(), 1024).is_err());
+        }
+    }
+
+    #[test]
+    fn truncated_headers_are_refused() {
+        let bytes = header_bytes();
+        for len in 0..bytes.len() {
+            assert!(Header::from_file(&mut &bytes[..len]).is_err());
+        }
+        // And with the length prefix fixed up to match, so the header itself is cut short (which
+        // is only valid between the extensions, where an older version would have stopped)
+        let header = Header::from_file(&mut bytes.as_slice()).unwrap();
+        let base = bincode::serialized_size(&header).unwrap();
+        let first = base + bincode::serialized_size(&header.extensions.plaintext_padding).unwrap();
+        let second = first + bincode::serialized_size(&header.extensions.added_suffix).unwrap();
+        for len in 0..bytes.len() as u64 - 8 {
+            let mut truncated = len.to_le_bytes().to_vec();
+            truncated.extend_from_slice(&bytes[8..8 + len as usize]);
+            let result = Header::from_file(&mut truncated.as_slice());
+            assert_eq!(
+                result.is_ok(),
+                [base, first, second].contains(&len),
+                "{len}"
+            );
+        }
+    }
+
+    #[test]
+    fn overlong_headers_are_refused() {
+        let mut bytes = u64::MAX.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&[0; 64]);
+        let err = Header::from_file(&mut bytes.as_slice()).err().unwrap();
+        assert!(err.to_string().contains("invalid length"));
+        let mut bytes = (MAX_HEADER_LEN + 1).to_le_bytes().to_vec();
+        bytes.extend_from_slice(&[0; 64]);
+        assert!(Header::from_file(&mut bytes.as_slice()).is_err());
+
+        let bytes = header_bytes();
+        let err = Header::from_file_with_limit(&mut bytes.as_slice(), 16)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("more than the limit of 16 bytes"));
+    }
+
+    #[test]
+    fn random_extensions_never_panic() {
+        let mut rng = ChaCha20Rng::seed_from_u64(360);
+        for _ in 0..1000 {
+            let bytes = (0..rng.gen_range(0..128))
+                .map(|_| rng.gen::<u8>())
+                .collect::<Vec<_>>();
+            // Garbage can happen to be valid extensions, so all that matters is that it's handled
+            let _ = HeaderExtensions::from_bytes(&mut bytes.as_slice());
+        }
+    }
+
+    #[test]
+    fn truncated_extensions_are_refused() {
+        let extensions = HeaderExtensions {
+            plaintext_padding: Some(PaddingScheme::PowerOfTwo),
+            added_suffix: Some(".cyst".to_string()),
+            option_bindings: Some(BTreeMap::from([(
+                "garbage".to_string(),
+                OptionBinding {
+                    name: "garbage".to_string(),
+                    factor_salts: vec![[7; 32]],
+                },
+            )])),
+        };
+        let bytes = extensions.to_bytes();
+        // Extensions that stop between fields were just written by an older version
+        let first = bincode::serialized_size(&extensions.plaintext_padding).unwrap() as usize;
+        let second = first + bincode::serialized_size(&extensions.added_suffix).unwrap() as usize;
+        for len in 0..bytes.len() {
+            let result = HeaderExtensions::from_bytes(&mut &bytes[..len]);
+            assert_eq!(result.is_ok(), len == first || len == second, "{len}");
+        }
+    }
+
+    #[test]
+    fn huge_extension_lengths_are_refused() {
+        // A suffix that claims to be far longer than the extensions are
+        let mut bytes = bincode::serialize(&None::<PaddingScheme>).unwrap();
+        bytes.push(1);
+        bytes.extend_from_slice(&u64::MAX.to_le_bytes());
+        bytes.extend_from_slice(b".cyst");
+        assert!(HeaderExtensions::from_bytes(&mut bytes.as_slice()).is_err());
+    }
+
+    /// Writes a file with a single option called "first" to a temporary directory, returning the
+    /// directory (which is deleted when dropped), the file's path, and its escrow key.
+    fn write_file(plaintext: &[u8]) -> (tempfile::TempDir, std::path::PathBuf, Vec<u8>) {
+        let (header, encryptor) = testing::header("first");
+        let escrow_key = header.escrow_key().unwrap().to_vec();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.cyst");
+        std::fs::write(&path, testing::encrypt(header, encryptor, plaintext)).unwrap();
+
+        (dir, path, escrow_key)
+    }
+
+    /// Rewrites the header of the file at the given path, checking that the file is then exactly
+    /// the new header followed by the untouched body.
+    fn rewrite(header: &Header, path: &Path) {
+        let old = std::fs::read(path).unwrap();
+        let old_header_len = Header::from_file(&mut old.as_slice())
+            .unwrap()
+            .to_bytes()
+            .len();
+        header.rewrite_in_file(path).unwrap();
+
+        let new = std::fs::read(path).unwrap();
+        let new_header = header.to_bytes();
+        assert_eq!(new.len(), new_header.len() + old.len() - old_header_len);
+        assert_eq!(new[..new_header.len()], new_header);
+        assert_eq!(new[new_header.len()..], old[old_header_len..]);
+    }
+
+    /// Merges an option called "second" into the given header (of a file written by
+    /// [`write_file`]).
+    fn merge_second(header: &mut Header, escrow_key: &[u8]) {
+        let (other, _) = testing::header("second");
+        let other_escrow_key = other.escrow_key().unwrap().to_vec();
+        header
+            .merge_options(
+                escrow_key,
+                other,
+                &other_escrow_key,
+                &mut testing::prompter("second"),
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn rewriting_with_a_larger_header_keeps_the_body() {
+        let plaintext = b"the body must survive the header growing";
+        let (_dir, path, escrow_key) = write_file(plaintext);
+        let mut header = Header::from_file(&mut File::open(&path).unwrap()).unwrap();
+        let old_len = header.to_bytes().len();
+
+        merge_second(&mut header, &escrow_key);
+        assert!(header.to_bytes().len() > old_len);
+        rewrite(&header, &path);
+
+        let file = std::fs::read(&path).unwrap();
+        assert_eq!(testing::decrypt(&file, "first").unwrap(), plaintext);
+        assert_eq!(testing::decrypt(&file, "second").unwrap(), plaintext);
+    }
+
+    #[test]
+    fn rewriting_with_a_smaller_header_keeps_the_body() {
+        let plaintext = b"the body must survive the header shrinking";
+        let (_dir, path, escrow_key) = write_file(plaintext);
+        let mut header = Header::from_file(&mut File::open(&path).unwrap()).unwrap();
+        merge_second(&mut header, &escrow_key);
+        header.rewrite_in_file(&path).unwrap();
+        let old_len = header.to_bytes().len();
+
+        header.options.remove("second").unwrap();
+        assert!(header.to_bytes().len() < old_len);
+        rewrite(&header, &path);
+
+        let file = std::fs::read(&path).unwrap();
+        assert_eq!(testing::decrypt(&file, "first").unwrap(), plaintext);
+        assert!(testing::decrypt(&file, "second").is_err());
+    }
+
+    #[test]
+    fn factor_memory_counts_towards_what_decryption_needs() {
+        let mut with_pin = registry();
+        with_pin.insert(<PinFactor as Factor>::name(), Box::new(PinFactor));
+        let mut prompter = testing::prompter("pin");
+        prompter.set("Choose an encryption factor", "PIN");
+        prompter.set("How many digits", "6");
+        prompter.set("How much memory should deriving the PIN take", "1");
+        prompter.set("How many iterations should deriving the PIN take", "1");
+        prompter.set("Enter a PIN", "123456");
+        prompter.set("This option can decrypt the entire file", "y");
+        let (mut header, _) = Header::new(&with_pin, None, 1, &mut prompter).unwrap();
+        let kdf_memory = header.options["pin"].kdf.memory_needed();
+        assert_eq!(header.memory_needed(&with_pin), kdf_memory.max(1024 * 1024));
+
+        // A hostile file can make the PIN's own derivation ask for far more than its option's KDF
+        // (`PinFactorData::memory_kib` comes after the salt and the length)
+        let data = &mut header.options.get_mut("pin").unwrap().factors[0].data;
+        data[33..37].copy_from_slice(&u32::MAX.to_le_bytes());
+        let needed = header.memory_needed(&with_pin);
+        assert_eq!(needed, u32::MAX as u64 * 1024);
+        assert!(needed > 1024 * 1024 * 1024 && needed > kdf_memory);
+        // Factors that aren't available can't be derived at all
+        assert_eq!(header.memory_needed(&registry()), kdf_memory);
+    }
+
+    /// Creates a header with a single option made of the given factor, answering its prompts with
+    /// the given answers (on top of [`testing::prompter`]'s), and returns the error from the
+    /// question that wasn't answered, which should be the warning that the option is weak.
+    fn weak_option_warning<F: Factor + 'static>(
+        factor: F,
+        answers: &[(&'static str, &str)],
+    ) -> String {
+        let mut registry = registry();
+        registry.insert(F::name(), Box::new(factor));
+        let mut prompter = testing::prompter("weak");
+        prompter.set("Choose an encryption factor", F::name());
+        for (prompt, answer) in answers {
+            prompter.set(prompt, answer);
+        }
+
+        let err = Header::new(&registry, None, 1, &mut prompter)
+            .err()
+            .unwrap();
+        format!("{err:#}")
+    }
+
+    #[test]
+    fn weak_typo_tolerant_passphrases_are_warned_about() {
+        let warning = weak_option_warning(
+            FuzzyPassphraseFactor,
+            &[
+                (
+                    "Which typos should be tolerated",
+                    "Two neighbouring characters swapped",
+                ),
+                ("Enter a passphrase", "a"),
+                ("Confirm the passphrase", "a"),
+            ],
+        );
+        assert!(
+            warning.contains("This option can decrypt the entire file with only about"),
+            "{warning}"
+        );
+    }
+
+    #[test]
+    fn security_questions_are_warned_about() {
+        let warning = weak_option_warning(
+            SecurityQuestionsFactor,
+            &[
+                ("How many questions", "2"),
+                ("Enter question #1", "What was my first pet's name?"),
+                (
+                    "What was my first pet's name?",
+                    "an answer nobody could ever guess",
+                ),
+                ("Enter question #2", "Where was I born?"),
+                ("Where was I born?", "somewhere nobody could ever guess"),
+            ],
+        );
+        assert!(
+            warning.contains("with only about 16 bits of entropy"),
+            "{warning}"
+        );
+    }
+
+    /// Answers the prompts for a file with a decoy, naming the real option `real` and the duress
+    /// option `duress` (each with [`testing::PASSPHRASE`]).
+    struct DecoyPrompter(ScriptedPrompter);
+    impl Prompter for DecoyPrompter {
+        fn input(
+            &mut self,
+            prompt: &str,
+            default: Option<&str>,
+            validate: &dyn Fn(&str) -> Result<(), String>,
+        ) -> Result<String> {
+            self.0.input(prompt, default, validate)
+        }
+        fn password(
+            &mut self,
+            prompt: &str,
+            validate: &dyn Fn(&str) -> Result<(), String>,
+        ) -> Result<String> {
+            self.0.password(prompt, validate)
+        }
+        fn select(&mut self, prompt: &str, items: &[&str]) -> Result<usize> {
+            self.0.select(prompt, items)
+        }
+        fn confirm(&mut self, prompt: &str) -> Result<bool> {
+            self.0.confirm(prompt)
+        }
+        fn message(&mut self, verbosity: Verbosity, msg: &str) {
+            if msg.starts_with("Now set up the duress options") {
+                self.0
+                    .set("Enter a name for this encryption option", "duress");
+            }
+            self.0.message(verbosity, msg);
+        }
+    }
+
+    #[test]
+    fn decoys_decrypt_separately() {
+        let real = b"the real plaintext, which only the real option should reveal".repeat(100);
+        let decoy = b"something harmless".to_vec();
+        let (mut header, encryptor, decoy_encryptor) = Header::with_decoy(
+            &registry(),
+            None,
+            real.len() as u64,
+            1,
+            &mut DecoyPrompter(testing::prompter("real")),
+        )
+        .unwrap();
+        header
+            .set_body_len(ciphertext_len(real.len() as u64) + ciphertext_len(decoy.len() as u64))
+            .unwrap();
+        let mut file = Vec::new();
+        encrypt_file(
+            &mut real.as_slice(),
+            &mut file,
+            &header,
+            encryptor,
+            Some((&mut decoy.as_slice(), decoy_encryptor)),
+        )
+        .unwrap();
+
+        assert_eq!(testing::decrypt(&file, "real").unwrap(), real);
+        assert_eq!(testing::decrypt(&file, "duress").unwrap(), decoy);
+
+        // The real stream has to end before the decoy starts
+        let location = |name| {
+            header
+                .to_decryptor(
+                    &registry(),
+                    Some(name),
+                    false,
+                    1,
+                    &mut testing::prompter(name),
+                )
+                .unwrap()
+                .1
+        };
+        let (real_location, decoy_location) = (location("real"), location("duress"));
+        assert_eq!(real_location.offset, 0);
+        assert_eq!(real_location.len, Some(ciphertext_len(real.len() as u64)));
+        assert_eq!(decoy_location.offset, real_location.len.unwrap());
+        assert_eq!(decoy_location.len, None);
+    }
+
+    /// Creates an option called `name` with passphrase factors with the given passphrases, which
+    /// needs any `quorum` of them (or all of them), returning it along with the secret it wraps.
+    fn passphrase_option(
+        name: &str,
+        passphrases: &[&str],
+        quorum: Option<u8>,
+    ) -> (OptionData, Vec<u8>) {
+        let secret = CystRng.gen::<[u8; 32]>().to_vec();
+        let factors = passphrases
+            .iter()
+            .map(|_| FactorInstance {
+                name: "Passphrase".to_string(),
+                data: bincode::serialize(&()).unwrap(),
+                hint: None,
+            })
+            .collect();
+        let keys = passphrases
+            .iter()
+            .map(|passphrase| passphrase.as_bytes().to_vec())
+            .collect::<Vec<_>>();
+        let mut option = OptionData::new(&secret, None, true, None, factors, &keys, quorum);
+        option.kdf = Kdf::Pbkdf2 { iterations: 1 };
+        option.wrap_secret(name, &secret, &keys, None);
+
+        (option, secret)
+    }
+
+    #[test]
+    fn any_quorum_of_factors_unlocks_an_option() {
+        // Every combination of the three factors, as a bitmask of which ones the user knows
+        for known in 0..8u32 {
+            let passphrases = (0..3)
+                .map(|idx| {
+                    if known & (1 << idx) != 0 {
+                        testing::PASSPHRASE
+                    } else {
+                        "not what the user will enter"
+                    }
+                })
+                .collect::<Vec<_>>();
+            let (option, secret) = passphrase_option("quorum", &passphrases, Some(2));
+            let mut prompter = ScriptedPrompter::new(vec![
+                ("Use factor", "y".to_string()),
+                ("Enter the passphrase", testing::PASSPHRASE.to_string()),
+            ]);
+
+            let res = option
+                .derive_keys(&registry(), &mut prompter)
+                .and_then(|keys| option.unwrap_secret(&keys, None));
+            if known.count_ones() >= 2 {
+                assert_eq!(res.unwrap(), secret, "{known:03b}");
+            } else {
+                assert!(res.unwrap_err().is::<AuthenticationFailed>(), "{known:03b}");
+            }
+        }
+    }
+
+    #[test]
+    fn fewer_keys_than_the_quorum_are_refused() {
+        let (option, secret) = passphrase_option("quorum", &["a", "b", "c"], Some(2));
+        let key = |passphrase: &str| Some(passphrase.as_bytes().to_vec());
+
+        for keys in [
+            [key("a"), key("b"), None],
+            [None, key("b"), key("c")],
+            [key("a"), None, key("c")],
+        ] {
+            assert_eq!(option.unwrap_secret(&keys, None).unwrap(), secret);
+        }
+        for keys in [
+            [key("a"), None, None],
+            [None, None, key("c")],
+            [None, None, None],
+        ] {
+            assert!(option.unwrap_secret(&keys, None).is_err());
+        }
+        // A factor's key can't open another factor's share
+        assert!(option
+            .unwrap_secret(&[key("b"), key("a"), None], None)
+            .is_err());
+    }
+
+    #[test]
+    fn option_keys_are_bound_to_their_name_and_factors() {
+        let keys = [Some(b"a".to_vec()), Some(b"b".to_vec())];
+        let (first, first_secret) = passphrase_option("first", &["a", "b"], None);
+        let (second, second_secret) = passphrase_option("second", &["a", "b"], None);
+        assert_eq!(first.unwrap_secret(&keys, None).unwrap(), first_secret);
+        assert_eq!(second.unwrap_secret(&keys, None).unwrap(), second_secret);
+        // The same factor keys give unrelated option keys
+        assert_ne!(
+            first.key(&keys, None).unwrap(),
+            second.key(&keys, None).unwrap()
+        );
+
+        // Renaming the option it was bound to
+        let (mut first, mut second) = (first, second);
+        first.binding.as_mut().unwrap().name = "second".to_string();
+        assert!(first.unwrap_secret(&keys, None).is_err());
+        first.binding.as_mut().unwrap().name = "first".to_string();
+
+        // Swapping a factor (and its salt) with the other option's
+        std::mem::swap(&mut first.factors[0], &mut second.factors[0]);
+        std::mem::swap(
+            &mut first.binding.as_mut().unwrap().factor_salts[0],
+            &mut second.binding.as_mut().unwrap().factor_salts[0],
+        );
+        assert!(first.unwrap_secret(&keys, None).is_err());
+        assert!(second.unwrap_secret(&keys, None).is_err());
+    }
+}
@@ -0,0 +1,96 @@
+use std::path::Path;
+
+/// Removes an incomplete output file if the user interrupts us (with Ctrl-C) while it exists, so
+/// it's never left behind as misleading garbage. Once this is dropped (because the output is
+/// complete, or because we're cleaning it up ourselves), an interrupt just kills the process as
+/// usual.
+///
+/// Only one file can be watched at a time, and this only works on Unix. Everywhere else, an
+/// interrupted output is left behind.
+pub struct CleanupOnInterrupt(());
+impl CleanupOnInterrupt {
+    /// Starts watching the given file.
+    pub fn new(path: &Path) -> Self {
+        #[cfg(unix)]
+        unix::watch(path);
+        #[cfg(not(unix))]
+        let _ = path;
+
+        Self(())
+    }
+}
+impl Drop for CleanupOnInterrupt {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        unix::unwatch();
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::{
+        ffi::CString,
+        os::unix::ffi::OsStrExt,
+        path::Path,
+        ptr,
+        sync::{
+            atomic::{AtomicPtr, Ordering},
+            Once,
+        },
+    };
+
+    /// The file being watched, and what to print once it's been removed. Everything the signal
+    /// handler needs is prepared in advance, since it can't safely allocate.
+    struct Cleanup {
+        path: CString,
+        message: Vec<u8>,
+    }
+
+    /// The file currently being watched, if there is one.
+    static CLEANUP: AtomicPtr<Cleanup> = AtomicPtr::new(ptr::null_mut());
+    /// Makes sure the signal handler is only installed once.
+    static INSTALL: Once = Once::new();
+
+    pub fn watch(path: &Path) {
+        let Ok(c_path) = CString::new(path.as_os_str().as_bytes()) else {
+            return;
+        };
+        let cleanup = Box::new(Cleanup {
+            path: c_path,
+            message: format!("\nInterrupted, so removed the incomplete output at {path:?}.\n")
+                .into_bytes(),
+        });
+        // The handler might be reading the old one at any moment, so it's leaked rather than freed
+        CLEANUP.store(Box::into_raw(cleanup), Ordering::SeqCst);
+
+        INSTALL.call_once(|| unsafe {
+            libc::signal(
+                libc::SIGINT,
+                handle_interrupt as *const () as libc::sighandler_t,
+            );
+        });
+    }
+
+    pub fn unwatch() {
+        CLEANUP.store(ptr::null_mut(), Ordering::SeqCst);
+    }
+
+    /// Removes the watched file (if there is one), and then dies of the interrupt like we would
+    /// have without a handler. Only async-signal-safe functions are called here.
+    extern "C" fn handle_interrupt(_: libc::c_int) {
+        let cleanup = CLEANUP.swap(ptr::null_mut(), Ordering::SeqCst);
+        unsafe {
+            if let Some(cleanup) = cleanup.as_ref() {
+                if libc::unlink(cleanup.path.as_ptr()) == 0 {
+                    libc::write(
+                        libc::STDERR_FILENO,
+                        cleanup.message.as_ptr().cast(),
+                        cleanup.message.len(),
+                    );
+                }
+            }
+            libc::signal(libc::SIGINT, libc::SIG_DFL);
+            libc::raise(libc::SIGINT);
+        }
+    }
+}
@@ -0,0 +1,366 @@
+//! The password-based key derivation functions an option's key can be derived with. Argon2id is
+//! the default, but some environments need scrypt (e.g. for compatibility) or PBKDF2 (e.g. for
+//! FIPS compliance). The choice is stored with each option, so options using different KDFs can be
+//! mixed in the same file.
+
+use crate::prompt::Prompter;
+use anyhow::{anyhow, bail, Result};
+use argon2::{Algorithm, Argon2, Params, Version};
+use ring::pbkdf2;
+use serde::{Deserialize, Serialize};
+use std::num::NonZeroU32;
+
+/// The default scrypt cost, as a power of two (which uses 128 MiB with `r = 8`).
+const DEFAULT_SCRYPT_LOG_N: u8 = 17;
+/// The default scrypt block size.
+const DEFAULT_SCRYPT_R: u32 = 8;
+/// The default number of PBKDF2 iterations, as OWASP recommends for HMAC-SHA256.
+const DEFAULT_PBKDF2_ITERATIONS: u32 = 600_000;
+
+/// A key derivation function, with its parameters.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Kdf {
+    /// Argon2id (version 0x13).
+    Argon2id {
+        memory_kib: u32,
+        iterations: u32,
+        parallelism: u32,
+    },
+    /// scrypt, with a cost of `2^log_n`.
+    Scrypt { log_n: u8, r: u32, p: u32 },
+    /// PBKDF2 with HMAC-SHA256.
+    Pbkdf2 { iterations: u32 },
+}
+impl Default for Kdf {
+    /// Argon2id with the `argon2` crate's default parameters, which is what every option used
+    /// before the KDF could be chosen.
+    fn default() -> Self {
+        Self::Argon2id {
+            memory_kib: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+        }
+    }
+}
+impl Kdf {
+    /// Prompts the user to choose a KDF (and its parameters, for anything but the default).
+    pub(crate) fn prompt(prompter: &mut dyn Prompter) -> Result<Self> {
+        Ok(
+            match prompter.select(
+                "Choose how to derive this option's key",
+                &[
+                    "Argon2id (recommended)",
+                    "scrypt",
+                    "PBKDF2-HMAC-SHA256 (e.g. for FIPS compliance)",
+                ],
+            )? {
+                0 => Self::default(),
+                1 => {
+                    let log_n = prompter.parsed(
+                        "What power of two should scrypt's cost be? (Each step up doubles the time and memory.)",
+                        Some(DEFAULT_SCRYPT_LOG_N),
+                    )?;
+                    let r = prompter.parsed(
+                        "What should scrypt's block size be?",
+                        Some(DEFAULT_SCRYPT_R),
+                    )?;
+                    let kdf = Self::Scrypt { log_n, r, p: 1 };
+                    kdf.check()?;
+                    kdf
+                }
+                _ => {
+                    let iterations = prompter.parsed(
+                        "How many iterations should PBKDF2 take? (Higher is slower to brute-force.)",
+                        Some(DEFAULT_PBKDF2_ITERATIONS),
+                    )?;
+                    let kdf = Self::Pbkdf2 { iterations };
+                    kdf.check()?;
+                    kdf
+                }
+            },
+        )
+    }
+
+    /// The name of this KDF, for showing to the user.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Self::Argon2id { .. } => "Argon2id",
+            Self::Scrypt { .. } => "scrypt",
+            Self::Pbkdf2 { .. } => "PBKDF2",
+        }
+    }
+
+    /// Roughly how much memory deriving a key with this KDF takes, in bytes.
+    pub(crate) fn memory_needed(&self) -> u64 {
+        match *self {
+            Self::Argon2id { memory_kib, .. } => memory_kib as u64 * 1024,
+            // The large table, and the blocks being mixed
+            Self::Scrypt { log_n, r, p } => 128 * r as u64 * ((1u64 << log_n.min(63)) + p as u64),
+            Self::Pbkdf2 { .. } => 0,
+        }
+    }
+
+    /// Derives a 32-byte key from the given input key material and salt.
+    pub(crate) fn derive(&self, input: &[u8], salt: &[u8]) -> Result<[u8; 32]> {
+        self.check()?;
+        let mut key = [0u8; 32];
+        match *self {
+            Self::Argon2id {
+                memory_kib,
+                iterations,
+                parallelism,
+            } => {
+                let params = Params::new(memory_kib, iterations, parallelism, Some(32))
+                    .map_err(|err| anyhow!("invalid Argon2 parameters: {err}"))?;
+                Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                    .hash_password_into(input, salt, &mut key)
+                    .map_err(|err| anyhow!("failed to run Argon2: {err}"))?;
+            }
+            Self::Scrypt { log_n, r, p } => scrypt(input, salt, log_n, r, p, &mut key),
+            Self::Pbkdf2 { iterations } => pbkdf2::derive(
+                pbkdf2::PBKDF2_HMAC_SHA256,
+                NonZeroU32::new(iterations).unwrap(),
+                salt,
+                input,
+                &mut key,
+            ),
+        }
+
+        Ok(key)
+    }
+
+    /// Makes sure the parameters are sensible, since they might have come from a corrupted header
+    /// (and an enormous scrypt cost would try to allocate more memory than any machine has).
+    pub(crate) fn check(&self) -> Result<()> {
+        match *self {
+            // The `argon2` crate checks these itself
+            Self::Argon2id { .. } => {}
+            Self::Scrypt { log_n, r, p } => {
+                if !(1..=24).contains(&log_n) || r == 0 || p == 0 || r > 64 || p > 16 {
+                    bail!("invalid scrypt parameters (the cost must be from 2^1 to 2^24, the block size from 1 to 64, and the parallelism from 1 to 16)");
+                }
+            }
+            Self::Pbkdf2 { iterations } => {
+                if iterations == 0 {
+                    bail!("PBKDF2 needs at least one iteration");
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Derives a key with scrypt (as in RFC 7914), with a cost of `2^log_n`, into `output`. The
+/// parameters must already have been checked.
+fn scrypt(password: &[u8], salt: &[u8], log_n: u8, r: u32, p: u32, output: &mut [u8]) {
+    let one = NonZeroU32::new(1).unwrap();
+    let block_len = 128 * r as usize;
+    let mut blocks = vec![0u8; block_len * p as usize];
+    pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, one, salt, password, &mut blocks);
+    for block in blocks.chunks_mut(block_len) {
+        ro_mix(block, 1 << log_n);
+    }
+    pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, one, &blocks, password, output);
+}
+
+/// scrypt's sequential memory-hard mixing of a single block, with a cost of `n`.
+fn ro_mix(block: &mut [u8], n: usize) {
+    let mut x = block
+        .chunks(4)
+        .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+        .collect::<Vec<_>>();
+    let words = x.len();
+    let mut v = Vec::with_capacity(n * words);
+    for _ in 0..n {
+        v.extend_from_slice(&x);
+        x = block_mix(&x);
+    }
+    for _ in 0..n {
+        // The first word of the last 64-byte chunk (only its low bits matter, as `n` is small)
+        let j = x[words - 16] as usize & (n - 1);
+        for (word, v_word) in x.iter_mut().zip(&v[j * words..(j + 1) * words]) {
+            *word ^= v_word;
+        }
+        x = block_mix(&x);
+    }
+
+    for (bytes, word) in block.chunks_mut(4).zip(x) {
+        bytes.copy_from_slice(&word.to_le_bytes());
+    }
+}
+
+/// scrypt's BlockMix, with Salsa20/8 as the hash function, on a block of `2r` 64-byte chunks.
+fn block_mix(block: &[u32]) -> Vec<u32> {
+    let chunks = block.len() / 16;
+    let mut x: [u32; 16] = block[block.len() - 16..].try_into().unwrap();
+    let mut output = vec![0u32; block.len()];
+    for (idx, chunk) in block.chunks(16).enumerate() {
+        for (word, chunk_word) in x.iter_mut().zip(chunk) {
+            *word ^= chunk_word;
+        }
+        salsa20_8(&mut x);
+        // The even chunks go in the first half, and the odd ones in the second
+        let pos = (idx / 2 + (idx % 2) * chunks / 2) * 16;
+        output[pos..pos + 16].copy_from_slice(&x);
+    }
+
+    output
+}
+
+/// The Salsa20/8 core, applied in place.
+fn salsa20_8(b: &mut [u32; 16]) {
+    let mut x = *b;
+    let mut quarter_round = |a: usize, b: usize, c: usize, d: usize| {
+        x[b] ^= x[a].wrapping_add(x[d]).rotate_left(7);
+        x[c] ^= x[b].wrapping_add(x[a]).rotate_left(9);
+        x[d] ^= x[c].wrapping_add(x[b]).rotate_left(13);
+        x[a] ^= x[d].wrapping_add(x[c]).rotate_left(18);
+    };
+    for _ in 0..4 {
+        // Columns
+        quarter_round(0, 4, 8, 12);
+        quarter_round(5, 9, 13, 1);
+        quarter_round(10, 14, 2, 6);
+        quarter_round(15, 3, 7, 11);
+        // Rows
+        quarter_round(0, 1, 2, 3);
+        quarter_round(5, 6, 7, 4);
+        quarter_round(10, 11, 8, 9);
+        quarter_round(15, 12, 13, 14);
+    }
+    for (word, mixed) in b.iter_mut().zip(x) {
+        *word = word.wrapping_add(mixed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        factor::{Factor, FactorRegistry},
+        factors::PassphraseFactor,
+        header::{FactorPlan, Header, OptionPlan},
+        prompt::Verbosity,
+        testing,
+        vectors::{registry, ScriptedPrompter},
+    };
+
+    /// A prompter that plans a single passphrase option with the given KDF up front (like the
+    /// full-screen editor does), answering everything else from the script in [`testing`].
+    struct PlanningPrompter {
+        script: ScriptedPrompter,
+        kdf: Kdf,
+    }
+    impl Prompter for PlanningPrompter {
+        fn input(
+            &mut self,
+            prompt: &str,
+            default: Option<&str>,
+            validate: &dyn Fn(&str) -> Result<(), String>,
+        ) -> Result<String> {
+            self.script.input(prompt, default, validate)
+        }
+        fn password(
+            &mut self,
+            prompt: &str,
+            validate: &dyn Fn(&str) -> Result<(), String>,
+        ) -> Result<String> {
+            self.script.password(prompt, validate)
+        }
+        fn select(&mut self, prompt: &str, items: &[&str]) -> Result<usize> {
+            self.script.select(prompt, items)
+        }
+        fn confirm(&mut self, prompt: &str) -> Result<bool> {
+            self.script.confirm(prompt)
+        }
+        fn message(&mut self, verbosity: Verbosity, msg: &str) {
+            self.script.message(verbosity, msg);
+        }
+        fn plan_options(
+            &mut self,
+            _registry: &FactorRegistry,
+            _existing: &[&str],
+            _gates: &[&str],
+        ) -> Result<Option<Vec<OptionPlan>>> {
+            let mut plan = OptionPlan::new("kdf".to_string());
+            plan.factors.push(FactorPlan {
+                name: PassphraseFactor::name().to_string(),
+                hint: None,
+            });
+            plan.kdf = self.kdf;
+
+            Ok(Some(vec![plan]))
+        }
+    }
+
+    /// Encrypts a file with a single option whose key is derived with the given KDF, then reads
+    /// its header back and decrypts it with that option.
+    fn round_trip(kdf: Kdf) {
+        let mut prompter = PlanningPrompter {
+            script: testing::prompter("kdf"),
+            kdf,
+        };
+        let (header, encryptor) = Header::new(&registry(), None, 1, &mut prompter).unwrap();
+        let plaintext = format!("derived with {}", kdf.name());
+        let file = testing::encrypt(header, encryptor, plaintext.as_bytes());
+
+        let read_header = Header::from_file(&mut file.as_slice()).unwrap();
+        assert_eq!(read_header.option_summaries()[0].kdf, kdf.name());
+        let decrypted = testing::decrypt(&file, "kdf").unwrap();
+        assert_eq!(decrypted, plaintext.as_bytes());
+    }
+
+    #[test]
+    fn argon2id_round_trips() {
+        round_trip(Kdf::Argon2id {
+            memory_kib: 64,
+            iterations: 1,
+            parallelism: 1,
+        });
+    }
+
+    #[test]
+    fn scrypt_round_trips() {
+        round_trip(Kdf::Scrypt {
+            log_n: 4,
+            r: 8,
+            p: 1,
+        });
+    }
+
+    #[test]
+    fn pbkdf2_round_trips() {
+        round_trip(Kdf::Pbkdf2 { iterations: 10 });
+    }
+
+    /// Checks that scrypt with the given parameters gives the given 64 bytes of output (in hex).
+    fn assert_scrypt(password: &[u8], salt: &[u8], log_n: u8, r: u32, p: u32, expected: &str) {
+        let mut output = [0u8; 64];
+        scrypt(password, salt, log_n, r, p, &mut output);
+        assert_eq!(hex::encode(output), expected, "N = 2^{log_n}");
+    }
+
+    #[test]
+    fn scrypt_matches_rfc_7914() {
+        // The test vectors from section 12 of RFC 7914 that run quickly
+        assert_scrypt(
+            b"",
+            b"",
+            4,
+            1,
+            1,
+            "77d6576238657b203b19ca42c18a0497f16b4844e3074ae8dfdffa3fede21442\
+             fcd0069ded0948f8326a753a0fc81f17e8d3e0fb2e0d3628cf35e20c38d18906",
+        );
+        assert_scrypt(
+            b"password",
+            b"NaCl",
+            10,
+            8,
+            16,
+            "fdbabe1c9d3472007856e7190d01e9fe7c6ad7cbc8237830e77376634b373162\
+             2eaf30d92e22a3886ff109279d9830dac727afb94a83ee6d8360cbdfa2cc0640",
+        );
+    }
+}
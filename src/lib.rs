@@ -0,0 +1,60 @@
+//! Cyst encrypts files so that they can be decrypted with any one of several *options*, each of
+//! which requires one or more *factors* (like a passphrase, a keyfile, or Shamir shares). This
+//! crate exposes the file format and the factors, so they can be used outside the CLI.
+//!
+//! Encryption starts with [`Header::new`], which sets up the options and returns an encryptor for
+//! [`encrypt_file`]. Decryption reads the header back with [`Header::from_file`], recovers a
+//! decryptor with [`Header::to_decryptor`], and then uses [`decrypt_file`]. Frontends that want to
+//! show progress can use [`encrypt_file_with_progress`] and [`decrypt_file_with_progress`] instead.
+//! To encrypt several files with the same options, set them up once with [`HeaderTemplate::new`]
+//! and create each file's header with [`HeaderTemplate::header`].
+//!
+//! Files can also be encrypted in a dedup-friendly mode, with independently encrypted chunks,
+//! which is described in [`dedup`], and with error correction, which is described in [`ecc`].
+//! Plaintexts can be padded to hide their length, which is described in [`padding`].
+//! Changes to the format itself are caught by the test vectors in [`vectors`], and the layout of
+//! headers is described for other tools in [`schema`]. Whole files can be signed to show who made
+//! them, which is described in [`signature`].
+
+pub mod armor;
+pub mod dedup;
+pub mod ecc;
+mod error;
+mod factor;
+pub mod factors;
+mod file;
+mod header;
+mod kdf;
+pub mod padding;
+mod prompt;
+mod rng;
+pub mod schema;
+pub mod signature;
+#[cfg(test)]
+mod testing;
+#[cfg(feature = "tui")]
+mod tui;
+pub mod vectors;
+
+pub use armor::{ArmoredReader, ArmoredWriter};
+pub use dedup::{
+    decrypt_file_dedup, decrypt_file_dedup_with_progress, encrypt_file_dedup,
+    encrypt_file_dedup_with_progress, verify_file_dedup, DedupCipher, DedupParams,
+};
+pub use ecc::{EccParams, EccReader, EccWriter};
+pub use error::AuthenticationFailed;
+pub use factor::{round_trip, BoxedFactor, Factor, FactorRegistry};
+pub use factors::get_factors;
+pub use file::{
+    ciphertext_len, decrypt_file, decrypt_file_with_progress, encrypt_body,
+    encrypt_body_with_progress, encrypt_file, encrypt_file_with_progress, known_len,
+    plaintext_hash, resume_encrypt_file, verify_file, PlaintextHasher,
+};
+pub use header::{
+    FactorProbe, FileMetadata, Header, HeaderTemplate, OptionEstimate, OptionPlan, OptionSummary,
+    RawStream, StreamLocation, FORMAT_VERSION,
+};
+pub use padding::{PaddedReader, PaddingScheme, UnpaddingWriter};
+pub use prompt::{Cancelled, DialoguerPrompter, Prompter, Verbosity};
+pub use rng::with_rng;
+pub use signature::{SigningKey, VerifyingKey};
@@ -0,0 +1,99 @@
+use log::{LevelFilter, Log, Metadata, Record};
+
+/// A logger that writes everything the library (and its dependencies) logs to stderr, for
+/// diagnosing failures. What's logged is controlled by `$RUST_LOG` if it's set, and otherwise by
+/// how many times `-v` was given.
+struct StderrLogger {
+    /// The most detailed level to log for targets that don't match any directive.
+    default: LevelFilter,
+    /// Target prefixes with the most detailed level to log for them. The longest match wins.
+    directives: Vec<(String, LevelFilter)>,
+}
+impl StderrLogger {
+    /// Gets the most detailed level to log for the given target.
+    fn level(&self, target: &str) -> LevelFilter {
+        self.directives
+            .iter()
+            .filter(|(prefix, _)| {
+                target == prefix
+                    || target
+                        .strip_prefix(prefix.as_str())
+                        .is_some_and(|rest| rest.starts_with("::"))
+            })
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.default, |(_, level)| *level)
+    }
+}
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("[{} {}] {}", record.level(), record.target(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Starts logging to stderr, if the user has asked for it. `$RUST_LOG` takes a comma-separated
+/// list of directives, each either a level (for everything) or `target=level`, like
+/// `warn,cyst=debug`. Without it, `-vv` logs cyst's debug messages, and `-vvv` everything cyst
+/// logs.
+pub fn init(verbose: u8) {
+    let logger = match std::env::var("RUST_LOG") {
+        Ok(spec) => parse_spec(&spec),
+        Err(_) if verbose >= 2 => StderrLogger {
+            default: LevelFilter::Warn,
+            directives: vec![(
+                "cyst".to_string(),
+                if verbose >= 3 {
+                    LevelFilter::Trace
+                } else {
+                    LevelFilter::Debug
+                },
+            )],
+        },
+        Err(_) => return,
+    };
+
+    let max_level = logger
+        .directives
+        .iter()
+        .map(|(_, level)| *level)
+        .fold(logger.default, Ord::max);
+    // This lives for the rest of the program anyway
+    if log::set_logger(Box::leak(Box::new(logger))).is_ok() {
+        log::set_max_level(max_level);
+    }
+}
+
+/// Parses a `$RUST_LOG`-style spec (see [`init`]). A directive that's just a target logs everything
+/// from it, and ones with levels that can't be parsed are ignored.
+fn parse_spec(spec: &str) -> StderrLogger {
+    let mut logger = StderrLogger {
+        default: LevelFilter::Off,
+        directives: Vec::new(),
+    };
+    for directive in spec.split(',').map(str::trim) {
+        match directive.split_once('=') {
+            Some((target, level)) => {
+                if let Ok(level) = level.trim().parse() {
+                    logger.directives.push((target.trim().to_string(), level));
+                }
+            }
+            // A bare target means everything from it
+            None => match directive.parse() {
+                Ok(level) => logger.default = level,
+                Err(_) if !directive.is_empty() => logger
+                    .directives
+                    .push((directive.to_string(), LevelFilter::Trace)),
+                Err(_) => {}
+            },
+        }
+    }
+
+    logger
+}
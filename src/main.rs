@@ -1,47 +1,3009 @@
-use anyhow::Result;
-use clap::{Parser, Subcommand};
-use factors::get_factors;
-use file::{decrypt_file, encrypt_file};
-use header::Header;
-use std::{fs::File, path::PathBuf};
-
-mod factor;
-mod factors;
-mod file;
-mod header;
+use anyhow::{anyhow, bail, Context, Result};
+use calibrate::calibrate;
+use chacha20poly1305::{
+    aead::stream::{DecryptorBE32, EncryptorBE32},
+    ChaCha20Poly1305,
+};
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
+#[cfg(feature = "ephemeral")]
+use cyst::factors::EphemeralFactor;
+use cyst::{
+    armor::ARMOR_BEGIN,
+    ciphertext_len, decrypt_file, decrypt_file_dedup, encrypt_body, encrypt_file,
+    encrypt_file_dedup,
+    factors::{PassphraseFactor, PASSPHRASE_FILE_ENV},
+    get_factors, known_len, plaintext_hash, resume_encrypt_file, round_trip,
+    schema::{self, SchemaField, SchemaKind},
+    signature::{read_signature, signature_path},
+    vectors, verify_file, verify_file_dedup, ArmoredReader, ArmoredWriter, AuthenticationFailed,
+    Cancelled, DedupCipher, DedupParams, DialoguerPrompter, EccParams, EccReader, EccWriter,
+    Factor, FactorRegistry, FileMetadata, Header, HeaderTemplate, PaddedReader, PaddingScheme,
+    PlaintextHasher, Prompter, SigningKey, StreamLocation, UnpaddingWriter, Verbosity,
+    VerifyingKey, FORMAT_VERSION,
+};
+use interrupt::CleanupOnInterrupt;
+use json::Json;
+#[cfg(feature = "network")]
+use remote::RemoteReader;
+use resume::PartialOutput;
+use ring::digest;
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Seek, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    num::{NonZeroU32, NonZeroUsize},
+    path::{Path, PathBuf},
+    process::{self, Child, ChildStdin, Stdio},
+    time::Duration,
+};
+use tempfile::NamedTempFile;
+
+mod calibrate;
+mod interrupt;
+mod json;
+mod logger;
+#[cfg(feature = "network")]
+mod remote;
+mod resume;
+
+/// The size of plaintext above which we warn the user before showing it in a pager.
+const VIEW_WARN_LEN: u64 = 10 * 1024 * 1024;
+/// How many tries the user gets at satisfying an option, unless they ask for a different number.
+const DEFAULT_ATTEMPTS: u32 = 3;
+/// The byte `receive` sends back once it's decrypted and written the whole file, so `send` knows
+/// it arrived intact.
+const RECEIVED_ACK: u8 = 0x06;
+
+// Exit codes for each kind of failure, so scripts can tell them apart. Anything else exits with 1,
+// and clap uses 2 for invalid arguments.
+
+/// The exit code used when a decryption option can't be satisfied or the ciphertext can't be
+/// authenticated (a wrong factor looks the same as tampering).
+const EXIT_AUTHENTICATION: i32 = 3;
+/// The exit code used when reading or writing a file fails.
+const EXIT_IO: i32 = 4;
+/// The exit code used when a network request made by a factor fails.
+const EXIT_NETWORK: i32 = 5;
+/// The exit code used when the user cancels a prompt (the same as for an interrupt).
+const EXIT_CANCELLED: i32 = 130;
+/// The exit code used when whatever's reading our output from stdout stops early (the same as for
+/// other tools, which are killed by `SIGPIPE`).
+const EXIT_BROKEN_PIPE: i32 = 141;
+/// How long to wait before trying again to write to stdout, if it's been made non-blocking and
+/// isn't ready for more.
+const STDOUT_RETRY_DELAY: Duration = Duration::from_millis(10);
 
 fn main() -> Result<()> {
     let opts = Opts::parse();
+    if let Some(passphrase_file) = &opts.passphrase_file {
+        // This is how the passphrase factor finds it (we're still single-threaded here)
+        std::env::set_var(PASSPHRASE_FILE_ENV, passphrase_file);
+    }
+    #[cfg(feature = "shamir")]
+    if let Some(shares_file) = &opts.shares_file {
+        // Likewise for the Shamir factor
+        std::env::set_var(cyst::factors::SHARES_FILE_ENV, shares_file);
+    }
+    logger::init(opts.verbose);
+    let verbosity = if opts.quiet {
+        Verbosity::Quiet
+    } else if opts.verbose > 0 {
+        Verbosity::Verbose
+    } else {
+        Verbosity::Normal
+    };
+    // If stdout is only for the payload, whatever's reading it can stop whenever it likes (like
+    // with `cyst decrypt file.cyst | head`), which isn't worth an error
+    let quiet_broken_pipe = opts.command.writes_to_stdout()
+        && !matches!(
+            opts.command,
+            Command::Decrypt {
+                also_stdout: true,
+                ..
+            }
+        );
+    let mut prompter = DialoguerPrompter::new(verbosity);
+    prompter.always_prompt = opts.always_prompt;
+    #[cfg(feature = "tui")]
+    {
+        prompter.tui = opts.tui;
+    }
+    if !opts.json {
+        return match run(opts.command, false, prompter) {
+            Ok(_) => Ok(()),
+            Err(err) if quiet_broken_pipe && is_broken_pipe(&err) => {
+                std::process::exit(EXIT_BROKEN_PIPE)
+            }
+            Err(err) => {
+                if err.is::<Cancelled>() {
+                    eprintln!("Error: {err:#}");
+                } else {
+                    eprintln!("Error: {err:?}");
+                }
+                std::process::exit(classify(&err).0);
+            }
+        };
+    }
+
+    // The report goes to stdout, unless that's where the payload is going
+    let payload_on_stdout = opts.command.writes_to_stdout();
+    let command_name = opts.command.name();
+    let (report, exit_code) = match run(opts.command, true, prompter) {
+        Err(err) if quiet_broken_pipe && is_broken_pipe(&err) => {
+            std::process::exit(EXIT_BROKEN_PIPE)
+        }
+        Ok(fields) => (
+            Json::object(
+                [("command", command_name.into()), ("success", true.into())]
+                    .into_iter()
+                    .chain(fields),
+            ),
+            0,
+        ),
+        Err(err) => {
+            let (exit_code, kind) = classify(&err);
+            (
+                Json::object([
+                    ("command", command_name.into()),
+                    ("success", false.into()),
+                    ("error", format!("{err:#}").into()),
+                    ("error_kind", kind.into()),
+                ]),
+                exit_code,
+            )
+        }
+    };
+    if payload_on_stdout {
+        eprintln!("{report}");
+    } else {
+        println!("{report}");
+    }
+
+    if exit_code != 0 {
+        std::process::exit(exit_code);
+    }
+    Ok(())
+}
+
+/// Works out what kind of failure the given error is, returning the exit code for it and a name
+/// for the kind (for JSON reports).
+fn classify(err: &anyhow::Error) -> (i32, &'static str) {
+    if caused_by::<Cancelled>(err) {
+        (EXIT_CANCELLED, "cancelled")
+    } else if caused_by::<AuthenticationFailed>(err) {
+        (EXIT_AUTHENTICATION, "authentication")
+    // Network errors often wrap I/O errors, so these have to be checked first
+    } else if is_network_error(err) {
+        (EXIT_NETWORK, "network")
+    } else if caused_by::<io::Error>(err) {
+        (EXIT_IO, "io")
+    } else {
+        (1, "other")
+    }
+}
+
+/// Checks whether the given error came from a network request made by a factor.
+#[cfg(feature = "network")]
+fn is_network_error(err: &anyhow::Error) -> bool {
+    caused_by::<ureq::Error>(err)
+}
+/// Checks whether the given error came from a network request made by a factor (which it never
+/// can, since this build has no factors that use the network).
+#[cfg(not(feature = "network"))]
+fn is_network_error(_err: &anyhow::Error) -> bool {
+    false
+}
+
+/// Checks whether the given error came from writing to a pipe whose reader has gone away.
+fn is_broken_pipe(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause
+            .downcast_ref::<io::Error>()
+            .is_some_and(|err| err.kind() == io::ErrorKind::BrokenPipe)
+    })
+}
+
+/// Checks whether the given error is, or was caused by, an error of the given type. This checks
+/// both the context `anyhow` has added, and the sources of the underlying error.
+fn caused_by<E>(err: &anyhow::Error) -> bool
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    err.is::<E>() || err.chain().any(|cause| cause.is::<E>())
+}
+
+/// Runs the given command, printing human-readable messages if `json` is `false`, and returning
+/// the fields that describe the operation for a JSON report.
+fn run(
+    command: Command,
+    json: bool,
+    mut prompter: DialoguerPrompter,
+) -> Result<Vec<(&'static str, Json)>> {
     let factors = get_factors();
-    match opts.command {
-        Command::Encrypt { input, output } => {
-            let (header, encryptor) = Header::new(&factors)?;
-            encrypt_file(&input, output.as_deref(), header, encryptor)?;
+    if let Command::Encrypt {
+        format_version: Some(version),
+        ..
+    } = command
+    {
+        if version != FORMAT_VERSION {
+            bail!("this build writes version {FORMAT_VERSION} of the file format, not version {version}");
+        }
+    }
+    // Don't make the user go through all the prompts only to find the key can't be read
+    let signing_key = match &command {
+        Command::Encrypt {
+            sign: Some(_),
+            format: Some(Format::Split),
+            ..
+        } => bail!(
+            "the split format can't be signed, since the header and body are in different files"
+        ),
+        Command::Encrypt {
+            sign: Some(_),
+            output: None,
+            more_inputs,
+            ..
+        } if more_inputs.is_empty() => {
+            bail!("signing needs an output file to put the signature beside")
+        }
+        Command::Encrypt {
+            sign: Some(path), ..
+        } => Some(SigningKey::from_file(path)?),
+        _ => None,
+    };
+    match command {
+        Command::Encrypt {
+            input,
+            output,
+            dry_run: true,
+            threads,
+            ..
+        } => {
+            let threads = kdf_threads(threads);
+            // Make sure the input is actually there before we go through all the prompts
+            File::open(&input)?;
+            let (header, _) = Header::new(&factors, None, threads, &mut prompter)?;
+
+            // Try every option so the user knows which ones they can actually satisfy
+            let mut results = Vec::new();
+            for summary in header.option_summaries() {
+                let name = summary.name;
+                prompter.info(&format!("Checking option '{name}'..."));
+                let result = match header.check_option(name, &factors, &mut prompter) {
+                    Err(err) if err.is::<Cancelled>() => return Err(err),
+                    result => result,
+                };
+                if !json {
+                    match &result {
+                        Ok(_) => prompter.info(&format!("Option '{name}' is satisfiable.")),
+                        Err(err) => prompter
+                            .info(&format!("Option '{name}' could not be satisfied: {err:#}")),
+                    }
+                }
+                results.push((name, result));
+            }
+            if results.iter().all(|(_, result)| result.is_err()) {
+                bail!("dry run failed: none of the options could be satisfied");
+            }
+            if !json {
+                prompter.info("Dry run successful! No output was written.");
+            }
+
+            Ok(vec![
+                ("input", path_json(&input)),
+                ("output", output.as_deref().map(path_json).into()),
+                ("dry_run", true.into()),
+                (
+                    "options",
+                    Json::Array(
+                        results
+                            .into_iter()
+                            .map(|(name, result)| {
+                                Json::object([
+                                    ("name", name.into()),
+                                    ("satisfiable", result.is_ok().into()),
+                                    ("error", result.err().map(|err| format!("{err:#}")).into()),
+                                ])
+                            })
+                            .collect(),
+                    ),
+                ),
+            ])
+        }
+        Command::Encrypt {
+            input,
+            output,
+            dedup,
+            dedup_base,
+            threads,
+            format,
+            escrow_out,
+            print_digest,
+            ..
+        } if dedup || dedup_base.is_some() => {
+            let threads = kdf_threads(threads);
+            if format == Some(Format::Split) {
+                bail!("the split format can't be used in dedup mode");
+            }
+            let mut input_file = File::open(&input)?;
+            // Don't make the user go through all the prompts only to fail at the end
+            if let Some(escrow_out) = escrow_out.as_deref().filter(|path| path.exists()) {
+                bail!("{escrow_out:?} already exists, refusing to overwrite it with an escrow key");
+            }
+            let (header, cipher) = match &dedup_base {
+                // Reusing the header (and so the key) of the previous version is what keeps the
+                // unchanged chunks the same
+                Some(base) => {
+                    let header = Header::from_file(&mut File::open(base)?)?;
+                    if header.dedup_params().is_none() {
+                        bail!("{base:?} was not encrypted in dedup mode");
+                    }
+                    prompter.info(&format!(
+                        "To reuse the key of {base:?}, please satisfy one of its options."
+                    ));
+                    let cipher = header.to_dedup_cipher(
+                        &factors,
+                        None,
+                        false,
+                        DEFAULT_ATTEMPTS,
+                        &mut prompter,
+                    )?;
+                    (header, cipher)
+                }
+                None => {
+                    Header::new_dedup(&factors, DedupParams::default(), threads, &mut prompter)?
+                }
+            };
+            if let Some(escrow_out) = &escrow_out {
+                write_escrow_key(escrow_out, &header, &input, &mut prompter)?;
+            }
+
+            // Dedup mode can't be resumed, but it's still written to a `.partial` file first
+            let partial = output.as_deref().map(PartialOutput::new);
+            let mut output_writer =
+                EncryptedOutput::new(partial.as_ref(), None, format, print_digest)?;
+            let _cleanup = partial
+                .as_ref()
+                .map(|partial| CleanupOnInterrupt::new(&partial.path));
+            encrypt_file_dedup(&mut input_file, &mut output_writer, &header, &cipher)?;
+            let digest = output_writer.finish()?;
+
+            if let (Some(partial), Some(output)) = (partial, &output) {
+                partial.finish()?;
+                if !json {
+                    prompter.info(&format!(
+                        "Encryption successful! Output written to {output:?}."
+                    ));
+                }
+            }
+            let signature = write_signature(signing_key.as_ref(), output.as_deref())?;
+            if let Some(signature) = signature.as_deref().filter(|_| !json) {
+                prompter.info(&format!(
+                    "Signature written to {signature:?} (made with the key {}).",
+                    signing_key.as_ref().unwrap().public_key()
+                ));
+            }
+            if let Some(digest) = digest.as_deref().filter(|_| !json) {
+                show_digest(&mut prompter, digest, output.as_deref());
+            }
+
+            Ok(vec![
+                ("input", path_json(&input)),
+                ("output", output.as_deref().map(path_json).into()),
+                ("options", options_json(&header)),
+                ("dedup", true.into()),
+                ("format", format.unwrap_or(Format::Raw).name().into()),
+                ("escrow_out", escrow_out.as_deref().map(path_json).into()),
+                ("ecc_parity", Json::Null),
+                ("header_padded_to", Json::Null),
+                ("plaintext_padded_to", Json::Null),
+                ("public_note", Json::Null),
+                ("added_suffix", Json::Null),
+                ("signature", signature.as_deref().map(path_json).into()),
+                ("sha256", digest.into()),
+            ])
+        }
+        Command::Encrypt {
+            input,
+            more_inputs,
+            output,
+            store_name,
+            comment,
+            mime,
+            store_hash,
+            store_mode,
+            threads,
+            format,
+            ecc,
+            pad_header,
+            pad_to,
+            public_note,
+            ext,
+            print_digest,
+            ..
+        } if !more_inputs.is_empty() => {
+            let threads = kdf_threads(threads);
+            let ecc = ecc.map(EccParams::new).transpose()?;
+            let format = format.unwrap_or(Format::Raw);
+            if let Some(dir) = output.as_deref().filter(|dir| !dir.is_dir()) {
+                bail!("{dir:?} is not a directory (with several inputs, `--output` must be the directory to write them into)");
+            }
+            let inputs = std::iter::once(input)
+                .chain(more_inputs)
+                .collect::<Vec<_>>();
+            let outputs = inputs
+                .iter()
+                .map(|input| {
+                    let mut name = input
+                        .file_name()
+                        .ok_or_else(|| anyhow!("{input:?} is not a file"))?
+                        .to_owned();
+                    name.push(&ext);
+                    Ok(match &output {
+                        Some(dir) => dir.join(name),
+                        None => input.with_file_name(name),
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+            // Inputs with the same name in different directories (or the same input given twice)
+            // would be encrypted over each other
+            let mut taken = Vec::<(PathBuf, &PathBuf)>::new();
+            for (input, output) in inputs.iter().zip(&outputs) {
+                let resolved = resolve_output(output);
+                if let Some((_, other)) = taken.iter().find(|(taken, _)| *taken == resolved) {
+                    bail!("{other:?} and {input:?} would both be encrypted to {output:?}");
+                }
+                taken.push((resolved, input));
+            }
+            let existing = outputs
+                .iter()
+                .filter(|output| output.exists())
+                .collect::<Vec<_>>();
+            if !existing.is_empty()
+                && !prompter.confirm(&format!(
+                    "{} output(s) already exist ({}), overwrite them?",
+                    existing.len(),
+                    existing
+                        .iter()
+                        .map(|output| format!("{output:?}"))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ))?
+            {
+                bail!("refusing to overwrite existing outputs");
+            }
+            let wants_metadata =
+                store_name || comment.is_some() || mime.is_some() || store_hash || store_mode;
+
+            // The options are only set up once, and then reused for every file
+            let template = HeaderTemplate::new(&factors, &mut prompter)?;
+            let encrypt = |input: &Path,
+                           output: &Path,
+                           prompter: &mut dyn Prompter|
+             -> Result<(Header, Option<String>)> {
+                let mut input_file = File::open(input)?;
+                let input_len = known_len(&mut input_file)?;
+                if input_len.is_none() && (wants_metadata || ecc.is_some() || pad_to.is_some()) {
+                    bail!("its length can't be known in advance (is it a pipe?), so it can't be encrypted with metadata, error correction, or padding");
+                }
+                let hash = if store_hash {
+                    let hash = plaintext_hash(&mut input_file)?;
+                    input_file.rewind()?;
+                    Some(hash)
+                } else {
+                    None
+                };
+                let metadata = if wants_metadata {
+                    Some(FileMetadata {
+                        filename: store_name
+                            .then(|| input.file_name())
+                            .flatten()
+                            .map(|name| name.to_string_lossy().into_owned()),
+                        mime: mime.clone(),
+                        comment: comment.clone(),
+                        // This was checked above
+                        size: input_len.unwrap(),
+                        hash,
+                        mode: store_mode.then(|| file_mode(input)).transpose()?,
+                    })
+                } else {
+                    None
+                };
+                let (mut header, encryptor) = template.header(metadata, threads, prompter)?;
+                if let Some(pad_to) = pad_to {
+                    header.set_plaintext_padding(pad_to)?;
+                }
+                if let Some(input_len) = input_len {
+                    let input_len = pad_to.map_or(input_len, |pad_to| pad_to.padded_len(input_len));
+                    header.set_body_len(ciphertext_len(input_len))?;
+                }
+                if let Some(ecc) = ecc {
+                    header.set_ecc_params(ecc)?;
+                }
+                if let Some(pad_header) = pad_header {
+                    header.set_padded_len(pad_header)?;
+                }
+                if let Some(public_note) = &public_note {
+                    header.set_public_note(public_note.clone())?;
+                }
+                header.set_added_suffix(ext.clone())?;
+
+                let header_out = (format == Format::Split).then(|| header_sidecar(output));
+                let partial = PartialOutput::new(output);
+                let mut output_writer =
+                    EncryptedOutput::new(Some(&partial), None, Some(format), print_digest)?;
+                let _cleanup = CleanupOnInterrupt::new(&partial.path);
+                write_encrypted(
+                    &mut input_file,
+                    &mut output_writer,
+                    &header,
+                    encryptor,
+                    None,
+                    header_out.is_some(),
+                    ecc,
+                )?;
+                if let Some(header_out) = &header_out {
+                    std::fs::write(header_out, header.to_bytes())?;
+                }
+                let digest = output_writer.finish()?;
+                partial.finish()?;
+                write_signature(signing_key.as_ref(), Some(output))?;
+
+                Ok((header, digest))
+            };
+
+            // A file that can't be encrypted shouldn't stop the rest
+            let mut encrypted = Vec::new();
+            let mut failed = Vec::new();
+            for (input, output) in inputs.iter().zip(&outputs) {
+                match encrypt(input, output, &mut prompter) {
+                    Ok((header, digest)) => {
+                        if !json {
+                            prompter.info(&format!("Encrypted {input:?} to {output:?}."));
+                            if let Some(digest) = &digest {
+                                show_digest(&mut prompter, digest, Some(output));
+                            }
+                        }
+                        encrypted.push((input, output, header, digest));
+                    }
+                    Err(err) if err.is::<Cancelled>() => return Err(err),
+                    Err(err) => {
+                        prompter.notice(&format!("Failed to encrypt {input:?}: {err:#}"));
+                        failed.push((input, err));
+                    }
+                }
+            }
+            if encrypted.is_empty() {
+                bail!("none of the inputs could be encrypted");
+            }
+            if !json {
+                match failed.len() {
+                    0 => prompter.info(&format!(
+                        "Encryption successful! {} file(s) encrypted.",
+                        encrypted.len()
+                    )),
+                    _ => prompter.notice(&format!(
+                        "Warning: {} file(s) were encrypted, but {} could not be: {}.",
+                        encrypted.len(),
+                        failed.len(),
+                        failed
+                            .iter()
+                            .map(|(input, _)| format!("{input:?}"))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    )),
+                }
+                if let Some(signing_key) = &signing_key {
+                    prompter.info(&format!(
+                        "Each encrypted file was signed, with its signature beside it (made with the key {}).",
+                        signing_key.public_key()
+                    ));
+                }
+            }
+
+            Ok(vec![
+                ("options", options_json(&encrypted[0].2)),
+                (
+                    "encrypted",
+                    Json::Array(
+                        encrypted
+                            .iter()
+                            .map(|(input, output, header, digest)| {
+                                Json::object([
+                                    ("input", path_json(input)),
+                                    ("output", path_json(output)),
+                                    (
+                                        "header_out",
+                                        (format == Format::Split)
+                                            .then(|| path_json(&header_sidecar(output)))
+                                            .into(),
+                                    ),
+                                    ("header_padded_to", header.padded_len().into()),
+                                    (
+                                        "signature",
+                                        signing_key
+                                            .is_some()
+                                            .then(|| path_json(&signature_path(output)))
+                                            .into(),
+                                    ),
+                                    ("sha256", digest.clone().into()),
+                                ])
+                            })
+                            .collect(),
+                    ),
+                ),
+                (
+                    "failed",
+                    Json::Array(
+                        failed
+                            .iter()
+                            .map(|(input, err)| {
+                                Json::object([
+                                    ("input", path_json(input)),
+                                    ("error", format!("{err:#}").into()),
+                                ])
+                            })
+                            .collect(),
+                    ),
+                ),
+                ("dedup", false.into()),
+                ("format", format.name().into()),
+                ("ecc_parity", ecc.map(|ecc| u64::from(ecc.parity)).into()),
+                ("plaintext_padded_to", pad_to.map(padding_json).into()),
+                ("public_note", public_note.into()),
+                ("added_suffix", ext.into()),
+            ])
+        }
+        Command::Encrypt {
+            input,
+            output,
+            store_name,
+            comment,
+            mime,
+            store_hash,
+            store_mode,
+            decoy,
+            header_out,
+            threads,
+            format,
+            escrow_out,
+            ecc,
+            pad_header,
+            pad_to,
+            public_note,
+            ext,
+            print_digest,
+            ..
+        } => {
+            let threads = kdf_threads(threads);
+            let ecc = ecc.map(EccParams::new).transpose()?;
+            // An output directory means naming the output after the input
+            let (output, added_suffix) = match output {
+                Some(dir) if dir.is_dir() => {
+                    let mut name = input
+                        .file_name()
+                        .ok_or_else(|| anyhow!("{input:?} is not a file"))?
+                        .to_owned();
+                    name.push(&ext);
+                    (Some(dir.join(name)), Some(ext))
+                }
+                output => (output, None),
+            };
+            // A detached header means the split format, which otherwise puts it beside the output
+            let header_out = match (format, header_out) {
+                (None | Some(Format::Split), Some(header_out)) => Some(header_out),
+                (Some(_), Some(_)) => {
+                    bail!("`--header-out` can only be used with the split format")
+                }
+                (Some(Format::Split), None) => match &output {
+                    Some(output) => Some(header_sidecar(output)),
+                    None => bail!("the split format needs an output file (or `--header-out`)"),
+                },
+                (_, None) => None,
+            };
+            let format = if header_out.is_some() {
+                Format::Split
+            } else {
+                format.unwrap_or(Format::Raw)
+            };
+            let mut input_file = File::open(&input)?;
+            let input_len = known_len(&mut input_file)?;
+            let mut decoy_file = decoy.as_deref().map(File::open).transpose()?;
+            let decoy_len = decoy_file.as_mut().map(known_len).transpose()?;
+            // Don't make the user go through all the prompts only to fail at the end
+            if let Some(escrow_out) = escrow_out.as_deref().filter(|path| path.exists()) {
+                bail!("{escrow_out:?} already exists, refusing to overwrite it with an escrow key");
+            }
+            let wants_metadata =
+                store_name || comment.is_some() || mime.is_some() || store_hash || store_mode;
+            if decoy_len == Some(None) && ecc.is_some() {
+                bail!("the length of the decoy can't be known in advance (is it a pipe?), so error correction can't be used");
+            }
+            if input_len.is_none()
+                && (wants_metadata || decoy.is_some() || ecc.is_some() || pad_to.is_some())
+            {
+                bail!("the length of {input:?} can't be known in advance (is it a pipe?), so it can't be encrypted with metadata, a decoy, error correction, or padding");
+            }
+            // Offer to pick up where an interrupted encryption of this input left off (decoys,
+            // detached headers, armor, escrowed keys, error correction, and padding can't be
+            // resumed, so they never leave any state behind, and neither can inputs that can't be
+            // read again)
+            let can_resume = decoy.is_none()
+                && format == Format::Raw
+                && escrow_out.is_none()
+                && ecc.is_none()
+                && pad_to.is_none()
+                && input_len.is_some();
+            let resumable = output.as_deref().filter(|_| can_resume);
+            if let Some(partial) = resumable.map(PartialOutput::new) {
+                if partial.can_resume(&input)?
+                    && prompter.confirm(&format!(
+                        "Found an interrupted encryption of this input at {:?}. Resume it?",
+                        partial.path
+                    ))?
+                {
+                    let mut partial_file = partial.open()?;
+                    let header = Header::from_file(&mut partial_file)?;
+                    prompter.info("To resume, please satisfy any one of the file's options.");
+                    let (stream, _) =
+                        header.to_stream(&factors, None, false, DEFAULT_ATTEMPTS, &mut prompter)?;
+                    let skipped = resume_encrypt_file(
+                        &mut input_file,
+                        &mut partial_file,
+                        stream,
+                        &header.authenticated_data(),
+                    )?;
+                    partial.finish()?;
+
+                    let output = output.unwrap();
+                    if !json {
+                        prompter.info(&format!("Encryption successful (resumed after {skipped} bytes)! Output written to {output:?}."));
+                    }
+                    let signature = write_signature(signing_key.as_ref(), Some(&output))?;
+                    if let Some(signature) = signature.as_deref().filter(|_| !json) {
+                        prompter.info(&format!(
+                            "Signature written to {signature:?} (made with the key {}).",
+                            signing_key.as_ref().unwrap().public_key()
+                        ));
+                    }
+                    // Part of the output was written by an earlier run, so it has to be read back
+                    let digest = print_digest.then(|| file_digest(&output)).transpose()?;
+                    if let Some(digest) = digest.as_deref().filter(|_| !json) {
+                        show_digest(&mut prompter, digest, Some(&output));
+                    }
+
+                    return Ok(vec![
+                        ("input", path_json(&input)),
+                        ("output", path_json(&output)),
+                        ("header_out", Json::Null),
+                        ("options", options_json(&header)),
+                        ("dedup", false.into()),
+                        ("resumed_after", skipped.into()),
+                        ("format", Format::Raw.name().into()),
+                        ("escrow_out", Json::Null),
+                        ("ecc_parity", Json::Null),
+                        ("header_padded_to", header.padded_len().into()),
+                        ("plaintext_padded_to", Json::Null),
+                        ("public_note", header.public_note().into()),
+                        ("added_suffix", header.added_suffix().into()),
+                        ("signature", signature.as_deref().map(path_json).into()),
+                        ("sha256", digest.into()),
+                    ]);
+                }
+            }
+
+            let hash = if store_hash {
+                prompter.info("Hashing the input...");
+                let hash = plaintext_hash(&mut input_file)?;
+                input_file.rewind()?;
+                Some(hash)
+            } else {
+                None
+            };
+            let mode = store_mode.then(|| file_mode(&input)).transpose()?;
+            let metadata = if wants_metadata {
+                Some(FileMetadata {
+                    filename: store_name
+                        .then(|| input.file_name())
+                        .flatten()
+                        .map(|name| name.to_string_lossy().into_owned()),
+                    mime,
+                    comment,
+                    // This was checked above
+                    size: input_len.unwrap(),
+                    hash,
+                    mode,
+                })
+            } else {
+                None
+            };
+            let (mut header, encryptor, decoy_encryptor) = if decoy_file.is_some() {
+                // The input's length was checked above
+                let (header, encryptor, decoy_encryptor) = Header::with_decoy(
+                    &factors,
+                    metadata,
+                    input_len.unwrap(),
+                    threads,
+                    &mut prompter,
+                )?;
+                (header, encryptor, Some(decoy_encryptor))
+            } else {
+                let (header, encryptor) = Header::new(&factors, metadata, threads, &mut prompter)?;
+                (header, encryptor, None)
+            };
+            if let Some(escrow_out) = &escrow_out {
+                write_escrow_key(escrow_out, &header, &input, &mut prompter)?;
+            }
+            if let Some(pad_to) = pad_to {
+                header.set_plaintext_padding(pad_to)?;
+            }
+            // The body's length can only be recorded if we know how long everything in it will be
+            let body_len = match (input_len, decoy_len) {
+                (Some(input_len), None) => Some(ciphertext_len(
+                    pad_to.map_or(input_len, |pad_to| pad_to.padded_len(input_len)),
+                )),
+                (Some(input_len), Some(Some(decoy_len))) => {
+                    Some(ciphertext_len(input_len) + ciphertext_len(decoy_len))
+                }
+                _ => None,
+            };
+            if let Some(body_len) = body_len {
+                header.set_body_len(body_len)?;
+            }
+            if let Some(ecc) = ecc {
+                header.set_ecc_params(ecc)?;
+            }
+            if let Some(pad_header) = pad_header {
+                header.set_padded_len(pad_header)?;
+            }
+            if let Some(public_note) = &public_note {
+                header.set_public_note(public_note.clone())?;
+            }
+            if let Some(added_suffix) = &added_suffix {
+                header.set_added_suffix(added_suffix.clone())?;
+            }
+
+            // Output to a file goes to a `.partial` file until it's complete
+            let partial = output.as_deref().map(PartialOutput::new);
+            let mut output_writer = EncryptedOutput::new(
+                partial.as_ref(),
+                can_resume.then_some(&*input),
+                Some(format),
+                print_digest,
+            )?;
+            // If it can be resumed, an interrupted output is worth keeping
+            let _cleanup = partial
+                .as_ref()
+                .filter(|_| !can_resume)
+                .map(|partial| CleanupOnInterrupt::new(&partial.path));
+            let decoy = decoy_file
+                .as_mut()
+                .map(|file| file as &mut dyn Read)
+                .zip(decoy_encryptor);
+            write_encrypted(
+                &mut input_file,
+                &mut output_writer,
+                &header,
+                encryptor,
+                decoy,
+                header_out.is_some(),
+                ecc,
+            )?;
+            // This is only written once the body is complete, so it's never left behind by an
+            // interrupted encryption
+            if let Some(header_out) = &header_out {
+                std::fs::write(header_out, header.to_bytes())?;
+            }
+            let digest = output_writer.finish()?;
+
+            if let (Some(partial), Some(output)) = (partial, &output) {
+                partial.finish()?;
+                if !json {
+                    prompter.info(&format!(
+                        "Encryption successful! Output written to {output:?}."
+                    ));
+                }
+            }
+            let signature = write_signature(signing_key.as_ref(), output.as_deref())?;
+            if let Some(signature) = signature.as_deref().filter(|_| !json) {
+                prompter.info(&format!(
+                    "Signature written to {signature:?} (made with the key {}).",
+                    signing_key.as_ref().unwrap().public_key()
+                ));
+            }
+            if let Some(digest) = digest.as_deref().filter(|_| !json) {
+                show_digest(&mut prompter, digest, output.as_deref());
+            }
+
+            Ok(vec![
+                ("input", path_json(&input)),
+                ("output", output.as_deref().map(path_json).into()),
+                ("header_out", header_out.as_deref().map(path_json).into()),
+                ("options", options_json(&header)),
+                ("dedup", false.into()),
+                ("resumed_after", Json::Null),
+                ("format", format.name().into()),
+                ("escrow_out", escrow_out.as_deref().map(path_json).into()),
+                ("ecc_parity", ecc.map(|ecc| u64::from(ecc.parity)).into()),
+                ("header_padded_to", pad_header.into()),
+                ("plaintext_padded_to", pad_to.map(padding_json).into()),
+                ("public_note", public_note.into()),
+                ("added_suffix", added_suffix.into()),
+                ("signature", signature.as_deref().map(path_json).into()),
+                ("sha256", digest.into()),
+            ])
+        }
+        Command::Decrypt {
+            input,
+            output,
+            header_in,
+            also_stdout,
+            option,
+            auto_option,
+            attempts,
+            primary_key,
+            limit,
+            verify_hash,
+            mode,
+            format,
+            view,
+            max_memory,
+            verify_sig,
+            sig,
+        } => {
+            // The signature covers the file exactly as it was written, so it's checked before
+            // anything is read from it (and then it's decrypted from the same handle, so it can't
+            // be swapped for another file in between)
+            let mut signed_file = None;
+            let signed_by = match verify_sig {
+                Some(_) if input_url(&input).is_some() => {
+                    bail!("signatures can only be checked on local files, since the whole file has to be read before it's decrypted (download it first)")
+                }
+                Some(verify_sig) => {
+                    let key = VerifyingKey::from_file(&verify_sig)?;
+                    let sig = sig.unwrap_or_else(|| signature_path(&input));
+                    let mut file =
+                        File::open(&input).with_context(|| format!("failed to open {input:?}"))?;
+                    key.verify_file(&mut file, &input, &read_signature(&sig)?)?;
+                    signed_file = Some(file);
+                    if !json {
+                        prompter.info(&format!(
+                            "The signature {sig:?} is valid, and was made with the key {}.",
+                            key.to_hex()
+                        ));
+                    }
+                    Some(key.to_hex())
+                }
+                None => None,
+            };
+            let (header, mut input_file) = open_input(
+                &input,
+                signed_file,
+                header_in.as_deref(),
+                format,
+                max_memory,
+                &factors,
+                &mut prompter,
+            )?;
+            // Check there's a hash to verify before making the user go through any prompts
+            let stored_hash = header
+                .metadata()
+                .and_then(|metadata| metadata.hash.as_deref());
+            if verify_hash && stored_hash.is_none() {
+                bail!("no plaintext hash is stored in this file (it must be encrypted with `--store-hash`)");
+            }
+            let filename = header
+                .metadata()
+                .and_then(|metadata| metadata.filename.as_deref())
+                // Never let a stored name take us outside the output directory
+                .and_then(|filename| Path::new(filename).file_name());
+            // If we've been given a directory, restore the original filename inside it
+            let output = match (output, filename) {
+                (Some(dir), Some(filename)) if dir.is_dir() => Some(dir.join(filename)),
+                (Some(dir), None) if dir.is_dir() => match header.added_suffix() {
+                    // Otherwise, strip off exactly what was added to it
+                    Some(suffix) => Some(dir.join(strip_added_suffix(&input, suffix)?)),
+                    None => bail!("cannot decrypt into a directory, no filename or added extension was stored at encryption"),
+                },
+                (None, Some(filename)) => {
+                    if !json && !view {
+                        prompter.info(&format!("Note: the original filename was {filename:?} (pass a directory to `-o` to restore it)."));
+                    }
+                    None
+                }
+                (output, _) => output,
+            };
+
+            let body_decryptor = match primary_key {
+                Some(primary_key) => {
+                    prompter.notice("Warning: decrypting with an escrowed primary key, bypassing all of the file's options.");
+                    BodyDecryptor::from_escrow_key(&header, &read_escrow_key(&primary_key)?)?
+                }
+                None => BodyDecryptor::from_header(
+                    &header,
+                    &factors,
+                    option.as_deref(),
+                    auto_option,
+                    attempts.get(),
+                    &mut prompter,
+                )?,
+            };
+            // Output to a file is written to a temporary file first, and only moved into place once
+            // the whole ciphertext has been authenticated, so a failed decryption never leaves
+            // partial plaintext behind. Something that isn't a regular file (like a named pipe)
+            // can't be replaced like that, so it has to be written to as we go instead.
+            let streamed_output = output
+                .as_deref()
+                .filter(|output| is_streamed_output(output));
+            let mut streamed_file = match streamed_output {
+                Some(output) => {
+                    if mode.is_some() {
+                        bail!("{output:?} isn't a regular file, so its permissions can't be set");
+                    }
+                    prompter.notice(&format!("WARNING: {output:?} isn't a regular file (is it a named pipe?), so the plaintext will be written to it as it's decrypted. If decryption fails part-way through, what was already written is NOT authentic, and whatever's reading it must discard it."));
+                    Some(OpenOptions::new().write(true).open(output)?)
+                }
+                None => None,
+            };
+            let mut temp_file = output
+                .as_deref()
+                .filter(|_| streamed_output.is_none())
+                .map(temp_file_beside)
+                .transpose()?;
+            let _cleanup = temp_file
+                .as_ref()
+                .map(|temp_file| CleanupOnInterrupt::new(temp_file.path()));
+            let to_stdout = !view && (output.is_none() || also_stdout);
+            let mut hasher = verify_hash.then(PlaintextHasher::default);
+            let mut pager = if view {
+                let len = header
+                    .metadata()
+                    .map(|metadata| metadata.size)
+                    .or(header.body_len());
+                if let Some(len) = len.filter(|&len| len > VIEW_WARN_LEN) {
+                    prompter.notice(&format!("Warning: this file is about {len} bytes long, which is a lot to view in a pager."));
+                }
+                Some(Pager::spawn()?)
+            } else {
+                None
+            };
+            let res = {
+                let mut sinks: Vec<Box<dyn Write + '_>> = Vec::new();
+                if let Some(hasher) = &mut hasher {
+                    sinks.push(Box::new(hasher));
+                }
+                if let Some(pager) = &mut pager {
+                    sinks.push(Box::new(pager));
+                }
+                if let Some(temp_file) = &mut temp_file {
+                    sinks.push(Box::new(temp_file.as_file_mut()));
+                }
+                if let Some(streamed_file) = &mut streamed_file {
+                    sinks.push(Box::new(streamed_file));
+                }
+                if to_stdout {
+                    sinks.push(Box::new(StdoutWriter::new()));
+                }
+                body_decryptor.decrypt(
+                    &mut input_file,
+                    &mut Tee(sinks),
+                    &header.authenticated_data(),
+                    limit,
+                )
+            };
+            // Give the terminal back before printing anything else
+            if let Some(pager) = pager {
+                pager.wait()?;
+            }
+            // Authentication has already passed, so a different hash means the plaintext isn't what
+            // whoever encrypted the file said it was
+            let res = res.and_then(|complete| {
+                if hasher.is_some_and(|hasher| Some(hasher.finish().as_slice()) != stored_hash) {
+                    return Err(anyhow::Error::new(AuthenticationFailed)
+                        .context("the plaintext doesn't match the hash stored in the header"));
+                }
+                Ok(complete)
+            });
+            let complete = match res {
+                Ok(complete) => complete,
+                Err(err) => {
+                    // The temporary file will be deleted when it's dropped, but we can't take back
+                    // what we've already written to stdout (unless nothing's reading it anymore)
+                    if to_stdout && !is_broken_pipe(&err) {
+                        prompter.notice("WARNING: decryption failed, but some plaintext may already have been written to stdout! It is NOT authentic, and should be discarded.");
+                    } else if let Some(output) = streamed_output {
+                        prompter.notice(&format!("WARNING: decryption failed, but some plaintext may already have been written to {output:?}! It is NOT authentic, and should be discarded."));
+                    } else if view {
+                        prompter.notice("WARNING: decryption failed, but some plaintext may already have been shown in the pager! It is NOT authentic, and should be disregarded.");
+                    }
+                    return Err(err);
+                }
+            };
+            if let (Some(temp_file), Some(output)) = (temp_file, &output) {
+                // The temporary file is only readable by us until the plaintext is all there
+                let stored_mode = header.metadata().and_then(|metadata| metadata.mode);
+                if let Some(mode) = mode.or(stored_mode) {
+                    set_file_mode(temp_file.as_file(), mode)?;
+                }
+                temp_file.persist(output)?;
+            }
+            if !complete {
+                prompter.notice(&format!("WARNING: stopped after {} bytes, so the rest of the file was not checked. Each chunk written was authenticated, but truncation or tampering later in the file will not have been detected.", limit.unwrap()));
+            }
+
+            if let Some(output) = &output {
+                if !json {
+                    prompter.info(&format!(
+                        "Decryption successful! Output written to {output:?}."
+                    ));
+                }
+            }
+            if verify_hash && !json {
+                prompter.info("The plaintext matches the hash stored in the header.");
+            }
+
+            Ok(vec![
+                ("input", path_json(&input)),
+                ("output", output.as_deref().map(path_json).into()),
+                ("complete", complete.into()),
+                ("hash_verified", verify_hash.into()),
+                ("signed_by", signed_by.into()),
+            ])
+        }
+        Command::Verify {
+            input,
+            header_in,
+            option,
+            auto_option,
+            attempts,
+            format,
+            max_memory,
+        } => {
+            let (header, mut input_file) = open_input(
+                &input,
+                None,
+                header_in.as_deref(),
+                format,
+                max_memory,
+                &factors,
+                &mut prompter,
+            )?;
+            let verified = BodyDecryptor::from_header(
+                &header,
+                &factors,
+                option.as_deref(),
+                auto_option,
+                attempts.get(),
+                &mut prompter,
+            )?
+            .verify(&mut input_file, &header.authenticated_data())?;
+
+            let hash = header
+                .metadata()
+                .and_then(|metadata| metadata.hash.as_deref())
+                .map(hex::encode);
+            if !json {
+                // The padding can't be told apart from the plaintext without decrypting it
+                let padded = if header.plaintext_padding().is_some() {
+                    " padded"
+                } else {
+                    ""
+                };
+                prompter.info(&format!(
+                    "Verification successful! All {verified} bytes of{padded} plaintext are intact."
+                ));
+                if let Some(hash) = &hash {
+                    prompter.info(&format!("Stored plaintext hash (BLAKE2b-512): {hash}"));
+                }
+            }
+
+            Ok(vec![
+                ("input", path_json(&input)),
+                ("bytes_verified", verified.into()),
+                ("hash", hash.into()),
+            ])
+        }
+        Command::Info { input } => {
+            let header = Header::from_file(&mut File::open(&input)?)?;
+
+            if !json {
+                let mut notes = Vec::new();
+                if header.dedup_params().is_some() {
+                    notes.push(
+                        "Encrypted in dedup mode (independent, content-defined chunks)."
+                            .to_string(),
+                    );
+                }
+                if let Some(ecc) = header.ecc_params() {
+                    notes.push(format!(
+                        "Error correction: {} parity bytes in every 255 (up to {} corrected).",
+                        ecc.parity,
+                        ecc.parity / 2
+                    ));
+                }
+                if let Some(padded_len) = header.padded_len() {
+                    notes.push(format!(
+                        "Header padded to (a multiple of) {padded_len} bytes."
+                    ));
+                }
+                if let Some(scheme) = header.plaintext_padding() {
+                    notes.push(format!(
+                        "Plaintext padded {scheme} (its true length is encrypted)."
+                    ));
+                }
+                if let Some(note) = header.public_note() {
+                    notes.push(format!("Public note (unverified until decryption): {note}"));
+                }
+                if let Some(suffix) = header.added_suffix() {
+                    notes.push(format!(
+                        "Named by adding {suffix:?} to the original filename."
+                    ));
+                }
+                if let Some(metadata) = header.metadata() {
+                    notes.push("File metadata (unverified until decryption):".to_string());
+                    if let Some(filename) = &metadata.filename {
+                        notes.push(format!("  Filename: {filename}"));
+                    }
+                    if let Some(mime) = &metadata.mime {
+                        notes.push(format!("  Type: {mime}"));
+                    }
+                    if let Some(comment) = &metadata.comment {
+                        notes.push(format!("  Comment: {comment}"));
+                    }
+                    notes.push(format!("  Size: {} bytes", metadata.size));
+                    if let Some(hash) = &metadata.hash {
+                        notes.push(format!("  Hash (BLAKE2b-512): {}", hex::encode(hash)));
+                    }
+                    if let Some(mode) = metadata.mode {
+                        notes.push(format!("  Permissions: {mode:04o}"));
+                    }
+                }
 
-            if let Some(output) = output {
-                eprintln!("Encryption successful! Output written to {output:?}.");
+                #[cfg(feature = "tui")]
+                if prompter.tui {
+                    prompter.browse_options(&header, &factors, &notes)?;
+                } else {
+                    print_info(&header, &notes);
+                }
+                #[cfg(not(feature = "tui"))]
+                print_info(&header, &notes);
             }
+
+            Ok(vec![
+                ("input", path_json(&input)),
+                ("options", options_json(&header)),
+                ("dedup", header.dedup_params().is_some().into()),
+                (
+                    "ecc_parity",
+                    header.ecc_params().map(|ecc| u64::from(ecc.parity)).into(),
+                ),
+                ("header_padded_to", header.padded_len().into()),
+                (
+                    "plaintext_padded_to",
+                    header.plaintext_padding().map(padding_json).into(),
+                ),
+                ("public_note", header.public_note().into()),
+                ("added_suffix", header.added_suffix().into()),
+                (
+                    "metadata",
+                    header
+                        .metadata()
+                        .map(|metadata| {
+                            Json::object([
+                                ("filename", metadata.filename.clone().into()),
+                                ("mime", metadata.mime.clone().into()),
+                                ("comment", metadata.comment.clone().into()),
+                                ("size", metadata.size.into()),
+                                ("hash", metadata.hash.as_deref().map(hex::encode).into()),
+                                (
+                                    "mode",
+                                    metadata.mode.map(|mode| format!("{mode:04o}")).into(),
+                                ),
+                            ])
+                        })
+                        .into(),
+                ),
+            ])
         }
-        Command::Decrypt { input, output } => {
-            let mut input = File::open(&input)?;
-            let header = Header::from_file(&mut input)?;
-            let decryptor = header.to_decryptor(&factors)?;
-            decrypt_file(&mut input, output.as_deref(), decryptor)?;
+        Command::CheckFactors { input } => {
+            let header = Header::from_file(&mut File::open(&input)?)?;
+            if !json {
+                prompter.info("Probing each option's factors (nothing will be decrypted)...");
+            }
+            let probes = header.probe_factors(&factors);
+            let failed = probes.iter().filter(|probe| probe.result.is_err()).count();
 
-            if let Some(output) = output {
-                eprintln!("Decryption successful! Output written to {output:?}.");
+            if !json {
+                let rows = probes
+                    .iter()
+                    .map(|probe| {
+                        let status = match &probe.result {
+                            Ok(Some(found)) => format!("ok: {found}"),
+                            Ok(None) => "nothing to check (needs only you)".to_string(),
+                            Err(err) => format!("FAILED: {err:#}"),
+                        };
+                        (
+                            format!("{} / {}", probe.option, probe.factor),
+                            status,
+                            probe.elapsed.as_millis(),
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                let width = rows
+                    .iter()
+                    .map(|(factor, _, _)| factor.chars().count())
+                    .chain(["Option / factor".len()])
+                    .max()
+                    .unwrap();
+                println!("{:width$}  {:>8}  Status", "Option / factor", "Time");
+                for (factor, status, millis) in rows {
+                    println!("{factor:width$}  {:>8}  {status}", format!("{millis} ms"));
+                }
+                if failed == 0 {
+                    prompter.info("Every factor that could be checked is ready.");
+                } else {
+                    prompter.notice(&format!(
+                        "Warning: {failed} factor(s) can't be used right now, so the options they're in may not be satisfiable."
+                    ));
+                }
             }
+
+            Ok(vec![
+                ("input", path_json(&input)),
+                (
+                    "factors",
+                    Json::Array(
+                        probes
+                            .iter()
+                            .map(|probe| {
+                                let (status, detail) = match &probe.result {
+                                    Ok(Some(found)) => ("ok", Some(found.clone())),
+                                    Ok(None) => ("unchecked", None),
+                                    Err(err) => ("failed", Some(format!("{err:#}"))),
+                                };
+                                Json::object([
+                                    ("option", probe.option.into()),
+                                    ("factor", probe.factor.into()),
+                                    ("status", status.into()),
+                                    ("detail", detail.into()),
+                                    ("millis", (probe.elapsed.as_millis() as u64).into()),
+                                ])
+                            })
+                            .collect(),
+                    ),
+                ),
+                ("failed", (failed as u64).into()),
+            ])
         }
+        Command::Estimate { input, max_memory } => {
+            let header = Header::from_file(&mut File::open(&input)?)?;
+            check_memory(
+                &header,
+                max_memory.map(|mib| mib.saturating_mul(1024 * 1024)),
+                &factors,
+                &mut prompter,
+            )?;
+            if !json {
+                prompter.info("Timing each option's key derivation (nothing will be decrypted)...");
+            }
+            let estimates = header.estimate_options(&factors, &mut prompter)?;
+
+            if !json {
+                let rows = estimates
+                    .iter()
+                    .map(|estimate| {
+                        let mut needs = vec![format!("{} factor(s)", estimate.factors_needed)];
+                        if estimate.gates > 0 {
+                            needs.push(format!("{} gate(s)", estimate.gates));
+                        }
+                        if estimate.requires_network {
+                            needs.push("network access".to_string());
+                        }
+                        if let Err(err) = &estimate.usable {
+                            needs.push(format!("UNUSABLE: {err:#}"));
+                        }
+                        (
+                            estimate.name,
+                            format!("{:.2}s", estimate.kdf_time.as_secs_f64()),
+                            format!("{} MiB", estimate.memory.div_ceil(1024 * 1024)),
+                            estimate.kdf,
+                            needs.join(", "),
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                let width = rows
+                    .iter()
+                    .map(|(name, _, _, _, _)| name.chars().count())
+                    .chain(["Option".len()])
+                    .max()
+                    .unwrap();
+                println!(
+                    "{:width$}  {:>8}  {:>9}  {:8}  Needs",
+                    "Option", "Time", "Memory", "KDF"
+                );
+                for (name, time, memory, kdf, needs) in rows {
+                    println!("{name:width$}  {time:>8}  {memory:>9}  {kdf:8}  {needs}");
+                }
+                if estimates.iter().any(|estimate| estimate.usable.is_ok()) {
+                    prompter.info("Times are for the key derivation alone, so add however long it takes you to satisfy each factor.");
+                } else {
+                    prompter.notice("Warning: none of the options can be used with this build.");
+                }
+            }
+
+            Ok(vec![
+                ("input", path_json(&input)),
+                (
+                    "options",
+                    Json::Array(
+                        estimates
+                            .iter()
+                            .map(|estimate| {
+                                Json::object([
+                                    ("name", estimate.name.into()),
+                                    ("kdf", estimate.kdf.into()),
+                                    ("kdf_millis", (estimate.kdf_time.as_millis() as u64).into()),
+                                    ("memory", estimate.memory.into()),
+                                    ("gates", (estimate.gates as u64).into()),
+                                    ("factors_needed", (estimate.factors_needed as u64).into()),
+                                    ("requires_network", estimate.requires_network.into()),
+                                    (
+                                        "unusable",
+                                        estimate
+                                            .usable
+                                            .as_ref()
+                                            .err()
+                                            .map(|err| format!("{err:#}"))
+                                            .into(),
+                                    ),
+                                ])
+                            })
+                            .collect(),
+                    ),
+                ),
+            ])
+        }
+        Command::Extract {
+            input,
+            unsafe_show_key,
+            option,
+            auto_option,
+            attempts,
+        } => {
+            if !unsafe_show_key {
+                bail!("this shows the file's key, which decrypts it without any of its factors, so it needs `--unsafe-show-key`");
+            }
+            let mut input_file = File::open(&input)?;
+            let header = Header::from_file(&mut input_file)?;
+            if header.ecc_params().is_some() {
+                bail!("this file has error correction, so its ciphertext isn't stored as-is (decrypt it normally instead)");
+            }
+            let body_start = input_file.stream_position()?;
+            let file_len = input_file.metadata()?.len();
+
+            prompter.notice("Warning: this will show the file's key, which decrypts it without any of its factors. Don't do this anywhere the output could be seen or saved by someone else.");
+            let raw = header.unlock_raw_stream(
+                &factors,
+                option.as_deref(),
+                auto_option,
+                attempts.get(),
+                &mut prompter,
+            )?;
+            let stream_start = body_start + raw.location.offset;
+            let stream_len = raw
+                .location
+                .len
+                .unwrap_or(file_len.saturating_sub(stream_start));
+
+            if !json {
+                println!("Algorithm:    ChaCha20-Poly1305 STREAM (nonce = prefix, 32-bit big-endian chunk index, 1 for the last chunk or 0)");
+                println!(
+                    "Chunks:       {} bytes of plaintext, each followed by a 16-byte tag",
+                    raw.chunk_len
+                );
+                println!("Key:          {}", hex::encode(&raw.key));
+                println!("Nonce prefix: {}", hex::encode(raw.nonce_prefix));
+                println!("AAD:          {}", hex::encode(raw.aad));
+                println!(
+                    "Ciphertext:   {stream_len} bytes from offset {stream_start} of {input:?}"
+                );
+                if let Some(scheme) = header.plaintext_padding() {
+                    println!("Plaintext:    padded {scheme}, after its true length (8 bytes, little-endian)");
+                }
+                prompter.notice(
+                    "Anyone with this key can decrypt the file, so re-encrypt it if it leaks.",
+                );
+            }
+
+            Ok(vec![
+                ("input", path_json(&input)),
+                ("algorithm", "chacha20poly1305-stream-be32".into()),
+                ("chunk_len", raw.chunk_len.into()),
+                ("key", hex::encode(&raw.key).into()),
+                ("nonce_prefix", hex::encode(raw.nonce_prefix).into()),
+                ("aad", hex::encode(raw.aad).into()),
+                ("offset", stream_start.into()),
+                ("len", stream_len.into()),
+                (
+                    "plaintext_padded_to",
+                    header.plaintext_padding().map(padding_json).into(),
+                ),
+            ])
+        }
+        Command::ChangePassphrase { input, option } => {
+            let mut header = Header::from_file(&mut File::open(&input)?)?;
+
+            let passphrase_options = header
+                .option_summaries()
+                .into_iter()
+                .filter(|summary| summary.factors.contains(&PassphraseFactor::name()))
+                .map(|summary| summary.name.to_string())
+                .collect::<Vec<_>>();
+            let option = match option {
+                Some(option) if passphrase_options.contains(&option) => option,
+                Some(option) => bail!("option '{option}' doesn't exist or has no passphrase"),
+                None if passphrase_options.is_empty() => {
+                    bail!("none of the options in this file have a passphrase")
+                }
+                None => {
+                    let items = passphrase_options
+                        .iter()
+                        .map(String::as_str)
+                        .collect::<Vec<_>>();
+                    let idx =
+                        prompter.select("Choose an option to change the passphrase of", &items)?;
+                    passphrase_options[idx].clone()
+                }
+            };
+
+            header.recreate_factor(&option, PassphraseFactor::name(), &factors, &mut prompter)?;
+            header.rewrite_in_file(&input)?;
+
+            if !json {
+                prompter.info(&format!("Passphrase changed for option '{option}'!"));
+            }
+
+            Ok(vec![
+                ("input", path_json(&input)),
+                ("option", option.into()),
+            ])
+        }
+        #[cfg(feature = "ephemeral")]
+        Command::RefreshEphemeral {
+            input,
+            option,
+            auto_option,
+            attempts,
+        } => {
+            let mut header = Header::from_file(&mut File::open(&input)?)?;
+
+            let ephemeral_options = header
+                .option_summaries()
+                .into_iter()
+                .filter(|summary| summary.factors.contains(&EphemeralFactor::name()))
+                .map(|summary| summary.name.to_string())
+                .collect::<Vec<_>>();
+            if ephemeral_options.is_empty() {
+                bail!("none of the options in this file have ephemeral data");
+            }
+
+            // This is only needed to revive options whose ephemeral data has already expired
+            prompter.info("First, satisfy one of the options, to recover the file's key.");
+            let escrow_key = header.unlock_escrow_key(
+                &factors,
+                option.as_deref(),
+                auto_option,
+                attempts.get(),
+                &mut prompter,
+            )?;
+
+            let mut refreshed = Vec::new();
+            let mut revived = Vec::new();
+            let mut failed = Vec::new();
+            for name in ephemeral_options {
+                prompter.info(&format!("Refreshing option '{name}'..."));
+                match header.refresh_factor(
+                    &name,
+                    EphemeralFactor::name(),
+                    &escrow_key,
+                    &factors,
+                    &mut prompter,
+                ) {
+                    Ok(still_worked) => {
+                        if !still_worked {
+                            revived.push(name.clone());
+                        }
+                        refreshed.push(name);
+                    }
+                    Err(err) if err.is::<Cancelled>() => return Err(err),
+                    Err(err) => {
+                        prompter.notice(&format!(
+                            "Option '{name}' couldn't be refreshed, and may be unusable: {err:#}"
+                        ));
+                        failed.push(name);
+                    }
+                }
+            }
+            if refreshed.is_empty() {
+                bail!("none of the options with ephemeral data could be refreshed");
+            }
+            header.rewrite_in_file(&input)?;
+
+            if !json {
+                prompter.info(&format!(
+                    "Refreshed the ephemeral data of {} option(s): {}",
+                    refreshed.len(),
+                    refreshed.join(", ")
+                ));
+                if !revived.is_empty() {
+                    prompter.notice(&format!(
+                        "These option(s) were unusable, as their ephemeral data had expired, but work again now: {}",
+                        revived.join(", ")
+                    ));
+                }
+            }
+
+            Ok(vec![
+                ("input", path_json(&input)),
+                ("refreshed", refreshed.into()),
+                ("revived", revived.into()),
+                ("failed", failed.into()),
+            ])
+        }
+        Command::MergeOptions {
+            primary,
+            secondary,
+            attempts,
+        } => {
+            let mut primary_file = File::open(&primary)?;
+            let mut header = Header::from_file(&mut primary_file)?;
+            let mut secondary_file = File::open(&secondary)?;
+            let secondary_header = Header::from_file(&mut secondary_file)?;
+
+            prompter.info(&format!(
+                "First, satisfy one of the options of {}.",
+                primary.display()
+            ));
+            let escrow_key =
+                header.unlock_escrow_key(&factors, None, false, attempts.get(), &mut prompter)?;
+            prompter.info(&format!(
+                "Now satisfy one of the options of {}.",
+                secondary.display()
+            ));
+            let secondary_escrow_key = secondary_header.unlock_escrow_key(
+                &factors,
+                None,
+                false,
+                attempts.get(),
+                &mut prompter,
+            )?;
+
+            // Otherwise, the merged options would decrypt something their owners never encrypted
+            prompter.info("Checking that both files hold the same plaintext...");
+            let hash = body_hash(
+                &header,
+                &escrow_key,
+                &mut body_reader(&header, primary_file)?,
+            )?;
+            let secondary_hash = body_hash(
+                &secondary_header,
+                &secondary_escrow_key,
+                &mut body_reader(&secondary_header, secondary_file)?,
+            )?;
+            if hash != secondary_hash {
+                bail!(
+                    "{} and {} don't hold the same plaintext, so their options can't be merged",
+                    primary.display(),
+                    secondary.display()
+                );
+            }
+
+            let added = header.merge_options(
+                &escrow_key,
+                secondary_header,
+                &secondary_escrow_key,
+                &mut prompter,
+            )?;
+            header.rewrite_in_file(&primary)?;
+
+            if !json {
+                prompter.info(&format!(
+                    "Merged {} option(s) into {}: {}",
+                    added.len(),
+                    primary.display(),
+                    added.join(", ")
+                ));
+            }
+
+            Ok(vec![
+                ("primary", path_json(&primary)),
+                ("secondary", path_json(&secondary)),
+                ("added", added.into()),
+            ])
+        }
+        Command::Send {
+            input,
+            addr,
+            threads,
+        } => {
+            let threads = kdf_threads(threads);
+            let mut input_file = File::open(&input)?;
+            let input_len = known_len(&mut input_file)?;
+            let (mut header, encryptor) = Header::new(&factors, None, threads, &mut prompter)?;
+            // This lets the receiver tell a dropped connection from a complete file
+            if let Some(input_len) = input_len {
+                header.set_body_len(ciphertext_len(input_len))?;
+            }
+
+            prompter.info(&format!("Connecting to {addr}..."));
+            let stream = TcpStream::connect(&addr)
+                .with_context(|| format!("failed to connect to {addr}"))?;
+            prompter.info("Connected, sending the file...");
+            let mut writer = BufWriter::new(&stream);
+            encrypt_file(&mut input_file, &mut writer, &header, encryptor, None)
+                .and_then(|_| Ok(writer.flush()?))
+                .map_err(|err| connection_lost(err, &addr))?;
+            drop(writer);
+            stream.shutdown(Shutdown::Write)?;
+
+            // The receiver only acknowledges the file once it's been authenticated and written
+            prompter.info("Waiting for the receiver to decrypt the file...");
+            // If it fails, it just drops the connection, which can reset it rather than closing it
+            let mut ack = [0u8; 1];
+            if !matches!((&stream).read(&mut ack), Ok(1)) || ack[0] != RECEIVED_ACK {
+                bail!("the receiver didn't confirm that it decrypted the file (check its output for why)");
+            }
+            if !json {
+                prompter.info(&format!("Sent successfully to {addr}!"));
+            }
+
+            Ok(vec![
+                ("input", path_json(&input)),
+                ("addr", addr.into()),
+                ("options", options_json(&header)),
+            ])
+        }
+        Command::Receive {
+            bind,
+            output,
+            option,
+            auto_option,
+            attempts,
+            max_memory,
+        } => {
+            let max_memory = max_memory.map(|mib| mib.saturating_mul(1024 * 1024));
+            let listener =
+                TcpListener::bind(&bind).with_context(|| format!("failed to listen on {bind}"))?;
+            prompter.info(&format!(
+                "Waiting for a connection on {}...",
+                listener.local_addr()?
+            ));
+            let (stream, peer) = listener.accept()?;
+            let peer = peer.to_string();
+            prompter.info(&format!("Receiving from {peer}..."));
+
+            let mut reader = BufReader::new(&stream);
+            // Whoever connects chooses the header, so it's held to the limit before anything in it
+            // is acted on
+            let header = Header::from_file_with_limit(&mut reader, max_memory.unwrap_or(u64::MAX))
+                .map_err(|err| connection_lost(err, &peer))?;
+            check_memory(&header, max_memory, &factors, &mut prompter)?;
+            let body_decryptor = BodyDecryptor::from_header(
+                &header,
+                &factors,
+                option.as_deref(),
+                auto_option,
+                attempts.get(),
+                &mut prompter,
+            )?;
+            // Like decryption to a file, nothing's written to the output until it's authentic
+            let mut temp_file = temp_file_beside(&output)?;
+            let _cleanup = CleanupOnInterrupt::new(temp_file.path());
+            body_decryptor
+                .decrypt(
+                    &mut body_reader(&header, reader)?,
+                    temp_file.as_file_mut(),
+                    &header.authenticated_data(),
+                    None,
+                )
+                .map_err(|err| connection_lost(err, &peer))?;
+            temp_file.persist(&output)?;
+            // The sender can't tell whether we succeeded otherwise (a failure just drops the
+            // connection without this)
+            (&stream).write_all(&[RECEIVED_ACK])?;
+
+            if !json {
+                prompter.info(&format!(
+                    "Received successfully! Output written to {output:?}."
+                ));
+            }
+
+            Ok(vec![("output", path_json(&output)), ("peer", peer.into())])
+        }
+        Command::ListFactors => {
+            let mut factors = factors.values().collect::<Vec<_>>();
+            factors.sort_by_key(|factor| factor.name());
+
+            if !json {
+                let width = factors
+                    .iter()
+                    .map(|factor| factor.name().chars().count())
+                    .max()
+                    .unwrap_or(0);
+                for factor in &factors {
+                    let (name, description) = (factor.name(), factor.description());
+                    let network = if factor.requires_network() {
+                        " (needs network access)"
+                    } else {
+                        ""
+                    };
+                    let available = match factor.available() {
+                        Ok(()) => String::new(),
+                        Err(err) => format!(" [unavailable: {err:#}]"),
+                    };
+                    println!("{name:width$}  {description}{network}{available}");
+                }
+            }
+
+            Ok(vec![(
+                "factors",
+                Json::Array(
+                    factors
+                        .into_iter()
+                        .map(|factor| {
+                            let unavailable = factor.available().err();
+                            Json::object([
+                                ("name", factor.name().into()),
+                                ("description", factor.description().into()),
+                                ("requires_network", factor.requires_network().into()),
+                                ("available", unavailable.is_none().into()),
+                                (
+                                    "unavailable_reason",
+                                    unavailable.map(|err| format!("{err:#}")).into(),
+                                ),
+                            ])
+                        })
+                        .collect(),
+                ),
+            )])
+        }
+        Command::SelfTest { skip } => {
+            let mut names = factors.keys().copied().collect::<Vec<_>>();
+            names.sort();
+
+            let mut results = Vec::new();
+            for name in names {
+                if skip.iter().any(|skipped| skipped == name) {
+                    results.push((name, "skip", None));
+                    continue;
+                }
+                // A factor that can't be created here isn't broken, it just can't be tested
+                if let Err(err) = factors[name].available() {
+                    results.push((name, "unavailable", Some(format!("{err:#}"))));
+                    continue;
+                }
+
+                prompter.info(&format!(
+                    "Testing factor '{name}' (follow the prompts to create and then re-derive it):"
+                ));
+                match round_trip(factors[name].as_ref(), &mut prompter) {
+                    Ok(_) => results.push((name, "pass", None)),
+                    Err(err) if err.is::<Cancelled>() => return Err(err),
+                    Err(err) => results.push((name, "fail", Some(format!("{err:#}")))),
+                }
+            }
+
+            if !json {
+                for (name, status, err) in &results {
+                    let status = status.to_uppercase();
+                    match err {
+                        Some(err) => println!("{status}  {name} ({err})"),
+                        None => println!("{status}  {name}"),
+                    }
+                }
+            }
+            let failed = results
+                .iter()
+                .filter(|(_, status, _)| *status == "fail")
+                .map(|(name, _, _)| *name)
+                .collect::<Vec<_>>();
+            if !failed.is_empty() {
+                bail!("some factors failed the self-test: {}", failed.join(", "));
+            }
+
+            Ok(vec![(
+                "factors",
+                Json::Array(
+                    results
+                        .into_iter()
+                        .map(|(name, status, err)| {
+                            Json::object([
+                                ("name", name.into()),
+                                ("status", status.into()),
+                                ("error", err.into()),
+                            ])
+                        })
+                        .collect(),
+                ),
+            )])
+        }
+        Command::CheckVectors { write: Some(dir) } => {
+            let dir = dir.join(format!("v{FORMAT_VERSION}"));
+            std::fs::create_dir_all(&dir)?;
+            let mut written = Vec::new();
+            for name in vectors::VECTORS {
+                let (file, keyfile) = vectors::generate(name)
+                    .with_context(|| format!("failed to generate the '{name}' test vector"))?;
+                let path = dir.join(format!("{name}.cyst"));
+                std::fs::write(&path, file)?;
+                written.push(path_json(&path));
+                if let Some(keyfile) = keyfile {
+                    let path = dir.join(format!("{name}.key"));
+                    std::fs::write(&path, keyfile)?;
+                    written.push(path_json(&path));
+                }
+            }
+            if !json {
+                prompter.info(&format!(
+                    "Wrote the test vectors for format version {FORMAT_VERSION} to {dir:?}."
+                ));
+            }
+
+            Ok(vec![
+                ("format_version", u64::from(FORMAT_VERSION).into()),
+                ("written", Json::Array(written)),
+            ])
+        }
+        Command::CheckVectors { write: None } => {
+            prompter.info("Checking the test vectors (this may take a while)...");
+            let passed = vectors::check()?;
+            if !json {
+                for check in &passed {
+                    prompter.info(check);
+                }
+                prompter.info(&format!(
+                    "All test vectors passed! This build writes version {FORMAT_VERSION} of the file format."
+                ));
+            }
+
+            Ok(vec![
+                ("format_version", u64::from(FORMAT_VERSION).into()),
+                ("passed", passed.into()),
+            ])
+        }
+        Command::HeaderSchema => {
+            let fields_json = |fields: &[SchemaField]| {
+                Json::Array(
+                    fields
+                        .iter()
+                        .map(|field| {
+                            Json::object([
+                                ("name", field.name.into()),
+                                ("type", field.ty.into()),
+                                ("doc", field.doc.into()),
+                            ])
+                        })
+                        .collect(),
+                )
+            };
+            let types = schema::TYPES
+                .iter()
+                .map(|ty| {
+                    let (kind, members) = match &ty.kind {
+                        SchemaKind::Struct(fields) => ("struct", ("fields", fields_json(fields))),
+                        SchemaKind::Enum(variants) => (
+                            "enum",
+                            (
+                                "variants",
+                                Json::Array(
+                                    variants
+                                        .iter()
+                                        .enumerate()
+                                        .map(|(idx, variant)| {
+                                            Json::object([
+                                                ("index", (idx as u64).into()),
+                                                ("name", variant.name.into()),
+                                                ("fields", fields_json(variant.fields)),
+                                            ])
+                                        })
+                                        .collect(),
+                                ),
+                            ),
+                        ),
+                    };
+                    Json::object([
+                        ("name", ty.name.into()),
+                        ("doc", ty.doc.into()),
+                        ("kind", kind.into()),
+                        members,
+                    ])
+                })
+                .collect();
+            let encoding = schema::ENCODING
+                .iter()
+                .map(|(ty, encoding)| {
+                    Json::object([("type", (*ty).into()), ("encoding", (*encoding).into())])
+                })
+                .collect();
+            println!(
+                "{}",
+                Json::object([
+                    ("format_version", u64::from(FORMAT_VERSION).into()),
+                    ("root", schema::ROOT.into()),
+                    ("file_layout", schema::FILE_LAYOUT.to_vec().into()),
+                    ("encoding", Json::Array(encoding)),
+                    ("types", Json::Array(types)),
+                ])
+            );
+
+            Ok(vec![("format_version", u64::from(FORMAT_VERSION).into())])
+        }
+        Command::Calibrate { target, memory } => {
+            let target = Duration::try_from_secs_f64(target)
+                .ok()
+                .filter(|target| !target.is_zero())
+                .ok_or(anyhow!("the target must be a positive number of seconds"))?;
+
+            prompter.info(&format!(
+                "Timing Argon2 to find parameters that take about {:.2}s (this may take a while)...",
+                target.as_secs_f64()
+            ));
+            let calibration = calibrate(target, memory, &mut prompter)?;
+
+            if !json {
+                if calibration.duration > target {
+                    prompter.notice(&format!("Warning: even the least memory we'd suggest takes {:.2}s with a single iteration, which is longer than the target.", calibration.duration.as_secs_f64()));
+                }
+                println!(
+                    "Recommended Argon2id parameters (took {:.2}s on this machine):",
+                    calibration.duration.as_secs_f64()
+                );
+                println!(
+                    "  Memory: {} MiB (m_cost = {} KiB)",
+                    calibration.memory_mib,
+                    calibration.memory_mib * 1024
+                );
+                println!("  Iterations: {} (t_cost)", calibration.iterations);
+            }
+
+            Ok(vec![
+                ("memory_mib", u64::from(calibration.memory_mib).into()),
+                ("iterations", u64::from(calibration.iterations).into()),
+                (
+                    "duration_ms",
+                    (calibration.duration.as_millis() as u64).into(),
+                ),
+            ])
+        }
+    }
+}
+
+/// Adds an explanation to the given error if it happened because the connection to the given peer
+/// was lost part-way through a transfer (e.g. the other end was killed), which otherwise just looks
+/// like a truncated file or an obscure I/O error.
+fn connection_lost(err: anyhow::Error, peer: &str) -> anyhow::Error {
+    let lost = err.chain().any(|cause| {
+        cause.downcast_ref::<io::Error>().is_some_and(|err| {
+            matches!(
+                err.kind(),
+                io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::UnexpectedEof
+            )
+        })
+    });
+    if lost {
+        err.context(format!(
+            "the connection to {peer} was lost before the whole file was transferred"
+        ))
+    } else {
+        err
+    }
+}
+
+/// Opens the given encrypted input and reads its header, returning the header and a reader
+/// positioned at the start of the body. If the input has already been opened (e.g. to check its
+/// signature), it's read from that handle, rather than being opened again.
+///
+/// If the format isn't given, it's worked out from the input. Armored input is recognised by its
+/// first line. Otherwise, the input is split if a detached header was given, or if it has no header
+/// of its own but there's one beside it (see [`header_sidecar`]), and raw if not.
+///
+/// If a memory limit is given (in MiB), headers that are bigger than that, or that ask for more
+/// memory than that to decrypt, are refused before the user is asked to satisfy anything. Whatever
+/// the limit, a header that says its body is longer than what's left of the input is refused too,
+/// rather than finding out only after decrypting everything there is.
+fn open_input(
+    input: &Path,
+    opened: Option<File>,
+    header_in: Option<&Path>,
+    format: Option<Format>,
+    max_memory: Option<u64>,
+    factors: &FactorRegistry,
+    prompter: &mut dyn Prompter,
+) -> Result<(Header, Box<dyn Read>)> {
+    let max_memory = max_memory.map(|mib| mib.saturating_mul(1024 * 1024));
+    let max_header_len = max_memory.unwrap_or(u64::MAX);
+    let (header, reader): (Header, Box<dyn Read>) = if let Some(url) = input_url(input) {
+        open_url(url, header_in, format, max_header_len, prompter)?
+    } else {
+        let mut input_file = match opened {
+            Some(opened) => opened,
+            None => File::open(input)?,
+        };
+        let format = match format {
+            Some(format) => format,
+            None if header_in.is_some() => Format::Split,
+            None => detect_format(input, &mut input_file)?,
+        };
+        prompter.detail(&format!(
+            "Reading the input in the {} format.",
+            format.name()
+        ));
+
+        match format {
+            Format::Raw => {
+                let header = Header::from_file_with_limit(&mut input_file, max_header_len).context(
+                    "failed to read the header (if it's stored separately, pass it with `--header-in`)",
+                )?;
+                check_body_fits(&header, &mut input_file)?;
+                (header, Box::new(input_file))
+            }
+            Format::Split => {
+                let header_in = header_in.map_or_else(|| header_sidecar(input), Path::to_path_buf);
+                prompter.detail(&format!("Reading the header from {header_in:?}."));
+                // Having two headers would be ambiguous, so make sure the input doesn't have its own
+                if Header::from_file(&mut input_file).is_ok() {
+                    bail!(
+                        "{input:?} has its own header, so a detached header can't be used with it"
+                    );
+                }
+                input_file.rewind()?;
+                let header =
+                    Header::from_file_with_limit(&mut File::open(header_in)?, max_header_len)?;
+                check_body_fits(&header, &mut input_file)?;
+                (header, Box::new(input_file))
+            }
+            Format::Armor => {
+                if header_in.is_some() {
+                    bail!("armored files always have their own header, so `--header-in` can't be used");
+                }
+                let mut reader = ArmoredReader::new(BufReader::new(input_file))
+                    .with_context(|| format!("{input:?} is not armored"))?;
+                let header = Header::from_file_with_limit(&mut reader, max_header_len)?;
+                (header, Box::new(reader))
+            }
+        }
+    };
+    prompter.detail(&format!(
+        "The header has {} option(s), and is {} bytes long.",
+        header.option_summaries().len(),
+        header.to_bytes().len()
+    ));
+    if let Some(body_len) = header.body_len() {
+        prompter.detail(&format!("The body should be {body_len} bytes long."));
+    }
+    check_memory(&header, max_memory, factors, prompter)?;
+    let reader = body_reader(&header, reader)?;
+
+    Ok((header, reader))
+}
+
+/// Makes sure decrypting a file with the given header won't need more than the given amount of
+/// memory (in bytes) at once, if there's a limit.
+fn check_memory(
+    header: &Header,
+    max_memory: Option<u64>,
+    factors: &FactorRegistry,
+    prompter: &mut dyn Prompter,
+) -> Result<()> {
+    let memory_needed = header.memory_needed(factors);
+    prompter.detail(&format!(
+        "Decrypting will need up to about {} MiB of memory.",
+        memory_needed.div_ceil(1024 * 1024)
+    ));
+    if let Some(max_memory) = max_memory.filter(|&max_memory| memory_needed > max_memory) {
+        bail!(
+            "decrypting this file would need up to about {} MiB of memory, which is more than the limit of {} MiB",
+            memory_needed.div_ceil(1024 * 1024),
+            max_memory / (1024 * 1024)
+        );
     }
 
     Ok(())
 }
 
+/// Resolves the directory of the given output path (which needn't exist yet), so two paths to the
+/// same output can be recognised as such.
+fn resolve_output(output: &Path) -> PathBuf {
+    let dir = output
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let dir = dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf());
+    match output.file_name() {
+        Some(name) => dir.join(name),
+        None => dir,
+    }
+}
+
+/// Gets the URL to download the given input from, if it's a URL rather than a path.
+fn input_url(input: &Path) -> Option<&str> {
+    input
+        .to_str()
+        .filter(|input| input.starts_with("https://") || input.starts_with("http://"))
+}
+
+/// Opens the encrypted file at the given URL for [`open_input`], streaming it rather than
+/// downloading it first. The header is read from the start of the same stream, and the format is
+/// worked out from what's read first, since the stream can't be rewound.
+#[cfg(feature = "network")]
+fn open_url(
+    url: &str,
+    header_in: Option<&Path>,
+    format: Option<Format>,
+    max_header_len: u64,
+    prompter: &mut dyn Prompter,
+) -> Result<(Header, Box<dyn Read>)> {
+    if header_in.is_some() || format == Some(Format::Split) {
+        bail!("the split format can't be read from a URL (download the file and its header first)");
+    }
+    prompter.detail(&format!("Downloading the input from {url}."));
+    let mut reader = RemoteReader::open(url)?;
+    if reader.url() != url {
+        prompter.detail(&format!("Redirected to {}.", reader.url()));
+    }
+    let mut start = Vec::new();
+    (&mut reader)
+        .take(ARMOR_BEGIN.len() as u64)
+        .read_to_end(&mut start)?;
+    let format = format.unwrap_or(if start == ARMOR_BEGIN.as_bytes() {
+        Format::Armor
+    } else {
+        Format::Raw
+    });
+    prompter.detail(&format!(
+        "Reading the input in the {} format.",
+        format.name()
+    ));
+    // Put back what we read to work out the format
+    let mut input = io::Cursor::new(start).chain(reader);
+
+    Ok(match format {
+        Format::Armor => {
+            let mut reader = ArmoredReader::new(BufReader::new(input))
+                .with_context(|| format!("{url} is not armored"))?;
+            let header = Header::from_file_with_limit(&mut reader, max_header_len)?;
+            (header, Box::new(reader))
+        }
+        _ => {
+            let header = Header::from_file_with_limit(&mut input, max_header_len)
+                .with_context(|| format!("failed to read the header from {url}"))?;
+            (header, Box::new(input))
+        }
+    })
+}
+
+/// Fails to open a URL, since this build can't make HTTP requests.
+#[cfg(not(feature = "network"))]
+fn open_url(
+    url: &str,
+    _header_in: Option<&Path>,
+    _format: Option<Format>,
+    _max_header_len: u64,
+    _prompter: &mut dyn Prompter,
+) -> Result<(Header, Box<dyn Read>)> {
+    bail!("{url} can't be downloaded by this build (rebuild cyst with the 'network' feature)")
+}
+
+/// Makes sure the body the given header describes isn't longer than what's left of the given
+/// input (if that's a regular file), so a truncated file or a bogus header is caught before the
+/// user's asked to satisfy any options. This leaves the input's cursor where it was.
+fn check_body_fits(header: &Header, input: &mut File) -> Result<()> {
+    let Some(body_len) = header.body_len() else {
+        return Ok(());
+    };
+    let metadata = input.metadata()?;
+    if !metadata.is_file() {
+        return Ok(());
+    }
+    // With error correction, the body takes up more room on disk
+    let stored_len = header
+        .ecc_params()
+        .map_or(body_len, |params| params.encoded_len(body_len));
+    let remaining = metadata.len().saturating_sub(input.stream_position()?);
+    if stored_len > remaining {
+        return Err(anyhow::Error::new(AuthenticationFailed).context(format!(
+            "the header says the body is {stored_len} bytes long, but there are only {remaining} \
+             bytes of it (it has been truncated, or the header is bogus)"
+        )));
+    }
+
+    Ok(())
+}
+
+/// Wraps the given reader of a file's body so it corrects errors, if the file has error correction.
+fn body_reader<'a>(header: &Header, reader: impl Read + 'a) -> Result<Box<dyn Read + 'a>> {
+    Ok(match header.ecc_params() {
+        Some(params) => {
+            let body_len = header.body_len().ok_or(anyhow!(
+                "error correction without a body length (corrupted)"
+            ))?;
+            Box::new(EccReader::new(reader, params, body_len)?)
+        }
+        None => Box::new(reader),
+    })
+}
+
+/// Works out the format of the given encrypted input, for [`open_input`]. This leaves the input's
+/// cursor at the start.
+fn detect_format(input: &Path, input_file: &mut File) -> Result<Format> {
+    let mut start = Vec::new();
+    (&mut *input_file)
+        .take(ARMOR_BEGIN.len() as u64)
+        .read_to_end(&mut start)?;
+    input_file.rewind()?;
+    if start == ARMOR_BEGIN.as_bytes() {
+        return Ok(Format::Armor);
+    }
+
+    let has_header = Header::from_file(input_file).is_ok();
+    input_file.rewind()?;
+    if !has_header && header_sidecar(input).is_file() {
+        Ok(Format::Split)
+    } else {
+        Ok(Format::Raw)
+    }
+}
+
+/// Signs the given output with the given key, if there is one, writing the signature beside it
+/// (see `encrypt --sign`). This returns where the signature was written.
+fn write_signature(key: Option<&SigningKey>, output: Option<&Path>) -> Result<Option<PathBuf>> {
+    let (Some(key), Some(output)) = (key, output) else {
+        return Ok(None);
+    };
+    let path = signature_path(output);
+    std::fs::write(&path, key.sign_file(output)?)
+        .with_context(|| format!("failed to write the signature to {path:?}"))?;
+
+    Ok(Some(path))
+}
+
+/// Gets where the header for the given output goes in the split format, if it isn't given
+/// explicitly: beside the output, with `.header` added to its name.
+fn header_sidecar(output: &Path) -> PathBuf {
+    let mut path = output.as_os_str().to_owned();
+    path.push(".header");
+    PathBuf::from(path)
+}
+
+/// Wraps the given input so it's padded, if the header says its plaintext is (which needs the
+/// input's length to be known).
+fn plaintext_reader<'a>(header: &Header, input: &'a mut File) -> Result<Box<dyn Read + 'a>> {
+    Ok(match header.plaintext_padding() {
+        Some(scheme) => {
+            let len = known_len(input)?.ok_or(anyhow!(
+                "the input's length can't be known in advance, so it can't be padded"
+            ))?;
+            Box::new(PaddedReader::new(input, len, scheme))
+        }
+        None => Box::new(input),
+    })
+}
+
+/// Encrypts the input (and the decoy, if there is one) to the given output with the given header,
+/// adding error correction if it's asked for, and padding the input if the header says to. If the
+/// header is `detached`, it's left out of the output, and must be written somewhere else by the
+/// caller.
+fn write_encrypted(
+    input: &mut File,
+    output: &mut EncryptedOutput,
+    header: &Header,
+    encryptor: EncryptorBE32<ChaCha20Poly1305>,
+    decoy: Option<(&mut dyn Read, EncryptorBE32<ChaCha20Poly1305>)>,
+    detached: bool,
+    ecc: Option<EccParams>,
+) -> Result<()> {
+    let input = &mut plaintext_reader(header, input)?;
+    match (detached, ecc) {
+        (_, Some(ecc)) => {
+            // The header goes before the error correction, since it's needed to decode it
+            if !detached {
+                output.write_all(&header.to_bytes())?;
+            }
+            let mut ecc_writer = EccWriter::new(output, ecc)?;
+            encrypt_body(input, &mut ecc_writer, header, encryptor, decoy)?;
+            ecc_writer.finish()?;
+        }
+        (true, None) => encrypt_body(input, output, header, encryptor, decoy)?,
+        (false, None) => encrypt_file(input, output, header, encryptor, decoy)?,
+    }
+
+    Ok(())
+}
+
+/// Where encrypted output is written (a `.partial` file, or stdout if there isn't one), armored if
+/// the user asked for that, and hashed as it's written if they asked for its digest.
+enum EncryptedOutput<'a> {
+    Plain(DigestWriter<'a>),
+    Armored(ArmoredWriter<DigestWriter<'a>>),
+}
+impl EncryptedOutput<'_> {
+    /// Opens the output in the given format, creating the given partial output file (with the state
+    /// to resume encrypting the given input, see [`PartialOutput::create`]) if there is one. If
+    /// `digest` is set, everything written is hashed (see [`EncryptedOutput::finish`]).
+    fn new(
+        partial: Option<&PartialOutput>,
+        resume_input: Option<&Path>,
+        format: Option<Format>,
+        digest: bool,
+    ) -> Result<Self> {
+        let writer: Box<dyn Write> = match partial {
+            Some(partial) => Box::new(partial.create(resume_input)?),
+            None => Box::new(StdoutWriter::new()),
+        };
+        let writer = DigestWriter::new(writer, digest);
+        Ok(match format {
+            Some(Format::Armor) => Self::Armored(ArmoredWriter::new(writer)?),
+            _ => Self::Plain(writer),
+        })
+    }
+
+    /// Finishes writing the output, which must be done before it's moved into place. This returns
+    /// the SHA-256 digest of everything written, as hex, if it was asked for.
+    fn finish(self) -> io::Result<Option<String>> {
+        let mut writer = match self {
+            Self::Plain(writer) => writer,
+            Self::Armored(writer) => writer.finish()?,
+        };
+        writer.flush()?;
+
+        Ok(writer.finish())
+    }
+}
+impl Write for EncryptedOutput<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(writer) => writer.write(buf),
+            Self::Armored(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(writer) => writer.flush(),
+            Self::Armored(writer) => writer.flush(),
+        }
+    }
+}
+
+/// A writer that passes everything through to another, hashing it with SHA-256 along the way if
+/// it's asked to, so the digest of an output can be had without reading it back.
+struct DigestWriter<'a> {
+    inner: Box<dyn Write + 'a>,
+    context: Option<digest::Context>,
+}
+impl<'a> DigestWriter<'a> {
+    /// Wraps the given writer, hashing what's written to it only if `digest` is set.
+    fn new(inner: Box<dyn Write + 'a>, digest: bool) -> Self {
+        Self {
+            inner,
+            context: digest.then(|| digest::Context::new(&digest::SHA256)),
+        }
+    }
+
+    /// Gets the digest of everything written, as hex, if it was being hashed.
+    fn finish(self) -> Option<String> {
+        self.context
+            .map(|context| hex::encode(context.finish().as_ref()))
+    }
+}
+impl Write for DigestWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        if let Some(context) = &mut self.context {
+            context.update(&buf[..written]);
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Gets the SHA-256 digest of the given file, as hex (see `encrypt --print-digest`).
+fn file_digest(path: &Path) -> Result<String> {
+    let mut writer = DigestWriter::new(Box::new(io::sink()), true);
+    io::copy(&mut File::open(path)?, &mut writer)?;
+
+    Ok(writer.finish().unwrap())
+}
+
+/// Shows the user the digest of an output (see `encrypt --print-digest`) on stderr, in the format
+/// `sha256sum` prints, so it can be checked with `sha256sum -c` (stdout is shown as `-`).
+fn show_digest(prompter: &mut dyn Prompter, digest: &str, output: Option<&Path>) {
+    let name = output.map_or_else(|| "-".to_string(), |output| output.display().to_string());
+    prompter.notice(&format!("{digest}  {name}"));
+}
+
+/// Stdout, for writing the payload to. If it's been made non-blocking (which whatever's reading it
+/// might do), writes that would block are tried again after a moment, rather than failing.
+struct StdoutWriter(io::StdoutLock<'static>);
+impl StdoutWriter {
+    fn new() -> Self {
+        Self(io::stdout().lock())
+    }
+}
+impl Write for StdoutWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        loop {
+            match self.0.write(buf) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(STDOUT_RETRY_DELAY)
+                }
+                res => return res,
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        loop {
+            match self.0.flush() {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(STDOUT_RETRY_DELAY)
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+/// What's needed to decrypt the body of a file, which depends on how it was encrypted.
+enum BodyDecryptor {
+    /// A decryptor for a STREAM body, the location of the stream, the length of the whole body, if
+    /// it's known, and whether the plaintext is padded.
+    Stream(
+        DecryptorBE32<ChaCha20Poly1305>,
+        StreamLocation,
+        Option<u64>,
+        bool,
+    ),
+    /// The cipher for a dedup-mode body.
+    Dedup(DedupCipher),
+}
+impl BodyDecryptor {
+    /// Prompts the user to satisfy one of the header's options (see [`Header::to_decryptor`]) to
+    /// get whichever kind of decryptor the file needs.
+    fn from_header(
+        header: &Header,
+        factors: &FactorRegistry,
+        option: Option<&str>,
+        auto_option: bool,
+        attempts: u32,
+        prompter: &mut dyn Prompter,
+    ) -> Result<Self> {
+        Ok(match header.dedup_params() {
+            Some(_) => Self::Dedup(header.to_dedup_cipher(
+                factors,
+                option,
+                auto_option,
+                attempts,
+                prompter,
+            )?),
+            None => {
+                let (decryptor, location) =
+                    header.to_decryptor(factors, option, auto_option, attempts, prompter)?;
+                Self::Stream(
+                    decryptor,
+                    location,
+                    header.body_len(),
+                    header.plaintext_padding().is_some(),
+                )
+            }
+        })
+    }
+
+    /// Gets whichever kind of decryptor the file needs from an escrow key (see
+    /// [`Header::escrow_key`]), without any prompting.
+    fn from_escrow_key(header: &Header, escrow_key: &[u8]) -> Result<Self> {
+        Ok(match header.dedup_params() {
+            Some(_) => Self::Dedup(header.escrow_dedup_cipher(escrow_key)?),
+            None => {
+                let (decryptor, location) = header.escrow_decryptor(escrow_key)?;
+                Self::Stream(
+                    decryptor,
+                    location,
+                    header.body_len(),
+                    header.plaintext_padding().is_some(),
+                )
+            }
+        })
+    }
+
+    /// Decrypts the body from the given reader (see [`decrypt_file`]), stripping any padding from
+    /// the plaintext. The limit is on the plaintext itself, not counting the padding.
+    fn decrypt(
+        self,
+        input: &mut impl Read,
+        output: &mut impl Write,
+        aad: &[u8],
+        limit: Option<u64>,
+    ) -> Result<bool> {
+        match self {
+            Self::Stream(decryptor, location, body_len, false) => {
+                decrypt_file(input, location, body_len, output, decryptor, aad, limit)
+            }
+            Self::Stream(decryptor, location, body_len, true) => {
+                let mut output = UnpaddingWriter::new(output);
+                // The true length comes before the plaintext
+                let limit = limit.map(|limit| limit + cyst::padding::LEN_PREFIX_LEN);
+                let complete = decrypt_file(
+                    input,
+                    location,
+                    body_len,
+                    &mut output,
+                    decryptor,
+                    aad,
+                    limit,
+                )?;
+                if complete {
+                    output.finish()?;
+                }
+                Ok(complete)
+            }
+            Self::Dedup(cipher) => decrypt_file_dedup(input, output, &cipher, aad, limit),
+        }
+    }
+
+    /// Verifies the body from the given reader (see [`verify_file`]).
+    fn verify(self, input: &mut impl Read, aad: &[u8]) -> Result<u64> {
+        match self {
+            Self::Stream(decryptor, location, body_len, _) => {
+                verify_file(input, location, body_len, decryptor, aad)
+            }
+            Self::Dedup(cipher) => verify_file_dedup(input, &cipher, aad),
+        }
+    }
+}
+
+/// Decrypts the body from the given reader with an escrow key, returning the hash of its plaintext
+/// (see [`PlaintextHasher`]).
+fn body_hash(header: &Header, escrow_key: &[u8], input: &mut impl Read) -> Result<Vec<u8>> {
+    let mut hasher = PlaintextHasher::default();
+    BodyDecryptor::from_escrow_key(header, escrow_key)?.decrypt(
+        input,
+        &mut hasher,
+        &header.authenticated_data(),
+        None,
+    )?;
+
+    Ok(hasher.finish())
+}
+
+/// Describes a padding scheme as JSON, the same way it's given on the command line: `"pow2"`, or a
+/// number of bytes.
+fn padding_json(scheme: PaddingScheme) -> Json {
+    match scheme {
+        PaddingScheme::PowerOfTwo => "pow2".into(),
+        PaddingScheme::Multiple(block) => block.into(),
+    }
+}
+
+/// Prints the decryption options of the given header, followed by the given notes about the file.
+fn print_info(header: &Header, notes: &[String]) {
+    println!("Decryption options:");
+    for summary in header.option_summaries() {
+        println!("  - {summary}");
+    }
+    for note in notes {
+        println!("{note}");
+    }
+}
+
+/// Describes the options in the given header as JSON.
+fn options_json(header: &Header) -> Json {
+    Json::Array(
+        header
+            .option_summaries()
+            .into_iter()
+            .map(|summary| {
+                Json::object([
+                    ("name", summary.name.into()),
+                    ("description", summary.description.into()),
+                    ("is_gate", summary.is_gate.into()),
+                    ("gated_behind", summary.gated_behind.into()),
+                    ("factors", summary.factors.into()),
+                    ("quorum", summary.quorum.map(u64::from).into()),
+                    ("kdf", summary.kdf.into()),
+                ])
+            })
+            .collect(),
+    )
+}
+
+/// Writes the escrow key of a header that was just created to a new file (which only we can
+/// read, on Unix), warning the user loudly about what it is.
+fn write_escrow_key(
+    path: &Path,
+    header: &Header,
+    input: &Path,
+    prompter: &mut dyn Prompter,
+) -> Result<()> {
+    let escrow_key = header
+        .escrow_key()
+        .ok_or(anyhow!("the new header has no escrow key"))?;
+    let mut file = OpenOptions::new();
+    file.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut file, 0o600);
+    let mut file = file
+        .open(path)
+        .with_context(|| format!("failed to create the escrow key file {path:?}"))?;
+    write!(
+        file,
+        "# The cyst primary key for {input:?}. This decrypts it on its own, without any of its\n\
+         # options, so store it somewhere physically secure (see `cyst decrypt --primary-key`).\n\
+         {}\n",
+        hex::encode(escrow_key)
+    )?;
+
+    prompter.notice(&format!("Warning: {path:?} now holds the primary key of this file, which decrypts it without any of its options (even if they're changed later). Store it offline somewhere physically secure, never alongside the encrypted file, and then delete it from this machine."));
+    Ok(())
+}
+
+/// Reads an escrow key written by [`write_escrow_key`].
+fn read_escrow_key(path: &Path) -> Result<Vec<u8>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read the primary key file {path:?}"))?;
+    let hex_key = contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect::<String>();
+
+    hex::decode(hex_key).with_context(|| format!("{path:?} does not hold a valid primary key"))
+}
+
+/// Gets the number of threads to derive the keys of new options on, which is the number given by
+/// the user, or otherwise as many as this machine can run at once.
+fn kdf_threads(threads: Option<NonZeroUsize>) -> usize {
+    threads
+        .or_else(|| std::thread::available_parallelism().ok())
+        .map_or(1, NonZeroUsize::get)
+}
+
+/// Creates a temporary file in the same directory as the given path, so it can later be moved
+/// into place atomically.
+fn temp_file_beside(path: &Path) -> io::Result<NamedTempFile> {
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    NamedTempFile::new_in(dir)
+}
+
+/// Whether the given output has to be written to directly as the plaintext is decrypted, rather
+/// than replaced once it's complete. That's anything that's already there but isn't a regular file
+/// or a directory, like a named pipe or a device.
+fn is_streamed_output(path: &Path) -> bool {
+    path.metadata()
+        .is_ok_and(|metadata| !metadata.is_file() && !metadata.is_dir())
+}
+
+/// Gets the permissions of the file at the given path, to store with `encrypt --store-mode`. Only
+/// the permission bits are kept, never setuid, setgid, or sticky.
+fn file_mode(path: &Path) -> Result<u32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        Ok(path.metadata()?.permissions().mode() & 0o777)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        bail!("file permissions can only be stored on Unix")
+    }
+}
+
+/// Sets the permissions of the given file (see [`file_mode`]). This does nothing except on Unix.
+fn set_file_mode(file: &File, mode: u32) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(mode & 0o777))?;
+        Ok(())
+    }
+    // There's nothing equivalent to restore elsewhere
+    #[cfg(not(unix))]
+    {
+        let _ = (file, mode);
+        Ok(())
+    }
+}
+
+/// Parses permissions given in octal, like `644` or `0600`.
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o777)
+        .ok_or(format!(
+            "'{mode}' is not a valid mode (it should be octal, like 644)"
+        ))
+}
+
+/// Works out the original filename of the given encrypted file by stripping off the suffix that
+/// was added to it at encryption (see `encrypt --ext`).
+fn strip_added_suffix<'a>(input: &'a Path, suffix: &str) -> Result<&'a str> {
+    let name = input
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| anyhow!("{input:?} has no filename to restore the original from"))?;
+    match name.strip_suffix(suffix) {
+        // Never let what's left take us outside the output directory
+        Some(original) if !["", ".", ".."].contains(&original) => Ok(original),
+        _ => bail!("{input:?} no longer ends with the {suffix:?} added at encryption (was it renamed?), so the original filename can't be restored (pass an output file instead)"),
+    }
+}
+
+/// Parses an extension to add to the names of encrypted files, like `cyst` or `.tar.cyst`,
+/// returning it as the suffix to add (with its leading dot).
+fn parse_ext(ext: &str) -> Result<String, String> {
+    let ext = ext.strip_prefix('.').unwrap_or(ext);
+    if ext.is_empty() || ext.contains(['/', '\\']) {
+        return Err(format!("'{ext}' is not a valid extension"));
+    }
+
+    Ok(format!(".{ext}"))
+}
+
+/// A pager that plaintext can be written to, for `decrypt --view`: `$PAGER` if it's set, or `less`
+/// if not. If the user quits the pager early, anything written after that is quietly thrown away.
+struct Pager {
+    child: Child,
+    /// The pager's input, until it's been closed.
+    stdin: Option<ChildStdin>,
+}
+impl Pager {
+    /// Starts the pager.
+    fn spawn() -> Result<Self> {
+        let pager = std::env::var("PAGER")
+            .ok()
+            .filter(|pager| !pager.trim().is_empty())
+            .unwrap_or_else(|| "less".to_string());
+        // Pagers are often set with arguments, like `less -R`
+        let mut args = pager.split_whitespace();
+        let mut child = process::Command::new(args.next().unwrap())
+            .args(args)
+            .stdin(Stdio::piped())
+            .spawn()
+            .with_context(|| {
+                format!("failed to start the pager '{pager}' (set $PAGER to use another one)")
+            })?;
+        let stdin = child.stdin.take();
+
+        Ok(Self { child, stdin })
+    }
+
+    /// Closes the pager's input, and waits for the user to quit it.
+    fn wait(mut self) -> io::Result<()> {
+        drop(self.stdin.take());
+        self.child.wait()?;
+
+        Ok(())
+    }
+}
+impl Write for Pager {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(stdin) = &mut self.stdin {
+            match stdin.write_all(buf) {
+                // The user has quit the pager, which isn't a reason to stop decrypting
+                Err(err) if err.kind() == io::ErrorKind::BrokenPipe => self.stdin = None,
+                res => res?,
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.stdin {
+            Some(stdin) => match stdin.flush() {
+                Err(err) if err.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+                res => res,
+            },
+            None => Ok(()),
+        }
+    }
+}
+
+/// A writer that duplicates everything written to it across several sinks, failing if any of them
+/// fail.
+struct Tee<'a>(Vec<Box<dyn Write + 'a>>);
+impl Write for Tee<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for sink in &mut self.0 {
+            sink.write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        for sink in &mut self.0 {
+            sink.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Converts the given path to a JSON string (lossily, if it isn't valid Unicode).
+fn path_json(path: &Path) -> Json {
+    path.to_string_lossy().into_owned().into()
+}
+
+/// The formats an encrypted file can be in.
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum Format {
+    /// The header, followed directly by the ciphertext
+    Raw,
+    /// Like `raw`, but base64-encoded between BEGIN and END lines, so it can be pasted as text
+    Armor,
+    /// The header in a separate file from the ciphertext
+    Split,
+}
+impl Format {
+    /// Gets the name of this format, as it's given on the command line.
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Raw => "raw",
+            Self::Armor => "armor",
+            Self::Split => "split",
+        }
+    }
+}
+
 /// A utility for encrypting and decrypting files with multiple factors.
 #[derive(Parser)]
+#[command(
+    after_help = "Exit codes: 0 on success, 2 for invalid arguments, 3 if decryption fails (wrong factors or corrupted data), 4 for I/O errors, 5 for network errors, 130 if cancelled, 141 if whatever's reading the output from stdout stops early, and 1 for anything else."
+)]
 struct Opts {
     #[clap(subcommand)]
     command: Command,
+    /// Print a JSON report of the operation instead of human-readable messages. This goes to
+    /// stdout, or to stderr if stdout is being used for encrypted/decrypted data
+    #[arg(long, global = true)]
+    json: bool,
+    /// Read the passphrase for any passphrase factors from this file, rather than prompting for
+    /// it (a single trailing newline is ignored). This is the same as setting
+    /// `$CYST_PASSPHRASE_FILE`, and `$CYST_PASSPHRASE` can be used to give the passphrase itself
+    #[arg(long, global = true)]
+    passphrase_file: Option<PathBuf>,
+    /// Read the shares for any Shamir secret sharing factors from this file, rather than prompting
+    /// for them. It should have one hex share per line (blank lines and lines starting with `#` are
+    /// ignored). This is the same as setting `$CYST_SHARES_FILE`
+    #[cfg(feature = "shamir")]
+    #[arg(long, global = true)]
+    shares_file: Option<PathBuf>,
+    /// Only print messages that can't be done without, like warnings and Shamir shares. Messages
+    /// are always printed to stderr, never mixed in with output on stdout
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Print extra details about what's happening. Give this twice to also log what's going on
+    /// inside (for bug reports), or three times to log everything. `$RUST_LOG` can be used to
+    /// control the logging more precisely
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    verbose: u8,
+    /// Ask for a choice to be made even when there's only one to choose from (e.g. a file with
+    /// only one option), rather than making it automatically
+    #[arg(long, global = true)]
+    always_prompt: bool,
+    /// Set up new encryption options in a full-screen editor, where they can all be laid out and
+    /// rearranged before any of them are created, rather than being asked about them one at a time.
+    /// `info` shows a file's options this way too
+    #[cfg(feature = "tui")]
+    #[arg(long, global = true)]
+    tui: bool,
 }
 
 #[derive(Subcommand)]
@@ -49,13 +3011,414 @@ enum Command {
     /// Encrypt a file
     Encrypt {
         input: PathBuf,
+        /// More files to encrypt with the same options, which are only set up once (each file still
+        /// gets its own key). Each one is written beside itself with `--ext` added, or into the
+        /// directory given by `--output`. If some fail, the rest are still encrypted
+        #[arg(conflicts_with_all = ["dry_run", "decoy", "dedup", "dedup_base", "header_out", "escrow_out"])]
+        more_inputs: Vec<PathBuf>,
+        /// The file to write to, or a directory to write into with `--ext` added to the input's
+        /// name
         #[arg(short, long)]
         output: Option<PathBuf>,
+        /// Set up the encryption options, then check that they can be satisfied, without
+        /// encrypting anything
+        #[arg(long)]
+        dry_run: bool,
+        /// Store the input's filename in the header, so it can be restored on decryption. This is
+        /// visible without decrypting the file!
+        #[arg(long)]
+        store_name: bool,
+        /// A comment to store in the header (visible without decrypting the file)
+        #[arg(long)]
+        comment: Option<String>,
+        /// A hint as to the MIME type of the file, to store in the header (visible without
+        /// decrypting the file)
+        #[arg(long)]
+        mime: Option<String>,
+        /// Hash the input and store the hash in the header, so the decrypted plaintext can be
+        /// checked against it (see `decrypt --verify-hash`). The hash is BLAKE2b-512, which is what
+        /// `b2sum` prints (not BLAKE3, so it won't match `b3sum`). This takes an extra pass over
+        /// the input, and anyone who can guess the contents can use the hash to confirm their guess
+        #[arg(long)]
+        store_hash: bool,
+        /// Store the input's permissions in the header, so they can be restored on decryption
+        /// (Unix only). Otherwise, decrypted files are only readable by whoever decrypts them
+        #[arg(long)]
+        store_mode: bool,
+        /// A decoy file to encrypt alongside the input. After setting up the normal options,
+        /// you'll set up duress options, which decrypt the decoy instead. Nothing in the header
+        /// shows that there's a decoy, so this can't be combined with stored metadata
+        #[arg(long, conflicts_with_all = ["dry_run", "store_name", "comment", "mime", "store_hash", "store_mode"])]
+        decoy: Option<PathBuf>,
+        /// Split the input into content-defined chunks and encrypt each independently, so small
+        /// changes only change the chunks around them (for deduplicating backup tools). This
+        /// reveals which chunks are unchanged between versions, and can't store metadata
+        #[arg(long, conflicts_with_all = ["dry_run", "store_name", "comment", "mime", "store_hash", "store_mode", "decoy"])]
+        dedup: bool,
+        /// Encrypt in dedup mode with the same header and key as this previous version of the
+        /// file, so the chunks that haven't changed stay exactly the same. You'll need to satisfy
+        /// one of its options
+        #[arg(long, conflicts_with_all = ["dry_run", "store_name", "comment", "mime", "store_hash", "store_mode", "decoy"])]
+        dedup_base: Option<PathBuf>,
+        /// Write the header to this file instead of the output, which will then hold just the
+        /// ciphertext. Both are needed to decrypt (see `decrypt --header-in`)
+        #[arg(long, conflicts_with_all = ["dry_run", "dedup", "dedup_base"])]
+        header_out: Option<PathBuf>,
+        /// How many threads to derive the keys of the new options on, once they've all been set up
+        /// (by default, as many as this machine can run at once)
+        #[arg(long)]
+        threads: Option<NonZeroUsize>,
+        /// The format to write the output in (by default, `raw`). `split` writes the header to the
+        /// output's name with `.header` added, unless `--header-out` is given, which implies
+        /// `split` and can't be combined with any other format
+        #[arg(long)]
+        format: Option<Format>,
+        /// Also write the file's primary key to this file, for "break glass" recovery (see
+        /// `decrypt --primary-key`). This decrypts the file on its own, bypassing every option,
+        /// so it must be stored somewhere physically secure (like a safe)
+        #[arg(long, conflicts_with_all = ["dry_run", "dedup_base"])]
+        escrow_out: Option<PathBuf>,
+        /// Add Reed-Solomon error correction to the body, with this many parity bytes in every
+        /// 255 (32 if just `--ecc` is given). Up to half that many corrupted bytes in every 255
+        /// can be corrected, so the file survives some bit rot. The header isn't protected
+        #[arg(
+            long,
+            num_args = 0..=1,
+            default_missing_value = "32",
+            value_parser = clap::value_parser!(u8).range(2..=128),
+            conflicts_with_all = ["dry_run", "dedup", "dedup_base"],
+        )]
+        ecc: Option<u8>,
+        /// Pad the header with zeroes to this many bytes (65536 if just `--pad-header` is given),
+        /// so its size doesn't give away how many options and factors it has. This only hides the
+        /// header's structure, not that the file is encrypted with cyst
+        #[arg(
+            long,
+            num_args = 0..=1,
+            default_missing_value = "65536",
+            value_parser = clap::value_parser!(u64).range(1..=16 * 1024 * 1024),
+            conflicts_with_all = ["dry_run", "dedup", "dedup_base"],
+        )]
+        pad_header: Option<u64>,
+        /// Pad the plaintext before encrypting it, so the file's size doesn't give away exactly
+        /// how long it is: `pow2` pads it to the next power of two, and a number of bytes pads it
+        /// to a multiple of that. The true length is encrypted with it. This can't be combined with
+        /// stored metadata, which records the length
+        #[arg(
+            long,
+            value_parser = PaddingScheme::parse,
+            conflicts_with_all = ["dry_run", "store_name", "comment", "mime", "store_hash", "store_mode", "decoy", "dedup", "dedup_base"],
+        )]
+        pad_to: Option<PaddingScheme>,
+        /// Leave a note on the file that anyone can read without decrypting it (e.g. "Backup of
+        /// 2024 taxes, ask Jane for the keys"), shown by `info`. It isn't encrypted, but any change
+        /// to it will make decryption fail
+        #[arg(long, conflicts_with_all = ["dry_run", "dedup", "dedup_base"])]
+        public_note: Option<String>,
+        /// Refuse to encrypt unless this build writes this version of the file format (see
+        /// `check-vectors`), so scripts can be sure every file they make can be read the same way
+        #[arg(long)]
+        format_version: Option<u32>,
+        /// The extension to add to the name of each encrypted file that's named automatically
+        /// (`file.tar.gz` becomes `file.tar.gz.cyst` by default). This is recorded in the header, so
+        /// `decrypt` can strip exactly it off again to restore the original name
+        #[arg(long, default_value = "cyst", value_parser = parse_ext, conflicts_with_all = ["dry_run", "dedup", "dedup_base"])]
+        ext: String,
+        /// Sign each output with this Ed25519 private key (a PEM file, as made by `openssl genpkey
+        /// -algorithm ed25519`), writing the signature beside it with `.sig` added. Anyone with the
+        /// public key can then check who made the file (see `decrypt --verify-sig`), which the
+        /// encryption alone doesn't show. The signature covers the whole output, so this can't be
+        /// used with the split format
+        #[arg(long, conflicts_with_all = ["dry_run", "header_out"])]
+        sign: Option<PathBuf>,
+        /// Print the SHA-256 digest of each output to stderr, in the format `sha256sum` prints, so
+        /// wherever it's sent can be checked later. This is worked out as the output is written,
+        /// so it works with stdout too. With the split format, it doesn't cover the header
+        #[arg(long, conflicts_with = "dry_run")]
+        print_digest: bool,
     },
     /// Decrypt a previously encrypted file
     Decrypt {
+        /// The file to decrypt, or an `http://` or `https://` URL to stream it from without
+        /// downloading it first
         input: PathBuf,
+        /// The file to write to, or a directory to write into with the original filename. A file
+        /// is only put in place once it's all been authenticated, except for something like a
+        /// named pipe, which is written to as the plaintext is decrypted
         #[arg(short, long)]
         output: Option<PathBuf>,
+        /// Read the header from this file, for an input encrypted with `--header-out`
+        #[arg(long)]
+        header_in: Option<PathBuf>,
+        /// Write the plaintext to stdout as well as to the output file
+        #[arg(long, requires = "output")]
+        also_stdout: bool,
+        /// The name of the option to decrypt with, rather than asking which one to use
+        #[arg(long, conflicts_with = "auto_option")]
+        option: Option<String>,
+        /// Try each option in turn until one succeeds, rather than asking which one to use
+        #[arg(long)]
+        auto_option: bool,
+        /// How many tries you get at satisfying an option before giving up
+        #[arg(long, default_value_t = NonZeroU32::new(DEFAULT_ATTEMPTS).unwrap())]
+        attempts: NonZeroU32,
+        /// Decrypt with the primary key in this file (written by `encrypt --escrow-out`) instead
+        /// of satisfying any option
+        #[arg(long, conflicts_with_all = ["option", "auto_option"])]
+        primary_key: Option<PathBuf>,
+        /// Stop after writing this many bytes of plaintext. The end of the file won't be checked,
+        /// so truncation or tampering after this point won't be detected
+        #[arg(long)]
+        limit: Option<u64>,
+        /// Check that the plaintext matches the hash stored in the header (see
+        /// `encrypt --store-hash`)
+        #[arg(long, conflicts_with = "limit")]
+        verify_hash: bool,
+        /// The permissions to give the output file, in octal (like `644`). By default, these are
+        /// the ones stored with `encrypt --store-mode`, or `600` (only readable by you) if there
+        /// aren't any. The output is only readable by you until it's complete either way
+        #[arg(long, value_parser = parse_mode, requires = "output")]
+        mode: Option<u32>,
+        /// Show the plaintext in a pager (`$PAGER`, or `less`) instead of writing it anywhere, so
+        /// it never touches the disk. This is meant for small, textual files
+        #[arg(long, conflicts_with_all = ["output", "also_stdout"])]
+        view: bool,
+        /// Refuse to decrypt if the file's header asks for more than this many MiB of memory at
+        /// once (e.g. for key derivation), or is itself that big. This is for decrypting files from
+        /// sources you don't trust
+        #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+        max_memory: Option<u64>,
+        /// The format of the input, if it can't be worked out automatically. `split` reads the
+        /// header from `--header-in`, or from the input's name with `.header` added
+        #[arg(long)]
+        format: Option<Format>,
+        /// Check that the input was signed with the private key that goes with this Ed25519 public
+        /// key (a PEM file, as made by `openssl pkey -pubout`) before decrypting anything (see
+        /// `encrypt --sign`)
+        #[arg(long)]
+        verify_sig: Option<PathBuf>,
+        /// The signature to check with `--verify-sig` (by default, the input's name with `.sig`
+        /// added)
+        #[arg(long, requires = "verify_sig")]
+        sig: Option<PathBuf>,
     },
+    /// Check that a file can be decrypted and is intact, without writing out the plaintext
+    Verify {
+        /// The file to check, or an `http://` or `https://` URL to stream it from
+        input: PathBuf,
+        /// Read the header from this file, for an input encrypted with `--header-out`
+        #[arg(long)]
+        header_in: Option<PathBuf>,
+        /// The name of the option to decrypt with, rather than asking which one to use
+        #[arg(long, conflicts_with = "auto_option")]
+        option: Option<String>,
+        /// Try each option in turn until one succeeds, rather than asking which one to use
+        #[arg(long)]
+        auto_option: bool,
+        /// How many tries you get at satisfying an option before giving up
+        #[arg(long, default_value_t = NonZeroU32::new(DEFAULT_ATTEMPTS).unwrap())]
+        attempts: NonZeroU32,
+        /// The format of the input, if it can't be worked out automatically (see `decrypt
+        /// --format`)
+        #[arg(long)]
+        format: Option<Format>,
+        /// Refuse to verify if the file's header asks for more than this many MiB of memory at
+        /// once (e.g. for key derivation), or is itself that big. This is for decrypting files from
+        /// sources you don't trust
+        #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+        max_memory: Option<u64>,
+    },
+    /// Show the decryption options available for an encrypted file
+    Info { input: PathBuf },
+    /// Check that each factor of an encrypted file's options can be used right now (e.g. that its
+    /// keyfile is there, or that its server can be reached), and how long that took, without
+    /// prompting for anything or decrypting the file. Factors that only need you (like a
+    /// passphrase) can't be checked
+    CheckFactors { input: PathBuf },
+    /// Estimate how long each option of an encrypted file would take to decrypt with, cheapest
+    /// first, by timing its key derivation on this machine (along with those of any gates it's
+    /// behind), without prompting for anything or decrypting the file. Factors with their own slow
+    /// derivation (like a PIN) take longer than this
+    Estimate {
+        input: PathBuf,
+        /// Refuse to time the file's key derivations if any of them would need more than this many
+        /// MiB of memory (see `decrypt --max-memory`)
+        #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+        max_memory: Option<u64>,
+    },
+    /// Show the raw key, nonce and associated data of an encrypted file's ciphertext once you've
+    /// satisfied one of its options, so it can be inspected or decrypted with other tools. This is
+    /// only for debugging: anyone who sees the key can decrypt the file without any of its factors
+    Extract {
+        input: PathBuf,
+        /// Confirm that you really want the file's key shown
+        #[arg(long)]
+        unsafe_show_key: bool,
+        /// The name of the option to decrypt with, rather than asking which one to use
+        #[arg(long, conflicts_with = "auto_option")]
+        option: Option<String>,
+        /// Try each option in turn until one succeeds, rather than asking which one to use
+        #[arg(long)]
+        auto_option: bool,
+        /// How many tries you get at satisfying an option before giving up
+        #[arg(long, default_value_t = NonZeroU32::new(DEFAULT_ATTEMPTS).unwrap())]
+        attempts: NonZeroU32,
+    },
+    /// Change the passphrase of an option, without re-encrypting the file
+    ChangePassphrase {
+        input: PathBuf,
+        /// The option to change the passphrase of (you'll be asked if there's more than one)
+        #[arg(long)]
+        option: Option<String>,
+    },
+    /// Upload fresh ephemeral data for every option that has an ephemeral factor, extending its
+    /// life without re-encrypting the file. You'll need to satisfy an option first, and then each
+    /// refreshed option's other factors. An option whose ephemeral data has already expired is
+    /// revived with the key recovered from the first option (so if the file has a decoy, that
+    /// must be an option for the same plaintext)
+    #[cfg(feature = "ephemeral")]
+    RefreshEphemeral {
+        input: PathBuf,
+        /// The name of the option to satisfy first, rather than asking which one to use
+        #[arg(long, conflicts_with = "auto_option")]
+        option: Option<String>,
+        /// Try each option in turn until one succeeds, rather than asking which one to use
+        #[arg(long)]
+        auto_option: bool,
+        /// How many tries you get at satisfying that option before giving up
+        #[arg(long, default_value_t = NonZeroU32::new(DEFAULT_ATTEMPTS).unwrap())]
+        attempts: NonZeroU32,
+    },
+    /// Add the options of another file holding the same plaintext to this one, so either's options
+    /// can decrypt it. You'll need to satisfy an option of each file, and both are fully decrypted
+    /// to check they match. The other file can't have a decoy
+    MergeOptions {
+        /// The file to add the options to
+        primary: PathBuf,
+        /// The file whose options should be added (this isn't changed)
+        secondary: PathBuf,
+        /// How many tries you get at satisfying each file's option before giving up
+        #[arg(long, default_value_t = NonZeroU32::new(DEFAULT_ATTEMPTS).unwrap())]
+        attempts: NonZeroU32,
+    },
+    /// Encrypt a file and send it straight to `cyst receive` on another machine over TCP, without
+    /// writing the ciphertext anywhere. The options are set up before connecting
+    Send {
+        input: PathBuf,
+        /// The address to send to, like `192.168.1.5:7979`
+        addr: String,
+        /// How many threads to derive the keys of the new options on (see `encrypt --threads`)
+        #[arg(long)]
+        threads: Option<NonZeroUsize>,
+    },
+    /// Wait for a file from `cyst send` and decrypt it as it arrives. The output is only written
+    /// once the whole file has arrived and been authenticated
+    Receive {
+        /// The address to listen on, like `0.0.0.0:7979`
+        bind: String,
+        /// The file to write the plaintext to
+        #[arg(short, long)]
+        output: PathBuf,
+        /// The name of the option to decrypt with, rather than asking which one to use
+        #[arg(long, conflicts_with = "auto_option")]
+        option: Option<String>,
+        /// Try each option in turn until one succeeds, rather than asking which one to use
+        #[arg(long)]
+        auto_option: bool,
+        /// How many tries you get at satisfying an option before giving up
+        #[arg(long, default_value_t = NonZeroU32::new(DEFAULT_ATTEMPTS).unwrap())]
+        attempts: NonZeroU32,
+        /// Refuse to decrypt if the header that's sent asks for more than this many MiB of memory
+        /// at once (e.g. for key derivation), or is itself that big. Anyone who can connect chooses
+        /// the header, so this should almost always be set
+        #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+        max_memory: Option<u64>,
+    },
+    /// List the factors available in this build, with a short description of each, and why it
+    /// can't be used here if it can't (e.g. a program it needs isn't installed)
+    ListFactors,
+    /// Check that every available factor can be created and then derived to the same key (factors
+    /// that can't be used here are reported as unavailable, rather than failing)
+    SelfTest {
+        /// The names of factors to skip (e.g. those that need network access)
+        #[arg(long)]
+        skip: Vec<String>,
+    },
+    /// Check that this build still reads and writes the file format exactly as it should, using the
+    /// test vectors built into it (including those from every earlier format version)
+    CheckVectors {
+        /// Instead of checking them, write the vectors this build generates into this directory
+        /// (for when the format has been changed on purpose)
+        #[arg(long)]
+        write: Option<PathBuf>,
+    },
+    /// Print a description of how headers are laid out (every field's name and type, and how each
+    /// type is encoded) as JSON, for other tools that want to read them
+    HeaderSchema,
+    /// Time Argon2 on this machine to suggest parameters that take about as long as you want (e.g.
+    /// for a PIN factor)
+    Calibrate {
+        /// How long a derivation should take, in seconds
+        #[arg(long, default_value_t = 1.0)]
+        target: f64,
+        /// How much memory a derivation should use, in MiB (this is reduced if even a single
+        /// iteration takes longer than the target)
+        #[arg(long, default_value_t = 256)]
+        memory: u32,
+    },
+}
+impl Command {
+    /// Gets the name of this command, for reporting.
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Encrypt { .. } => "encrypt",
+            Self::Decrypt { .. } => "decrypt",
+            Self::Verify { .. } => "verify",
+            Self::Info { .. } => "info",
+            Self::CheckFactors { .. } => "check-factors",
+            Self::Estimate { .. } => "estimate",
+            Self::Extract { .. } => "extract",
+            Self::ChangePassphrase { .. } => "change-passphrase",
+            #[cfg(feature = "ephemeral")]
+            Self::RefreshEphemeral { .. } => "refresh-ephemeral",
+            Self::MergeOptions { .. } => "merge-options",
+            Self::Send { .. } => "send",
+            Self::Receive { .. } => "receive",
+            Self::ListFactors => "list-factors",
+            Self::SelfTest { .. } => "self-test",
+            Self::CheckVectors { .. } => "check-vectors",
+            Self::HeaderSchema => "header-schema",
+            Self::Calibrate { .. } => "calibrate",
+        }
+    }
+
+    /// Whether or not this command writes its payload to stdout.
+    fn writes_to_stdout(&self) -> bool {
+        match self {
+            Self::Encrypt { dry_run: true, .. } => false,
+            // Several inputs are always written to files
+            Self::Encrypt { more_inputs, .. } if !more_inputs.is_empty() => false,
+            Self::Decrypt {
+                also_stdout: true, ..
+            } => true,
+            Self::Decrypt { view: true, .. } => false,
+            Self::HeaderSchema => true,
+            Self::Encrypt { output, .. } | Self::Decrypt { output, .. } => output.is_none(),
+            Self::Verify { .. }
+            | Self::Info { .. }
+            | Self::CheckFactors { .. }
+            | Self::Estimate { .. }
+            | Self::Extract { .. }
+            | Self::ChangePassphrase { .. }
+            | Self::MergeOptions { .. }
+            | Self::Send { .. }
+            | Self::Receive { .. }
+            | Self::ListFactors
+            | Self::SelfTest { .. }
+            | Self::CheckVectors { .. }
+            | Self::Calibrate { .. } => false,
+            #[cfg(feature = "ephemeral")]
+            Self::RefreshEphemeral { .. } => false,
+        }
+    }
 }
@@ -0,0 +1,249 @@
+//! Padding for plaintexts, to hide how long they are. The ciphertext is only ever a few bytes
+//! longer than the plaintext (a 16-byte tag for every chunk), so without this, anyone can tell how
+//! long the plaintext is, which can say a lot about a small secret.
+//!
+//! A padded plaintext starts with its true length (as 8 little-endian bytes), followed by the
+//! plaintext itself, and then zeros up to the length given by the [`PaddingScheme`]. All of that
+//! is encrypted, so only someone who can decrypt the file learns the true length. The scheme is
+//! recorded in the header (see [`Header::set_plaintext_padding`](crate::Header::set_plaintext_padding)),
+//! and the padding is added with a [`PaddedReader`] and stripped again with an
+//! [`UnpaddingWriter`].
+//!
+//! This only hides the length within a bucket: a 3-byte and a 900-byte plaintext padded to
+//! multiples of 1024 bytes look the same, but a 2000-byte one doesn't. Anything else that gives
+//! the length away (like the size stored in a file's metadata) defeats it entirely.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::{self, Display},
+    io::{self, Read, Write},
+};
+
+/// The length of the true length at the start of a padded plaintext.
+pub const LEN_PREFIX_LEN: u64 = 8;
+
+/// How a plaintext is padded. This is stored in the header, so the padding can be stripped again.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PaddingScheme {
+    /// Pad to the next power of two. This never more than doubles the length, but only hides it
+    /// to within a factor of two.
+    PowerOfTwo,
+    /// Pad to the next multiple of this many bytes.
+    Multiple(u64),
+}
+impl PaddingScheme {
+    /// Gets how long a plaintext of the given length will be once padded (including its true
+    /// length at the start). This is what should be given to [`ciphertext_len`](crate::ciphertext_len).
+    pub fn padded_len(&self, plaintext_len: u64) -> u64 {
+        let len = plaintext_len + LEN_PREFIX_LEN;
+        match *self {
+            Self::PowerOfTwo => len.next_power_of_two(),
+            Self::Multiple(block) => len.div_ceil(block.max(1)) * block.max(1),
+        }
+    }
+
+    /// Parses a scheme given by the user: `pow2` for [`PaddingScheme::PowerOfTwo`], or a number
+    /// of bytes for [`PaddingScheme::Multiple`].
+    pub fn parse(scheme: &str) -> Result<Self, String> {
+        if scheme == "pow2" {
+            return Ok(Self::PowerOfTwo);
+        }
+        scheme
+            .parse()
+            .ok()
+            .filter(|&block| block > 0)
+            .map(Self::Multiple)
+            .ok_or(format!(
+                "'{scheme}' is not a padding scheme (it should be `pow2` or a number of bytes)"
+            ))
+    }
+}
+impl Display for PaddingScheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::PowerOfTwo => write!(f, "to the next power of two"),
+            Self::Multiple(block) => write!(f, "to a multiple of {block} bytes"),
+        }
+    }
+}
+
+/// A reader that pads everything read from the given reader with the given scheme. The inner
+/// reader must give exactly the number of bytes it was created with, or reading will fail, since
+/// the length has to be written before any of the plaintext.
+pub struct PaddedReader<R: Read> {
+    inner: R,
+    /// The true length of the plaintext.
+    len: u64,
+    /// The length of the padded plaintext.
+    padded_len: u64,
+    /// How much of the padded plaintext has been read so far.
+    pos: u64,
+}
+impl<R: Read> PaddedReader<R> {
+    /// Creates a new padding reader for a plaintext of the given length.
+    pub fn new(inner: R, len: u64, scheme: PaddingScheme) -> Self {
+        Self {
+            inner,
+            len,
+            padded_len: scheme.padded_len(len),
+            pos: 0,
+        }
+    }
+}
+impl<R: Read> Read for PaddedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = if self.pos < LEN_PREFIX_LEN {
+            let prefix = &self.len.to_le_bytes()[self.pos as usize..];
+            let read = prefix.len().min(buf.len());
+            buf[..read].copy_from_slice(&prefix[..read]);
+            read
+        } else if self.pos < LEN_PREFIX_LEN + self.len {
+            let left = LEN_PREFIX_LEN + self.len - self.pos;
+            let max = buf.len().min(usize::try_from(left).unwrap_or(usize::MAX));
+            let read = self.inner.read(&mut buf[..max])?;
+            if read == 0 && max > 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "input was shorter than the length given for padding",
+                ));
+            }
+            read
+        } else {
+            // Anything more would be silently dropped
+            if self.pos == LEN_PREFIX_LEN + self.len && self.inner.read(&mut [0u8])? != 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "input was longer than the length given for padding",
+                ));
+            }
+            let left = self.padded_len - self.pos;
+            let read = buf.len().min(usize::try_from(left).unwrap_or(usize::MAX));
+            buf[..read].fill(0);
+            read
+        };
+        self.pos += read as u64;
+
+        Ok(read)
+    }
+}
+
+/// A writer that strips the padding from a padded plaintext written to it, passing only the
+/// plaintext itself through to the inner writer.
+pub struct UnpaddingWriter<W: Write> {
+    inner: W,
+    /// The true length at the start, as far as it's been written.
+    prefix: Vec<u8>,
+    /// How much of the plaintext is still to be passed through, once the true length is known.
+    left: Option<u64>,
+}
+impl<W: Write> UnpaddingWriter<W> {
+    /// Creates a new unpadding writer.
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            prefix: Vec::with_capacity(LEN_PREFIX_LEN as usize),
+            left: None,
+        }
+    }
+
+    /// Makes sure the whole plaintext was written (which it won't have been if decryption
+    /// stopped early), returning the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.left != Some(0) {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the padded plaintext is shorter than the length at its start",
+            ));
+        }
+        self.inner.flush()?;
+
+        Ok(self.inner)
+    }
+}
+impl<W: Write> Write for UnpaddingWriter<W> {
+    fn write(&mut self, mut buf: &[u8]) -> io::Result<usize> {
+        let written = buf.len();
+        if self.left.is_none() {
+            let needed = LEN_PREFIX_LEN as usize - self.prefix.len();
+            let (prefix, rest) = buf.split_at(needed.min(buf.len()));
+            self.prefix.extend_from_slice(prefix);
+            buf = rest;
+            if self.prefix.len() == LEN_PREFIX_LEN as usize {
+                self.left = Some(u64::from_le_bytes(self.prefix[..].try_into().unwrap()));
+            }
+        }
+        if let Some(left) = &mut self.left {
+            let plaintext = &buf[..buf.len().min(usize::try_from(*left).unwrap_or(usize::MAX))];
+            self.inner.write_all(plaintext)?;
+            *left -= plaintext.len() as u64;
+        }
+
+        Ok(written)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    /// Encrypts the given plaintext padded with the given scheme, returning the whole file.
+    fn encrypt(plaintext: &[u8], scheme: PaddingScheme) -> Vec<u8> {
+        let (mut header, encryptor) = testing::header("padded");
+        header.set_plaintext_padding(scheme).unwrap();
+        let mut padded = Vec::new();
+        PaddedReader::new(plaintext, plaintext.len() as u64, scheme)
+            .read_to_end(&mut padded)
+            .unwrap();
+        assert_eq!(
+            padded.len() as u64,
+            scheme.padded_len(plaintext.len() as u64)
+        );
+
+        testing::encrypt(header, encryptor, &padded)
+    }
+
+    /// Decrypts the given file and strips its padding.
+    fn decrypt(file: &[u8]) -> Vec<u8> {
+        let padded = testing::decrypt(file, "padded").unwrap();
+        let mut writer = UnpaddingWriter::new(Vec::new());
+        writer.write_all(&padded).unwrap();
+
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn padded_plaintexts_round_trip() {
+        for scheme in [PaddingScheme::PowerOfTwo, PaddingScheme::Multiple(1024)] {
+            for len in [0, 1, 1016, 1017, 5000] {
+                let plaintext = (0..len).map(|idx| (idx % 251) as u8).collect::<Vec<_>>();
+                assert_eq!(
+                    decrypt(&encrypt(&plaintext, scheme)),
+                    plaintext,
+                    "{len} bytes padded {scheme}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn plaintexts_in_the_same_bucket_look_the_same() {
+        let file_len = |len: usize, scheme: PaddingScheme| encrypt(&vec![1; len], scheme).len();
+
+        let scheme = PaddingScheme::Multiple(1024);
+        // Everything up to 1016 bytes fits in one block with the length in front of it
+        for len in [0, 1, 500, 1016] {
+            assert_eq!(file_len(len, scheme), file_len(0, scheme), "{len} bytes");
+        }
+        assert!(file_len(1017, scheme) > file_len(1016, scheme));
+
+        let scheme = PaddingScheme::PowerOfTwo;
+        for len in [1017, 1500, 2040] {
+            assert_eq!(file_len(len, scheme), file_len(2000, scheme), "{len} bytes");
+        }
+        assert!(file_len(2041, scheme) > file_len(2040, scheme));
+    }
+}
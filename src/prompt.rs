@@ -0,0 +1,365 @@
+use crate::{factor::FactorRegistry, header::OptionPlan};
+use anyhow::{anyhow, bail, Result};
+use dialoguer::{console::Term, Confirm, Input, Password, Select};
+#[cfg(unix)]
+use std::io::IsTerminal;
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+};
+
+/// A way of asking the user questions. Factors (and the header) do all their prompting through
+/// this, so the CLI can use the terminal while other users of the library can answer the
+/// questions some other way (e.g. from a script, or a GUI).
+pub trait Prompter {
+    /// Asks the user for a line of text, which must be accepted by `validate` (which returns an
+    /// explanation of the problem otherwise). If a default is given, it's used when the user
+    /// enters nothing (so an empty default makes the input optional).
+    fn input(
+        &mut self,
+        prompt: &str,
+        default: Option<&str>,
+        validate: &dyn Fn(&str) -> Result<(), String>,
+    ) -> Result<String>;
+    /// Asks the user for a secret, which shouldn't be shown as it's entered. This must be
+    /// accepted by `validate`, like with [`Prompter::input`].
+    fn password(
+        &mut self,
+        prompt: &str,
+        validate: &dyn Fn(&str) -> Result<(), String>,
+    ) -> Result<String>;
+    /// Asks the user to choose one of the given items, returning its index.
+    fn select(&mut self, prompt: &str, items: &[&str]) -> Result<usize>;
+    /// Asks the user a yes/no question.
+    fn confirm(&mut self, prompt: &str) -> Result<bool>;
+    /// Tells the user something, if the message is important enough for how verbose the user
+    /// wants us to be. Messages are never mixed in with the actual output (which might be
+    /// ciphertext going to stdout).
+    fn message(&mut self, verbosity: Verbosity, msg: &str);
+    /// Lets the user lay out all the new encryption options at once (e.g. in a full-screen editor),
+    /// rather than being asked about them one at a time, returning them in the order they should be
+    /// created. The new options can't reuse the names in `existing`, and can be put behind the
+    /// `gates` that already exist (both from options set up earlier, like the real options of a
+    /// file with a decoy).
+    ///
+    /// This returns `None` if the prompter can't do that (the default), in which case the options
+    /// are asked about one at a time.
+    fn plan_options(
+        &mut self,
+        _registry: &FactorRegistry,
+        _existing: &[&str],
+        _gates: &[&str],
+    ) -> Result<Option<Vec<OptionPlan>>> {
+        Ok(None)
+    }
+
+    /// Tells the user something they need to see even when they've asked for quiet, like a warning
+    /// or secrets they have to store (e.g. Shamir shares).
+    fn notice(&mut self, msg: &str) {
+        self.message(Verbosity::Quiet, msg);
+    }
+    /// Tells the user about what's happening (e.g. that a download has started).
+    fn info(&mut self, msg: &str) {
+        self.message(Verbosity::Normal, msg);
+    }
+    /// Tells the user extra details they'll only want if they're trying to work out what's going
+    /// on.
+    fn detail(&mut self, msg: &str) {
+        self.message(Verbosity::Verbose, msg);
+    }
+}
+impl dyn Prompter + '_ {
+    /// Asks the user for a value that can be parsed from a line of text (like a number),
+    /// re-prompting until they enter a valid one.
+    pub fn parsed<T>(&mut self, prompt: &str, default: Option<T>) -> Result<T>
+    where
+        T: FromStr + Display,
+        T::Err: Display,
+    {
+        let default = default.map(|default| default.to_string());
+        let input = self.input(prompt, default.as_deref(), &|input| {
+            input
+                .trim()
+                .parse::<T>()
+                .map(|_| ())
+                .map_err(|err| err.to_string())
+        })?;
+        // We validated this above
+        Ok(input.trim().parse().ok().unwrap())
+    }
+
+    /// Asks the user to set a new passphrase, and then to enter it again, since a typo in it would
+    /// lock them out. If the two don't match, they're asked again (a few times), so nothing done
+    /// before this (like uploading data or showing Shamir shares) is wasted.
+    pub fn new_passphrase(
+        &mut self,
+        prompt: &str,
+        validate: &dyn Fn(&str) -> Result<(), String>,
+    ) -> Result<String> {
+        for attempt in 1..=NEW_PASSPHRASE_ATTEMPTS {
+            let passphrase = self.password(prompt, validate)?;
+            let confirmation = self.password("Confirm the passphrase", &|_| Ok(()))?;
+            if passphrase == confirmation {
+                return Ok(passphrase);
+            }
+            if attempt < NEW_PASSPHRASE_ATTEMPTS {
+                self.notice("The passphrases didn't match, try again.");
+            }
+        }
+
+        bail!("the passphrases didn't match {NEW_PASSPHRASE_ATTEMPTS} times")
+    }
+}
+
+/// How many times the user can enter a new passphrase that doesn't match its confirmation before
+/// giving up.
+const NEW_PASSPHRASE_ATTEMPTS: u32 = 3;
+
+/// How much the user wants to be told. Each message has the least verbosity at which it's shown.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Verbosity {
+    /// Only messages the user can't do without.
+    Quiet,
+    /// Progress messages as well.
+    #[default]
+    Normal,
+    /// Extra details as well.
+    Verbose,
+}
+
+/// The error for when the user cancels a prompt, or when they can't be prompted at all (e.g.
+/// because there's no terminal). Callers can check for this with `anyhow::Error::is`.
+#[derive(Debug)]
+pub struct Cancelled;
+impl Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "operation cancelled")
+    }
+}
+impl std::error::Error for Cancelled {}
+
+/// A [`Prompter`] that asks questions in the terminal, using `dialoguer`. Pressing Esc in a
+/// selection or confirmation cancels the operation. Messages are written to stderr, if they're
+/// important enough for the given verbosity.
+///
+/// On Unix, questions are asked on the controlling terminal (`/dev/tty`) rather than through
+/// stdin and stderr, so they still work when those are redirected (e.g. when piping data through
+/// us). If there's no terminal at all, every question fails with [`Cancelled`], so factors have to
+/// be given some non-interactive way (like `$CYST_PASSPHRASE`).
+///
+/// A choice between only one item is made automatically (saying which item was chosen), unless
+/// `always_prompt` is set, so there's nothing to answer (and no need for a terminal) when there's
+/// only one option to decrypt with, for instance.
+pub struct DialoguerPrompter {
+    pub verbosity: Verbosity,
+    /// Whether to ask the user to choose even when there's only one choice.
+    pub always_prompt: bool,
+    /// Whether to lay out new encryption options in a full-screen editor, rather than asking about
+    /// them one at a time.
+    #[cfg(feature = "tui")]
+    pub tui: bool,
+    /// The terminal questions are asked on.
+    term: Term,
+}
+impl DialoguerPrompter {
+    /// Creates a new prompter that only shows messages important enough for the given verbosity.
+    pub fn new(verbosity: Verbosity) -> Self {
+        Self {
+            verbosity,
+            always_prompt: false,
+            #[cfg(feature = "tui")]
+            tui: false,
+            term: controlling_terminal(),
+        }
+    }
+
+    /// Shows the options of the given header in a full-screen browser until the user closes it,
+    /// with the given notes about the file as a whole underneath.
+    #[cfg(feature = "tui")]
+    pub fn browse_options(
+        &self,
+        header: &crate::Header,
+        registry: &FactorRegistry,
+        notes: &[String],
+    ) -> Result<()> {
+        crate::tui::browse_options(
+            self.term("how to show the options")?,
+            header,
+            registry,
+            notes,
+        )
+    }
+
+    /// Gets the terminal to ask the given question on, failing if there isn't one.
+    fn term(&self, prompt: &str) -> Result<&Term> {
+        if self.term.is_term() {
+            Ok(&self.term)
+        } else {
+            Err(anyhow!(
+                "no terminal to ask {prompt:?} on (factors that can be given non-interactively, like passphrases, must be given that way)"
+            )
+            .context(Cancelled))
+        }
+    }
+}
+impl Default for DialoguerPrompter {
+    fn default() -> Self {
+        Self::new(Verbosity::default())
+    }
+}
+impl Prompter for DialoguerPrompter {
+    fn input(
+        &mut self,
+        prompt: &str,
+        default: Option<&str>,
+        validate: &dyn Fn(&str) -> Result<(), String>,
+    ) -> Result<String> {
+        let term = self.term(prompt)?;
+        let mut input = Input::<String>::new()
+            .with_prompt(prompt)
+            .validate_with(|input: &String| validate(input));
+        match default {
+            // Showing an empty default would just be confusing
+            Some("") => input = input.allow_empty(true),
+            Some(default) => input = input.default(default.to_string()),
+            None => {}
+        }
+
+        input.interact_text_on(term).map_err(cancelled)
+    }
+    fn password(
+        &mut self,
+        prompt: &str,
+        validate: &dyn Fn(&str) -> Result<(), String>,
+    ) -> Result<String> {
+        let term = self.term(prompt)?;
+        // `dialoguer` can't read secrets from the terminal when stdin is something else
+        #[cfg(unix)]
+        if !std::io::stdin().is_terminal() {
+            return loop {
+                term.write_str(&format!("{prompt}: "))?;
+                let password = read_hidden_line().map_err(|err| anyhow!(err).context(Cancelled))?;
+                term.write_line("")?;
+                match validate(&password) {
+                    Ok(()) => break Ok(password),
+                    Err(err) => term.write_line(&format!("error: {err}"))?,
+                }
+            };
+        }
+
+        Password::new()
+            .with_prompt(prompt)
+            .validate_with(|password: &String| validate(password))
+            .interact_on(term)
+            .map_err(cancelled)
+    }
+    fn select(&mut self, prompt: &str, items: &[&str]) -> Result<usize> {
+        if let [item] = items {
+            if !self.always_prompt {
+                self.info(&format!("{prompt}: {item} (the only choice)"));
+                return Ok(0);
+            }
+        }
+
+        Select::new()
+            .with_prompt(prompt)
+            .items(items)
+            .interact_on_opt(self.term(prompt)?)
+            .map_err(cancelled)?
+            .ok_or(Cancelled.into())
+    }
+    fn confirm(&mut self, prompt: &str) -> Result<bool> {
+        Confirm::new()
+            .with_prompt(prompt)
+            .interact_on_opt(self.term(prompt)?)
+            .map_err(cancelled)?
+            .ok_or(Cancelled.into())
+    }
+    fn message(&mut self, verbosity: Verbosity, msg: &str) {
+        if verbosity <= self.verbosity {
+            eprintln!("{msg}");
+        }
+    }
+    #[cfg(feature = "tui")]
+    fn plan_options(
+        &mut self,
+        registry: &FactorRegistry,
+        existing: &[&str],
+        gates: &[&str],
+    ) -> Result<Option<Vec<OptionPlan>>> {
+        if !self.tui {
+            return Ok(None);
+        }
+        let term = self.term("how to set up the encryption options")?.clone();
+
+        crate::tui::edit_options(&term, self, registry, existing, gates).map(Some)
+    }
+}
+
+/// Opens the controlling terminal, falling back to stderr if it can't be opened (which is also what
+/// we use on platforms without one, where `dialoguer` reads from the console itself).
+fn controlling_terminal() -> Term {
+    #[cfg(unix)]
+    {
+        let tty = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/tty");
+        if let Ok((read, write)) = tty.and_then(|tty| Ok((tty.try_clone()?, tty))) {
+            return Term::read_write_pair(read, write);
+        }
+    }
+
+    Term::stderr()
+}
+
+/// Reads a line from the controlling terminal without echoing it.
+#[cfg(unix)]
+fn read_hidden_line() -> std::io::Result<String> {
+    use std::{
+        io::{BufRead, BufReader, ErrorKind},
+        mem::MaybeUninit,
+        os::fd::AsRawFd,
+    };
+
+    let tty = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")?;
+    let fd = tty.as_raw_fd();
+    let check = |result: libc::c_int| {
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(std::io::Error::last_os_error())
+        }
+    };
+
+    let mut termios = MaybeUninit::uninit();
+    check(unsafe { libc::tcgetattr(fd, termios.as_mut_ptr()) })?;
+    let original = unsafe { termios.assume_init() };
+    let mut hidden = original;
+    hidden.c_lflag &= !libc::ECHO;
+    check(unsafe { libc::tcsetattr(fd, libc::TCSAFLUSH, &hidden) })?;
+
+    let mut line = String::new();
+    let read = BufReader::new(&tty).read_line(&mut line);
+    // Always turn echoing back on, even if the read failed
+    check(unsafe { libc::tcsetattr(fd, libc::TCSAFLUSH, &original) })?;
+    if read? == 0 {
+        return Err(std::io::Error::new(
+            ErrorKind::UnexpectedEof,
+            "the terminal was closed",
+        ));
+    }
+
+    let len = line.trim_end_matches(['\r', '\n']).len();
+    line.truncate(len);
+    Ok(line)
+}
+
+/// Converts an error from `dialoguer` (like stdin being closed) into a cancellation, keeping the
+/// original error as the cause.
+fn cancelled(err: dialoguer::Error) -> anyhow::Error {
+    anyhow::Error::new(err).context(Cancelled)
+}
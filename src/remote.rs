@@ -0,0 +1,169 @@
+use anyhow::{bail, Result};
+use log::debug;
+use std::{
+    io::{self, Read},
+    time::Duration,
+};
+use ureq::{Agent, AgentBuilder, Response};
+
+/// The timeout for connecting to the server, and for each read from it, in seconds. There's no
+/// timeout on the whole download, since files can be arbitrarily large.
+const TIMEOUT_SECS: u64 = 30;
+/// How many times a download that's cut off can be picked up where it left off, over the whole
+/// download.
+const MAX_RESUMES: u32 = 5;
+
+/// A reader that streams an encrypted file from an HTTP(S) URL, so it can be decrypted without
+/// downloading it first. Redirects are followed, and if the connection drops part-way through
+/// and the server supports range requests, the download is picked up where it left off. If the
+/// file has changed in the meantime, that will be refused where the server can tell us about it,
+/// and will fail authentication anyway where it can't.
+pub struct RemoteReader {
+    agent: Agent,
+    /// The URL the file was actually served from, after any redirects.
+    url: String,
+    /// The body of the current response.
+    body: Box<dyn Read + Send + Sync>,
+    /// The number of bytes read so far.
+    pos: u64,
+    /// The length of the whole file, if the server said.
+    len: Option<u64>,
+    /// Whether the server accepts range requests, so the download can be resumed.
+    resumable: bool,
+    /// Something that identifies this version of the file (an `ETag` or `Last-Modified` date), for
+    /// making sure a resumed download is of the same file.
+    validator: Option<String>,
+    /// How many more times the download can be resumed.
+    resumes_left: u32,
+}
+impl RemoteReader {
+    /// Starts downloading the given URL.
+    pub fn open(url: &str) -> Result<Self> {
+        let agent = AgentBuilder::new()
+            .timeout_connect(Duration::from_secs(TIMEOUT_SECS))
+            .timeout_read(Duration::from_secs(TIMEOUT_SECS))
+            .build();
+        // The download has to be exactly the bytes of the file for it to be resumed at the right
+        // place, so it mustn't be compressed
+        let resp = match agent.get(url).set("Accept-Encoding", "identity").call() {
+            Ok(resp) => resp,
+            Err(ureq::Error::Status(status, resp)) => {
+                bail!(
+                    "{} answered with status {status} ({})",
+                    resp.get_url(),
+                    resp.status_text()
+                )
+            }
+            Err(err) => {
+                return Err(anyhow::Error::new(err).context(format!("couldn't reach {url}")))
+            }
+        };
+        debug!("{} responded with status {}", resp.get_url(), resp.status());
+
+        let len = resp
+            .header("Content-Length")
+            .and_then(|len| len.parse().ok());
+        let resumable = resp.header("Accept-Ranges") == Some("bytes");
+        // A weak `ETag` can't be used to resume a download
+        let validator = resp
+            .header("ETag")
+            .filter(|etag| !etag.starts_with("W/"))
+            .or(resp.header("Last-Modified"))
+            .map(str::to_string);
+        Ok(Self {
+            agent,
+            url: resp.get_url().to_string(),
+            len,
+            resumable,
+            validator,
+            body: Box::new(resp.into_reader()),
+            pos: 0,
+            resumes_left: MAX_RESUMES,
+        })
+    }
+
+    /// Gets the URL the file is being downloaded from, after any redirects.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Asks the server for the rest of the file, from where we got up to.
+    fn resume(&mut self) -> io::Result<()> {
+        debug!(
+            "Resuming the download of {} from byte {}",
+            self.url, self.pos
+        );
+        let mut req = self
+            .agent
+            .get(&self.url)
+            .set("Accept-Encoding", "identity")
+            .set("Range", &format!("bytes={}-", self.pos));
+        if let Some(validator) = &self.validator {
+            req = req.set("If-Range", validator);
+        }
+        let resp = req.call().map_err(io::Error::other)?;
+        check_resumed(&resp, self.pos)?;
+        self.body = Box::new(resp.into_reader());
+
+        Ok(())
+    }
+}
+impl Read for RemoteReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let err = match self.body.read(buf) {
+                // Stopping short of the length the server gave means the connection was cut
+                Ok(0) if self.len.is_some_and(|len| self.pos < len) && !buf.is_empty() => {
+                    io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "the connection was closed before the whole file was sent",
+                    )
+                }
+                Ok(n) => {
+                    self.pos += n as u64;
+                    return Ok(n);
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => err,
+            };
+            if !self.resumable {
+                return Err(io::Error::new(
+                    err.kind(),
+                    format!("the download was cut off, and the server can't resume it: {err}"),
+                ));
+            }
+            if self.resumes_left == 0 {
+                return Err(io::Error::new(
+                    err.kind(),
+                    format!("the download was cut off too many times: {err}"),
+                ));
+            }
+            debug!("The download of {} was cut off: {err}", self.url);
+            self.resumes_left -= 1;
+            self.resume()?;
+        }
+    }
+}
+
+/// Checks that the given response to a range request is the rest of the file from `pos`, and not
+/// the whole file again (which is what servers send if it's changed).
+fn check_resumed(resp: &Response, pos: u64) -> io::Result<()> {
+    if resp.status() != 206 {
+        return Err(io::Error::other(format!(
+            "the server sent the whole file again instead of the rest of it (has it changed?), status {}",
+            resp.status()
+        )));
+    }
+    let start = resp
+        .header("Content-Range")
+        .and_then(|range| range.strip_prefix("bytes "))
+        .and_then(|range| range.split_once('-'))
+        .and_then(|(start, _)| start.parse::<u64>().ok());
+    if start != Some(pos) {
+        return Err(io::Error::other(format!(
+            "the server sent the wrong part of the file (asked for byte {pos} onwards)"
+        )));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,127 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// The bytes every state file starts with, so we never mistake some other file for one.
+const STATE_MAGIC: &[u8] = b"cyst resume state v1\n";
+
+/// Encrypted output that's written to a `.partial` file beside where it should end up, and only
+/// moved into place once it's complete. If encryption is interrupted, the `.partial` file is left
+/// behind, along with a state file recording which input it was encrypting, so it can be resumed.
+///
+/// The state file deliberately contains nothing secret (just the input's path, size, and
+/// modification time). The `.partial` file starts with the final header, and the number of chunks
+/// already encrypted follows from its length, so the only other thing needed to resume is the
+/// primary key. Rather than storing that (which would leave a key to the whole file on disk
+/// until the encryption finished, protected by at most whatever we sealed it under), the user has
+/// to satisfy one of the file's options again. The `.partial` file itself reveals no more than the
+/// finished file would.
+pub struct PartialOutput {
+    /// Where the output should end up.
+    output: PathBuf,
+    /// Where the output is written until it's complete.
+    pub path: PathBuf,
+    /// Where the state needed to resume is written.
+    state_path: PathBuf,
+}
+impl PartialOutput {
+    /// Creates the partial output for the given final output path. This doesn't touch the
+    /// filesystem.
+    pub fn new(output: &Path) -> Self {
+        let with_suffix = |suffix: &str| {
+            let mut path = OsString::from(output);
+            path.push(suffix);
+            PathBuf::from(path)
+        };
+
+        Self {
+            output: output.to_path_buf(),
+            path: with_suffix(".partial"),
+            state_path: with_suffix(".partial.state"),
+        }
+    }
+
+    /// Checks whether there's an interrupted encryption of the given input that can be resumed.
+    pub fn can_resume(&self, input: &Path) -> Result<bool> {
+        if !self.path.is_file() {
+            return Ok(false);
+        }
+        let mut bytes = Vec::new();
+        match File::open(&self.state_path) {
+            Ok(mut file) => file.read_to_end(&mut bytes)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err.into()),
+        };
+        let saved = match bytes.strip_prefix(STATE_MAGIC) {
+            Some(bytes) => bincode::deserialize::<ResumeState>(bytes).ok(),
+            None => None,
+        };
+
+        Ok(saved.is_some() && saved == ResumeState::of(input).ok())
+    }
+
+    /// Creates a fresh partial output file, replacing any existing one. If an input is given, its
+    /// state is saved so the encryption can be resumed (otherwise any old state is removed).
+    pub fn create(&self, input: Option<&Path>) -> Result<File> {
+        let file = File::create(&self.path)?;
+        match input {
+            Some(input) => {
+                let mut bytes = STATE_MAGIC.to_vec();
+                bytes.extend(bincode::serialize(&ResumeState::of(input)?)?);
+                File::create(&self.state_path)?.write_all(&bytes)?;
+            }
+            None => remove_if_exists(&self.state_path)?,
+        }
+
+        Ok(file)
+    }
+
+    /// Opens the existing partial output file, to resume writing it.
+    pub fn open(&self) -> Result<File> {
+        Ok(OpenOptions::new().read(true).write(true).open(&self.path)?)
+    }
+
+    /// Moves the complete output into place and cleans up the state file.
+    pub fn finish(self) -> Result<()> {
+        fs::rename(&self.path, &self.output)?;
+        remove_if_exists(&self.state_path)?;
+
+        Ok(())
+    }
+}
+
+/// Removes the file at the given path, if there is one.
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        res => res,
+    }
+}
+
+/// What we record about the input, to make sure we only resume with the same one.
+#[derive(Serialize, Deserialize, PartialEq)]
+struct ResumeState {
+    /// The canonical path of the input.
+    input: PathBuf,
+    /// The size of the input, in bytes.
+    len: u64,
+    /// When the input was last modified, if the platform supports it.
+    modified: Option<SystemTime>,
+}
+impl ResumeState {
+    /// Gets the state of the given input as it currently is.
+    fn of(input: &Path) -> Result<Self> {
+        let metadata = input.metadata()?;
+        Ok(Self {
+            input: input.canonicalize()?,
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+        })
+    }
+}
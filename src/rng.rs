@@ -0,0 +1,70 @@
+//! Where all the randomness cyst uses (for keys, salts, nonces, and the like) comes from. This is
+//! the operating system's RNG, unless it's been replaced on the current thread with [`with_rng`],
+//! which lets tests make encryption reproducible with a seeded RNG.
+
+use rand::{rngs::OsRng, CryptoRng, RngCore};
+use std::cell::RefCell;
+
+thread_local! {
+    /// The RNG that's replaced [`OsRng`] on this thread, if there is one.
+    static OVERRIDE: RefCell<Option<Box<dyn RngCore>>> = const { RefCell::new(None) };
+}
+
+/// Runs the given closure with every random value cyst generates on this thread coming from the
+/// given RNG, rather than the operating system. This should only ever be used for testing, with a
+/// seeded RNG: anything encrypted this way is only as secret as the seed!
+///
+/// Shamir shares are the exception: quorum keys and the Shamir factors' secrets are split by the
+/// `shamirsecretsharing` crate, which draws the coefficients of its polynomials from its own RNG,
+/// so shares are never reproducible under a seeded RNG. Headers with quorum options or Shamir
+/// factors therefore won't be exactly reproducible.
+pub fn with_rng<T>(rng: impl RngCore + CryptoRng + 'static, f: impl FnOnce() -> T) -> T {
+    /// Puts back whatever RNG was in use before, even if `f` panics.
+    struct Restore(Option<Box<dyn RngCore>>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let previous = self.0.take();
+            OVERRIDE.with(|rng| *rng.borrow_mut() = previous);
+        }
+    }
+
+    let previous = OVERRIDE.with(|current| current.borrow_mut().replace(Box::new(rng)));
+    let _restore = Restore(previous);
+    f()
+}
+
+/// Whether the RNG has been replaced on this thread with [`with_rng`]. Anything that would
+/// otherwise generate randomness on other threads should stay on this one if so, or it won't use
+/// the replacement.
+pub(crate) fn is_replaced() -> bool {
+    OVERRIDE.with(|rng| rng.borrow().is_some())
+}
+
+/// The RNG used for everything random in cyst (see the [module docs](self)).
+#[derive(Clone, Copy, Default)]
+pub(crate) struct CystRng;
+impl CystRng {
+    /// Runs the given closure on the RNG that's currently in use.
+    fn with<T>(f: impl FnOnce(&mut dyn RngCore) -> T) -> T {
+        OVERRIDE.with(|rng| match rng.borrow_mut().as_deref_mut() {
+            Some(rng) => f(rng),
+            None => f(&mut OsRng),
+        })
+    }
+}
+impl RngCore for CystRng {
+    fn next_u32(&mut self) -> u32 {
+        Self::with(|rng| rng.next_u32())
+    }
+    fn next_u64(&mut self) -> u64 {
+        Self::with(|rng| rng.next_u64())
+    }
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        Self::with(|rng| rng.fill_bytes(dest))
+    }
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        Self::with(|rng| rng.try_fill_bytes(dest))
+    }
+}
+// Only RNGs that are themselves cryptographically secure can be put in place of `OsRng`
+impl CryptoRng for CystRng {}
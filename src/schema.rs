@@ -0,0 +1,240 @@
+//! A machine-readable description of how headers are laid out, so other tools can read them
+//! without linking this crate. Headers are serialized with bincode, which isn't self-describing, so
+//! this gives the name and type of every field in the order they're written, along with how each
+//! type is encoded (see [`ENCODING`]).
+//!
+//! This is written by hand alongside the structs in the header, so it must be updated whenever
+//! they change (which also means bumping [`FORMAT_VERSION`](crate::FORMAT_VERSION), as the test
+//! vectors in [`crate::vectors`] will point out).
+
+/// The root type of a serialized header.
+pub const ROOT: &str = "Header";
+
+/// How a file is laid out around its header, from the start of the file.
+pub const FILE_LAYOUT: &[&str] = &[
+    "The length of the header in bytes, as a u64.",
+    "The header itself, as a `Header`.",
+    "The header's extensions, as a `HeaderExtensions`, only if any of its fields are set. Each field after the first is only present if there are bytes left, so older extensions stop short; an unset field is a single zero byte.",
+    "Zeroes up to the header's length, if it's padded (see `Header.padded_len`).",
+    "The body: ChaCha20-Poly1305 STREAM ciphertext (big-endian 32-bit counter) in chunks of 4096 bytes of plaintext plus a 16-byte tag, or independent chunks if `Header.dedup` is set. Every chunk's associated data is the BLAKE2s-256 digest of the bincode serialization of (`metadata`, `body_len`, `padded_len`, `public_note`) followed by the extensions as they would be written with `option_bindings` unset. If `Header.ecc` is set, the body is Reed-Solomon encoded on top of that.",
+];
+
+/// How each kind of type is encoded by bincode (with its default options, as used here).
+pub const ENCODING: &[(&str, &str)] = &[
+    ("u8, u16, u32, u64", "Little-endian, at their full width."),
+    ("bool", "One byte, 0 or 1."),
+    (
+        "string",
+        "A u64 length in bytes, then that many bytes of UTF-8.",
+    ),
+    ("bytes", "A u64 length, then that many bytes."),
+    ("[u8; N]", "Exactly N bytes, with no length."),
+    ("option<T>", "One byte, 0 for none, or 1 followed by the T."),
+    ("list<T>", "A u64 count, then that many Ts."),
+    (
+        "map<K, V>",
+        "A u64 count, then that many pairs of K and V, in no particular order.",
+    ),
+    ("struct", "Each field in order, with nothing in between."),
+    (
+        "enum",
+        "The variant's index as a u32, then its fields in order.",
+    ),
+    (
+        "factor data",
+        "The bincode serialization of the factor's own data, which differs for every factor.",
+    ),
+];
+
+/// A type in the header.
+pub struct SchemaType {
+    /// The name of the type, which other types refer to it by.
+    pub name: &'static str,
+    /// What the type is for.
+    pub doc: &'static str,
+    /// The fields of the type, if it's a struct, or its variants, if it's an enum.
+    pub kind: SchemaKind,
+}
+
+/// Whether a type in the header is a struct or an enum.
+pub enum SchemaKind {
+    /// A struct, with its fields in the order they're serialized.
+    Struct(&'static [SchemaField]),
+    /// An enum, with its variants in the order of their indices.
+    Enum(&'static [SchemaVariant]),
+}
+
+/// A field of a type in the header.
+pub struct SchemaField {
+    /// The name of the field.
+    pub name: &'static str,
+    /// The type of the field, as one of the types in [`ENCODING`] or the name of another type.
+    pub ty: &'static str,
+    /// What the field holds.
+    pub doc: &'static str,
+}
+
+/// A variant of an enum in the header.
+pub struct SchemaVariant {
+    /// The name of the variant.
+    pub name: &'static str,
+    /// The fields of the variant, in order.
+    pub fields: &'static [SchemaField],
+}
+
+/// Shorthand for a field.
+const fn field(name: &'static str, ty: &'static str, doc: &'static str) -> SchemaField {
+    SchemaField { name, ty, doc }
+}
+
+/// Every type that can appear in a header, starting with [`ROOT`].
+pub const TYPES: &[SchemaType] = &[
+    SchemaType {
+        name: "Header",
+        doc: "The header of an encrypted file.",
+        kind: SchemaKind::Struct(&[
+            field("options", "map<string, OptionData>", "The options that can decrypt the file, by name."),
+            field("metadata", "option<FileMetadata>", "Information about the plaintext, if it was stored (not encrypted, but authenticated)."),
+            field("dedup", "option<DedupParams>", "How the plaintext was chunked, if the body was encrypted in dedup mode."),
+            field("body_len", "option<u64>", "The length of the body, if it was known when the file was encrypted."),
+            field("ecc", "option<EccParams>", "The error correction added to the body, if any."),
+            field("padded_len", "option<u64>", "The length the header is padded to a multiple of, if it's padded."),
+            field("public_note", "option<string>", "A note anyone can read (not encrypted, but authenticated)."),
+        ]),
+    },
+    SchemaType {
+        name: "HeaderExtensions",
+        doc: "Fields added since the first version of the format, stored after the header (see the file layout).",
+        kind: SchemaKind::Struct(&[
+            field("plaintext_padding", "option<PaddingScheme>", "How the plaintext was padded to hide its length, if it was (added in format version 2)."),
+            field("added_suffix", "option<string>", "The suffix added to the plaintext's filename to name the encrypted file, if it was named automatically (added in format version 3)."),
+            field("option_bindings", "option<map<string, OptionBinding>>", "What each option's key derivation is bound to, by the option's name, for options wrapped since format version 4 (added in format version 4)."),
+        ]),
+    },
+    SchemaType {
+        name: "OptionBinding",
+        doc: "What an option's key is bound to. Each key that goes into the option's key (the quorum key, or each factor's key, and the gate key) is expanded with HKDF-SHA256, salted with its factor's salt (or the option's salt), with the info `<label>\\0cyst option: <name>`.",
+        kind: SchemaKind::Struct(&[
+            field("name", "string", "The name the option had when it was wrapped, which is kept if the option is renamed later."),
+            field("factor_salts", "list<[u8; 32]>", "A salt for each factor, in the same order as the factors."),
+        ]),
+    },
+    SchemaType {
+        name: "FileMetadata",
+        doc: "Information about the plaintext.",
+        kind: SchemaKind::Struct(&[
+            field("filename", "option<string>", "The original name of the file."),
+            field("mime", "option<string>", "A hint as to the MIME type of the file."),
+            field("comment", "option<string>", "A free-form comment."),
+            field("size", "u64", "The size of the plaintext in bytes."),
+            field("hash", "option<bytes>", "A BLAKE2b-512 digest of the plaintext."),
+            field("mode", "option<u32>", "The Unix permissions of the original file."),
+        ]),
+    },
+    SchemaType {
+        name: "DedupParams",
+        doc: "How the plaintext is split into chunks in dedup mode.",
+        kind: SchemaKind::Struct(&[
+            field("min_size", "u32", "The smallest a chunk can be (unless it's the last one)."),
+            field("avg_bits", "u8", "The bits of the rolling hash that must be zero at a boundary."),
+            field("max_size", "u32", "The largest a chunk can be."),
+        ]),
+    },
+    SchemaType {
+        name: "EccParams",
+        doc: "The Reed-Solomon error correction added to the body.",
+        kind: SchemaKind::Struct(&[
+            field("parity", "u8", "The parity bytes in each 255-byte codeword."),
+            field("depth", "u16", "The number of codewords interleaved in each block."),
+        ]),
+    },
+    SchemaType {
+        name: "PaddingScheme",
+        doc: "How the plaintext is padded. A padded plaintext starts with its true length as a u64.",
+        kind: SchemaKind::Enum(&[
+            SchemaVariant {
+                name: "PowerOfTwo",
+                fields: &[],
+            },
+            SchemaVariant {
+                name: "Multiple",
+                fields: &[field("block", "u64", "The length is padded to a multiple of this.")],
+            },
+        ]),
+    },
+    SchemaType {
+        name: "OptionData",
+        doc: "One of the options that can decrypt the file.",
+        kind: SchemaKind::Struct(&[
+            field("salt", "[u8; 32]", "The salt for deriving the option's key from its factors' keys."),
+            field("kdf", "Kdf", "The key derivation function for the option's key."),
+            field("description", "option<string>", "A reminder of what the option is for."),
+            field("is_gate", "bool", "Whether the option is a gate, which only unlocks the options behind it."),
+            field("gated_behind", "option<string>", "The gate this option is behind, if any."),
+            field("factors", "list<FactorInstance>", "The option's factors."),
+            field("quorum", "option<Quorum>", "How many of the factors are needed, if not all of them."),
+            field("primary_key_nonce", "[u8; 12]", "The nonce the primary key is encrypted with."),
+            field("primary_key_ciphertext", "bytes", "The 32-byte primary key and 16-byte location of its stream (or a gate key), encrypted with ChaCha20-Poly1305 under the option's key."),
+            field("links", "list<OptionLink>", "Links to the primary key of another file, if the option was merged in from one."),
+        ]),
+    },
+    SchemaType {
+        name: "Kdf",
+        doc: "A key derivation function, with its parameters.",
+        kind: SchemaKind::Enum(&[
+            SchemaVariant {
+                name: "Argon2id",
+                fields: &[
+                    field("memory_kib", "u32", "The memory cost in KiB."),
+                    field("iterations", "u32", "The number of iterations."),
+                    field("parallelism", "u32", "The degree of parallelism."),
+                ],
+            },
+            SchemaVariant {
+                name: "Scrypt",
+                fields: &[
+                    field("log_n", "u8", "The base-2 logarithm of the cost."),
+                    field("r", "u32", "The block size."),
+                    field("p", "u32", "The parallelism."),
+                ],
+            },
+            SchemaVariant {
+                name: "Pbkdf2",
+                fields: &[field("iterations", "u32", "The number of iterations of HMAC-SHA256.")],
+            },
+        ]),
+    },
+    SchemaType {
+        name: "FactorInstance",
+        doc: "One of an option's factors.",
+        kind: SchemaKind::Struct(&[
+            field("name", "string", "The name of the factor (as listed by `cyst list-factors`)."),
+            field("data", "bytes", "The factor's data, which is factor data."),
+            field("hint", "option<string>", "A hint shown before the factor is needed."),
+        ]),
+    },
+    SchemaType {
+        name: "Quorum",
+        doc: "How many of an option's factors are needed, if not all of them.",
+        kind: SchemaKind::Struct(&[
+            field("threshold", "u8", "The number of factors needed."),
+            field("shares", "list<EncryptedShare>", "Each factor's share of the quorum key, in the same order as the factors."),
+        ]),
+    },
+    SchemaType {
+        name: "EncryptedShare",
+        doc: "A factor's share of a quorum key, encrypted under that factor's key.",
+        kind: SchemaKind::Struct(&[
+            field("nonce", "[u8; 12]", "The nonce of the ciphertext."),
+            field("ciphertext", "bytes", "The encrypted share."),
+        ]),
+    },
+    SchemaType {
+        name: "OptionLink",
+        doc: "The primary key of another file, encrypted under the primary key an option decrypts.",
+        kind: SchemaKind::Struct(&[
+            field("nonce", "[u8; 12]", "The nonce of the ciphertext."),
+            field("ciphertext", "bytes", "The encrypted primary key and stream location."),
+        ]),
+    },
+];
@@ -0,0 +1,157 @@
+//! Detached Ed25519 signatures over whole encrypted files, so whoever receives one can check who
+//! made it. The encryption itself only proves that a file hasn't been changed since it was
+//! encrypted, not who encrypted it (anyone who can satisfy an option could have), so this is
+//! separate from it, and covers the file exactly as it was written (header, body, and armor, if
+//! any).
+//!
+//! The signature is made over [`SIGNATURE_CONTEXT`] followed by the BLAKE2b-512 digest of the
+//! file (what `b2sum` prints), and is stored as its raw 64 bytes, so it can be checked without cyst
+//! like this:
+//!
+//! ```text
+//! (printf 'cyst detached signature v1\0'; b2sum file.cyst | cut -d' ' -f1 | xxd -r -p) > message
+//! openssl pkeyutl -verify -pubin -inkey public.pem -rawin -in message -sigfile file.cyst.sig
+//! ```
+//!
+//! Keys are read in the PEM formats OpenSSL uses, so a key pair can be made with
+//! `openssl genpkey -algorithm ed25519 -out private.pem` and
+//! `openssl pkey -in private.pem -pubout -out public.pem`.
+
+use crate::{error::authentication_failed, file::plaintext_hash};
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use std::{
+    ffi::OsString,
+    fs::File,
+    io::Seek,
+    path::{Path, PathBuf},
+};
+
+/// What the signed message starts with, before the file's digest, so a signature over a cyst file
+/// can't be passed off as a signature over anything else.
+pub const SIGNATURE_CONTEXT: &[u8] = b"cyst detached signature v1\0";
+/// The length of a signature in bytes.
+pub const SIGNATURE_LEN: usize = 64;
+/// What the DER encoding of an Ed25519 public key (as a `SubjectPublicKeyInfo`) starts with,
+/// before the 32 bytes of the key itself.
+const SPKI_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+
+/// An Ed25519 private key, for signing files.
+pub struct SigningKey(Ed25519KeyPair);
+impl SigningKey {
+    /// Reads a private key from the given PEM file (PKCS#8, as written by
+    /// `openssl genpkey -algorithm ed25519`).
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let der = read_pem(path, "PRIVATE KEY")?;
+        let pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(&der)
+            .map_err(|err| anyhow!("{path:?} is not an Ed25519 private key: {err}"))?;
+
+        Ok(Self(pair))
+    }
+
+    /// Gets the public key that goes with this one, as hex.
+    pub fn public_key(&self) -> String {
+        hex::encode(self.0.public_key())
+    }
+
+    /// Signs the given file, returning the signature.
+    pub fn sign_file(&self, path: &Path) -> Result<Vec<u8>> {
+        let mut file = File::open(path).with_context(|| format!("failed to open {path:?}"))?;
+        let message = signed_message(&mut file, path)?;
+        Ok(self.0.sign(&message).as_ref().to_vec())
+    }
+}
+
+/// An Ed25519 public key, for checking the signatures on files.
+pub struct VerifyingKey([u8; 32]);
+impl VerifyingKey {
+    /// Reads a public key from the given PEM file (a `SubjectPublicKeyInfo`, as written by
+    /// `openssl pkey -pubout`).
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let der = read_pem(path, "PUBLIC KEY")?;
+        match der.strip_prefix(&SPKI_PREFIX) {
+            Some(key) if key.len() == 32 => Ok(Self(key.try_into().unwrap())),
+            _ => bail!("{path:?} is not an Ed25519 public key"),
+        }
+    }
+
+    /// Gets this key as hex.
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0)
+    }
+
+    /// Checks that the given signature was made over the given file (open from `path`) with the
+    /// private key that goes with this one. A signature that doesn't match is an
+    /// [`crate::AuthenticationFailed`].
+    ///
+    /// The file is rewound afterwards, and whatever is done with it next should read it through
+    /// this same handle: opening `path` again would let the file be swapped for another one (e.g.
+    /// by renaming over it) after it was checked. Even then, anyone who can write to the file
+    /// itself could still change it in place, so a signature only says anything about a file kept
+    /// where nobody else can write to it.
+    pub fn verify_file(&self, file: &mut File, path: &Path, signature: &[u8]) -> Result<()> {
+        let message = signed_message(file, path)?;
+        file.rewind()?;
+        UnparsedPublicKey::new(&ED25519, &self.0)
+            .verify(&message, signature)
+            .map_err(|_| {
+                authentication_failed(format!(
+                    "the signature on {path:?} wasn't made with this key (or the file has been changed since it was signed)"
+                ))
+            })
+    }
+}
+
+/// Gets where the detached signature for the given file goes by default: beside it, with `.sig`
+/// added to its name.
+pub fn signature_path(path: &Path) -> PathBuf {
+    let mut sig_path = OsString::from(path.as_os_str());
+    sig_path.push(".sig");
+    PathBuf::from(sig_path)
+}
+
+/// Reads a detached signature from the given file.
+pub fn read_signature(path: &Path) -> Result<Vec<u8>> {
+    let signature =
+        std::fs::read(path).with_context(|| format!("failed to read the signature {path:?}"))?;
+    if signature.len() != SIGNATURE_LEN {
+        bail!(
+            "{path:?} is not a signature (it should be {SIGNATURE_LEN} bytes long, but it's {})",
+            signature.len()
+        );
+    }
+
+    Ok(signature)
+}
+
+/// Builds the message that's signed for the given file: [`SIGNATURE_CONTEXT`], followed by the
+/// file's digest (read from the given handle, from wherever its cursor is, which was opened from
+/// `path`). The file is streamed through the hash, so it doesn't have to fit in memory.
+fn signed_message(file: &mut File, path: &Path) -> Result<Vec<u8>> {
+    let digest = plaintext_hash(file).with_context(|| format!("failed to read {path:?}"))?;
+
+    let mut message = SIGNATURE_CONTEXT.to_vec();
+    message.extend_from_slice(&digest);
+    Ok(message)
+}
+
+/// Reads the DER bytes out of the given PEM file, which must hold a block with the given label.
+fn read_pem(path: &Path, label: &str) -> Result<Vec<u8>> {
+    let pem = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read the key {path:?}"))?;
+    let begin = format!("-----BEGIN {label}-----");
+    let end = format!("-----END {label}-----");
+    let body = pem
+        .split_once(&begin)
+        .and_then(|(_, rest)| rest.split_once(&end))
+        .map(|(body, _)| body)
+        .ok_or_else(|| anyhow!("{path:?} is not a PEM file with a {label}"))?;
+    let body = body.split_whitespace().collect::<String>();
+
+    STANDARD
+        .decode(body)
+        .with_context(|| format!("{path:?} is not valid PEM"))
+}
@@ -0,0 +1,77 @@
+//! Helpers for the unit tests, which set up headers without anyone there to answer the prompts
+//! (with the [`ScriptedPrompter`] the test vectors use).
+
+use crate::{
+    file::{ciphertext_len, decrypt_file, encrypt_file},
+    header::Header,
+    vectors::{registry, ScriptedPrompter},
+};
+use anyhow::Result;
+use chacha20poly1305::{aead::stream::EncryptorBE32, ChaCha20Poly1305};
+
+/// The passphrase of every option the tests create.
+pub(crate) const PASSPHRASE: &str = "correct horse battery staple";
+
+/// Creates a prompter that sets up a single passphrase option with the given name, and satisfies
+/// it when decrypting. Its key is derived with a single iteration of PBKDF2, so the tests don't
+/// spend their time on key derivation (any of the answers can be changed with
+/// [`ScriptedPrompter::set`]).
+pub(crate) fn prompter(name: &str) -> ScriptedPrompter {
+    ScriptedPrompter::new(vec![
+        ("Enter a name for this encryption option", name.to_string()),
+        ("Enter a description", String::new()),
+        ("Make this option a gate", "n".to_string()),
+        ("Choose an encryption factor", "Passphrase".to_string()),
+        ("Enter a passphrase", PASSPHRASE.to_string()),
+        ("Confirm the passphrase", PASSPHRASE.to_string()),
+        ("Enter a hint", String::new()),
+        ("Add another factor", "n".to_string()),
+        ("Choose how to derive", "PBKDF2".to_string()),
+        ("How many iterations should PBKDF2 take", "1".to_string()),
+        ("Add another encryption option", "n".to_string()),
+        ("Enter the passphrase", PASSPHRASE.to_string()),
+    ])
+}
+
+/// Creates a header with a single passphrase option with the given name (see [`prompter`]),
+/// returning it with the encryptor for the body.
+pub(crate) fn header(name: &str) -> (Header, EncryptorBE32<ChaCha20Poly1305>) {
+    Header::new(&registry(), None, 1, &mut prompter(name)).unwrap()
+}
+
+/// Encrypts the given plaintext under the given header (recording the length of the body in it),
+/// returning the whole file.
+pub(crate) fn encrypt(
+    mut header: Header,
+    encryptor: EncryptorBE32<ChaCha20Poly1305>,
+    plaintext: &[u8],
+) -> Vec<u8> {
+    header
+        .set_body_len(ciphertext_len(plaintext.len() as u64))
+        .unwrap();
+    let mut file = Vec::new();
+    encrypt_file(&mut &plaintext[..], &mut file, &header, encryptor, None).unwrap();
+
+    file
+}
+
+/// Decrypts the given file with the option called `name`, which must have been set up by
+/// [`prompter`].
+pub(crate) fn decrypt(file: &[u8], name: &str) -> Result<Vec<u8>> {
+    let mut input = file;
+    let header = Header::from_file(&mut input)?;
+    let (decryptor, location) =
+        header.to_decryptor(&registry(), Some(name), false, 1, &mut prompter(name))?;
+    let mut plaintext = Vec::new();
+    decrypt_file(
+        &mut input,
+        location,
+        header.body_len(),
+        &mut plaintext,
+        decryptor,
+        &header.authenticated_data(),
+        None,
+    )?;
+
+    Ok(plaintext)
+}
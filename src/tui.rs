@@ -0,0 +1,553 @@
+//! Full-screen views of encryption options as a tree, with each option above its factors: an editor
+//! for laying out new options before any of them are created (see [`Prompter::plan_options`]), and
+//! a browser for the options of an existing file. These are drawn with plain terminal control
+//! sequences, and anything that has to be typed in (like an option's name) is asked for with the
+//! usual prompts, with the tree drawn again afterwards.
+
+use crate::{
+    factor::FactorRegistry,
+    header::{check_plans, choose_factor, prompt_hint, FactorPlan, Header, OptionPlan},
+    kdf::Kdf,
+    prompt::{Cancelled, Prompter},
+};
+use anyhow::Result;
+use dialoguer::console::{style, truncate_str, Key, Term};
+
+/// The keys the editor understands, shown underneath it.
+const EDITOR_KEYS: &str = "Up/Down move, a add option, f add factor, Enter edit, x remove, [ ] reorder, g gate, b put behind gate, q quorum, k KDF, c create, Esc cancel";
+/// The keys the browser understands, shown underneath it.
+const BROWSER_KEYS: &str = "Up/Down move, Esc or q close";
+
+/// A row of the tree: an option, or one of its factors (by their indices).
+#[derive(Clone, Copy, PartialEq)]
+enum Row {
+    Option(usize),
+    Factor(usize, usize),
+}
+impl Row {
+    /// Gets the index of the option this row is (or is in).
+    fn option(self) -> usize {
+        match self {
+            Self::Option(idx) | Self::Factor(idx, _) => idx,
+        }
+    }
+}
+
+/// Lets the user lay out a series of new options in a full-screen editor, returning them once
+/// they're ready to be created (checked with [`check_plans`]). The new options can't reuse the
+/// names in `existing`, and can be put behind the `gates` that already exist, as well as behind
+/// new gates that come before them.
+pub(crate) fn edit_options(
+    term: &Term,
+    prompter: &mut dyn Prompter,
+    registry: &FactorRegistry,
+    existing: &[&str],
+    gates: &[&str],
+) -> Result<Vec<OptionPlan>> {
+    let mut editor = Editor {
+        plans: Vec::new(),
+        selected: 0,
+        status: Some("Press a to add the first option.".to_string()),
+        existing,
+        gates,
+        registry,
+    };
+    term.hide_cursor()?;
+    let result = editor.run(term, prompter);
+    term.show_cursor()?;
+    term.clear_screen()?;
+
+    result
+}
+
+/// Shows the options of the given header in a full-screen browser until the user closes it, with
+/// the given notes about the file as a whole underneath.
+pub(crate) fn browse_options(
+    term: &Term,
+    header: &Header,
+    registry: &FactorRegistry,
+    notes: &[String],
+) -> Result<()> {
+    let plans = header.option_plans();
+    let rows = rows(&plans);
+    let mut selected = 0;
+    term.hide_cursor()?;
+    let result = loop {
+        let mut footer = details(&plans, rows.get(selected).copied(), registry);
+        footer.push(String::new());
+        footer.extend(notes.iter().cloned());
+        if let Err(err) = draw(
+            term,
+            "Decryption options",
+            &plans,
+            &rows,
+            selected,
+            &footer,
+            BROWSER_KEYS,
+        ) {
+            break Err(err);
+        }
+        match term.read_key() {
+            Ok(Key::ArrowUp) => selected = selected.saturating_sub(1),
+            Ok(Key::ArrowDown) => selected = (selected + 1).min(rows.len().saturating_sub(1)),
+            Ok(Key::Escape | Key::Enter | Key::Char('q')) => break Ok(()),
+            Ok(_) => {}
+            Err(err) => break Err(err.into()),
+        }
+    };
+    term.show_cursor()?;
+    term.clear_screen()?;
+
+    result
+}
+
+/// The state of the option editor.
+struct Editor<'a> {
+    /// The options laid out so far, in the order they'll be created.
+    plans: Vec<OptionPlan>,
+    /// The index of the selected row.
+    selected: usize,
+    /// A message about the last thing that happened, shown until the next key is pressed.
+    status: Option<String>,
+    /// The names of the options that already exist.
+    existing: &'a [&'a str],
+    /// The gates that already exist.
+    gates: &'a [&'a str],
+    registry: &'a FactorRegistry,
+}
+impl Editor<'_> {
+    /// Runs the editor until the user creates the options or cancels.
+    fn run(&mut self, term: &Term, prompter: &mut dyn Prompter) -> Result<Vec<OptionPlan>> {
+        loop {
+            let rows = rows(&self.plans);
+            self.selected = self.selected.min(rows.len().saturating_sub(1));
+            let row = rows.get(self.selected).copied();
+
+            let mut footer = details(&self.plans, row, self.registry);
+            footer.push(String::new());
+            footer.push(match check_plans(&self.plans, self.existing, self.gates) {
+                Ok(()) => format!("Ready to create {} option(s) (press c).", self.plans.len()),
+                Err(err) => format!("Not ready yet: {err}."),
+            });
+            if let Some(status) = self.status.take() {
+                footer.push(status);
+            }
+            draw(
+                term,
+                "Set up the encryption options",
+                &self.plans,
+                &rows,
+                self.selected,
+                &footer,
+                EDITOR_KEYS,
+            )?;
+
+            let key = term.read_key()?;
+            let result = match key {
+                Key::ArrowUp => {
+                    self.selected = self.selected.saturating_sub(1);
+                    continue;
+                }
+                Key::ArrowDown => {
+                    self.selected += 1;
+                    continue;
+                }
+                Key::Char('c') => match check_plans(&self.plans, self.existing, self.gates) {
+                    Ok(()) => return Ok(std::mem::take(&mut self.plans)),
+                    Err(err) => {
+                        self.status = Some(format!("Can't create these options yet: {err}."));
+                        continue;
+                    }
+                },
+                Key::Escape => {
+                    term.clear_screen()?;
+                    if self.plans.is_empty() {
+                        return Err(Cancelled.into());
+                    }
+                    match prompter.confirm("Discard these options and cancel?") {
+                        Ok(true) => return Err(Cancelled.into()),
+                        result => result.map(|_| ()),
+                    }
+                }
+                _ => self.act(key, row, term, prompter),
+            };
+            match result {
+                Ok(()) => {}
+                // Backing out of one of the prompts just goes back to the editor
+                Err(err) if err.is::<Cancelled>() => {
+                    self.status = Some("Cancelled.".to_string());
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Does whatever the given key does to the selected row, asking for anything that has to be
+    /// typed in on the terminal (after clearing it).
+    fn act(
+        &mut self,
+        key: Key,
+        row: Option<Row>,
+        term: &Term,
+        prompter: &mut dyn Prompter,
+    ) -> Result<()> {
+        if key == Key::Char('a') {
+            term.clear_screen()?;
+            let name = self.prompt_name(prompter, None)?;
+            self.plans.push(OptionPlan::new(name));
+            self.select(Row::Option(self.plans.len() - 1));
+            self.status = Some("Press f to add a factor to the new option.".to_string());
+            return Ok(());
+        }
+        // Everything else needs something to act on
+        let Some(row) = row else {
+            return Ok(());
+        };
+        let idx = row.option();
+
+        match (key, row) {
+            (Key::Char('f'), _) => {
+                term.clear_screen()?;
+                let factor = choose_factor(self.registry, prompter)?;
+                let hint = prompt_hint(prompter)?;
+                self.plans[idx].factors.push(FactorPlan {
+                    name: factor.name().to_string(),
+                    hint,
+                });
+                self.select(Row::Factor(idx, self.plans[idx].factors.len() - 1));
+            }
+            (Key::Enter, Row::Option(_)) => {
+                term.clear_screen()?;
+                let old_name = self.plans[idx].name.clone();
+                let name = self.prompt_name(prompter, Some(idx))?;
+                let description = prompter.input(
+                    "Enter a description for this option (optional, enter a space to remove it)",
+                    Some(self.plans[idx].description.as_deref().unwrap_or("")),
+                    &|_| Ok(()),
+                )?;
+                // Keep anything behind this option (if it's a gate) behind it
+                for plan in &mut self.plans {
+                    if plan.gated_behind.as_ref() == Some(&old_name) {
+                        plan.gated_behind = Some(name.clone());
+                    }
+                }
+                let plan = &mut self.plans[idx];
+                plan.name = name;
+                plan.description =
+                    Some(description.trim().to_string()).filter(|desc| !desc.is_empty());
+            }
+            (Key::Enter, Row::Factor(_, factor_idx)) => {
+                term.clear_screen()?;
+                self.plans[idx].factors[factor_idx].hint = prompt_hint(prompter)?;
+            }
+            (Key::Char('x') | Key::Del, Row::Option(_)) => {
+                let plan = self.plans.remove(idx);
+                let mut freed = 0;
+                for other in &mut self.plans {
+                    if other.gated_behind.as_ref() == Some(&plan.name) {
+                        other.gated_behind = None;
+                        freed += 1;
+                    }
+                }
+                self.status = Some(if freed > 0 {
+                    format!(
+                        "Removed '{}', and took {freed} option(s) out from behind it.",
+                        plan.name
+                    )
+                } else {
+                    format!("Removed '{}'.", plan.name)
+                });
+            }
+            (Key::Char('x') | Key::Del, Row::Factor(_, factor_idx)) => {
+                let plan = &mut self.plans[idx];
+                plan.factors.remove(factor_idx);
+                // A quorum of all the factors (or more) is no quorum at all
+                plan.quorum = plan
+                    .quorum
+                    .filter(|&quorum| (quorum as usize) < plan.factors.len());
+            }
+            (Key::Char('['), Row::Option(_)) if idx > 0 => {
+                self.plans.swap(idx, idx - 1);
+                self.select(Row::Option(idx - 1));
+            }
+            (Key::Char(']'), Row::Option(_)) if idx + 1 < self.plans.len() => {
+                self.plans.swap(idx, idx + 1);
+                self.select(Row::Option(idx + 1));
+            }
+            (Key::Char('['), Row::Factor(_, factor_idx)) if factor_idx > 0 => {
+                self.plans[idx].factors.swap(factor_idx, factor_idx - 1);
+                self.select(Row::Factor(idx, factor_idx - 1));
+            }
+            (Key::Char(']'), Row::Factor(_, factor_idx))
+                if factor_idx + 1 < self.plans[idx].factors.len() =>
+            {
+                self.plans[idx].factors.swap(factor_idx, factor_idx + 1);
+                self.select(Row::Factor(idx, factor_idx + 1));
+            }
+            (Key::Char('g'), _) => {
+                let plan = &mut self.plans[idx];
+                plan.is_gate = !plan.is_gate;
+                if !plan.is_gate {
+                    let name = plan.name.clone();
+                    for other in &mut self.plans {
+                        if other.gated_behind.as_ref() == Some(&name) {
+                            other.gated_behind = None;
+                        }
+                    }
+                }
+            }
+            (Key::Char('b'), _) => {
+                // Only gates that come first can be chosen, so gates can never form a loop
+                let gates = self
+                    .gates
+                    .iter()
+                    .map(|gate| gate.to_string())
+                    .chain(
+                        self.plans[..idx]
+                            .iter()
+                            .filter(|plan| plan.is_gate)
+                            .map(|plan| plan.name.clone()),
+                    )
+                    .collect::<Vec<_>>();
+                if gates.is_empty() {
+                    self.status = Some(
+                        "There are no gates before this option to put it behind (move it after one, or press g on another option to make it a gate).".to_string(),
+                    );
+                    return Ok(());
+                }
+                term.clear_screen()?;
+                let items = ["(none)"]
+                    .into_iter()
+                    .chain(gates.iter().map(String::as_str))
+                    .collect::<Vec<_>>();
+                self.plans[idx].gated_behind =
+                    match prompter.select("Choose a gate to put this option behind", &items)? {
+                        0 => None,
+                        gate_idx => Some(gates[gate_idx - 1].clone()),
+                    };
+            }
+            (Key::Char('q'), _) => {
+                let num_factors = self.plans[idx].factors.len();
+                if num_factors < 2 || num_factors > u8::MAX as usize {
+                    self.status = Some(
+                        "Only an option with several factors can require just some of them."
+                            .to_string(),
+                    );
+                    return Ok(());
+                }
+                term.clear_screen()?;
+                let current = self.plans[idx]
+                    .quorum
+                    .map_or(num_factors, usize::from)
+                    .to_string();
+                let quorum = prompter.input(
+                    &format!("How many of the {num_factors} factors should be required?"),
+                    Some(&current),
+                    &|input| match input.trim().parse::<usize>() {
+                        Ok(quorum) if (1..=num_factors).contains(&quorum) => Ok(()),
+                        _ => Err(format!("must be a number from 1 to {num_factors}")),
+                    },
+                )?;
+                // We validated this above, and requiring all of them is just a normal option
+                self.plans[idx].quorum = Some(quorum.trim().parse::<u8>().unwrap())
+                    .filter(|&quorum| quorum as usize != num_factors);
+            }
+            (Key::Char('k'), _) => {
+                term.clear_screen()?;
+                self.plans[idx].kdf = Kdf::prompt(prompter)?;
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Prompts the user for the name of an option, which must be different from every other
+    /// option's (besides the one at `current`, if it's being renamed).
+    fn prompt_name(&self, prompter: &mut dyn Prompter, current: Option<usize>) -> Result<String> {
+        let taken = self
+            .existing
+            .iter()
+            .copied()
+            .chain(
+                self.plans
+                    .iter()
+                    .enumerate()
+                    .filter(|(idx, _)| Some(*idx) != current)
+                    .map(|(_, plan)| plan.name.as_str()),
+            )
+            .collect::<Vec<_>>();
+        let default = current.map(|idx| self.plans[idx].name.as_str());
+
+        prompter.input(
+            "Enter a name for this encryption option",
+            default,
+            &|name| {
+                if name.is_empty() {
+                    Err("the option needs a name".to_string())
+                } else if taken.contains(&name) {
+                    Err(format!("there's already an option named '{name}'"))
+                } else {
+                    Ok(())
+                }
+            },
+        )
+    }
+
+    /// Selects the given row.
+    fn select(&mut self, row: Row) {
+        self.selected = rows(&self.plans)
+            .iter()
+            .position(|&other| other == row)
+            .unwrap_or(0);
+    }
+}
+
+/// Lists the rows of the tree for the given options, with each option followed by its factors.
+fn rows(plans: &[OptionPlan]) -> Vec<Row> {
+    plans
+        .iter()
+        .enumerate()
+        .flat_map(|(idx, plan)| {
+            [Row::Option(idx)]
+                .into_iter()
+                .chain((0..plan.factors.len()).map(move |factor_idx| Row::Factor(idx, factor_idx)))
+        })
+        .collect()
+}
+
+/// Describes the given row of the tree in a single line.
+fn row_line(plans: &[OptionPlan], row: Row) -> String {
+    match row {
+        Row::Option(idx) => {
+            let plan = &plans[idx];
+            let mut line = plan.name.clone();
+            if plan.is_gate {
+                line.push_str(" [gate]");
+            }
+            if let Some(gate) = &plan.gated_behind {
+                line.push_str(&format!(" (behind '{gate}')"));
+            }
+            line.push_str(&match (plan.factors.len(), plan.quorum) {
+                (0, _) => " \u{2014} no factors yet".to_string(),
+                (num_factors, Some(quorum)) => {
+                    format!(" \u{2014} any {quorum} of {num_factors} factors")
+                }
+                (num_factors, None) => format!(" \u{2014} {num_factors} factor(s)"),
+            });
+            line.push_str(&format!(", derived with {}", plan.kdf.name()));
+
+            line
+        }
+        Row::Factor(idx, factor_idx) => {
+            let factors = &plans[idx].factors;
+            let branch = if factor_idx + 1 == factors.len() {
+                "\u{2514}\u{2500}"
+            } else {
+                "\u{251c}\u{2500}"
+            };
+            let factor = &factors[factor_idx];
+            match &factor.hint {
+                Some(hint) => format!("  {branch} {} (hint: {hint})", factor.name),
+                None => format!("  {branch} {}", factor.name),
+            }
+        }
+    }
+}
+
+/// Describes the given row of the tree in more detail, for showing underneath it.
+fn details(plans: &[OptionPlan], row: Option<Row>, registry: &FactorRegistry) -> Vec<String> {
+    match row {
+        None => vec!["There are no options yet.".to_string()],
+        Some(Row::Option(idx)) => {
+            let plan = &plans[idx];
+            let mut lines = vec![format!(
+                "Option '{}': {}",
+                plan.name,
+                plan.description.as_deref().unwrap_or("(no description)")
+            )];
+            lines.push(if plan.is_gate {
+                "A gate, which only unlocks the options behind it.".to_string()
+            } else {
+                "Decrypts the file.".to_string()
+            });
+            let behind = plans
+                .iter()
+                .filter(|other| other.gated_behind.as_ref() == Some(&plan.name))
+                .map(|other| other.name.as_str())
+                .collect::<Vec<_>>();
+            if !behind.is_empty() {
+                lines.push(format!("Options behind it: {}", behind.join(", ")));
+            }
+
+            lines
+        }
+        Some(Row::Factor(idx, factor_idx)) => {
+            let factor = &plans[idx].factors[factor_idx];
+            vec![
+                format!(
+                    "Factor '{}': {}",
+                    factor.name,
+                    registry
+                        .get(factor.name.as_str())
+                        .map_or("(not available in this build)", |factor| factor
+                            .description())
+                ),
+                format!("Hint: {}", factor.hint.as_deref().unwrap_or("(none)")),
+            ]
+        }
+    }
+}
+
+/// Draws the tree of the given options on the terminal, with the selected row highlighted, and the
+/// given lines and list of keys underneath. Only as many rows as fit are drawn, scrolling to keep
+/// the selected one in view.
+fn draw(
+    term: &Term,
+    title: &str,
+    plans: &[OptionPlan],
+    rows: &[Row],
+    selected: usize,
+    footer: &[String],
+    keys: &str,
+) -> Result<()> {
+    let (height, width) = term.size();
+    let width = width as usize;
+    // The title and a blank line, then the tree, then a blank line, the footer, another blank line,
+    // and the keys
+    let space = (height as usize).saturating_sub(footer.len() + 5).max(1);
+    let first = selected.saturating_sub(space - 1);
+
+    let mut screen = vec![style(title).bold().to_string(), String::new()];
+    if rows.is_empty() {
+        screen.push(style("(no options)").dim().to_string());
+    }
+    for (idx, &row) in rows.iter().enumerate().skip(first).take(space) {
+        let line = truncate_str(&row_line(plans, row), width, "\u{2026}").into_owned();
+        screen.push(if idx == selected {
+            style(line).reverse().to_string()
+        } else if let Row::Option(_) = row {
+            style(line).bold().to_string()
+        } else {
+            line
+        });
+    }
+    screen.push(String::new());
+    screen.extend(
+        footer
+            .iter()
+            .map(|line| truncate_str(line, width, "\u{2026}").into_owned()),
+    );
+    screen.push(String::new());
+    screen.push(
+        style(truncate_str(keys, width, "\u{2026}"))
+            .dim()
+            .to_string(),
+    );
+
+    term.clear_screen()?;
+    term.write_str(&screen.join("\n"))?;
+    term.flush()?;
+
+    Ok(())
+}
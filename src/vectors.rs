@@ -0,0 +1,448 @@
+//! Test vectors for the file format, to catch changes that would stop files encrypted by one
+//! version of cyst from being decrypted by another.
+//!
+//! Each vector is a small file encrypted with a single option, with all its randomness coming from
+//! a seeded RNG (see [`with_rng`]), so encrypting it again must give exactly the same bytes. The
+//! vectors generated for every format version so far are committed in `vectors/` and built in, and
+//! [`check`] makes sure that all of them still decrypt, and that the current
+//! [`FORMAT_VERSION`]'s are still generated byte-for-byte. Anything that changes how headers are
+//! serialized, how keys are derived, or how the body is encrypted will fail one of those.
+//!
+//! If the format is changed on purpose, bump [`FORMAT_VERSION`], write the new vectors to a new
+//! directory with [`generate`] (or `cyst check-vectors --write`), and add them to the list here.
+//! The old vectors stay, so files from older versions keep being checked.
+
+use crate::{
+    factor::{Factor, FactorRegistry},
+    factors::{KeyfileFactor, PassphraseFactor, PASSPHRASE_ENV, PASSPHRASE_FILE_ENV},
+    file::{ciphertext_len, decrypt_file, encrypt_file, plaintext_hash, ENCRYPTION_BUF_SIZE},
+    header::{FileMetadata, Header, FORMAT_VERSION},
+    padding::{PaddedReader, PaddingScheme, UnpaddingWriter},
+    prompt::{Prompter, Verbosity},
+    rng::with_rng,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use log::debug;
+use rand::SeedableRng;
+use rand_chacha::ChaCha20Rng;
+use std::io::Write;
+
+/// The names of the vectors. Most are encrypted with a single factor of the same name, but the rest
+/// are encrypted with a passphrase: `padded` has its plaintext padded (see [`crate::padding`]),
+/// `exact-chunks` has a plaintext that exactly fills two chunks, so its last chunk is a full one,
+/// and `suffixed` records the suffix added to its name (see [`Header::set_added_suffix`]).
+pub const VECTORS: &[&str] = &[
+    "passphrase",
+    "keyfile",
+    "padded",
+    "exact-chunks",
+    "suffixed",
+];
+/// The seed of the RNG every vector is generated with.
+const SEED: [u8; 32] = *b"cyst test vector seed, not a key";
+/// The passphrase of the passphrase vector.
+const PASSPHRASE: &str = "correct horse battery staple";
+/// How long each vector's plaintext is, which is enough to span several chunks, unless it's
+/// testing a particular length.
+const PLAINTEXT_LEN: usize = 10_000;
+
+/// A vector committed for a format version.
+struct Committed {
+    /// The format version the vector was generated for.
+    version: u32,
+    /// The name of the vector.
+    name: &'static str,
+    /// The encrypted file.
+    file: &'static [u8],
+    /// The keyfile needed to decrypt it, if it needs one.
+    keyfile: Option<&'static [u8]>,
+}
+
+/// Every vector that's been committed, for every format version.
+const COMMITTED: &[Committed] = &[
+    Committed {
+        version: 1,
+        name: "passphrase",
+        file: include_bytes!("../vectors/v1/passphrase.cyst"),
+        keyfile: None,
+    },
+    Committed {
+        version: 1,
+        name: "keyfile",
+        file: include_bytes!("../vectors/v1/keyfile.cyst"),
+        keyfile: Some(include_bytes!("../vectors/v1/keyfile.key")),
+    },
+    Committed {
+        version: 2,
+        name: "passphrase",
+        file: include_bytes!("../vectors/v2/passphrase.cyst"),
+        keyfile: None,
+    },
+    Committed {
+        version: 2,
+        name: "keyfile",
+        file: include_bytes!("../vectors/v2/keyfile.cyst"),
+        keyfile: Some(include_bytes!("../vectors/v2/keyfile.key")),
+    },
+    Committed {
+        version: 2,
+        name: "padded",
+        file: include_bytes!("../vectors/v2/padded.cyst"),
+        keyfile: None,
+    },
+    Committed {
+        version: 2,
+        name: "exact-chunks",
+        file: include_bytes!("../vectors/v2/exact-chunks.cyst"),
+        keyfile: None,
+    },
+    Committed {
+        version: 3,
+        name: "passphrase",
+        file: include_bytes!("../vectors/v3/passphrase.cyst"),
+        keyfile: None,
+    },
+    Committed {
+        version: 3,
+        name: "keyfile",
+        file: include_bytes!("../vectors/v3/keyfile.cyst"),
+        keyfile: Some(include_bytes!("../vectors/v3/keyfile.key")),
+    },
+    Committed {
+        version: 3,
+        name: "padded",
+        file: include_bytes!("../vectors/v3/padded.cyst"),
+        keyfile: None,
+    },
+    Committed {
+        version: 3,
+        name: "exact-chunks",
+        file: include_bytes!("../vectors/v3/exact-chunks.cyst"),
+        keyfile: None,
+    },
+    Committed {
+        version: 3,
+        name: "suffixed",
+        file: include_bytes!("../vectors/v3/suffixed.cyst"),
+        keyfile: None,
+    },
+    Committed {
+        version: 4,
+        name: "passphrase",
+        file: include_bytes!("../vectors/v4/passphrase.cyst"),
+        keyfile: None,
+    },
+    Committed {
+        version: 4,
+        name: "keyfile",
+        file: include_bytes!("../vectors/v4/keyfile.cyst"),
+        keyfile: Some(include_bytes!("../vectors/v4/keyfile.key")),
+    },
+    Committed {
+        version: 4,
+        name: "padded",
+        file: include_bytes!("../vectors/v4/padded.cyst"),
+        keyfile: None,
+    },
+    Committed {
+        version: 4,
+        name: "exact-chunks",
+        file: include_bytes!("../vectors/v4/exact-chunks.cyst"),
+        keyfile: None,
+    },
+    Committed {
+        version: 4,
+        name: "suffixed",
+        file: include_bytes!("../vectors/v4/suffixed.cyst"),
+        keyfile: None,
+    },
+];
+
+/// Checks every committed vector against this version of cyst, returning a description of each
+/// check that passed, and failing on the first one that doesn't. The passphrase mustn't be given
+/// through the environment (see [`PASSPHRASE_ENV`]), since it would be used in place of the
+/// vector's.
+pub fn check() -> Result<Vec<String>> {
+    if std::env::var_os(PASSPHRASE_ENV).is_some() || std::env::var_os(PASSPHRASE_FILE_ENV).is_some()
+    {
+        bail!(
+            "${PASSPHRASE_ENV} and ${PASSPHRASE_FILE_ENV} must be unset to check the test vectors"
+        );
+    }
+
+    let mut passed = Vec::new();
+    for committed in COMMITTED {
+        let Committed {
+            version,
+            name,
+            file,
+            keyfile,
+        } = committed;
+        let decrypted = decrypt(name, file, *keyfile).with_context(|| {
+            format!("the '{name}' test vector for format version {version} no longer decrypts")
+        })?;
+        if decrypted != plaintext(name) {
+            bail!("the '{name}' test vector for format version {version} decrypts to the wrong plaintext");
+        }
+        passed.push(format!(
+            "The '{name}' vector for format version {version} decrypts."
+        ));
+
+        if *version == FORMAT_VERSION {
+            let (generated, generated_keyfile) = generate(name)?;
+            if let Some(idx) = first_difference(&generated, file) {
+                bail!(
+                    "encrypting the '{name}' test vector no longer gives the committed file (they \
+                     differ from byte {idx}), so the file format has changed (if that's on purpose, \
+                     bump the format version and add new vectors)"
+                );
+            }
+            if generated_keyfile.as_deref() != *keyfile {
+                bail!("the keyfile generated for the '{name}' test vector isn't the committed one, so keyfiles are generated differently");
+            }
+            passed.push(format!(
+                "The '{name}' vector for format version {version} is generated exactly."
+            ));
+        }
+    }
+    if !COMMITTED
+        .iter()
+        .any(|committed| committed.version == FORMAT_VERSION)
+    {
+        bail!("no test vectors have been committed for format version {FORMAT_VERSION}");
+    }
+
+    Ok(passed)
+}
+
+/// Generates the vector with the given name (one of [`VECTORS`]) with this version of cyst,
+/// returning the encrypted file, and the keyfile needed to decrypt it if it needs one.
+pub fn generate(name: &str) -> Result<(Vec<u8>, Option<Vec<u8>>)> {
+    let factor = match name {
+        "passphrase" | "padded" | "exact-chunks" | "suffixed" => PassphraseFactor::name(),
+        "keyfile" => KeyfileFactor::name(),
+        _ => bail!("there's no test vector named '{name}'"),
+    };
+    let dir = tempfile::tempdir()?;
+    let keyfile_path = dir.path().join("keyfile");
+    let mut prompter = ScriptedPrompter::new(vec![
+        ("Enter a name for this encryption option", name.to_string()),
+        ("Enter a description", String::new()),
+        ("Make this option a gate", "n".to_string()),
+        ("Choose an encryption factor", factor.to_string()),
+        ("Enter a passphrase", PASSPHRASE.to_string()),
+        ("Confirm the passphrase", PASSPHRASE.to_string()),
+        (
+            "Enter a path to write the keyfile to",
+            keyfile_path.to_string_lossy().into_owned(),
+        ),
+        ("Enter a hint", String::new()),
+        ("Add another factor", "n".to_string()),
+        ("Choose how to derive", "Argon2id".to_string()),
+        ("Add another encryption option", "n".to_string()),
+    ]);
+
+    let plaintext = plaintext(name);
+    let padding = (name == "padded").then_some(PaddingScheme::PowerOfTwo);
+    let file = with_rng(ChaCha20Rng::from_seed(SEED), || -> Result<Vec<u8>> {
+        // Metadata would give away the length that padding hides
+        let metadata = match padding {
+            Some(_) => None,
+            None => Some(FileMetadata {
+                filename: Some(format!("{name}.bin")),
+                mime: Some("application/octet-stream".to_string()),
+                comment: Some(format!("The '{name}' test vector")),
+                size: plaintext.len() as u64,
+                hash: Some(plaintext_hash(&mut plaintext.as_slice())?),
+                mode: None,
+            }),
+        };
+        let (mut header, encryptor) = Header::new(&registry(), metadata, 1, &mut prompter)?;
+        let mut file = Vec::new();
+        match padding {
+            Some(padding) => {
+                header.set_plaintext_padding(padding)?;
+                header.set_body_len(ciphertext_len(padding.padded_len(plaintext.len() as u64)))?;
+                header.set_public_note(format!("The '{name}' test vector, not a secret"))?;
+                encrypt_file(
+                    &mut PaddedReader::new(plaintext.as_slice(), plaintext.len() as u64, padding),
+                    &mut file,
+                    &header,
+                    encryptor,
+                    None,
+                )?;
+            }
+            None => {
+                // If the last chunk weren't handled properly, this would be the wrong length
+                header.set_body_len(ciphertext_len(plaintext.len() as u64))?;
+                header.set_public_note(format!("The '{name}' test vector, not a secret"))?;
+                if name == "suffixed" {
+                    header.set_added_suffix(".cyst".to_string())?;
+                }
+                encrypt_file(
+                    &mut plaintext.as_slice(),
+                    &mut file,
+                    &header,
+                    encryptor,
+                    None,
+                )?;
+            }
+        }
+        Ok(file)
+    })?;
+    let keyfile = keyfile_path
+        .exists()
+        .then(|| std::fs::read(&keyfile_path))
+        .transpose()?;
+
+    Ok((file, keyfile))
+}
+
+/// Decrypts the vector with the given name from the given file (with the given keyfile, if it
+/// needs one).
+fn decrypt(name: &str, file: &[u8], keyfile: Option<&[u8]>) -> Result<Vec<u8>> {
+    let dir = tempfile::tempdir()?;
+    let keyfile_path = dir.path().join("keyfile");
+    if let Some(keyfile) = keyfile {
+        std::fs::write(&keyfile_path, keyfile)?;
+    }
+    let mut prompter = ScriptedPrompter::new(vec![
+        ("Enter the passphrase", PASSPHRASE.to_string()),
+        (
+            "Enter the path to the keyfile",
+            keyfile_path.to_string_lossy().into_owned(),
+        ),
+    ]);
+
+    let mut input = file;
+    let header = Header::from_file(&mut input)?;
+    let (decryptor, location) =
+        header.to_decryptor(&registry(), Some(name), false, 1, &mut prompter)?;
+    let mut plaintext = Vec::new();
+    let decrypt = |mut output: &mut dyn Write| {
+        decrypt_file(
+            &mut input,
+            location,
+            header.body_len(),
+            &mut output,
+            decryptor,
+            &header.authenticated_data(),
+            None,
+        )
+    };
+    let complete = match header.plaintext_padding() {
+        Some(_) => {
+            let mut output = UnpaddingWriter::new(&mut plaintext);
+            let complete = decrypt(&mut output)?;
+            if complete {
+                output.finish()?;
+            }
+            complete
+        }
+        None => decrypt(&mut plaintext)?,
+    };
+    if !complete {
+        bail!("the file ended early");
+    }
+
+    Ok(plaintext)
+}
+
+/// The plaintext of the vector with the given name, which is just a fixed pattern of bytes.
+fn plaintext(name: &str) -> Vec<u8> {
+    let len = match name {
+        "exact-chunks" => 2 * ENCRYPTION_BUF_SIZE as usize,
+        _ => PLAINTEXT_LEN,
+    };
+    (0..len).map(|idx| (idx * 7 % 251) as u8).collect()
+}
+
+/// The factors the vectors use. This is built separately from the CLI's registry, so which other
+/// factors are enabled can't change the prompts.
+pub(crate) fn registry() -> FactorRegistry {
+    let mut factors = FactorRegistry::new();
+    factors.insert(PassphraseFactor::name(), Box::new(PassphraseFactor));
+    factors.insert(KeyfileFactor::name(), Box::new(KeyfileFactor));
+
+    factors
+}
+
+/// Finds the index of the first byte at which the given byte strings differ, if they differ at
+/// all.
+fn first_difference(a: &[u8], b: &[u8]) -> Option<usize> {
+    a.iter()
+        .zip(b)
+        .position(|(a, b)| a != b)
+        .or_else(|| (a.len() != b.len()).then(|| a.len().min(b.len())))
+}
+
+/// A prompter that answers every question from a script, so the vectors can be generated and
+/// decrypted without anyone there. Each answer goes to the questions starting with its prompt, and
+/// a choice is answered with the first item starting with the answer. Any other question is an
+/// error, since it means the prompts (and probably the format) have changed.
+pub(crate) struct ScriptedPrompter {
+    /// The answers, each with the start of the questions it answers.
+    answers: Vec<(&'static str, String)>,
+}
+impl ScriptedPrompter {
+    /// Creates a prompter that answers from the given script.
+    pub(crate) fn new(answers: Vec<(&'static str, String)>) -> Self {
+        Self { answers }
+    }
+
+    /// Answers the questions starting with the given prompt with the given answer from now on,
+    /// instead of whatever the script said.
+    #[cfg(test)]
+    pub(crate) fn set(&mut self, prompt: &'static str, answer: &str) {
+        self.answers.insert(0, (prompt, answer.to_string()));
+    }
+
+    /// Finds the answer to the given question.
+    fn answer(&self, prompt: &str) -> Result<&str> {
+        self.answers
+            .iter()
+            .find(|(start, _)| prompt.starts_with(start))
+            .map(|(_, answer)| answer.as_str())
+            .ok_or_else(|| anyhow!("unexpected question while running a test vector: {prompt:?}"))
+    }
+}
+impl Prompter for ScriptedPrompter {
+    fn input(
+        &mut self,
+        prompt: &str,
+        _default: Option<&str>,
+        validate: &dyn Fn(&str) -> Result<(), String>,
+    ) -> Result<String> {
+        let answer = self.answer(prompt)?;
+        validate(answer).map_err(|err| anyhow!("the answer to {prompt:?} was rejected: {err}"))?;
+        Ok(answer.to_string())
+    }
+    fn password(
+        &mut self,
+        prompt: &str,
+        validate: &dyn Fn(&str) -> Result<(), String>,
+    ) -> Result<String> {
+        self.input(prompt, None, validate)
+    }
+    fn select(&mut self, prompt: &str, items: &[&str]) -> Result<usize> {
+        let answer = self.answer(prompt)?;
+        items
+            .iter()
+            .position(|item| item.starts_with(answer))
+            .ok_or_else(|| anyhow!("there's no choice {answer:?} for {prompt:?}"))
+    }
+    fn confirm(&mut self, prompt: &str) -> Result<bool> {
+        Ok(self.answer(prompt)? == "y")
+    }
+    fn message(&mut self, _verbosity: Verbosity, msg: &str) {
+        debug!("Test vector message: {msg}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn vectors() {
+        super::check().unwrap();
+    }
+}